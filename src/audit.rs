@@ -0,0 +1,94 @@
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tracing::{info, warn};
+
+// Record is one line of the audit log, for an operation a rule fired on or
+// failed
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Record<'a> {
+    pub timestamp: String,
+    pub op: String,
+    #[serde(serialize_with = "crate::utils::os_path::serialize")]
+    pub path: &'a Path,
+    // the process calling the operation, if it's known
+    pub pid: Option<u32>,
+    pub experiment: &'a str,
+    pub rule: usize,
+    // the type of the rule which fired, or the errno returned to the caller
+    // once it failed the operation
+    pub action: String,
+}
+
+// The log is written a line at a time, so that it's complete up to the last
+// injection even if toda is killed.
+static LOG: Lazy<Mutex<Option<LineWriter<File>>>> = Lazy::new(|| Mutex::new(None));
+
+// The log is only locked once it's opened, as every operation checks it.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// open appends the audit log to the file. It's opened before the namespaces
+// are entered, so the path is the one seen by the caller of toda.
+pub fn open(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("open audit log {}", path.display()))?;
+    info!("audit injections to {}", path.display());
+    configure(file);
+    Ok(())
+}
+
+// open_fd writes the audit log to the inherited file descriptor
+pub fn open_fd(fd: RawFd) -> Result<()> {
+    // the descriptor is checked, as writing to a closed one would only fail
+    // on the first fault
+    nix::fcntl::fcntl(fd, nix::fcntl::FcntlArg::F_GETFD)
+        .with_context(|| format!("audit log fd {}", fd))?;
+    info!("audit injections to fd {}", fd);
+    configure(unsafe { File::from_raw_fd(fd) });
+    Ok(())
+}
+
+fn configure(file: File) {
+    *LOG.lock().unwrap() = Some(LineWriter::new(file));
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+// close stops the audit and closes the log
+pub fn close() {
+    ENABLED.store(false, Ordering::SeqCst);
+    *LOG.lock().unwrap() = None;
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn record(record: &Record) {
+    let mut log = LOG.lock().unwrap();
+    let writer = match log.as_mut() {
+        Some(writer) => writer,
+        None => return,
+    };
+    let result = serde_json::to_writer(&mut *writer, record)
+        .map_err(std::io::Error::from)
+        .and_then(|_| writer.write_all(b"\n"));
+    if let Err(err) = result {
+        warn!("fail to write audit record {:?}: {}", record, err);
+    }
+}
+
+pub fn timestamp() -> String {
+    humantime::format_rfc3339_micros(SystemTime::now()).to_string()
+}
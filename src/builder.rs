@@ -0,0 +1,311 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use tracing::{info, instrument, warn};
+
+use crate::fuse_device;
+use crate::hookfs::budget::{MemoryBudget, OverflowPolicy};
+use crate::hookfs::{HookFs, KernelOptions, Overlay};
+use crate::injector::{DrainPolicy, Experiment, Experiments, InjectorConfig, DEFAULT_EXPERIMENT};
+use crate::manifest::Manifest;
+use crate::mount_injector::{MountInjectionGuard, MountInjector};
+use crate::replacer::{Replacer, UnionReplacer};
+use crate::utils::encode_path;
+
+// TodaBuilder configures an injection into a mounted path, for the tools
+// embedding toda instead of running the binary. `inject` mounts the hookfs
+// over the path like toda does on start, and the returned `Toda` recovers it
+// like toda does on exit. The control plane (the jsonrpc, the signals) is
+// left to the caller, which drives the rules through `Toda::update`.
+//
+// Several paths can be injected at once, each with its own rules, drain policy
+// and memory budget. The tokio runtime, the memory budget of the injectors,
+// the audit log, the metrics and the recorded decisions are shared by the
+// whole process.
+#[derive(Debug, Clone)]
+pub struct TodaBuilder {
+    path: PathBuf,
+    rules: Vec<InjectorConfig>,
+    mount_only: bool,
+    keep_cwd: bool,
+    shadow: Option<PathBuf>,
+    shadow_commit: bool,
+    snapshot: Option<PathBuf>,
+    checksum: bool,
+    experiment: Option<String>,
+    manifest: Option<PathBuf>,
+    drain: DrainPolicy,
+    shutdown_timeout: Duration,
+    kernel: KernelOptions,
+    memory: Option<(Option<usize>, OverflowPolicy)>,
+}
+
+impl TodaBuilder {
+    pub fn new<P: AsRef<Path>>(path: P) -> TodaBuilder {
+        TodaBuilder {
+            path: path.as_ref().to_owned(),
+            rules: Vec::new(),
+            mount_only: false,
+            keep_cwd: false,
+            shadow: None,
+            shadow_commit: false,
+            snapshot: None,
+            checksum: false,
+            experiment: None,
+            manifest: None,
+            drain: DrainPolicy::Release,
+            shutdown_timeout: Duration::from_secs(10),
+            kernel: KernelOptions::default(),
+            memory: None,
+        }
+    }
+
+    // rules are injected from the start
+    pub fn rules(mut self, rules: Vec<InjectorConfig>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    // mount_only skips replacing the files opened by the processes under the
+    // path, which keep using the original mount
+    pub fn mount_only(mut self, mount_only: bool) -> Self {
+        self.mount_only = mount_only;
+        self
+    }
+
+    pub fn keep_cwd(mut self, keep_cwd: bool) -> Self {
+        self.keep_cwd = keep_cwd;
+        self
+    }
+
+    // shadow redirects the writes to the directory, which is committed to the
+    // original mount on recovery or discarded
+    pub fn shadow<P: AsRef<Path>>(mut self, path: P, commit: bool) -> Self {
+        self.shadow = Some(path.as_ref().to_owned());
+        self.shadow_commit = commit;
+        self
+    }
+
+    pub fn snapshot<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.snapshot = Some(path.as_ref().to_owned());
+        self
+    }
+
+    pub fn verify_checksum(mut self, checksum: bool) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    pub fn experiment(mut self, experiment: &str) -> Self {
+        self.experiment = Some(experiment.to_owned());
+        self
+    }
+
+    // manifest is captured before the injection, to verify the path against
+    // after the recovery
+    pub fn manifest<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.manifest = Some(path.as_ref().to_owned());
+        self
+    }
+
+    pub fn drain(mut self, drain: DrainPolicy) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    // kernel_options tunes the caching and the size of the requests of the
+    // kernel, which trade the fidelity of the injection for the throughput
+    pub fn kernel_options(mut self, kernel: KernelOptions) -> Self {
+        self.kernel = kernel;
+        self
+    }
+
+    // memory_budget bounds the checksums and the journals of the mount with
+    // its own budget, instead of the one of the process
+    pub fn memory_budget(mut self, limit: Option<usize>, policy: OverflowPolicy) -> Self {
+        self.memory = Some((limit, policy));
+        self
+    }
+
+    #[instrument(skip(self))]
+    pub fn inject(self) -> Result<Toda> {
+        info!("inject with config {:?}", self.rules);
+
+        info!("canonicalizing path {}", self.path.display());
+        let path = self.path.canonicalize()?;
+
+        // the rules are validated before the mount, which would be left behind
+        // by an error later
+        let mut experiments = Experiments::default();
+        experiments.start(Experiment::build(
+            DEFAULT_EXPERIMENT.to_owned(),
+            self.rules.clone(),
+            None,
+        )?);
+
+        if let Some(manifest) = &self.manifest {
+            Manifest::capture(&path)?.save(manifest)?;
+            info!("manifest saved to {}", manifest.display());
+        }
+
+        let replacer = if !self.mount_only {
+            let mut replacer = UnionReplacer::default();
+            if self.keep_cwd {
+                replacer = replacer.keep_cwd();
+            }
+            replacer.prepare(&path, &path)?;
+
+            Some(replacer)
+        } else {
+            None
+        };
+
+        if let Err(err) = fuse_device::mkfuse_node() {
+            info!("fail to make /dev/fuse node: {}", err)
+        }
+
+        let mut injection = MountInjector::create_injection(&self.path, Vec::new())?;
+        if let Some(shadow) = &self.shadow {
+            injection.set_shadow(shadow);
+        }
+        if let Some(snapshot) = &self.snapshot {
+            injection.set_snapshot(snapshot);
+        }
+        if self.checksum {
+            injection.enable_checksum();
+        }
+        if let Some(experiment) = &self.experiment {
+            injection.set_experiment(experiment);
+        }
+        injection.set_kernel_options(self.kernel.clone());
+        if let Some((limit, policy)) = &self.memory {
+            injection.set_memory_budget(Arc::new(MemoryBudget::new(*limit, policy.clone())));
+        }
+        let guard = injection.mount()?;
+        info!("mount successfully");
+        let hookfs = guard.hookfs.clone();
+        futures::executor::block_on(async {
+            *hookfs.injector.write().await = experiments.injector(&hookfs.label().path);
+        });
+
+        if let Some(mut replacer) = replacer {
+            // At this time, `mount --move` has already been executed.
+            // Our FUSE are mounted on the "path", so we replace the files
+            // the processes opened under it with the ones opened through it.
+            replacer.run()?;
+            drop(replacer);
+            info!("replacer detached");
+        }
+
+        info!("enable injection");
+        guard.enable_injection();
+
+        Ok(Toda {
+            hookfs,
+            guard,
+            experiments: Mutex::new(experiments),
+            options: self,
+        })
+    }
+}
+
+// Toda is an injection mounted by `TodaBuilder::inject`. It's recovered by
+// `recover`, and left behind if it's dropped, like a killed toda.
+pub struct Toda {
+    pub hookfs: Arc<HookFs>,
+    guard: MountInjectionGuard,
+    experiments: Mutex<Experiments>,
+    options: TodaBuilder,
+}
+
+impl Toda {
+    pub fn path(&self) -> &Path {
+        &self.options.path
+    }
+
+    // update replaces the rules being injected, and releases the operations
+    // delayed by the replaced ones. The rules are kept if the new ones are
+    // invalid.
+    pub fn update(&self, rules: Vec<InjectorConfig>) -> Result<()> {
+        let experiment = Experiment::build(DEFAULT_EXPERIMENT.to_owned(), rules, None)?;
+        let mut experiments = self.experiments.lock().unwrap();
+        experiments.start(experiment);
+        let injector = experiments.injector(&self.hookfs.label().path);
+        futures::executor::block_on(async {
+            *self.hookfs.injector.write().await = injector;
+        });
+        Ok(())
+    }
+
+    pub fn enable_injection(&self) {
+        self.guard.enable_injection();
+    }
+
+    pub fn disable_injection(&self) {
+        self.guard.disable_injection();
+    }
+
+    // recover stops the injection, waits for the operations in flight and
+    // restores the original mount
+    #[instrument(skip(self))]
+    pub fn recover(self) -> Result<()> {
+        let options = self.options;
+        self.hookfs.set_drain_policy(options.drain);
+        info!("disable injection");
+        self.guard.disable_injection();
+        let in_flight = self.hookfs.wait_idle(options.shutdown_timeout);
+        if in_flight > 0 {
+            warn!(
+                "{} operations still in flight after {:?}",
+                in_flight, options.shutdown_timeout
+            );
+        }
+
+        info!("memory usage: {:?}", self.hookfs.memory_usage());
+        if let Some(report) = self.hookfs.checksum_report() {
+            info!("checksum report: {}", serde_json::to_string(&report)?);
+        }
+
+        match self.hookfs.overlay() {
+            Some(Overlay::Shadow(shadow)) if options.shadow_commit => shadow.commit()?,
+            Some(Overlay::Shadow(shadow)) => shadow.discard()?,
+            Some(Overlay::Snapshot(snapshot)) => snapshot.remove()?,
+            None => {}
+        }
+
+        info!("canonicalizing path {}", options.path.display());
+        let path = options.path.canonicalize()?;
+        let (_, new_path) = encode_path(&path)?;
+
+        let replacer = if !options.mount_only {
+            let mut replacer = UnionReplacer::default();
+            if options.keep_cwd {
+                replacer = replacer.keep_cwd();
+            }
+            replacer.prepare(&path, &new_path)?;
+            info!("running replacer");
+            let result = replacer.run();
+            info!("replace result: {:?}", result);
+
+            Some(replacer)
+        } else {
+            None
+        };
+
+        info!("recovering mount");
+        self.guard.recover_mount()?;
+
+        info!("replacers detached");
+        info!("recover successfully");
+
+        drop(replacer);
+        Ok(())
+    }
+}
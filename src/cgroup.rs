@@ -0,0 +1,115 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use tracing::{error, info};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// the period of the cpu quota in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+// Cgroup confines toda itself, so that a runaway injector under heavy IO load
+// cannot starve the workload being tested. Only the unified hierarchy (cgroup
+// v2) is supported.
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    // the cgroup which toda was in before, to move back on release
+    original: PathBuf,
+    // whether the cgroup is created by toda and should be removed on release
+    created: bool,
+}
+
+impl Cgroup {
+    // join moves the whole process into an existing cgroup
+    pub fn join<P: AsRef<Path>>(path: P) -> Result<Cgroup> {
+        let path = path.as_ref().to_owned();
+        let original = current()?;
+        move_into(&path)?;
+
+        Ok(Cgroup {
+            path,
+            original,
+            created: false,
+        })
+    }
+
+    // create moves the whole process into a new cgroup with the cpu (in cores)
+    // and memory (in bytes) limits
+    pub fn create(cpu: Option<f64>, memory: Option<usize>) -> Result<Cgroup> {
+        let root = Path::new(CGROUP_ROOT);
+        if !root.join("cgroup.controllers").exists() {
+            return Err(anyhow!("cgroup v2 is not mounted on {}", CGROUP_ROOT));
+        }
+
+        let path = root.join(format!("toda-{}", std::process::id()));
+        info!("create cgroup {}", path.display());
+        let original = current()?;
+
+        let mut controllers = Vec::new();
+        if cpu.is_some() {
+            controllers.push("+cpu");
+        }
+        if memory.is_some() {
+            controllers.push("+memory");
+        }
+        if !controllers.is_empty() {
+            fs::write(root.join("cgroup.subtree_control"), controllers.join(" "))?;
+        }
+        fs::create_dir(&path)?;
+
+        let cgroup = Cgroup {
+            path,
+            original,
+            created: true,
+        };
+        if let Some(cpu) = cpu {
+            let quota = (cpu * CPU_PERIOD as f64) as u64;
+            fs::write(
+                cgroup.path.join("cpu.max"),
+                format!("{} {}", quota.max(1000), CPU_PERIOD),
+            )?;
+        }
+        if let Some(memory) = memory {
+            fs::write(cgroup.path.join("memory.max"), memory.to_string())?;
+        }
+        move_into(&cgroup.path)?;
+
+        Ok(cgroup)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // release moves the process back to its original cgroup, and removes the
+    // cgroup if it was created by toda
+    pub fn release(self) -> Result<()> {
+        move_into(&self.original)?;
+        if self.created {
+            info!("remove cgroup {}", self.path.display());
+            if let Err(err) = fs::remove_dir(&self.path) {
+                error!("fail to remove cgroup {}: {:?}", self.path.display(), err);
+            }
+        }
+        Ok(())
+    }
+}
+
+// current returns the cgroup of this process in the unified hierarchy
+fn current() -> Result<PathBuf> {
+    let content = fs::read_to_string("/proc/self/cgroup")?;
+    let path = content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .ok_or(anyhow!("not in a cgroup v2 hierarchy"))?;
+
+    Ok(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
+
+fn move_into(path: &Path) -> Result<()> {
+    info!("move into cgroup {}", path.display());
+    fs::write(path.join("cgroup.procs"), std::process::id().to_string())?;
+    Ok(())
+}
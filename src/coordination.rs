@@ -0,0 +1,372 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, trace, warn};
+
+use crate::injector::InjectorConfig;
+use crate::jsonrpc::ConfigLoader;
+
+// the time a follower waits for the first line of a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Request is sent by the leader on a new connection. The token is shared by
+// the leader and every follower, and a request with another one is refused.
+#[derive(Serialize, Deserialize, Debug)]
+struct Request {
+    token: String,
+    message: Message,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Message {
+    // Update carries the config to every follower. `at` is the unix timestamp
+    // in milliseconds when the configuration should be activated, so that all
+    // instances switch at the same time.
+    Update {
+        id: u64,
+        at: u64,
+        config: Vec<InjectorConfig>,
+    },
+    // Rollback restores the rules replaced by the update `id`, once it has
+    // been committed on some followers but not on all of them
+    Rollback {
+        id: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Response {
+    error: Option<String>,
+}
+
+// Decision is sent by the leader once every follower has responded. The
+// config is only activated if all of them have accepted it, and the follower
+// acknowledges the decision before the activation.
+#[derive(Serialize, Deserialize, Debug)]
+struct Decision {
+    commit: bool,
+}
+
+// Coordinator is owned by the leader. It forwards every update to all the
+// followers.
+#[derive(Debug)]
+pub struct Coordinator {
+    peers: Vec<String>,
+    delay: Duration,
+    token: String,
+}
+
+impl Coordinator {
+    pub fn new(peers: Vec<String>, delay: Duration, token: String) -> Coordinator {
+        Coordinator {
+            peers,
+            delay,
+            token,
+        }
+    }
+
+    // broadcast sends the config to all followers and returns the time when
+    // the config will be activated everywhere. The config is activated by
+    // either all or none of the followers: if a follower fails after the
+    // decision to commit, the followers which were told to commit are rolled
+    // back.
+    pub fn broadcast(&self, config: &[InjectorConfig]) -> Result<SystemTime> {
+        let id = rand::random();
+        let at = SystemTime::now() + self.delay;
+        let message = self.encode(Message::Update {
+            id,
+            at: at.duration_since(UNIX_EPOCH)?.as_millis() as u64,
+            config: config.to_vec(),
+        })?;
+
+        // every follower prepares at the same time, so that a slow one
+        // doesn't eat the delay of the others
+        let delay = self.delay;
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| (peer.clone(), message.clone()));
+        let mut prepared = Vec::new();
+        let mut errors = Vec::new();
+        for (peer, result) in parallel(peers, move |(peer, message)| {
+            let result = send(&peer, &message, delay);
+            (peer, result)
+        }) {
+            match result {
+                Ok(stream) => prepared.push((peer, stream)),
+                Err(err) => {
+                    error!("fail to update peer {}: {:?}", peer, err);
+                    errors.push(format!("{}: {}", peer, err));
+                }
+            }
+        }
+
+        let commit = errors.is_empty();
+        let decision = encode(&Decision { commit })?;
+        let committed: Vec<_> = prepared.iter().map(|(peer, _)| peer.clone()).collect();
+        for (peer, result) in parallel(prepared, move |(peer, stream)| {
+            let result = decide(stream, &decision);
+            (peer, result)
+        }) {
+            if let Err(err) = result {
+                error!("fail to send decision to peer {}: {:?}", peer, err);
+                errors.push(format!("{}: {}", peer, err));
+            }
+        }
+
+        if errors.is_empty() {
+            return Ok(at);
+        }
+        if commit {
+            // a follower which didn't acknowledge may have committed anyway
+            self.rollback(id, committed);
+        }
+        Err(anyhow!("fail to update peers: {}", errors.join(", ")))
+    }
+
+    fn rollback(&self, id: u64, peers: Vec<String>) {
+        warn!("roll back update {} on {} peers", id, peers.len());
+        let message = match self.encode(Message::Rollback { id }) {
+            Ok(message) => message,
+            Err(err) => {
+                error!("fail to encode rollback: {:?}", err);
+                return;
+            }
+        };
+        let delay = self.delay;
+        let peers = peers.into_iter().map(|peer| (peer, message.clone()));
+        for (peer, result) in parallel(peers, move |(peer, message)| {
+            let result = send(&peer, &message, delay);
+            (peer, result)
+        }) {
+            if let Err(err) = result {
+                error!("fail to roll back peer {}: {:?}", peer, err);
+            }
+        }
+    }
+
+    fn encode(&self, message: Message) -> Result<Vec<u8>> {
+        encode(&Request {
+            token: self.token.clone(),
+            message,
+        })
+    }
+}
+
+// parallel runs `f` on every item in its own thread, and returns the results
+// in the order of the items
+fn parallel<I, T, F>(items: I, f: F) -> Vec<T>
+where
+    I: IntoIterator,
+    I::Item: Send + 'static,
+    T: Send + 'static,
+    F: Fn(I::Item) -> T + Send + Sync + 'static,
+{
+    let f = std::sync::Arc::new(f);
+    let handles: Vec<_> = items
+        .into_iter()
+        .map(|item| {
+            let f = f.clone();
+            std::thread::spawn(move || f(item))
+        })
+        .collect();
+    handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect()
+}
+
+fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    let mut message = serde_json::to_vec(message)?;
+    message.push(b'\n');
+    Ok(message)
+}
+
+fn read<T: serde::de::DeserializeOwned>(reader: &mut impl BufRead) -> Result<T> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+// send sends the message to the peer, and returns the stream to send the
+// decision through once the peer has accepted it
+fn send(peer: &str, message: &[u8], timeout: Duration) -> Result<BufReader<TcpStream>> {
+    let addr = peer
+        .to_socket_addrs()?
+        .next()
+        .ok_or(anyhow!("cannot resolve address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(message)?;
+
+    let mut reader = BufReader::new(stream);
+    let response: Response = read(&mut reader)?;
+    match response.error {
+        Some(err) => Err(anyhow!(err)),
+        None => Ok(reader),
+    }
+}
+
+// decide sends the decision and waits for the acknowledgement of it
+fn decide(mut reader: BufReader<TcpStream>, decision: &[u8]) -> Result<()> {
+    reader.get_mut().write_all(decision)?;
+    let response: Response = read(&mut reader)?;
+    match response.error {
+        Some(err) => Err(anyhow!(err)),
+        None => Ok(()),
+    }
+}
+
+// wait_until blocks the current thread until the time `at`
+pub fn wait_until(at: SystemTime) {
+    if let Ok(duration) = at.duration_since(SystemTime::now()) {
+        trace!("wait {:?} before activation", duration);
+        std::thread::sleep(duration);
+    }
+}
+
+// listen binds the address to accept updates on. A bare port is bound on the
+// loopback interface only, as the followers trust nothing but the token.
+pub fn listen(addr: &str) -> Result<TcpListener> {
+    let listener = match addr.parse::<u16>() {
+        Ok(port) => TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))?,
+        Err(_) => TcpListener::bind(addr)?,
+    };
+    let local = listener.local_addr()?;
+    if !local.ip().is_loopback() {
+        warn!(
+            "listen for coordination on the non-loopback address {}",
+            local
+        );
+    }
+    info!("listen for coordination on {}", local);
+    Ok(listener)
+}
+
+// Follower applies the updates of the leader to the experiment of the loader,
+// through the same path as the update rpc
+struct Follower {
+    token: String,
+    loader: ConfigLoader,
+    // the id of the last committed update, and the config it replaced
+    last: Option<(u64, Option<Vec<InjectorConfig>>)>,
+}
+
+// serve accepts updates from the leader. The requests are handled one by one,
+// so that a rollback is only handled once the update it rolls back is
+// activated.
+pub fn serve(listener: TcpListener, token: String, loader: ConfigLoader) -> JoinHandle<()> {
+    let mut follower = Follower {
+        token,
+        loader,
+        last: None,
+    };
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| follower.handle(stream));
+            if let Err(err) = result {
+                error!("fail to handle coordination request: {:?}", err);
+            }
+        }
+    })
+}
+
+impl Follower {
+    fn handle(&mut self, stream: TcpStream) -> Result<()> {
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut reader = BufReader::new(stream);
+        let request: Request = read(&mut reader)?;
+        if !constant_time_eq(request.token.as_bytes(), self.token.as_bytes()) {
+            respond(reader.get_mut(), Err(anyhow!("invalid token")))?;
+            return Err(anyhow!(
+                "refuse request from {} with invalid token",
+                reader.get_ref().peer_addr()?
+            ));
+        }
+
+        match request.message {
+            Message::Update { id, at, config } => {
+                self.update(reader, id, UNIX_EPOCH + Duration::from_millis(at), config)
+            }
+            Message::Rollback { id } => {
+                let result = self.rollback(id);
+                respond(reader.get_mut(), result)
+            }
+        }
+    }
+
+    fn update(
+        &mut self,
+        mut reader: BufReader<TcpStream>,
+        id: u64,
+        at: SystemTime,
+        config: Vec<InjectorConfig>,
+    ) -> Result<()> {
+        info!("coordinated update {} with config {:?}", id, config);
+        // the config is validated before replying, so that the leader can
+        // report the error, and activated after the leader decides to commit
+        // it
+        let experiment = match self.loader.prepare(config) {
+            Ok(experiment) => {
+                respond(reader.get_mut(), Ok(()))?;
+                experiment
+            }
+            Err(err) => return respond(reader.get_mut(), Err(err)),
+        };
+
+        // the decision should arrive before the activation
+        let timeout = at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .max(Duration::from_millis(1));
+        reader.get_ref().set_read_timeout(Some(timeout))?;
+        let decision: Decision = read(&mut reader)?;
+        if !decision.commit {
+            info!("coordinated update {} aborted", id);
+            return Ok(());
+        }
+        respond(reader.get_mut(), Ok(()))?;
+
+        wait_until(at);
+        let previous = self.loader.rules();
+        self.loader.start(experiment);
+        self.last = Some((id, previous));
+        info!("coordinated update {} activated", id);
+        Ok(())
+    }
+
+    fn rollback(&mut self, id: u64) -> Result<()> {
+        match self.last.take() {
+            Some((last, previous)) if last == id => {
+                info!("roll back coordinated update {}", id);
+                self.loader.restore(previous)
+            }
+            last => {
+                // the update wasn't committed here
+                self.last = last;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn respond(stream: &mut TcpStream, result: Result<()>) -> Result<()> {
+    let response = Response {
+        error: result.err().map(|err| err.to_string()),
+    };
+    stream.write_all(&encode(&response)?)?;
+    Ok(())
+}
+
+// constant_time_eq compares the tokens without leaking the length of the
+// common prefix through the time it takes
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,118 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{dup2, fork, setsid, ForkResult, Pid};
+use structopt::StructOpt;
+use tracing::{error, info};
+
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct StopOptions {
+    /// Time to wait for the daemon to recover and exit
+    #[structopt(long, default_value = "60s", parse(try_from_str = humantime::parse_duration))]
+    pub timeout: Duration,
+}
+
+// Pidfile records the pid of toda, and is removed once toda exits
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    // create writes the pid of this process, or fails if the pidfile belongs
+    // to another running toda
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Pidfile> {
+        let path = path.as_ref().to_owned();
+        check_stale(&path)?;
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        info!("pid written to {}", path.display());
+
+        Ok(Pidfile { path })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        if read_pid(&self.path).ok() == Some(Pid::this()) {
+            if let Err(err) = fs::remove_file(&self.path) {
+                error!("fail to remove pidfile {}: {:?}", self.path.display(), err);
+            }
+        }
+    }
+}
+
+// daemonize detaches toda from the terminal with a double fork, and redirects
+// the standard input to /dev/null and the standard output and error, where the
+// logs are written, to `log`. It must be called before any thread is spawned,
+// as only the calling thread survives the fork.
+//
+// The jsonrpc server on the standard input isn't available in a daemon, which
+// is controlled through --rpc-socket instead.
+pub fn daemonize(pidfile: &Path, log: &Path) -> Result<()> {
+    // fail early, while the errors can still be seen from the terminal
+    check_stale(pidfile)?;
+    let null = File::open("/dev/null")?;
+    let log = OpenOptions::new().create(true).append(true).open(log)?;
+
+    if let ForkResult::Parent { .. } = fork()? {
+        std::process::exit(0);
+    }
+    setsid()?;
+    // the session leader exits, so that the daemon can never acquire a
+    // controlling terminal again
+    if let ForkResult::Parent { .. } = fork()? {
+        std::process::exit(0);
+    }
+
+    dup2(null.as_raw_fd(), libc::STDIN_FILENO)?;
+    dup2(log.as_raw_fd(), libc::STDOUT_FILENO)?;
+    dup2(log.as_raw_fd(), libc::STDERR_FILENO)?;
+    Ok(())
+}
+
+// stop sends SIGTERM to the toda recorded in the pidfile, which recovers the
+// mount and exits, and waits for it to exit
+pub fn stop<P: AsRef<Path>>(pidfile: P, option: &StopOptions) -> Result<()> {
+    let pidfile = pidfile.as_ref();
+    let pid = read_pid(pidfile)?;
+    info!("stop toda {}", pid);
+    kill(pid, Signal::SIGTERM)?;
+
+    let deadline = Instant::now() + option.timeout;
+    while alive(pid) {
+        if Instant::now() >= deadline {
+            return Err(anyhow!("toda {} doesn't exit in {:?}", pid, option.timeout));
+        }
+        thread::sleep(STOP_POLL_INTERVAL);
+    }
+
+    info!("toda {} exited", pid);
+    Ok(())
+}
+
+fn read_pid(path: &Path) -> Result<Pid> {
+    let pid = fs::read_to_string(path)?.trim().parse()?;
+    Ok(Pid::from_raw(pid))
+}
+
+fn alive(pid: Pid) -> bool {
+    !matches!(kill(pid, None), Err(nix::Error::Sys(Errno::ESRCH)))
+}
+
+// check_stale fails if the pidfile exists and the process in it is running
+fn check_stale(path: &Path) -> Result<()> {
+    match read_pid(path) {
+        Ok(pid) if pid != Pid::this() && alive(pid) => {
+            Err(anyhow!("toda {} in {} is running", pid, path.display()))
+        }
+        _ => Ok(()),
+    }
+}
@@ -2,32 +2,111 @@ use std::ffi::OsString;
 use std::fmt::Debug;
 use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use fuser::*;
-use tracing::trace_span;
+use tracing::{trace_span, warn, Span};
 use tracing_futures::Instrument;
 
+use super::caller::{self, Caller};
 use super::errors::Result;
+use super::magic::Headers;
 use super::reply::*;
 use super::runtime::spawn;
+use crate::injector::Drain;
+use crate::metrics;
 
-pub fn spawn_reply<F, R, V>(id: u64, reply: R, f: F)
-where
+// InFlight counts the operations spawned on a mount and not replied to yet
+#[derive(Debug, Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+// Entered is an operation counted in flight until it's dropped, so that an
+// operation which panics is never counted forever
+#[derive(Debug)]
+pub struct Entered(Arc<AtomicUsize>);
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl InFlight {
+    pub fn enter(&self) -> Entered {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Entered(self.0.clone())
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // wait_idle waits until every operation is replied to, or the timeout,
+    // and returns the count of the operations still in flight
+    pub fn wait_idle(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let in_flight = self.count();
+            if in_flight == 0 || Instant::now() >= deadline {
+                return in_flight;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+pub fn spawn_reply<T, F, R, V>(
+    fs: &AsyncFileSystem<T>,
+    req: &Request,
+    method: &'static str,
+    reply: R,
+    f: F,
+) where
+    T: AsyncFileSystemImpl,
     F: Future<Output = Result<V>> + Send + 'static,
     R: FsReply<V> + Send + 'static,
     V: Debug,
 {
-    spawn(async move {
-        let result = f.instrument(trace_span!("request", id)).await;
-        reply.reply(result);
-    });
+    let id = req.unique();
+    let span = fs.1.clone();
+    let as_caller = fs.0.as_caller();
+    let drain = fs.0.drain();
+    let headers = fs.0.headers();
+    let start = Instant::now();
+    let entered = fs.0.in_flight().enter();
+    spawn(caller::scope(
+        Caller::from(req),
+        as_caller,
+        drain.scope(headers.scope(async move {
+            let result = f
+                .instrument(trace_span!(parent: &span, "request", id))
+                .await;
+            metrics::record_op(method, start.elapsed(), result.is_ok());
+            reply.reply(result);
+            drop(entered);
+        })),
+    ));
 }
 
 #[async_trait]
 pub trait AsyncFileSystemImpl: Send + Sync {
-    fn init(&self) -> Result<()>;
+    fn init(&self, config: &mut KernelConfig) -> Result<()>;
+
+    // as_caller tells whether the operations on the backing files are done as
+    // the caller of the request
+    fn as_caller(&self) -> bool;
+
+    // drain is the drain policy of the operations delayed on the mount
+    fn drain(&self) -> Drain;
+
+    // headers are the leading bytes of the files opened on the mount
+    fn headers(&self) -> Headers;
+
+    // in_flight counts the operations on the mount not replied to yet
+    fn in_flight(&self) -> InFlight;
 
     fn destroy(&self);
 
@@ -205,13 +284,39 @@ pub trait AsyncFileSystemImpl: Send + Sync {
     ) -> Result<()>;
 
     async fn bmap(&self, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap);
+
+    async fn fallocate(&self, ino: u64, fh: u64, offset: i64, length: i64, mode: i32)
+        -> Result<()>;
+
+    async fn lseek(&self, ino: u64, fh: u64, offset: i64, whence: i32) -> Result<Lseek>;
+
+    async fn copy_file_range(
+        &self,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+    ) -> Result<Write>;
 }
 
-pub struct AsyncFileSystem<T>(Arc<T>);
+// AsyncFileSystem spawns every request into the runtime. The spans of the
+// requests are children of its span, which labels the mount.
+pub struct AsyncFileSystem<T>(Arc<T>, Span);
 
 impl<T: AsyncFileSystemImpl> From<Arc<T>> for AsyncFileSystem<T> {
     fn from(inner: Arc<T>) -> Self {
-        Self(inner)
+        Self(inner, Span::none())
+    }
+}
+
+impl<T: AsyncFileSystemImpl> AsyncFileSystem<T> {
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.1 = span;
+        self
     }
 }
 
@@ -225,9 +330,17 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     fn init(
         &mut self,
         _req: &fuser::Request,
-        _config: &mut fuser::KernelConfig,
+        config: &mut fuser::KernelConfig,
     ) -> std::result::Result<(), nix::libc::c_int> {
-        self.0.init().map_err(|err| err.into())
+        // the POSIX locks are passed through to the backing files, so that
+        // they conflict with the locks taken outside of the mount
+        if let Err(unsupported) = config.add_capabilities(fuser::consts::FUSE_POSIX_LOCKS) {
+            warn!(
+                "posix locks are not supported by the kernel: {:x}",
+                unsupported
+            );
+        }
+        self.0.init(config).map_err(|err| err.into())
     }
 
     fn destroy(&mut self, _req: &fuser::Request) {
@@ -237,7 +350,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     fn lookup(&mut self, req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEntry) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "lookup", reply, async move {
             async_impl.lookup(parent, name).await
         });
     }
@@ -246,18 +359,19 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let async_impl = self.0.clone();
 
         // TODO: union the spawn function for request without reply
-        spawn(async move {
-            async_impl.forget(ino, nlookup).await;
-        });
+        spawn(
+            async move {
+                async_impl.forget(ino, nlookup).await;
+            }
+            .instrument(self.1.clone()),
+        );
     }
 
     fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.getattr(ino).await },
-        );
+        spawn_reply(self, req, "getattr", reply, async move {
+            async_impl.getattr(ino).await
+        });
     }
 
     fn setattr(
@@ -279,7 +393,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyAttr,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "setattr", reply, async move {
             async_impl
                 .setattr(
                     ino, mode, uid, gid, size, atime, mtime, ctime, fh, crtime, chgtime, bkuptime,
@@ -291,7 +405,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
     fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "readlink", reply, async move {
             async_impl.readlink(ino).await
         });
     }
@@ -309,7 +423,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let name = name.to_owned();
         let uid = req.uid();
         let gid = req.gid();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "mknod", reply, async move {
             async_impl
                 .mknod(parent, name, mode, umask, rdev, uid, gid)
                 .await
@@ -329,21 +443,21 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "mkdir", reply, async move {
             async_impl.mkdir(parent, name, mode, umask, uid, gid).await
         });
     }
     fn unlink(&mut self, req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "unlink", reply, async move {
             async_impl.unlink(parent, name).await
         });
     }
     fn rmdir(&mut self, req: &Request, parent: u64, name: &std::ffi::OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "rmdir", reply, async move {
             async_impl.rmdir(parent, name).await
         });
     }
@@ -360,7 +474,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let link = link.to_owned();
         let uid = req.uid();
         let gid = req.gid();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "symlink", reply, async move {
             async_impl.symlink(parent, name, link, uid, gid).await
         });
     }
@@ -377,7 +491,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let async_impl = self.0.clone();
         let name = name.to_owned();
         let newname = newname.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "rename", reply, async move {
             async_impl
                 .rename(parent, name, newparent, newname, flags)
                 .await
@@ -393,13 +507,13 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     ) {
         let async_impl = self.0.clone();
         let newname = newname.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "link", reply, async move {
             async_impl.link(ino, newparent, newname).await
         });
     }
     fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "open", reply, async move {
             async_impl.open(ino, flags).await
         });
     }
@@ -415,7 +529,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyData,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "read", reply, async move {
             async_impl
                 .read(ino, fh, offset, size, flags, lock_owner)
                 .await
@@ -435,7 +549,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     ) {
         let async_impl = self.0.clone();
         let data = data.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "write", reply, async move {
             async_impl
                 .write(ino, fh, offset, data, write_flags, flags, lock_owner)
                 .await
@@ -443,7 +557,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     }
     fn flush(&mut self, req: &Request, ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "flush", reply, async move {
             async_impl.flush(ino, fh, lock_owner).await
         });
     }
@@ -458,57 +572,66 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "release", reply, async move {
             async_impl.release(ino, fh, flags, lock_owner, flush).await
         });
     }
     fn fsync(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "fsync", reply, async move {
             async_impl.fsync(ino, fh, datasync).await
         });
     }
     fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "opendir", reply, async move {
             async_impl.opendir(ino, flags).await
         });
     }
     fn readdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
         let async_impl = self.0.clone();
-        spawn(async move {
-            match async_impl.readdir(ino, fh, offset, &mut reply).await {
-                Ok(_) => reply.ok(),
-                Err(err) => reply.error(err.into()),
-            }
-        });
+        let entered = self.0.in_flight().enter();
+        spawn(caller::scope(
+            Caller::from(req),
+            self.0.as_caller(),
+            self.0.drain().scope(
+                self.0.headers().scope(
+                    async move {
+                        match async_impl.readdir(ino, fh, offset, &mut reply).await {
+                            Ok(_) => reply.ok(),
+                            Err(err) => reply.error(err.into()),
+                        }
+                        drop(entered);
+                    }
+                    .instrument(self.1.clone()),
+                ),
+            ),
+        ));
     }
     fn releasedir(&mut self, req: &Request, ino: u64, fh: u64, flags: i32, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "releasedir", reply, async move {
             async_impl.releasedir(ino, fh, flags).await
         });
     }
     fn fsyncdir(&mut self, req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "fsyncdir", reply, async move {
             async_impl.fsyncdir(ino, fh, datasync).await
         });
     }
     fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
         let async_impl = self.0.clone();
-        spawn_reply(
-            req.unique(),
-            reply,
-            async move { async_impl.statfs(ino).await },
-        );
+        spawn_reply(self, req, "statfs", reply, async move {
+            async_impl.statfs(ino).await
+        });
     }
     fn setxattr(
         &mut self,
@@ -523,7 +646,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         let async_impl = self.0.clone();
         let name = name.to_owned();
         let value = value.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "setxattr", reply, async move {
             async_impl.setxattr(ino, name, value, flags, position).await
         });
     }
@@ -537,26 +660,26 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
     ) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "getxattr", reply, async move {
             async_impl.getxattr(ino, name, size).await
         });
     }
     fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "listxattr", reply, async move {
             async_impl.listxattr(ino, size).await
         });
     }
     fn removexattr(&mut self, req: &Request, ino: u64, name: &std::ffi::OsStr, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "removexattr", reply, async move {
             async_impl.removexattr(ino, name).await
         });
     }
     fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "access", reply, async move {
             async_impl.access(ino, mask).await
         });
     }
@@ -575,7 +698,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
 
         let async_impl = self.0.clone();
         let name = name.to_owned();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "create", reply, async move {
             async_impl
                 .create(parent, name, mode, umask, flags, uid, gid)
                 .await
@@ -594,7 +717,7 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyLock,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "getlk", reply, async move {
             async_impl
                 .getlk(ino, fh, lock_owner, start, end, typ, pid)
                 .await
@@ -614,16 +737,93 @@ impl<T: AsyncFileSystemImpl + 'static> Filesystem for AsyncFileSystem<T> {
         reply: ReplyEmpty,
     ) {
         let async_impl = self.0.clone();
-        spawn_reply(req.unique(), reply, async move {
+        spawn_reply(self, req, "setlk", reply, async move {
             async_impl
                 .setlk(ino, fh, lock_owner, start, end, typ, pid, sleep)
                 .await
         });
     }
-    fn bmap(&mut self, _req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+    fn fallocate(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let async_impl = self.0.clone();
+        spawn_reply(self, req, "fallocate", reply, async move {
+            async_impl.fallocate(ino, fh, offset, length, mode).await
+        });
+    }
+    fn lseek(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        whence: i32,
+        reply: ReplyLseek,
+    ) {
+        let async_impl = self.0.clone();
+        spawn_reply(self, req, "lseek", reply, async move {
+            async_impl.lseek(ino, fh, offset, whence).await
+        });
+    }
+    fn copy_file_range(
+        &mut self,
+        req: &Request,
+        ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
         let async_impl = self.0.clone();
-        spawn(async move {
-            async_impl.bmap(ino, blocksize, idx, reply).await;
+        spawn_reply(self, req, "copy_file_range", reply, async move {
+            async_impl
+                .copy_file_range(
+                    ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags,
+                )
+                .await
         });
     }
+    fn bmap(&mut self, _req: &Request, ino: u64, blocksize: u32, idx: u64, reply: ReplyBmap) {
+        let async_impl = self.0.clone();
+        spawn(
+            async move {
+                async_impl.bmap(ino, blocksize, idx, reply).await;
+            }
+            .instrument(self.1.clone()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight_on_panic() {
+        let in_flight = InFlight::default();
+        let other = InFlight::default();
+        let entered = in_flight.enter();
+        let _other = other.enter();
+        assert_eq!(in_flight.wait_idle(Duration::from_millis(10)), 1);
+
+        let result = std::thread::spawn(move || {
+            let _entered = entered;
+            panic!("operation panics");
+        })
+        .join();
+        assert!(result.is_err());
+        assert_eq!(in_flight.wait_idle(Duration::from_millis(10)), 0);
+        assert_eq!(other.count(), 1);
+    }
 }
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use nix::errno::Errno;
+use once_cell::sync::Lazy;
+use tracing::{debug, info};
+
+use super::{Error, Result};
+
+// OverflowPolicy decides what a subsystem does once its state would exceed
+// the memory budget
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverflowPolicy {
+    // drop the oldest state to make room
+    DropOldest,
+    // fail the operation which would add the state with ENOMEM
+    Fail,
+    // move the oldest state into this directory. The subsystems which cannot
+    // spill drop the oldest state instead.
+    Spill(PathBuf),
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = anyhow::Error;
+
+    // The policy is "drop-oldest", "fail" or "spill:<dir>"
+    fn from_str(policy: &str) -> anyhow::Result<Self> {
+        match policy {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "fail" => Ok(OverflowPolicy::Fail),
+            policy => match policy.strip_prefix("spill:") {
+                Some(dir) if !dir.is_empty() => Ok(OverflowPolicy::Spill(PathBuf::from(dir))),
+                _ => Err(anyhow!("unknown overflow policy {}", policy)),
+            },
+        }
+    }
+}
+
+// parse_size parses a count of bytes with an optional K, M or G suffix
+pub fn parse_size(size: &str) -> anyhow::Result<usize> {
+    let (number, unit) = match size.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => size.split_at(index),
+        None => (size, ""),
+    };
+    let unit = match unit
+        .to_uppercase()
+        .trim_end_matches("IB")
+        .trim_end_matches('B')
+    {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err(anyhow!("invalid size {}", size)),
+    };
+    number
+        .parse::<usize>()?
+        .checked_mul(unit)
+        .ok_or_else(|| anyhow!("too large size {}", size))
+}
+
+#[derive(Debug)]
+struct Budget {
+    limit: Option<usize>,
+    policy: OverflowPolicy,
+    // the bytes used by every subsystem
+    used: BTreeMap<&'static str, usize>,
+}
+
+impl Budget {
+    fn total(&self) -> usize {
+        self.used.values().sum()
+    }
+}
+
+// MemoryBudget bounds the state buffered by the subsystems, like the checksums
+// of the written blocks. It's unlimited by default.
+#[derive(Debug)]
+pub struct MemoryBudget(Mutex<Budget>);
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget(Mutex::new(Budget {
+            limit: None,
+            policy: OverflowPolicy::DropOldest,
+            used: BTreeMap::new(),
+        }))
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>, policy: OverflowPolicy) -> MemoryBudget {
+        let budget = MemoryBudget::default();
+        budget.configure(limit, policy);
+        budget
+    }
+
+    pub fn configure(&self, limit: Option<usize>, policy: OverflowPolicy) {
+        info!("set memory budget {:?} with policy {:?}", limit, policy);
+        let mut budget = self.0.lock().unwrap();
+        budget.limit = limit;
+        budget.policy = policy;
+    }
+
+    pub fn policy(&self) -> OverflowPolicy {
+        self.0.lock().unwrap().policy.clone()
+    }
+
+    // fits returns whether `bytes` more could be charged now
+    pub fn fits(&self, bytes: usize) -> bool {
+        let budget = self.0.lock().unwrap();
+        match budget.limit {
+            Some(limit) => budget.total().saturating_add(bytes) <= limit,
+            None => true,
+        }
+    }
+
+    // charge accounts `bytes` to the subsystem, or returns false without
+    // accounting anything if the budget would be exceeded
+    pub fn charge(&self, subsystem: &'static str, bytes: usize) -> bool {
+        let mut budget = self.0.lock().unwrap();
+        if let Some(limit) = budget.limit {
+            if budget.total().saturating_add(bytes) > limit {
+                debug!("memory budget exceeded by {}", subsystem);
+                return false;
+            }
+        }
+        *budget.used.entry(subsystem).or_default() += bytes;
+        true
+    }
+
+    pub fn release(&self, subsystem: &'static str, bytes: usize) {
+        let mut budget = self.0.lock().unwrap();
+        if let Some(used) = budget.used.get_mut(subsystem) {
+            *used = used.saturating_sub(bytes);
+        }
+    }
+
+    // overflow fails the operation if the policy is to fail, and does nothing
+    // otherwise
+    pub fn overflow(&self) -> Result<()> {
+        match self.policy() {
+            OverflowPolicy::Fail => Err(Error::Sys(Errno::ENOMEM)),
+            _ => Ok(()),
+        }
+    }
+
+    // usage returns the bytes used by every subsystem
+    pub fn usage(&self) -> BTreeMap<&'static str, usize> {
+        self.0.lock().unwrap().used.clone()
+    }
+}
+
+// The budget of the process, shared by the mounts which aren't given their own
+// and by the state of the injectors, which may serve several mounts
+static BUDGET: Lazy<Arc<MemoryBudget>> = Lazy::new(Default::default);
+
+pub fn global() -> Arc<MemoryBudget> {
+    BUDGET.clone()
+}
+
+pub fn configure(limit: Option<usize>, policy: OverflowPolicy) {
+    BUDGET.configure(limit, policy)
+}
+
+pub fn policy() -> OverflowPolicy {
+    BUDGET.policy()
+}
+
+pub fn charge(subsystem: &'static str, bytes: usize) -> bool {
+    BUDGET.charge(subsystem, bytes)
+}
+
+pub fn release(subsystem: &'static str, bytes: usize) {
+    BUDGET.release(subsystem, bytes)
+}
+
+pub fn overflow() -> Result<()> {
+    BUDGET.overflow()
+}
+
+pub fn usage() -> BTreeMap<&'static str, usize> {
+    BUDGET.usage()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("64M").unwrap(), 64 << 20);
+        assert_eq!(parse_size("2GiB").unwrap(), 2 << 30);
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size(&format!("{}G", usize::MAX >> 20)).is_err());
+    }
+
+    #[test]
+    fn test_separate_budgets() {
+        let first = MemoryBudget::new(Some(10), OverflowPolicy::Fail);
+        let second = MemoryBudget::new(Some(10), OverflowPolicy::DropOldest);
+        assert!(first.charge("test", 8));
+        assert!(!first.charge("test", 3));
+        assert!(first.overflow().is_err());
+        assert!(second.charge("test", 10));
+        assert!(second.overflow().is_ok());
+
+        first.release("test", 8);
+        assert!(first.fits(10));
+        assert_eq!(second.usage().get("test"), Some(&10));
+    }
+}
@@ -0,0 +1,224 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use fuser::Request;
+use once_cell::sync::Lazy;
+use tracing::warn;
+
+// Caller is the process which issued the fuse request handled by the current
+// task. The pid could be 0 if the request is issued by the kernel itself, e.g.
+// while writing back dirty pages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caller {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl From<&Request<'_>> for Caller {
+    fn from(req: &Request<'_>) -> Self {
+        Caller {
+            pid: req.pid(),
+            uid: req.uid(),
+            gid: req.gid(),
+        }
+    }
+}
+
+tokio::task_local! {
+    static CALLER: Caller;
+    // whether the operations on the backing files are done as the caller
+    static AS_CALLER: bool;
+    // the open flags of the file handle the request operates on, once the
+    // handle is looked up
+    static FLAGS: Cell<Option<i32>>;
+    // the size a setattr truncates the file to
+    static SIZE: Cell<Option<u64>>;
+    // the decisions made once per request, by their key
+    static DECIDED: RefCell<HashMap<usize, bool>>;
+}
+
+pub async fn scope<F: Future>(caller: Caller, as_caller: bool, f: F) -> F::Output {
+    let f = DECIDED.scope(RefCell::new(HashMap::new()), f);
+    let f = SIZE.scope(Cell::new(None), f);
+    let f = AS_CALLER.scope(as_caller, FLAGS.scope(Cell::new(None), f));
+    CALLER.scope(caller, f).await
+}
+
+// current returns the caller of the request, or None if the current task is
+// not handling a fuse request
+pub fn current() -> Option<Caller> {
+    CALLER.try_with(|caller| *caller).ok()
+}
+
+// credentials returns the caller to do the operations on the backing files as,
+// if the mount leaves the permissions to them. The requests issued by the
+// kernel itself are done as toda.
+pub fn credentials() -> Option<Caller> {
+    if !AS_CALLER.try_with(|as_caller| *as_caller).unwrap_or(false) {
+        return None;
+    }
+    current().filter(|caller| caller.pid != 0)
+}
+
+// Credentials are the file system ids and the supplementary groups of the
+// current thread, which are restored once it's dropped
+struct Credentials {
+    uid: u32,
+    gid: u32,
+    groups: Vec<libc::gid_t>,
+}
+
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        unsafe {
+            libc::setfsuid(self.uid);
+            libc::setfsgid(self.gid);
+        }
+        set_groups(&self.groups);
+    }
+}
+
+// run_as runs `f` with the file system ids and the supplementary groups of the
+// caller. They are switched on the current thread alone, unlike setgroups of
+// libc, and restored before returning, even if `f` panics.
+pub fn run_as<T, F: FnOnce() -> T>(caller: Caller, f: F) -> T {
+    let groups = process_groups(caller.pid);
+    let saved = thread_groups();
+    set_groups(&groups);
+    let gid = unsafe { libc::setfsgid(caller.gid) } as u32;
+    let uid = unsafe { libc::setfsuid(caller.uid) } as u32;
+    let _credentials = Credentials {
+        uid,
+        gid,
+        groups: saved,
+    };
+    f()
+}
+
+// the time the supplementary groups of the callers are cached for, which is
+// the delay before a change of them is seen
+const GROUPS_TTL: Duration = Duration::from_secs(1);
+
+// the bound of the cached callers, beyond which the expired ones are dropped
+const MAX_GROUPS: usize = 4096;
+
+static GROUPS: Lazy<Mutex<HashMap<u32, (Instant, Groups)>>> = Lazy::new(Default::default);
+
+type Groups = Arc<Vec<libc::gid_t>>;
+
+// process_groups returns the supplementary groups of the process, read again
+// once its cache expires
+fn process_groups(pid: u32) -> Groups {
+    if let Some((read, groups)) = GROUPS.lock().unwrap().get(&pid) {
+        if read.elapsed() < GROUPS_TTL {
+            return groups.clone();
+        }
+    }
+
+    let groups = Arc::new(read_groups(pid));
+    let mut cached = GROUPS.lock().unwrap();
+    if cached.len() >= MAX_GROUPS {
+        cached.retain(|_, (read, _)| read.elapsed() < GROUPS_TTL);
+        if cached.len() >= MAX_GROUPS {
+            cached.clear();
+        }
+    }
+    cached.insert(pid, (Instant::now(), groups.clone()));
+    groups
+}
+
+// read_groups reads the supplementary groups of the process, which has none
+// once it has exited
+fn read_groups(pid: u32) -> Vec<libc::gid_t> {
+    let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        Err(_) => return Vec::new(),
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|group| group.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn thread_groups() -> Vec<libc::gid_t> {
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups = vec![0; count.max(0) as usize];
+    let count = unsafe { libc::getgroups(groups.len() as i32, groups.as_mut_ptr()) };
+    groups.truncate(count.max(0) as usize);
+    groups
+}
+
+fn set_groups(groups: &[libc::gid_t]) {
+    // the raw syscall changes the groups of the current thread, while the one
+    // of libc changes them on every thread of the process
+    let ret = unsafe { libc::syscall(libc::SYS_setgroups, groups.len(), groups.as_ptr()) };
+    if ret == -1 {
+        warn!(
+            "fail to set supplementary groups: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+pub fn set_flags(flags: i32) {
+    let _ = FLAGS.try_with(|cell| cell.set(Some(flags)));
+}
+
+// flags returns the open flags of the file handle of the request, or None if
+// the request doesn't operate on a handle
+pub fn flags() -> Option<i32> {
+    FLAGS.try_with(|cell| cell.get()).ok().flatten()
+}
+
+pub fn set_size(size: u64) {
+    let _ = SIZE.try_with(|cell| cell.set(Some(size)));
+}
+
+// size returns the size the request truncates the file to, or None if it
+// doesn't truncate it
+pub fn size() -> Option<u64> {
+    SIZE.try_with(|cell| cell.get()).ok().flatten()
+}
+
+// once makes the decision of the key once per request, so that a filter asked
+// several times about the same operation decides it once. Outside of a
+// request, it's made every time.
+pub fn once<F: FnOnce() -> bool>(key: usize, decide: F) -> bool {
+    let decided = DECIDED
+        .try_with(|decided| decided.borrow().get(&key).copied())
+        .ok()
+        .flatten();
+    if let Some(decision) = decided {
+        return decision;
+    }
+    let decision = decide();
+    let _ = DECIDED.try_with(|decided| decided.borrow_mut().insert(key, decision));
+    decision
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_groups_cached() {
+        let pid = std::process::id();
+        let first = process_groups(pid);
+        assert!(Arc::ptr_eq(&first, &process_groups(pid)));
+
+        GROUPS.lock().unwrap().get_mut(&pid).unwrap().0 -= GROUPS_TTL;
+        let second = process_groups(pid);
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(first, second);
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::hash::Hasher;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use serde::Serialize;
+use tracing::{debug, error, trace};
+
+use super::budget::{MemoryBudget, OverflowPolicy};
+use super::{MountLabel, Result};
+use crate::utils::Fnv;
+
+const BLOCK_SIZE: u64 = 4096;
+
+// the name of this subsystem in the memory budget
+const SUBSYSTEM: &str = "checksum";
+
+// the memory accounted for the checksum of every block
+const BLOCK_COST: usize = 48;
+
+// at most this number of mismatches are kept in the report
+const MAX_MISMATCHES: usize = 1024;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Mismatch {
+    #[serde(serialize_with = "crate::utils::os_path::serialize")]
+    pub path: PathBuf,
+    pub offset: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ChecksumReport {
+    pub mount: MountLabel,
+    pub verified_blocks: u64,
+    pub mismatched_blocks: u64,
+    pub mismatches: Vec<Mismatch>,
+}
+
+// ChecksumVerifier records the checksum of every block written through the
+// mount and verifies the blocks read back from the backing directory. It only
+// reports the divergence, and never modifies the data.
+//
+// Only the blocks fully covered by a write are recorded. Modifications which
+// don't go through the mount are also reported as divergence.
+//
+// The checksums are accounted in the memory budget of the mount. Once it's
+// exceeded, the checksums of the inode recorded first are dropped, or spilled
+// into a file per inode and read back for the verification. The spill files
+// are read and written without the lock of the blocks, so that a write never
+// waits for them unless it spills.
+#[derive(Debug)]
+pub struct ChecksumVerifier {
+    blocks: Mutex<Blocks>,
+    // the spills queued in order under the lock of the blocks
+    queue: Mutex<Vec<Spill>>,
+    // held while the spill files are read or written
+    spill: Mutex<()>,
+    report: Mutex<ChecksumReport>,
+}
+
+#[derive(Debug)]
+struct Blocks {
+    budget: Arc<MemoryBudget>,
+    // map from inode to the checksums of its blocks
+    inodes: HashMap<u64, BTreeMap<u64, u64>>,
+    // the inodes in memory, in the order of their first record
+    order: VecDeque<u64>,
+    // the inodes with checksums in the spill directory
+    spilled: HashSet<u64>,
+    // the spills to apply once the lock is released
+    spills: Vec<Spill>,
+}
+
+// Spill is a change of the checksums in the spill file of an inode
+#[derive(Debug)]
+enum Spill {
+    Merge(PathBuf, BTreeMap<u64, u64>),
+    Remove(PathBuf, u64),
+    Truncate(PathBuf, u64),
+    Delete(PathBuf),
+}
+
+impl Spill {
+    fn apply(self) {
+        match self {
+            Spill::Merge(path, blocks) => {
+                let mut spilled = read_spilled(&path);
+                spilled.extend(blocks);
+                write_spilled(&path, &spilled);
+            }
+            Spill::Remove(path, block) => {
+                let mut spilled = read_spilled(&path);
+                if spilled.remove(&block).is_some() {
+                    write_spilled(&path, &spilled);
+                }
+            }
+            Spill::Truncate(path, block) => {
+                let mut spilled = read_spilled(&path);
+                spilled.split_off(&block);
+                write_spilled(&path, &spilled);
+            }
+            Spill::Delete(path) => {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+impl Blocks {
+    fn insert(&mut self, ino: u64, block: u64, checksum: u64) {
+        let exists = match self.inodes.get(&ino) {
+            Some(blocks) => blocks.contains_key(&block),
+            None => false,
+        };
+        if !exists {
+            while !self.budget.charge(SUBSYSTEM, BLOCK_COST) {
+                if !self.evict(ino) {
+                    trace!("no room for the checksum of block {} of {}", block, ino);
+                    return;
+                }
+            }
+        }
+
+        if !self.inodes.contains_key(&ino) {
+            self.order.push_back(ino);
+        }
+        self.inodes.entry(ino).or_default().insert(block, checksum);
+    }
+
+    fn remove(&mut self, ino: u64, block: u64) {
+        if let Some(blocks) = self.inodes.get_mut(&ino) {
+            if blocks.remove(&block).is_some() {
+                self.budget.release(SUBSYSTEM, BLOCK_COST);
+            }
+        }
+        if let Some(path) = self.spilled_path(ino) {
+            self.spills.push(Spill::Remove(path, block));
+        }
+    }
+
+    fn truncate(&mut self, ino: u64, block: u64) {
+        if let Some(blocks) = self.inodes.get_mut(&ino) {
+            let removed = blocks.split_off(&block);
+            self.budget.release(SUBSYSTEM, removed.len() * BLOCK_COST);
+        }
+        if let Some(path) = self.spilled_path(ino) {
+            self.spills.push(Spill::Truncate(path, block));
+        }
+    }
+
+    fn forget(&mut self, ino: u64) {
+        if let Some(blocks) = self.inodes.remove(&ino) {
+            self.budget.release(SUBSYSTEM, blocks.len() * BLOCK_COST);
+            self.order.retain(|item| *item != ino);
+        }
+        if let Some(path) = self.spilled_path(ino) {
+            self.spilled.remove(&ino);
+            self.spills.push(Spill::Delete(path));
+        }
+    }
+
+    // evict drops or spills the checksums of the oldest inode other than
+    // `current`, and returns false if there is nothing to evict
+    fn evict(&mut self, current: u64) -> bool {
+        let position = match self.order.iter().position(|ino| *ino != current) {
+            Some(position) => position,
+            None => return false,
+        };
+        let ino = self.order.remove(position).unwrap();
+        let blocks = self.inodes.remove(&ino).unwrap_or_default();
+        self.budget.release(SUBSYSTEM, blocks.len() * BLOCK_COST);
+
+        match spill_path(&self.budget, ino) {
+            Some(path) => {
+                debug!("spill checksums of {}", ino);
+                self.spilled.insert(ino);
+                self.spills.push(Spill::Merge(path, blocks));
+            }
+            None => debug!("drop checksums of {}", ino),
+        }
+        true
+    }
+
+    // spilled_path returns the spill file of the inode, if it has spilled
+    fn spilled_path(&self, ino: u64) -> Option<PathBuf> {
+        if !self.spilled.contains(&ino) {
+            return None;
+        }
+        spill_path(&self.budget, ino)
+    }
+}
+
+impl ChecksumVerifier {
+    pub fn new(budget: Arc<MemoryBudget>) -> ChecksumVerifier {
+        ChecksumVerifier {
+            blocks: Mutex::new(Blocks {
+                budget,
+                inodes: HashMap::new(),
+                order: VecDeque::new(),
+                spilled: HashSet::new(),
+                spills: Vec::new(),
+            }),
+            queue: Mutex::new(Vec::new()),
+            spill: Mutex::new(()),
+            report: Mutex::new(ChecksumReport::default()),
+        }
+    }
+
+    // reserve fails the write of `size` bytes if the policy is to fail once
+    // the memory budget is exceeded, and there is no room for its checksums
+    pub fn reserve(&self, size: usize) -> Result<()> {
+        let cost = (size / BLOCK_SIZE as usize) * BLOCK_COST;
+        let budget = self.blocks.lock().unwrap().budget.clone();
+        if budget.fits(cost) {
+            return Ok(());
+        }
+        budget.overflow()
+    }
+
+    // unlock queues the spills of the blocks in order before releasing their
+    // lock, and applies them once it's released
+    fn unlock(&self, mut blocks: MutexGuard<Blocks>) {
+        if blocks.spills.is_empty() {
+            return;
+        }
+        self.queue.lock().unwrap().append(&mut blocks.spills);
+        drop(blocks);
+        drop(self.flush());
+    }
+
+    // flush applies the queued spills, and returns the lock of the spill
+    // files
+    fn flush(&self) -> MutexGuard<()> {
+        let spill = self.spill.lock().unwrap();
+        let queue = std::mem::take(&mut *self.queue.lock().unwrap());
+        for item in queue {
+            item.apply();
+        }
+        spill
+    }
+
+    pub fn record(&self, ino: u64, offset: i64, data: &[u8]) {
+        let mut blocks = self.blocks.lock().unwrap();
+
+        let start = offset as u64;
+        let end = start + data.len() as u64;
+        // the partially written blocks are unknown now
+        if start % BLOCK_SIZE != 0 {
+            blocks.remove(ino, start / BLOCK_SIZE);
+        }
+        if end % BLOCK_SIZE != 0 {
+            blocks.remove(ino, end / BLOCK_SIZE);
+        }
+
+        for block in full_blocks(start, end) {
+            let begin = (block * BLOCK_SIZE - start) as usize;
+            let checksum = checksum(&data[begin..begin + BLOCK_SIZE as usize]);
+            blocks.insert(ino, block, checksum);
+        }
+        self.unlock(blocks);
+    }
+
+    pub fn verify(&self, ino: u64, path: &Path, offset: i64, data: &[u8]) {
+        let start = offset as u64;
+        let end = start + data.len() as u64;
+
+        let blocks = self.blocks.lock().unwrap();
+        let recorded = blocks.inodes.get(&ino);
+        let spilled = blocks.spilled_path(ino);
+        if recorded.is_none() && spilled.is_none() {
+            return;
+        }
+        let mut expected: BTreeMap<u64, u64> = match recorded {
+            Some(recorded) => recorded
+                .range(full_blocks(start, end))
+                .map(|(block, checksum)| (*block, *checksum))
+                .collect(),
+            None => BTreeMap::new(),
+        };
+        drop(blocks);
+
+        // the checksums in memory are newer than the spilled ones
+        if let Some(spilled) = spilled {
+            let _spill = self.flush();
+            for (block, checksum) in read_spilled(&spilled).range(full_blocks(start, end)) {
+                expected.entry(*block).or_insert(*checksum);
+            }
+        }
+
+        for (block, expected) in expected {
+            let begin = (block * BLOCK_SIZE - start) as usize;
+            let actual = checksum(&data[begin..begin + BLOCK_SIZE as usize]);
+
+            let mut report = self.report.lock().unwrap();
+            report.verified_blocks += 1;
+            if actual != expected {
+                error!(
+                    "checksum mismatch at offset {} of {}",
+                    block * BLOCK_SIZE,
+                    path.display()
+                );
+                report.mismatched_blocks += 1;
+                if report.mismatches.len() < MAX_MISMATCHES {
+                    report.mismatches.push(Mismatch {
+                        path: path.to_owned(),
+                        offset: block * BLOCK_SIZE,
+                    });
+                }
+            }
+        }
+    }
+
+    // truncate forgets the blocks after `size`
+    pub fn truncate(&self, ino: u64, size: u64) {
+        trace!("truncate checksums of {} to {}", ino, size);
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.truncate(ino, size / BLOCK_SIZE);
+        self.unlock(blocks);
+    }
+
+    pub fn forget(&self, ino: u64) {
+        trace!("forget checksums of {}", ino);
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.forget(ino);
+        self.unlock(blocks);
+    }
+
+    pub fn report(&self) -> ChecksumReport {
+        self.report.lock().unwrap().clone()
+    }
+}
+
+fn spill_path(budget: &MemoryBudget, ino: u64) -> Option<PathBuf> {
+    match budget.policy() {
+        OverflowPolicy::Spill(dir) => Some(dir.join(format!("checksum-{}", ino))),
+        _ => None,
+    }
+}
+
+fn read_spilled(path: &Path) -> BTreeMap<u64, u64> {
+    fs::read(path)
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+fn write_spilled(path: &Path, blocks: &BTreeMap<u64, u64>) {
+    let result = serde_json::to_vec(blocks)
+        .map_err(anyhow::Error::from)
+        .and_then(|content| Ok(fs::write(path, content)?));
+    if let Err(err) = result {
+        error!("fail to spill checksums into {}: {:?}", path.display(), err);
+    }
+}
+
+// full_blocks returns the blocks which are fully covered by [start, end)
+fn full_blocks(start: u64, end: u64) -> std::ops::Range<u64> {
+    let first = (start + BLOCK_SIZE - 1) / BLOCK_SIZE;
+    let last = end / BLOCK_SIZE;
+    first..last.max(first)
+}
+
+fn checksum(data: &[u8]) -> u64 {
+    let mut hasher = Fnv::default();
+    hasher.write(data);
+    hasher.finish()
+}
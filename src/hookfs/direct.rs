@@ -0,0 +1,99 @@
+use std::alloc::{self, Layout};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::RawFd;
+use std::ptr::NonNull;
+use std::slice;
+
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+
+// The alignment of the buffers passed to a file opened with O_DIRECT. The
+// kernel requires the logical block size of the device, which doesn't exceed
+// the page size on the common devices.
+pub const ALIGNMENT: usize = 4096;
+
+// AlignedBuffer is a zeroed buffer aligned for the direct io on the backing
+// file. The offset and the size of the io must be aligned by the caller, as
+// they are by an application using O_DIRECT.
+pub struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// the buffer is owned, like a Vec<u8>
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    pub fn new(len: usize) -> AlignedBuffer {
+        // an allocation of zero bytes is undefined
+        let layout = Layout::from_size_align(len.max(1), ALIGNMENT).unwrap();
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = match NonNull::new(ptr) {
+            Some(ptr) => ptr,
+            None => alloc::handle_alloc_error(layout),
+        };
+        AlignedBuffer { ptr, len, layout }
+    }
+
+    pub fn from_slice(data: &[u8]) -> AlignedBuffer {
+        let mut buf = AlignedBuffer::new(data.len());
+        buf.copy_from_slice(data);
+        buf
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+// is_aligned tells whether the io at the offset can be done with O_DIRECT
+pub fn is_aligned(offset: i64, len: usize) -> bool {
+    offset as usize % ALIGNMENT == 0 && len % ALIGNMENT == 0
+}
+
+// is_direct tells whether the fd is opened with O_DIRECT
+pub fn is_direct(fd: RawFd) -> bool {
+    match fcntl(fd, FcntlArg::F_GETFL) {
+        Ok(flags) => flags & libc::O_DIRECT != 0,
+        Err(_) => false,
+    }
+}
+
+// Buffered is the file of a direct fd opened again without O_DIRECT, for the
+// io which isn't aligned, like the part of a write left by an injector or the
+// undo of a journaled write. It goes through the page cache, which the kernel
+// keeps coherent with the direct io of the other fd.
+pub struct Buffered(pub RawFd);
+
+impl Buffered {
+    pub fn open(fd: RawFd) -> nix::Result<Buffered> {
+        let flags = fcntl(fd, FcntlArg::F_GETFL)?;
+        let flags = OFlag::from_bits_truncate(flags & libc::O_ACCMODE) | OFlag::O_CLOEXEC;
+        let path = format!("/proc/self/fd/{}", fd);
+        Ok(Buffered(open(path.as_str(), flags, Mode::empty())?))
+    }
+}
+
+impl Drop for Buffered {
+    fn drop(&mut self) {
+        let _ = close(self.0);
+    }
+}
@@ -23,6 +23,7 @@ pub enum HookFsError {
     #[error("strip prefix error")]
     StripPrefixError(#[from] std::path::StripPrefixError),
 
+    // an internal failure, like a blocking task which panicked
     #[error("unknown error")]
     UnknownError,
 }
@@ -37,13 +38,10 @@ impl HookFsError {
 
 impl From<nix::Error> for HookFsError {
     fn from(err: Error) -> HookFsError {
-        // TODO: match more error types
         match err {
             Error::Sys(errno) => HookFsError::Sys(errno),
-            _ => {
-                error!("unknown error {:?}", err);
-                HookFsError::UnknownError
-            }
+            Error::InvalidPath | Error::InvalidUtf8 => HookFsError::InvalidStr,
+            Error::UnsupportedOperation => HookFsError::Sys(Errno::ENOTSUP),
         }
     }
 }
@@ -56,8 +54,13 @@ impl From<std::ffi::NulError> for HookFsError {
 
 impl From<std::io::Error> for HookFsError {
     fn from(err: std::io::Error) -> HookFsError {
-        error!("unknown error {:?}", err);
-        HookFsError::UnknownError
+        match err.raw_os_error() {
+            Some(errno) => HookFsError::Sys(Errno::from_i32(errno)),
+            None => {
+                error!("unknown error {:?}", err);
+                HookFsError::UnknownError
+            }
+        }
     }
 }
 
@@ -68,17 +71,61 @@ impl From<tokio::task::JoinError> for HookFsError {
     }
 }
 
+// Every error is replied with a positive errno, as the kernel takes a zero as
+// a success, and fails the operation with EPERM or worse for the others.
 impl From<HookFsError> for libc::c_int {
     fn from(err: HookFsError) -> libc::c_int {
         use HookFsError::*;
 
         match err {
+            // an errno unknown to nix, which may be zero
+            Sys(Errno::UnknownErrno) => libc::EIO,
             Sys(errno) => errno as i32,
-            InodeNotFound { inode: _ } => libc::EFAULT,
-            FhNotFound { fh: _ } => libc::EFAULT,
-            UnknownFileType => libc::EINVAL,
+            // the inode is stale once all of its paths are removed
+            InodeNotFound { inode: _ } => libc::ENOENT,
+            FhNotFound { fh: _ } => libc::EBADF,
+            // the backing file is of a type which cannot be passed through
+            UnknownFileType => libc::EIO,
             InvalidStr => libc::EINVAL,
-            _ => libc::EFAULT,
+            // the path is out of the mount, which means the inode map is
+            // inconsistent
+            StripPrefixError(_) => libc::EIO,
+            UnknownError => libc::EIO,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn errno(err: HookFsError) -> libc::c_int {
+        err.into()
+    }
+
+    #[test]
+    fn test_errno() {
+        assert_eq!(errno(HookFsError::Sys(Errno::ENOSPC)), libc::ENOSPC);
+        assert_eq!(errno(HookFsError::Sys(Errno::UnknownErrno)), libc::EIO);
+        // a zero errno would be replied as a success
+        assert_eq!(errno(HookFsError::Sys(Errno::from_i32(0))), libc::EIO);
+        assert_eq!(errno(HookFsError::InodeNotFound { inode: 2 }), libc::ENOENT);
+        assert_eq!(errno(HookFsError::FhNotFound { fh: 3 }), libc::EBADF);
+        assert_eq!(errno(HookFsError::InvalidStr), libc::EINVAL);
+        assert_eq!(errno(HookFsError::UnknownFileType), libc::EIO);
+        let err = Path::new("/a").strip_prefix("/b").unwrap_err();
+        assert_eq!(errno(HookFsError::StripPrefixError(err)), libc::EIO);
+        assert_eq!(errno(HookFsError::UnknownError), libc::EIO);
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let err = std::io::Error::from_raw_os_error(libc::EROFS);
+        assert_eq!(errno(err.into()), libc::EROFS);
+        let err = std::io::Error::new(std::io::ErrorKind::Other, "no errno");
+        assert_eq!(errno(err.into()), libc::EIO);
+        assert_eq!(errno(Error::UnsupportedOperation.into()), libc::ENOTSUP);
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use tracing::debug;
+
+use super::budget::MemoryBudget;
+use super::{File, Result};
+
+// the name of this subsystem in the memory budget
+const SUBSYSTEM: &str = "journal";
+
+// Journal keeps the data overwritten by the writes to an inode since its last
+// sync, through any of its opened files, so that the writes can be undone to
+// emulate the loss of the buffered data. The truncates and the fallocates
+// aren't journaled.
+#[derive(Debug)]
+pub struct Journal {
+    budget: Arc<MemoryBudget>,
+    // the last file written through, whose fd undoes the writes
+    writer: Option<Arc<File>>,
+    // the size of the file before the first journaled write
+    size: Option<i64>,
+    // the offset and the overwritten data of every write, in order
+    undo: Vec<(i64, Vec<u8>)>,
+    // the writes cannot be undone as some were not journaled
+    overflowed: bool,
+    charged: usize,
+}
+
+impl Journal {
+    pub fn new(budget: Arc<MemoryBudget>) -> Journal {
+        Journal {
+            budget,
+            writer: None,
+            size: None,
+            undo: Vec::new(),
+            overflowed: false,
+            charged: 0,
+        }
+    }
+
+    // record journals the data `old` at the offset before it's overwritten. A
+    // journal which doesn't fit into the memory budget is given up, unless the
+    // policy is to fail the write.
+    pub fn record(
+        &mut self,
+        writer: &Arc<File>,
+        size: i64,
+        offset: i64,
+        old: Vec<u8>,
+    ) -> Result<()> {
+        if self.overflowed {
+            return Ok(());
+        }
+        if !self.budget.charge(SUBSYSTEM, old.len()) {
+            self.budget.overflow()?;
+            debug!("journal exceeds the memory budget, the writes can't be undone");
+            self.clear();
+            self.overflowed = true;
+            return Ok(());
+        }
+
+        self.charged += old.len();
+        self.writer = Some(writer.clone());
+        self.size.get_or_insert(size);
+        self.undo.push((offset, old));
+        Ok(())
+    }
+
+    // take returns the file to undo the writes through, the size to truncate
+    // it to and the writes to undo in reverse order, or None if they cannot be
+    // undone. The journal is cleared.
+    pub fn take(&mut self) -> Option<(Arc<File>, Option<i64>, Vec<(i64, Vec<u8>)>)> {
+        let overflowed = self.overflowed;
+        let writer = self.writer.take();
+        let size = self.size.take();
+        let mut undo = std::mem::take(&mut self.undo);
+        self.clear();
+        if overflowed {
+            return None;
+        }
+        undo.reverse();
+        Some((writer?, size, undo))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty() && !self.overflowed
+    }
+
+    pub fn clear(&mut self) {
+        self.budget.release(SUBSYSTEM, self.charged);
+        self.charged = 0;
+        self.writer = None;
+        self.size = None;
+        self.undo.clear();
+        self.overflowed = false;
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        self.budget.release(SUBSYSTEM, self.charged);
+    }
+}
@@ -0,0 +1,172 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use fuser::KernelConfig;
+use tracing::{info, warn};
+
+use super::budget;
+
+// The source and the subtype of the mount, unless they're set. The mount is
+// found in /proc/mounts by either of them.
+pub const LABEL: &str = "toda";
+
+// AllowAccess is who may access the mount besides toda
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllowAccess {
+    // toda alone
+    Owner,
+    // root, which toda usually is
+    Root,
+    // every user, as the application rarely runs with the uid of toda
+    Other,
+}
+
+impl Default for AllowAccess {
+    fn default() -> Self {
+        AllowAccess::Other
+    }
+}
+
+impl FromStr for AllowAccess {
+    type Err = anyhow::Error;
+
+    // The access is "owner", "root" or "other"
+    fn from_str(access: &str) -> anyhow::Result<Self> {
+        match access {
+            "owner" => Ok(AllowAccess::Owner),
+            "root" => Ok(AllowAccess::Root),
+            "other" => Ok(AllowAccess::Other),
+            _ => Err(anyhow!("unknown allowed access {}", access)),
+        }
+    }
+}
+
+// KernelOptions tunes the caching of the kernel and the size of the requests
+// it sends, which are negotiated on mount, along with who may access the mount
+// and how it's labeled. The kernel defaults are kept unless they're set, as
+// they make the requests match the calls of the application. Nothing is
+// cached by default, so that every lookup and getattr reaches the rules.
+#[derive(Debug, Clone, Default)]
+pub struct KernelOptions {
+    // cache the writes in the kernel, which flushes them in the background. The
+    // rules then see the writes coalesced by the kernel, and a failed one is
+    // reported on a later fsync or close rather than on the write. The flushed
+    // writes are sent by the kernel rather than the application, so the rules
+    // matching the caller don't match them, and through any file opened for
+    // writing, which the writes journaled per inode don't mind. A short or
+    // torn write loses the rest of the data, as the write the application
+    // made has already succeeded.
+    pub writeback_cache: bool,
+    // the largest write request, in bytes
+    pub max_write: Option<u32>,
+    // the largest read request and readahead, in bytes
+    pub max_read: Option<u32>,
+    // how long the kernel caches the attributes replied to getattr
+    pub attr_timeout: Duration,
+    // how long the kernel caches the entries replied to lookup, along with
+    // their attributes
+    pub entry_timeout: Duration,
+    // leave the permissions to the backing files instead of the kernel, which
+    // then sends every access to toda. Every operation on the backing files is
+    // done with the file system ids and the supplementary groups of the
+    // caller, except the ones issued by the kernel itself.
+    pub passthrough_permissions: bool,
+    pub allow: AllowAccess,
+    // the source of the mount, LABEL if it's not set
+    pub fsname: Option<String>,
+    // the type of the mount is "fuse.<subtype>", with LABEL if it's not set
+    pub subtype: Option<String>,
+}
+
+impl KernelOptions {
+    // negotiate requests the options in the reply to the init of the kernel
+    pub fn negotiate(&self, config: &mut KernelConfig) {
+        if self.writeback_cache {
+            if let Err(unsupported) = config.add_capabilities(fuser::consts::FUSE_WRITEBACK_CACHE) {
+                warn!(
+                    "writeback cache is not supported by the kernel: {:x}",
+                    unsupported
+                );
+            }
+        }
+        if let Some(max_write) = self.max_write {
+            // the kernel caps the requests to 128k without FUSE_MAX_PAGES
+            let capabilities = fuser::consts::FUSE_BIG_WRITES | fuser::consts::FUSE_MAX_PAGES;
+            if let Err(unsupported) = config.add_capabilities(capabilities) {
+                warn!(
+                    "big writes are not supported by the kernel: {:x}",
+                    unsupported
+                );
+            }
+            match config.set_max_write(max_write) {
+                Ok(_) => info!("negotiate max write {}", max_write),
+                Err(limit) => warn!("max write {} exceeds the limit {}", max_write, limit),
+            }
+        }
+        if let Some(max_read) = self.max_read {
+            match config.set_max_readahead(max_read) {
+                Ok(_) => info!("negotiate max readahead {}", max_read),
+                Err(limit) => warn!("max readahead {} exceeds the limit {}", max_read, limit),
+            }
+        }
+    }
+
+    // fsname returns the source of the mount
+    pub fn fsname(&self) -> &str {
+        self.fsname.as_deref().unwrap_or(LABEL)
+    }
+
+    // subtype returns the subtype of the mount
+    pub fn subtype(&self) -> &str {
+        self.subtype.as_deref().unwrap_or(LABEL)
+    }
+
+    // mount_options returns the options of the mount, as the size of the read
+    // requests and the checking of the permissions are set on mount rather
+    // than on init
+    pub fn mount_options(&self) -> Vec<String> {
+        let mut options = vec![
+            format!("fsname={}", self.fsname()),
+            format!("subtype={}", self.subtype()),
+        ];
+        match self.allow {
+            AllowAccess::Owner => {}
+            AllowAccess::Root => options.push("allow_root".to_owned()),
+            AllowAccess::Other => options.push("allow_other".to_owned()),
+        }
+        if !self.passthrough_permissions {
+            options.push("default_permissions".to_owned());
+        }
+        if let Some(max_read) = self.max_read {
+            options.push(format!("max_read={}", max_read));
+        }
+        options
+    }
+
+    // open_flags returns the flags the backing file is opened with. The kernel
+    // reads the pages around a cached write, so the file opened write-only is
+    // opened for reading as well.
+    pub fn open_flags(&self, flags: i32) -> i32 {
+        if self.writeback_cache && flags & libc::O_ACCMODE == libc::O_WRONLY {
+            (flags & !libc::O_ACCMODE) | libc::O_RDWR
+        } else {
+            flags
+        }
+    }
+}
+
+// parse_request_size parses the size of a request, like "1M"
+pub fn parse_request_size(size: &str) -> anyhow::Result<u32> {
+    Ok(u32::try_from(budget::parse_size(size)?)?)
+}
+
+// parse_mount_label checks the fsname or the subtype of the mount, which
+// cannot break the list of the mount options or the fields of /proc/mounts
+pub fn parse_mount_label(label: &str) -> anyhow::Result<String> {
+    if label.is_empty() || label.contains(|c: char| c == ',' || c.is_whitespace()) {
+        return Err(anyhow!("invalid mount label {:?}", label));
+    }
+    Ok(label.to_owned())
+}
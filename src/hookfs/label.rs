@@ -0,0 +1,23 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+// MountLabel tells the mounts (and the experiments on them) running in one
+// process apart in the logs, audit records and control responses
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MountLabel {
+    #[serde(serialize_with = "crate::utils::os_path::serialize")]
+    pub path: PathBuf,
+    pub experiment: Option<String>,
+}
+
+impl fmt::Display for MountLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.experiment {
+            Some(experiment) => write!(f, "{}@{}", experiment, self.path.display()),
+            None => write!(f, "{}", self.path.display()),
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
+use nix::sys::stat;
+use nix::unistd::close;
+use tracing::{error, trace};
+
+use super::reply::Lock;
+use super::{Error, Result};
+
+// the end of a lock reaching the end of the file
+const OFFSET_MAX: u64 = i64::MAX as u64;
+
+// the first and the longest delays before a waiter tries to lock again
+pub const RETRY_DELAY: Duration = Duration::from_millis(1);
+pub const MAX_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// LockFd is the file opened for a lock owner, which is closed once neither
+// the table nor a waiter uses it
+#[derive(Debug)]
+pub struct LockFd(RawFd);
+
+impl LockFd {
+    pub fn fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for LockFd {
+    fn drop(&mut self) {
+        if let Err(err) = close(self.0) {
+            error!("fail to close lock fd {}: {:?}", self.0, err);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Owner {
+    fd: Arc<LockFd>,
+    // the files which the owner has locked through
+    fhs: HashSet<u64>,
+}
+
+// LockTable passes the POSIX locks through to the backing files. The locks are
+// taken as open file description locks on a file opened for every lock owner,
+// so that the locks of different owners conflict like the locks of different
+// processes, although they are all held by toda.
+#[derive(Debug, Default)]
+pub struct LockTable {
+    owners: Mutex<HashMap<(u64, u64), Owner>>,
+}
+
+impl LockTable {
+    // fd returns the file opened for the lock owner, or opens it
+    pub fn fd(&self, ino: u64, owner: u64, fh: u64, path: &Path) -> Result<Arc<LockFd>> {
+        if let Some(opened) = self.owners.lock().unwrap().get_mut(&(ino, owner)) {
+            opened.fhs.insert(fh);
+            return Ok(opened.fd.clone());
+        }
+
+        let flags = OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let fd = match open(path, flags | OFlag::O_RDWR, stat::Mode::empty()) {
+            Err(nix::Error::Sys(Errno::EACCES)) | Err(nix::Error::Sys(Errno::EISDIR)) => {
+                open(path, flags | OFlag::O_RDONLY, stat::Mode::empty())?
+            }
+            fd => fd?,
+        };
+        let fd = Arc::new(LockFd(fd));
+        trace!("open {} for lock owner {:x}", path.display(), owner);
+
+        // the file opened concurrently for the same owner is kept, and this
+        // one is closed once dropped
+        let mut owners = self.owners.lock().unwrap();
+        let opened = owners.entry((ino, owner)).or_insert_with(|| Owner {
+            fd,
+            fhs: HashSet::new(),
+        });
+        opened.fhs.insert(fh);
+        Ok(opened.fd.clone())
+    }
+
+    // opened returns the file opened for the lock owner, if it has ever locked
+    pub fn opened(&self, ino: u64, owner: u64) -> Option<Arc<LockFd>> {
+        self.owners
+            .lock()
+            .unwrap()
+            .get(&(ino, owner))
+            .map(|opened| opened.fd.clone())
+    }
+
+    // holds tells whether `fd` is still the file of the lock owner, which it
+    // isn't once the owner has released its locks
+    pub fn holds(&self, ino: u64, owner: u64, fd: &Arc<LockFd>) -> bool {
+        self.opened(ino, owner)
+            .map_or(false, |opened| Arc::ptr_eq(&opened, fd))
+    }
+
+    // release drops all locks of the owner on the inode
+    pub fn release(&self, ino: u64, owner: u64) {
+        if self.owners.lock().unwrap().remove(&(ino, owner)).is_some() {
+            trace!("release locks of owner {:x}", owner);
+        }
+    }
+
+    // release_fh drops the locks of the owners which have locked through the
+    // file, as closing any file releases the locks of its process
+    pub fn release_fh(&self, fh: u64) {
+        self.owners
+            .lock()
+            .unwrap()
+            .retain(|_, opened| !opened.fhs.contains(&fh));
+    }
+
+    // forget drops the locks left on an inode which the kernel has forgotten
+    pub fn forget(&self, ino: u64) {
+        self.owners
+            .lock()
+            .unwrap()
+            .retain(|(locked, _), _| *locked != ino);
+    }
+}
+
+fn flock(start: u64, end: u64, typ: i32) -> libc::flock {
+    libc::flock {
+        l_type: typ as libc::c_short,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: start as libc::off_t,
+        // zero length locks reach the end of the file
+        l_len: if end >= OFFSET_MAX {
+            0
+        } else {
+            (end - start + 1) as libc::off_t
+        },
+        // it must be zero for open file description locks
+        l_pid: 0,
+    }
+}
+
+pub fn getlk(fd: RawFd, start: u64, end: u64, typ: i32) -> Result<Lock> {
+    let mut lock = flock(start, end, typ);
+    fcntl(fd, FcntlArg::F_OFD_GETLK(&mut lock))?;
+
+    if lock.l_type == libc::F_UNLCK as libc::c_short {
+        return Ok(Lock::new(start, end, libc::F_UNLCK, 0));
+    }
+    let start = lock.l_start as u64;
+    let end = if lock.l_len == 0 {
+        OFFSET_MAX
+    } else {
+        start + lock.l_len as u64 - 1
+    };
+    // the pid of the owner of a conflicting open file description lock is
+    // unknown
+    Ok(Lock::new(
+        start,
+        end,
+        lock.l_type as i32,
+        lock.l_pid.max(0) as u32,
+    ))
+}
+
+// setlk never waits for a conflicting lock, as a waiter would hold a blocking
+// thread until the lock is released
+pub fn setlk(fd: RawFd, start: u64, end: u64, typ: i32) -> Result<()> {
+    let lock = flock(start, end, typ);
+    match fcntl(fd, FcntlArg::F_OFD_SETLK(&lock)) {
+        Ok(_) => Ok(()),
+        // the kernel expects EAGAIN for a conflicting lock
+        Err(nix::Error::Sys(Errno::EACCES)) => Err(Error::Sys(Errno::EAGAIN)),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::future::Future;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tracing::trace;
+
+// the length of the leading bytes kept for every opened file
+pub const HEADER_LENGTH: usize = 16;
+
+// Headers maps the paths in a mount to the leading bytes of the files, by the
+// handles they are sniffed for when opened. Files are only sniffed while a
+// rule on the mount matches the magic, so that the other opens don't pay for
+// it, and a file opened before such a rule is installed doesn't match until
+// it's opened again.
+#[derive(Debug, Clone, Default)]
+pub struct Headers(Arc<Mutex<HashMap<PathBuf, BTreeMap<u64, Vec<u8>>>>>);
+
+impl Headers {
+    pub(super) fn opened(&self, path: PathBuf, fh: u64, header: Vec<u8>) {
+        trace!("sniffed {:?} from {}", header, path.display());
+        self.0
+            .lock()
+            .unwrap()
+            .entry(path)
+            .or_default()
+            .insert(fh, header);
+    }
+
+    pub(super) fn released(&self, path: &Path, fh: u64) {
+        let mut headers = self.0.lock().unwrap();
+        if let Some(handles) = headers.get_mut(path) {
+            handles.remove(&fh);
+            if handles.is_empty() {
+                headers.remove(path);
+            }
+        }
+    }
+
+    // get returns the leading bytes the file had when it was last opened
+    fn get(&self, path: &Path) -> Option<Vec<u8>> {
+        let headers = self.0.lock().unwrap();
+        let handles = headers.get(path)?;
+        handles.values().next_back().cloned()
+    }
+
+    // scope runs the operation with the headers of its mount
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        HEADERS.scope(self, f).await
+    }
+}
+
+tokio::task_local! {
+    static HEADERS: Headers;
+}
+
+// header returns the leading bytes of the file, if it's opened on the mount of
+// the current operation and sniffed
+pub fn header(path: &Path) -> Option<Vec<u8>> {
+    HEADERS.try_with(|headers| headers.get(path)).ok().flatten()
+}
+
+// sniff reads the leading bytes of the file. It blocks the current thread.
+pub(super) fn sniff(real_path: &Path) -> Vec<u8> {
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+    if let Ok(file) = File::open(real_path) {
+        let _ = file.take(HEADER_LENGTH as u64).read_to_end(&mut header);
+    }
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers_by_handle() {
+        let headers = Headers::default();
+        let path = Path::new("/mnt/db");
+        headers.opened(path.to_owned(), 1, b"SQLite".to_vec());
+        headers.opened(path.to_owned(), 2, b"SQLite".to_vec());
+        // releasing a handle which isn't sniffed keeps the others
+        headers.released(path, 3);
+        headers.released(path, 1);
+        assert_eq!(headers.get(path), Some(b"SQLite".to_vec()));
+        headers.released(path, 2);
+        assert_eq!(headers.get(path), None);
+    }
+
+    #[test]
+    fn test_header_of_mount() {
+        let headers = Headers::default();
+        let path = Path::new("/mnt/db");
+        headers.opened(path.to_owned(), 1, b"SQLite".to_vec());
+
+        // the header is only seen by the operations on its mount
+        assert_eq!(header(path), None);
+        let mount = futures::executor::block_on(headers.scope(async { header(path) }));
+        assert_eq!(mount, Some(b"SQLite".to_vec()));
+        let other = Headers::default();
+        let other = futures::executor::block_on(other.scope(async { header(path) }));
+        assert_eq!(other, None);
+    }
+}
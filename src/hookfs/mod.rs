@@ -1,45 +1,66 @@
 mod async_fs;
+pub mod budget;
+pub mod caller;
+mod checksum;
+mod direct;
 mod errors;
+mod journal;
+mod kernel;
+mod label;
+mod lock;
+pub mod magic;
 mod reply;
 pub mod runtime;
+mod shadow;
+mod snapshot;
 mod utils;
 
-use std::collections::{HashMap, LinkedList};
+use std::collections::{BTreeMap, HashMap, HashSet, LinkedList};
 use std::ffi::{CString, OsStr, OsString};
 use std::os::unix::ffi::OsStrExt;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-pub use async_fs::{AsyncFileSystem, AsyncFileSystemImpl};
+pub use async_fs::{AsyncFileSystem, AsyncFileSystemImpl, InFlight};
 use async_trait::async_trait;
+use budget::MemoryBudget;
+pub use checksum::{ChecksumReport, ChecksumVerifier};
 use derive_more::{Deref, DerefMut, From};
+use direct::{AlignedBuffer, Buffered};
 pub use errors::{HookFsError as Error, Result};
 use fuser::*;
+use journal::Journal;
+pub use kernel::{parse_mount_label, parse_request_size, AllowAccess, KernelOptions};
+pub use label::MountLabel;
 use libc::{c_void, lgetxattr, llistxattr, lremovexattr, lsetxattr};
+use lock::LockTable;
 use nix::dir;
 use nix::errno::Errno;
-use nix::fcntl::{open, readlink, renameat, OFlag};
-use nix::sys::{stat, statfs};
+use nix::fcntl::{fcntl, open, readlink, renameat, AtFlags, FcntlArg, OFlag};
+use nix::sys::{stat, statvfs};
 use nix::unistd::{
-    close, fchownat, fsync, linkat, mkdir, symlinkat, truncate, unlink, AccessFlags, FchownatFlags,
-    Gid, LinkatFlags, Uid,
+    close, dup3, fchown, fchownat, fsync, ftruncate, gettid, linkat, mkdir, symlinkat, truncate,
+    unlink, FchownatFlags, Gid, LinkatFlags, Uid,
 };
 pub use reply::Reply;
 use reply::*;
 use runtime::spawn_blocking;
+pub use shadow::Shadow;
 use slab::Slab;
-use tokio::sync::RwLock;
+pub use snapshot::Snapshot;
+use tokio::sync::{Mutex, RwLock};
 use tracing::{debug, error, instrument, trace};
 use utils::*;
 
-use crate::injector::{Injector, Method, MultiInjector};
-
-// use fuse::consts::FOPEN_DIRECT_IO;
+use crate::injector::{Drain, DrainPolicy, Injector, IoContext, Method, MultiInjector};
+use crate::metrics;
 
 macro_rules! inject {
     ($self:ident, $method:ident, $path:expr) => {
-        if $self.enable_injection.load(Ordering::SeqCst) {
+        if $self.injecting() {
             $self
                 .injector
                 .read()
@@ -66,6 +87,7 @@ macro_rules! inject_with_fh {
     ($self:ident, $method:ident, $fh:ident) => {{
         let opened_files = $self.opened_files.read().await;
         if let Ok(file) = opened_files.get($fh as usize) {
+            caller::set_flags(file.flags);
             let path = file.original_path().to_owned();
             drop(opened_files);
             inject!($self, $method, &path);
@@ -73,28 +95,116 @@ macro_rules! inject_with_fh {
     }};
 }
 
+// inject_io returns the io let through by the injectors, which should be told
+// how much of it is done through `io_done`
+macro_rules! inject_io {
+    ($self:ident, $method:ident, $fh:ident, $offset:expr, $size:expr) => {{
+        let opened_files = $self.opened_files.read().await;
+        let mut injected = None;
+        if let Ok(file) = opened_files.get($fh as usize) {
+            caller::set_flags(file.flags);
+            let path = file.original_path().to_owned();
+            let fd = file.fd;
+            drop(opened_files);
+            if $self.injecting() {
+                let io = IoContext {
+                    fh: $fh,
+                    fd,
+                    offset: $offset,
+                    size: $size,
+                };
+                let path = $self.rebuild_path(&path)?;
+                let injector = $self.injector.read().await;
+                if let Err(err) = injector.inject_io(&Method::$method, &path, &io).await {
+                    // the injectors which have let it through give it back
+                    injector.io_done(&io, 0);
+                    return Err(err);
+                }
+                injected = Some(io);
+            }
+        }
+        injected
+    }};
+}
+
+macro_rules! enter_with_fh {
+    ($self:ident, $method:ident, $fh:ident) => {{
+        let path = $self
+            .opened_files
+            .read()
+            .await
+            .get($fh as usize)
+            .map(|file| {
+                caller::set_flags(file.flags);
+                file.original_path().to_owned()
+            });
+        match path {
+            Ok(path) if $self.injecting() => {
+                $self
+                    .injector
+                    .read()
+                    .await
+                    .enter(&Method::$method, $self.rebuild_path(&path)?.as_path())
+                    .await?
+            }
+            _ => Vec::new(),
+        }
+    }};
+}
+
 macro_rules! inject_write_data {
-    ($self:ident, $fh:ident, $data:ident) => {{
+    ($self:ident, $fh:ident, $offset:expr, $data:ident) => {{
         let opened_files = $self.opened_files.read().await;
         if let Ok(file) = opened_files.get($fh as usize) {
             let path = file.original_path().to_owned();
+            let fd = file.fd;
+            drop(opened_files);
+            let io = IoContext {
+                fh: $fh,
+                fd,
+                offset: $offset,
+                size: $data.len() as u64,
+            };
             trace!("Write data before inject {:?}", $data);
-            $self
-                .injector
-                .read()
-                .await
-                .inject_write_data($self.rebuild_path(path)?.as_path(), &mut $data)?;
+            $self.injector.read().await.inject_write_data(
+                $self.rebuild_path(path)?.as_path(),
+                &io,
+                &mut $data,
+            )?;
             trace!("Write data after inject {:?}", $data);
         }
     }};
 }
 
+macro_rules! inject_read_data {
+    ($self:ident, $file:expr, $fh:ident, $offset:expr, $size:expr, $data:ident) => {
+        if $self.injecting() {
+            let io = IoContext {
+                fh: $fh,
+                fd: $file.fd,
+                offset: $offset,
+                size: $size,
+            };
+            $self.injector.read().await.inject_read_data(
+                $self.rebuild_path($file.original_path())?.as_path(),
+                &io,
+                &mut $data,
+            )?;
+        }
+    };
+}
+
 macro_rules! inject_with_dir_fh {
     ($self:ident, $method:ident, $fh:ident) => {{
-        let opened_dirs = $self.opened_dirs.read().await;
-        if let Ok(dir) = opened_dirs.get($fh as usize) {
-            let path = dir.original_path().to_owned();
-            drop(opened_dirs);
+        let dir = $self
+            .opened_dirs
+            .read()
+            .await
+            .get($fh as usize)
+            .ok()
+            .cloned();
+        if let Some(dir) = dir {
+            let path = dir.lock().await.original_path().to_owned();
             inject!($self, $method, &path);
         }
     }};
@@ -114,7 +224,7 @@ macro_rules! inject_with_parent_and_name {
 
 macro_rules! inject_attr {
     ($self:ident, $attr:ident, $path:expr) => {
-        if $self.enable_injection.load(Ordering::SeqCst) {
+        if $self.injecting() {
             $self
                 .injector
                 .read()
@@ -126,7 +236,7 @@ macro_rules! inject_attr {
 
 macro_rules! inject_reply {
     ($self:ident, $method:ident, $path:expr, $reply:ident, $reply_typ:ident) => {
-        if $self.enable_injection.load(Ordering::SeqCst) {
+        if $self.injecting() {
             trace!("before inject {:?}", $reply);
             $self.injector.read().await.inject_reply(
                 &Method::$method,
@@ -138,6 +248,20 @@ macro_rules! inject_reply {
     };
 }
 
+// HookFs is shared by the tasks handling the requests, which run in parallel.
+// The tables of the opened files and directories are only locked to look up,
+// insert or remove an entry, and never across the io on the backing files or
+// the injection, so the operations on the opened files and directories all run
+// in parallel, except for the readdirs of one directory. The inode map is
+// read-locked by the operations resolving an inode until they're done. The
+// ones adding an entry (lookup, mknod, mkdir, symlink, link and create) only
+// read-lock it to resolve the parent, and run in parallel with each other and
+// with the former until they write-lock it to remember the entry, which
+// re-validates the entry if it may have been removed since it was stated. The
+// ones removing or renaming entries (unlink, rmdir and rename) and forget
+// write-lock it until they're done, and are serialized with all the others.
+// The locks are taken in the order of the inode map, the tables, and the
+// injector.
 #[derive(Debug)]
 pub struct HookFs {
     mount_path: PathBuf,
@@ -145,19 +269,66 @@ pub struct HookFs {
 
     enable_injection: AtomicBool,
 
-    opened_files: RwLock<FhMap<File>>,
+    // the threads of toda going through the mount, whose requests are never
+    // injected
+    internal: Arc<std::sync::Mutex<HashSet<u32>>>,
+
+    opened_files: RwLock<FhMap<Arc<File>>>,
 
-    opened_dirs: RwLock<FhMap<Dir>>,
+    opened_dirs: RwLock<FhMap<Arc<Mutex<Dir>>>>,
 
     pub injector: RwLock<MultiInjector>,
 
     // map from inode to real path
     inode_map: RwLock<InodeMap>,
+
+    overlay: Option<Overlay>,
+
+    checksum: Option<ChecksumVerifier>,
+
+    // the budget of the checksums and the journals of this mount
+    budget: Arc<MemoryBudget>,
+
+    // the journals of the inodes written since their last sync, each locked
+    // by a write while it's journaled and done, and by a sync while it's
+    // undone
+    journals: std::sync::Mutex<HashMap<u64, Arc<Mutex<Journal>>>>,
+
+    label: MountLabel,
+
+    locks: LockTable,
+
+    kernel: KernelOptions,
+
+    // the generation of the next inode remembered, on the file systems which
+    // don't support FS_IOC_GETVERSION
+    next_generation: AtomicU64,
+
+    // the count of the paths removed from the inode map or renamed in it,
+    // which is bumped with the inode map write-locked
+    removals: AtomicU64,
+
+    drain: Drain,
+
+    // the leading bytes of the opened files, for the rules matching the magic
+    headers: magic::Headers,
+
+    in_flight: InFlight,
+}
+
+// Overlay redirects the operations to another directory than the backing one
+#[derive(Debug, Clone)]
+pub enum Overlay {
+    Shadow(Shadow),
+    Snapshot(Snapshot),
 }
 
 #[derive(Debug, Default)]
 struct Node {
     pub ref_count: u64,
+    // the generation replied to the kernel, which is kept until the kernel
+    // forgets the inode
+    generation: Option<u64>,
     // TODO: optimize paths with a combination data structure
     paths: LinkedList<PathBuf>,
 }
@@ -199,10 +370,21 @@ impl InodeMap {
         }
     }
 
+    fn contains(&self, inode: u64) -> bool {
+        self.0.contains_key(&inode)
+    }
+
+    // decrease_ref drops the inode once the kernel has forgotten every lookup
+    // of it. The root is never looked up, so it's never dropped.
     fn decrease_ref(&mut self, inode: u64, nlookup: u64) {
+        if inode == 1 {
+            return;
+        }
         if let Some(node) = self.0.get_mut(&inode) {
             if node.ref_count <= nlookup {
                 self.0.remove(&inode);
+            } else {
+                node.ref_count -= nlookup;
             }
         }
     }
@@ -214,6 +396,30 @@ impl InodeMap {
             .insert(path.as_ref().to_owned());
     }
 
+    // rename_paths moves the paths under `from` to be under `to`, replacing
+    // the paths under `to`, or swaps them if `exchange` is set
+    fn rename_paths(&mut self, from: &Path, to: &Path, exchange: bool) {
+        // renaming a hard link onto another link of the same file does nothing
+        let same_file = self.0.values().any(|node| {
+            node.paths.iter().any(|path| path == from) && node.paths.iter().any(|path| path == to)
+        });
+        if from == to || same_file {
+            return;
+        }
+        for node in self.0.values_mut() {
+            if !exchange {
+                node.paths.drain_filter(|path| path.starts_with(to));
+            }
+            for path in node.paths.iter_mut() {
+                if let Ok(tail) = path.strip_prefix(from) {
+                    *path = to.join(tail);
+                } else if let (true, Ok(tail)) = (exchange, path.strip_prefix(to)) {
+                    *path = from.join(tail);
+                }
+            }
+        }
+    }
+
     fn remove_path<P: AsRef<Path>>(&mut self, inode: u64, path: P) {
         match self.0.get_mut(&inode) {
             Some(set) => {
@@ -238,12 +444,25 @@ impl<T> FhMap<T> {
             .get_mut(key)
             .ok_or(Error::FhNotFound { fh: key as u64 })
     }
+    // remove frees the slot for reuse, and fails instead of panicking if it's
+    // vacant
+    fn remove(&mut self, key: usize) -> Result<T> {
+        if !self.0.contains(key) {
+            return Err(Error::FhNotFound { fh: key as u64 });
+        }
+        Ok(self.0.remove(key))
+    }
 }
 
+pub type DirEntry = (u64, FileType, OsString);
+
 #[derive(Debug)]
 pub struct Dir {
     dir: dir::Dir,
     original_path: PathBuf,
+    // the entries read by the readdir at offset 0, from which the following
+    // readdir calls are served
+    entries: Option<Vec<DirEntry>>,
 }
 
 impl Dir {
@@ -251,6 +470,7 @@ impl Dir {
         Dir {
             dir,
             original_path: path.as_ref().to_owned(),
+            entries: None,
         }
     }
     fn original_path(&self) -> &Path {
@@ -276,22 +496,48 @@ impl std::ops::DerefMut for Dir {
 pub struct File {
     pub fd: RawFd,
     original_path: PathBuf,
+    // the flags the file is opened with by the application
+    flags: i32,
+    // whether the backing file is opened with O_DIRECT, which it isn't if its
+    // file system refuses it
+    direct: bool,
 }
 
 impl File {
-    fn new<P: AsRef<Path>>(fd: RawFd, path: P) -> File {
+    fn new<P: AsRef<Path>>(fd: RawFd, path: P, flags: i32) -> File {
         File {
             fd,
             original_path: path.as_ref().to_owned(),
+            flags,
+            direct: direct::is_direct(fd),
         }
     }
     fn original_path(&self) -> &Path {
         &self.original_path
     }
+    fn direct(&self) -> bool {
+        self.direct
+    }
+}
+
+// The fd is closed once the last operation using the file is done, as the
+// operations in flight keep it after the release removes it from the table.
+impl Drop for File {
+    fn drop(&mut self) {
+        if self.fd >= 0 {
+            let _ = close(self.fd);
+        }
+    }
 }
 
+// the directory stream is only used by one task at a time, under its mutex
 unsafe impl Send for Dir {}
-unsafe impl Sync for Dir {}
+
+// the tasks handling the requests share the HookFs
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<HookFs>();
+};
 
 impl HookFs {
     pub fn new<P1: AsRef<Path>, P2: AsRef<Path>>(
@@ -312,13 +558,107 @@ impl HookFs {
             injector: RwLock::new(injector),
             inode_map,
             enable_injection: AtomicBool::from(false),
+            internal: Default::default(),
+            overlay: None,
+            checksum: None,
+            budget: budget::global(),
+            journals: Default::default(),
+            label: MountLabel {
+                path: mount_path.as_ref().to_owned(),
+                experiment: None,
+            },
+            locks: LockTable::default(),
+            kernel: KernelOptions::default(),
+            next_generation: AtomicU64::new(1),
+            removals: AtomicU64::new(0),
+            drain: Drain::default(),
+            headers: magic::Headers::default(),
+            in_flight: InFlight::default(),
+        }
+    }
+
+    // with_experiment adds the experiment id to the label of the mount
+    pub fn with_experiment(mut self, experiment: String) -> HookFs {
+        self.label.experiment = Some(experiment);
+        self
+    }
+
+    pub fn label(&self) -> &MountLabel {
+        &self.label
+    }
+
+    pub fn with_overlay(mut self, overlay: Overlay) -> HookFs {
+        self.overlay = Some(overlay);
+        self
+    }
+
+    pub fn overlay(&self) -> Option<&Overlay> {
+        self.overlay.as_ref()
+    }
+
+    // with_checksum verifies the data read back against the data written
+    pub fn with_checksum(mut self) -> HookFs {
+        self.checksum = Some(ChecksumVerifier::new(self.budget.clone()));
+        self
+    }
+
+    // with_memory_budget bounds the state buffered by this mount with its own
+    // budget, instead of the one of the process
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> HookFs {
+        if self.checksum.is_some() {
+            self.checksum = Some(ChecksumVerifier::new(budget.clone()));
         }
+        self.budget = budget;
+        self
+    }
+
+    pub fn memory_usage(&self) -> BTreeMap<&'static str, usize> {
+        self.budget.usage()
+    }
+
+    pub fn checksum_report(&self) -> Option<ChecksumReport> {
+        self.checksum.as_ref().map(|checksum| ChecksumReport {
+            mount: self.label.clone(),
+            ..checksum.report()
+        })
+    }
+
+    // with_kernel_options negotiates the caching and the size of the requests
+    // with the kernel
+    pub fn with_kernel_options(mut self, kernel: KernelOptions) -> HookFs {
+        self.kernel = kernel;
+        self
     }
 
     pub fn enable_injection(&self) {
         self.enable_injection.store(true, Ordering::SeqCst);
     }
 
+    // set_drain_policy decides what happens to the operations delayed on this
+    // mount once the injection is interrupted
+    pub fn set_drain_policy(&self, policy: DrainPolicy) {
+        self.drain.set(policy)
+    }
+
+    // injecting tells whether the current request should go through the
+    // injectors, which it doesn't once the injection is disabled or if it's
+    // issued by toda itself
+    fn injecting(&self) -> bool {
+        if !self.enable_injection.load(Ordering::SeqCst) {
+            return false;
+        }
+        match caller::current() {
+            Some(caller) => !self.internal.lock().unwrap().contains(&caller.pid),
+            None => true,
+        }
+    }
+
+    // wait_idle waits until every operation on this mount is replied to, or
+    // the timeout, and returns the count of the operations still in flight
+    pub fn wait_idle(&self, timeout: Duration) -> usize {
+        self.in_flight.wait_idle(timeout)
+    }
+
     pub fn disable_injection(&self) {
         self.enable_injection.store(false, Ordering::SeqCst);
 
@@ -331,6 +671,181 @@ impl HookFs {
         });
     }
 
+    // invalidate drops the page cache of the files under `path` (or the whole
+    // mount), so that they are read again through the injectors, and returns
+    // the count of them. The entries and attributes cached by the kernel are
+    // kept until their timeouts expire, which are zero unless configured by
+    // the kernel options.
+    //
+    // The files are opened through the mount, so it must not be called while
+    // handling a request. The requests of the thread opening them are never
+    // injected.
+    pub async fn invalidate(&self, path: Option<&Path>) -> Result<usize> {
+        let prefix = match path {
+            Some(path) => {
+                let relative = path
+                    .strip_prefix(&self.mount_path)
+                    .map_err(|_| Error::Sys(Errno::EINVAL))?;
+                self.original_path.join(relative)
+            }
+            None => self.original_path.clone(),
+        };
+        let paths = self
+            .inode_map
+            .read()
+            .await
+            .values()
+            .filter_map(|node| node.get_path())
+            .filter(|path| path.starts_with(&prefix))
+            .map(|path| self.rebuild_path(path))
+            .collect::<Result<Vec<_>>>()?;
+        trace!("invalidate page cache of {} files", paths.len());
+
+        let internal = self.internal.clone();
+        Ok(spawn_blocking(move || {
+            // the requests carry the id of the thread rather than the process
+            let thread = gettid().as_raw() as u32;
+            internal.lock().unwrap().insert(thread);
+            let count = paths.iter().filter(|path| drop_page_cache(path)).count();
+            internal.lock().unwrap().remove(&thread);
+            count
+        })
+        .await?)
+    }
+
+    // inode_count returns the count of the inodes remembered until the kernel
+    // forgets them
+    pub async fn inode_count(&self) -> usize {
+        self.inode_map.read().await.len()
+    }
+
+    // journal returns the journal of the inode
+    fn journal(&self, ino: u64) -> Arc<Mutex<Journal>> {
+        self.journals
+            .lock()
+            .unwrap()
+            .entry(ino)
+            .or_insert_with(|| Arc::new(Mutex::new(Journal::new(self.budget.clone()))))
+            .clone()
+    }
+
+    // forget_journal removes the emptied journal of the inode, unless another
+    // operation is about to use it
+    fn forget_journal(&self, ino: u64, journal: Arc<Mutex<Journal>>) {
+        let mut journals = self.journals.lock().unwrap();
+        if Arc::strong_count(&journal) == 2 {
+            journals.remove(&ino);
+        }
+    }
+
+    // record journals the data about to be overwritten by a write through the
+    // file, so that sync_file can undo the write
+    async fn record(
+        &self,
+        journal: &mut Journal,
+        file: &Arc<File>,
+        offset: i64,
+        length: usize,
+    ) -> Result<()> {
+        let fd = file.fd;
+        let size = spawn_blocking(move || stat::fstat(fd)).await??.st_size;
+        let old = async_read(fd, length, offset, file.direct()).await?;
+        journal.record(file, size, offset, old)
+    }
+
+    // remember inserts the path of the inode looked up by the kernel, and
+    // returns the generation of the inode, which tells it apart from another
+    // inode reusing its number, for the kernel and the clients of a re-export
+    // over NFS. It's the version of the backing file where the file system
+    // supports FS_IOC_GETVERSION, or a counter bumped whenever an inode is
+    // remembered anew. The version is queried without the inode map locked.
+    //
+    // The path is stated before the inode map is locked, so it may have been
+    // unlinked or renamed meanwhile. `seen` is the count of the removals
+    // before it was stated, and if any has happened since, the path is stated
+    // again and only remembered if it still names the inode.
+    async fn remember(&self, stat: &FileAttr, path: &Path, seen: u64) -> Result<u64> {
+        {
+            let mut inode_map = self.inode_map.write().await;
+            if self.removals() != seen && !self.names(path, stat.ino).await? {
+                trace!("{} is removed before it's remembered", path.display());
+                return Err(Error::Sys(Errno::ENOENT));
+            }
+            inode_map.insert_path(stat.ino, path);
+            inode_map.increase_ref(stat.ino);
+            if let Some(generation) = inode_map.get(&stat.ino).and_then(|node| node.generation) {
+                return Ok(generation);
+            }
+        }
+
+        let real_path = self.read_path(path).await?;
+        let kind = stat.kind;
+        let generation = spawn_blocking(move || path_generation(&real_path, kind))
+            .await?
+            .unwrap_or_else(|| self.next_generation.fetch_add(1, Ordering::SeqCst));
+        // the inode isn't forgotten before the reply, but another lookup may
+        // have set its generation meanwhile
+        Ok(match self.inode_map.write().await.get_mut(&stat.ino) {
+            Some(node) => *node.generation.get_or_insert(generation),
+            None => generation,
+        })
+    }
+
+    // removals returns the count of the paths removed or renamed so far
+    fn removals(&self) -> u64 {
+        self.removals.load(Ordering::SeqCst)
+    }
+
+    // names returns whether the path still names the inode
+    async fn names(&self, path: &Path, ino: u64) -> Result<bool> {
+        let real_path = self.read_path(path).await?;
+        Ok(spawn_blocking(move || stat::lstat(&real_path))
+            .await?
+            .map_or(false, |stat| stat.st_ino == ino))
+    }
+
+    // file returns the opened file, without keeping the table locked
+    async fn file(&self, fh: u64) -> Result<Arc<File>> {
+        Ok(self.opened_files.read().await.get(fh as usize)?.clone())
+    }
+
+    // sync_file syncs the file, after undoing the writes to its inode since
+    // the last sync if an injector drops them. The writes are kept in the
+    // journal by a flush which isn't injected, as closing a file doesn't sync
+    // it.
+    async fn sync_file(&self, method: Method, ino: u64, fh: u64) -> Result<()> {
+        let file = self.file(fh).await?;
+        let fd = file.fd;
+
+        let path = self.rebuild_path(file.original_path())?;
+        let drop_unsynced = self.injector.read().await.drop_unsynced(&method, &path);
+        let journal = self.journals.lock().unwrap().get(&ino).cloned();
+        if let Some(journal) = journal {
+            let mut journaled = journal.lock().await;
+            if drop_unsynced {
+                if let Some((writer, size, undo)) = journaled.take() {
+                    debug!("drop the unsynced writes to {}", path.display());
+                    for (offset, old) in undo {
+                        async_write(writer.fd, old, offset, writer.direct()).await?;
+                    }
+                    if let Some(size) = size {
+                        async_ftruncate(writer.fd, size).await?;
+                    }
+                }
+            } else if method == Method::FSYNC {
+                journaled.clear();
+            }
+            let emptied = journaled.is_empty();
+            drop(journaled);
+            if emptied {
+                self.forget_journal(ino, journal);
+            }
+        }
+
+        spawn_blocking(move || fsync(fd)).await??;
+        Ok(())
+    }
+
     pub fn rebuild_path<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
         let path_tail = path.as_ref().strip_prefix(self.original_path.as_path())?;
         let path = self.mount_path.join(path_tail);
@@ -340,10 +855,362 @@ impl HookFs {
 }
 
 impl HookFs {
+    // read_path returns the real path whose content should be read
+    async fn read_path(&self, path: &Path) -> Result<PathBuf> {
+        match &self.overlay {
+            Some(Overlay::Shadow(shadow)) => {
+                let shadow = shadow.clone();
+                let path = path.to_owned();
+                spawn_blocking(move || shadow.resolve(&path)).await?
+            }
+            Some(Overlay::Snapshot(snapshot)) => snapshot.resolve(path),
+            None => Ok(path.to_owned()),
+        }
+    }
+
+    // write_path returns the real path which should be modified
+    async fn write_path(&self, path: &Path) -> Result<PathBuf> {
+        match &self.overlay {
+            Some(Overlay::Shadow(shadow)) => {
+                let shadow = shadow.clone();
+                let path_clone = path.to_owned();
+                let (copy, copied) = spawn_blocking(move || shadow.copy_up(&path_clone)).await??;
+                if copied {
+                    self.reopen_copied(path, &copy).await?;
+                }
+                Ok(copy)
+            }
+            Some(Overlay::Snapshot(_)) => Err(Error::Sys(Errno::EROFS)),
+            None => Ok(path.to_owned()),
+        }
+    }
+
+    // reopen_copied switches the files opened on the backing file to its copy
+    // in the shadow directory, so that they see the writes through the other
+    // handles. The fds are replaced in place, as the operations in flight
+    // hold them.
+    async fn reopen_copied(&self, path: &Path, copy: &Path) -> Result<()> {
+        let fds: Vec<_> = self
+            .opened_files
+            .read()
+            .await
+            .iter()
+            .filter(|(_, file)| file.original_path() == path)
+            .map(|(_, file)| file.fd)
+            .collect();
+        for fd in fds {
+            trace!("reopen fd {} on {}", fd, copy.display());
+            let copy = copy.to_owned();
+            spawn_blocking(move || reopen(fd, &copy)).await??;
+        }
+        Ok(())
+    }
+
+    // remove removes the entry, which is hidden by a whiteout in the shadow
+    // directory if it's in the backing one
+    async fn remove(&self, path: &Path, dir: bool) -> Result<()> {
+        match &self.overlay {
+            Some(Overlay::Shadow(shadow)) => {
+                let shadow = shadow.clone();
+                let path = path.to_owned();
+                spawn_blocking(move || {
+                    if dir {
+                        shadow.rmdir(&path)
+                    } else {
+                        shadow.unlink(&path)
+                    }
+                })
+                .await?
+            }
+            Some(Overlay::Snapshot(_)) => Err(Error::Sys(Errno::EROFS)),
+            None if dir => async_rmdir(CString::new(path.as_os_str().as_bytes())?).await,
+            None => async_unlink(path).await,
+        }
+    }
+
+    // rename_path returns the real path which should be renamed. A directory
+    // of the backing directory cannot be renamed in the shadow one without
+    // leaving its entries behind, so it fails with EXDEV, on which the
+    // applications fall back to copy it.
+    async fn rename_path(&self, path: &Path) -> Result<PathBuf> {
+        if let Some(Overlay::Shadow(shadow)) = &self.overlay {
+            let shadow = shadow.clone();
+            let path = path.to_owned();
+            spawn_blocking(move || shadow.check_rename(&path)).await??;
+        }
+        self.write_path(path).await
+    }
+
+    // hide_renamed hides the entries of the backing directory at both paths of
+    // a rename, as the renamed entry replaces them
+    async fn hide_renamed(&self, old_path: &Path, new_path: &Path) -> Result<()> {
+        if let Some(Overlay::Shadow(shadow)) = &self.overlay {
+            let shadow = shadow.clone();
+            let old_path = old_path.to_owned();
+            let new_path = new_path.to_owned();
+            spawn_blocking(move || {
+                shadow.whiteout(&old_path)?;
+                shadow.whiteout(&new_path)
+            })
+            .await??;
+        }
+        Ok(())
+    }
+
+    // tracks_usage returns whether the injector is told the bytes and the
+    // inodes which the operations on the path allocate. It's told regardless
+    // of whether the injection is enabled, as the usage outlives it.
+    async fn tracks_usage(&self, path: &Path) -> Result<bool> {
+        let path = self.rebuild_path(path)?;
+        Ok(self.injector.read().await.tracks_usage(&path))
+    }
+
+    // used tells the injector the bytes and the inodes which an operation on
+    // the path allocated, or freed if they are negative
+    async fn used(&self, path: &Path, bytes: i64, inodes: i64) -> Result<()> {
+        if bytes != 0 || inodes != 0 {
+            let path = self.rebuild_path(path)?;
+            self.injector.read().await.used(&path, bytes, inodes);
+        }
+        Ok(())
+    }
+
+    // tracked_size returns the size of the opened file if its usage is
+    // tracked, to tell how much an operation grows it. The growth of
+    // concurrent operations on the same file may be counted by each of them.
+    async fn tracked_size(&self, file: &File) -> Result<Option<i64>> {
+        if !self.tracks_usage(file.original_path()).await? {
+            return Ok(None);
+        }
+        let fd = file.fd;
+        Ok(Some(
+            spawn_blocking(move || stat::fstat(fd)).await??.st_size,
+        ))
+    }
+
+    // grown tells the injector how much the opened file has grown, or shrunk,
+    // since its tracked size
+    async fn grown(&self, file: &File, before: Option<i64>) -> Result<()> {
+        if let Some(before) = before {
+            let fd = file.fd;
+            let after = spawn_blocking(move || stat::fstat(fd)).await??.st_size;
+            self.used(file.original_path(), after - before, 0).await?;
+        }
+        Ok(())
+    }
+
+    // entry_usage returns the bytes and the inodes which the entry allocates,
+    // with those of its entries if it's a directory, the way the quotas
+    // count them. The bytes of a file with other links stay with them.
+    async fn entry_usage(&self, path: &Path) -> Result<(i64, i64)> {
+        let real_path = self.read_path(path).await?;
+        spawn_blocking(move || -> Result<(i64, i64)> {
+            let stat = match stat::lstat(&real_path) {
+                Ok(stat) => stat,
+                Err(nix::Error::Sys(Errno::ENOENT)) => return Ok((0, 0)),
+                Err(err) => return Err(err.into()),
+            };
+            Ok(match stat.st_mode & libc::S_IFMT {
+                libc::S_IFDIR => {
+                    let (bytes, inodes) = crate::utils::usage(&real_path);
+                    (bytes as i64, inodes as i64 + 1)
+                }
+                _ if stat.st_nlink > 1 => (0, 1),
+                _ => (stat.st_size, 1),
+            })
+        })
+        .await?
+    }
+
+    // io_done tells the injectors how many bytes of the io, which inject_io
+    // let through, are done, so that they give back the rest
+    async fn io_done(&self, io: Option<IoContext>, done: u64) {
+        if let Some(io) = io {
+            self.injector.read().await.io_done(&io, done);
+        }
+    }
+
+    // read_data reads the data of a read let through by the injectors
+    async fn read_data(&self, ino: u64, fh: u64, offset: i64, size: u32) -> Result<Data> {
+        let file = self.file(fh).await?;
+        let mut buf = async_read(file.fd, size as usize, offset, file.direct()).await?;
+        metrics::record_read(buf.len());
+        if let Some(checksum) = &self.checksum {
+            checksum.verify(ino, file.original_path(), offset, &buf);
+        }
+        inject_read_data!(self, file, fh, offset, size as u64, buf);
+
+        let mut reply = Data::new(buf);
+        inject_reply!(self, READ, &file.original_path(), reply, Data);
+        Ok(reply)
+    }
+
+    // write_data writes the data of a write let through by the injectors
+    async fn write_data(&self, ino: u64, fh: u64, offset: i64, mut data: Vec<u8>) -> Result<Write> {
+        if let Some(checksum) = &self.checksum {
+            checksum.reserve(data.len())?;
+        }
+        // the checksum is calculated before the data is sabotaged
+        let original_data = self.checksum.as_ref().map(|_| data.clone());
+        inject_write_data!(self, fh, offset, data);
+        let file = self.file(fh).await?;
+        let journal = if self
+            .injector
+            .read()
+            .await
+            .journal_writes(self.rebuild_path(file.original_path())?.as_path())
+        {
+            Some(self.journal(ino))
+        } else {
+            None
+        };
+        // the journal is locked until the write is done, so that the data
+        // journaled by the other writes is the one they overwrite
+        let mut journaled = match &journal {
+            Some(journal) => Some(journal.lock().await),
+            None => None,
+        };
+        if let Some(journal) = journaled.as_mut() {
+            self.record(journal, &file, offset, data.len()).await?;
+        }
+
+        let before = self.tracked_size(&file).await?;
+
+        let torn = if self.injecting() {
+            let io = IoContext {
+                fh,
+                fd: file.fd,
+                offset,
+                size: data.len() as u64,
+            };
+            self.injector
+                .read()
+                .await
+                .tear_write(self.rebuild_path(file.original_path())?.as_path(), &io)
+        } else {
+            None
+        };
+        let size = match torn {
+            // only the ranges are written, but the whole write is replied
+            Some(ranges) => {
+                for range in ranges {
+                    let start = offset + range.start as i64;
+                    async_write(file.fd, data[range].to_vec(), start, file.direct()).await?;
+                }
+                data.len() as isize
+            }
+            None => async_write(file.fd, data, offset, file.direct()).await?,
+        };
+        metrics::record_written(size as usize);
+        self.grown(&file, before).await?;
+        if let (Some(checksum), Some(data)) = (&self.checksum, original_data) {
+            checksum.record(ino, offset, &data[..size as usize]);
+        }
+        let mut reply = Write::new(size as u32);
+        inject_reply!(self, WRITE, file.original_path(), reply, Write);
+        Ok(reply)
+    }
+
+    // allocate allocates the range of a fallocate let through by the injectors
+    async fn allocate(&self, ino: u64, fh: u64, offset: i64, length: i64, mode: i32) -> Result<()> {
+        let file = self.file(fh).await?;
+        let before = self.tracked_size(&file).await?;
+        async_fallocate(file.fd, mode, offset, length).await?;
+        self.grown(&file, before).await?;
+        // the recorded checksums don't describe the content after a range is
+        // removed or zeroed
+        if let Some(checksum) = self
+            .checksum
+            .as_ref()
+            .filter(|_| mode & !libc::FALLOC_FL_KEEP_SIZE != 0)
+        {
+            checksum.forget(ino);
+        }
+        Ok(())
+    }
+
+    // copy_range copies the range of a copy_file_range let through by the
+    // injectors
+    async fn copy_range(
+        &self,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+    ) -> Result<Write> {
+        let file_in = self.file(fh_in).await?;
+        let file_out = self.file(fh_out).await?;
+        let (fd_in, fd_out) = (file_in.fd, file_out.fd);
+        let before = self.tracked_size(&file_out).await?;
+        let size = spawn_blocking(move || {
+            let mut offset_in = offset_in;
+            let mut offset_out = offset_out;
+            let ret = unsafe {
+                libc::copy_file_range(
+                    fd_in,
+                    &mut offset_in,
+                    fd_out,
+                    &mut offset_out,
+                    len as usize,
+                    flags,
+                )
+            };
+            if ret == -1 {
+                Err(Error::last())
+            } else {
+                Ok(ret)
+            }
+        })
+        .await??;
+        self.grown(&file_out, before).await?;
+        if let Some(checksum) = &self.checksum {
+            checksum.forget(ino_out);
+        }
+
+        let mut reply = Write::new(size as u32);
+        inject_reply!(
+            self,
+            COPY_FILE_RANGE,
+            file_out.original_path(),
+            reply,
+            Write
+        );
+        Ok(reply)
+    }
+
+    // open_backing opens the backing file with the flags of the application,
+    // without O_APPEND as the kernel translates the offsets, and upgraded by
+    // the kernel options. The upgrade is given up if the backing file refuses
+    // it, and the writes cached by the kernel which read the pages around
+    // them fail then.
+    async fn open_backing(&self, path: &Path, flags: OFlag, mode: stat::Mode) -> Result<RawFd> {
+        let flags = flags - OFlag::O_APPEND;
+        let upgraded = OFlag::from_bits_truncate(self.kernel.open_flags(flags.bits()));
+        match async_open(path, upgraded, mode).await {
+            Err(Error::Sys(Errno::EACCES)) if upgraded != flags => {
+                debug!("{} refuses to be read, open it as is", path.display());
+                async_open(path, flags, mode).await
+            }
+            result => result,
+        }
+    }
+
+    // stable_inode replies the inode number of the backing entry for its copy
+    // in the shadow directory
+    fn stable_inode(&self, attr: &mut FileAttr) {
+        if let Some(Overlay::Shadow(shadow)) = &self.overlay {
+            attr.ino = shadow.inode(attr.ino);
+        }
+    }
+
     async fn get_file_attr(&self, path: &Path) -> Result<FileAttr> {
-        let mut attr = async_stat(path)
+        let mut attr = async_stat(&self.read_path(path).await?)
             .await
             .map(convert_libc_stat_to_fuse_stat)??;
+        self.stable_inode(&mut attr);
 
         trace!("before inject attr {:?}", &attr);
         inject_attr!(self, attr, path);
@@ -355,14 +1222,32 @@ impl HookFs {
 
 #[async_trait]
 impl AsyncFileSystemImpl for HookFs {
-    fn init(&self) -> Result<()> {
+    fn init(&self, config: &mut KernelConfig) -> Result<()> {
         trace!("init");
 
+        self.kernel.negotiate(config);
+
         stat::umask(stat::Mode::from_bits_truncate(0));
 
         Ok(())
     }
 
+    fn as_caller(&self) -> bool {
+        self.kernel.passthrough_permissions
+    }
+
+    fn drain(&self) -> Drain {
+        self.drain.clone()
+    }
+
+    fn headers(&self) -> magic::Headers {
+        self.headers.clone()
+    }
+
+    fn in_flight(&self) -> InFlight {
+        self.in_flight.clone()
+    }
+
     fn destroy(&self) {
         trace!("destroy");
     }
@@ -372,23 +1257,30 @@ impl AsyncFileSystemImpl for HookFs {
         trace!("lookup");
         inject_with_parent_and_name!(self, LOOKUP, parent, &name);
 
-        let mut inode_map = self.inode_map.write().await;
         let path = {
+            let inode_map = self.inode_map.read().await;
             let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(name)
+            // "." and ".." are resolved by the kernel, except for the lookups
+            // of exported file handles. ".." never leaves the mount.
+            match name.as_bytes() {
+                b"." => parent_path.to_owned(),
+                b".." if parent_path != self.original_path => {
+                    parent_path.parent().unwrap_or(parent_path).to_owned()
+                }
+                b".." => parent_path.to_owned(),
+                _ => parent_path.join(name),
+            }
         };
         trace!("lookup in {}", path.display());
 
+        let seen = self.removals();
         let stat = self.get_file_attr(&path).await?;
 
         trace!("insert ({}, {}) into inode_map", stat.ino, path.display());
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
-        // TODO: support generation number
-        // this can be implemented with ioctl FS_IOC_GETVERSION
+        let generation = self.remember(&stat, &path, seen).await?;
         trace!("return with {:?}", stat);
 
-        let mut reply = Entry::new(stat, 0);
+        let mut reply = Entry::new(stat, generation, self.kernel.entry_timeout);
         inject_reply!(self, LOOKUP, path.as_path(), reply, Entry);
 
         Ok(reply)
@@ -397,7 +1289,11 @@ impl AsyncFileSystemImpl for HookFs {
     #[instrument(skip(self))]
     async fn forget(&self, ino: u64, nlookup: u64) {
         trace!("forget");
-        self.inode_map.write().await.decrease_ref(ino, nlookup)
+        let mut inode_map = self.inode_map.write().await;
+        inode_map.decrease_ref(ino, nlookup);
+        if !inode_map.contains(ino) {
+            self.locks.forget(ino);
+        }
     }
 
     #[instrument(skip(self))]
@@ -413,7 +1309,7 @@ impl AsyncFileSystemImpl for HookFs {
 
         trace!("return with {:?}", stat);
 
-        let mut reply = Attr::new(stat);
+        let mut reply = Attr::new(stat, self.kernel.attr_timeout);
         inject_reply!(self, GETATTR, path, reply, Attr);
 
         Ok(reply)
@@ -430,37 +1326,92 @@ impl AsyncFileSystemImpl for HookFs {
         atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
         _ctime: Option<std::time::SystemTime>,
-        _fh: Option<u64>,
+        fh: Option<u64>,
         _crtime: Option<std::time::SystemTime>,
         _chgtime: Option<std::time::SystemTime>,
         _bkuptime: Option<std::time::SystemTime>,
         _flags: Option<u32>,
     ) -> Result<Attr> {
         trace!("setattr");
+        if let Some(size) = size {
+            caller::set_size(size);
+        }
         inject_with_ino!(self, SETATTR, ino);
 
-        // TODO: support setattr with fh
-
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?;
+        let real_path = self.write_path(path).await?;
 
-        async_lchown(path, uid, gid).await?;
+        // the opened file is changed and stated through the fd, as it doesn't
+        // require the write permission, and the file may be unlinked
+        let file = match fh {
+            Some(fh) => self.file(fh).await.ok(),
+            None => None,
+        };
+        let fd = file.as_ref().map(|file| file.fd);
+
+        if uid.is_some() || gid.is_some() {
+            match fd {
+                Some(fd) => async_fchown(fd, uid, gid).await?,
+                None => async_lchown(&real_path, uid, gid).await?,
+            }
+        }
 
         if let Some(mode) = mode {
-            async_fchmodat(path, mode).await?;
+            match fd {
+                Some(fd) => async_fchmod(fd, mode).await?,
+                None => async_fchmodat(&real_path, mode).await?,
+            }
         }
 
         if let Some(size) = size {
-            async_truncate(path, size as i64).await?;
+            let before = if self.tracks_usage(path).await? {
+                let real_path = real_path.clone();
+                let stat = spawn_blocking(move || match fd {
+                    Some(fd) => stat::fstat(fd),
+                    None => stat::lstat(&real_path),
+                })
+                .await??;
+                Some(stat.st_size)
+            } else {
+                None
+            };
+            match fd {
+                Some(fd) => async_ftruncate(fd, size as i64).await?,
+                None => async_truncate(&real_path, size as i64).await?,
+            }
+            if let Some(before) = before {
+                self.used(path, size as i64 - before, 0).await?;
+            }
+            if let Some(checksum) = &self.checksum {
+                checksum.truncate(ino, size);
+            }
         }
 
-        let times = [convert_time(atime), convert_time(mtime)];
-        let cpath = CString::new(path.as_os_str().as_bytes())?;
-        async_utimensat(cpath, times).await?;
+        if atime.is_some() || mtime.is_some() {
+            let times = [convert_time(atime), convert_time(mtime)];
+            match fd {
+                Some(fd) => async_futimens(fd, times).await?,
+                None => {
+                    let cpath = CString::new(real_path.as_os_str().as_bytes())?;
+                    async_utimensat(cpath, times).await?;
+                }
+            }
+        }
 
-        let stat = self.get_file_attr(path).await?;
+        let stat = match fd {
+            Some(fd) => {
+                let mut attr = convert_libc_stat_to_fuse_stat(
+                    spawn_blocking(move || stat::fstat(fd)).await??,
+                )?;
+                self.stable_inode(&mut attr);
+                inject_attr!(self, attr, path);
+                attr
+            }
+            None => self.get_file_attr(path).await?,
+        };
         trace!("return with {:?}", stat);
-        let mut reply = Attr::new(stat);
+        let mut reply = Attr::new(stat, self.kernel.attr_timeout);
         inject_reply!(self, GETATTR, path, reply, Attr);
 
         Ok(reply)
@@ -474,7 +1425,7 @@ impl AsyncFileSystemImpl for HookFs {
         let inode_map = self.inode_map.read().await;
         let link_path = inode_map.get_path(ino)?;
 
-        let path = async_readlink(link_path).await?;
+        let path = async_readlink(&self.read_path(link_path).await?).await?;
 
         let path = CString::new(path.as_os_str().as_bytes())?;
 
@@ -501,21 +1452,21 @@ impl AsyncFileSystemImpl for HookFs {
         trace!("mknod");
         inject_with_parent_and_name!(self, MKNOD, parent, &name);
 
-        let mut inode_map = self.inode_map.write().await;
-        let parent_path = inode_map.get_path(parent)?;
-        let path = parent_path.join(&name);
+        let path = self.inode_map.read().await.get_path(parent)?.join(&name);
         inject!(self, MKNOD, path.as_path());
-        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let real_path = self.write_path(&path).await?;
+        let cpath = CString::new(real_path.as_os_str().as_bytes())?;
 
         trace!("mknod for {:?}", cpath);
 
         async_mknod(cpath, mode, rdev as u64).await?;
-        async_lchown(&path, Some(uid), Some(gid)).await?;
+        self.used(&path, 0, 1).await?;
+        async_lchown(&real_path, Some(uid), Some(gid)).await?;
 
+        let seen = self.removals();
         let stat = self.get_file_attr(&path).await?;
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
-        let mut reply = Entry::new(stat, 0);
+        let generation = self.remember(&stat, &path, seen).await?;
+        let mut reply = Entry::new(stat, generation, self.kernel.entry_timeout);
         inject_reply!(self, LOOKUP, path.as_path(), reply, Entry);
 
         Ok(reply)
@@ -534,22 +1485,20 @@ impl AsyncFileSystemImpl for HookFs {
         trace!("mkdir");
         inject_with_parent_and_name!(self, MKDIR, parent, &name);
 
-        let mut inode_map = self.inode_map.write().await;
-        let path = {
-            let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(&name)
-        };
+        let path = self.inode_map.read().await.get_path(parent)?.join(&name);
 
+        let real_path = self.write_path(&path).await?;
         let mode = stat::Mode::from_bits_truncate(mode);
         trace!("create directory with mode: {:?}", mode);
-        async_mkdir(&path, mode).await?;
+        async_mkdir(&real_path, mode).await?;
+        self.used(&path, 0, 1).await?;
         trace!("setting owner {}:{}", uid, gid);
-        async_lchown(&path, Some(uid), Some(gid)).await?;
+        async_lchown(&real_path, Some(uid), Some(gid)).await?;
 
+        let seen = self.removals();
         let stat = self.get_file_attr(&path).await?;
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
-        let mut reply = Entry::new(stat, 0);
+        let generation = self.remember(&stat, &path, seen).await?;
+        let mut reply = Entry::new(stat, generation, self.kernel.entry_timeout);
         inject_reply!(self, LOOKUP, path.as_path(), reply, Entry);
 
         Ok(reply)
@@ -567,12 +1516,25 @@ impl AsyncFileSystemImpl for HookFs {
         };
 
         let stat = self.get_file_attr(&path).await?;
+        let usage = if self.tracks_usage(&path).await? {
+            self.entry_usage(&path).await?
+        } else {
+            (0, 0)
+        };
 
         trace!("unlinking {}", path.display());
-        async_unlink(&path).await?;
+        self.remove(&path, false).await?;
+        self.used(&path, -usage.0, -usage.1).await?;
+        if let Some(checksum) = self.checksum.as_ref().filter(|_| stat.nlink <= 1) {
+            checksum.forget(stat.ino);
+        }
+        if stat.nlink <= 1 {
+            self.journals.lock().unwrap().remove(&stat.ino);
+        }
 
         trace!("remove {:x} from inode_map", &stat.ino);
         inode_map.remove_path(stat.ino, &path);
+        self.removals.fetch_add(1, Ordering::SeqCst);
 
         Ok(())
     }
@@ -590,11 +1552,12 @@ impl AsyncFileSystemImpl for HookFs {
 
         let stat = self.get_file_attr(&path).await?;
 
-        let cpath = CString::new(path.as_os_str().as_bytes())?;
-        async_rmdir(cpath).await?;
+        self.remove(&path, true).await?;
+        self.used(&path, 0, -1).await?;
 
         trace!("remove {:x} from inode_map", &stat.ino);
         inode_map.remove_path(stat.ino, &path);
+        self.removals.fetch_add(1, Ordering::SeqCst);
 
         Ok(())
     }
@@ -611,24 +1574,23 @@ impl AsyncFileSystemImpl for HookFs {
         trace!("symlink");
         inject_with_parent_and_name!(self, SYMLINK, parent, &name);
 
-        let mut inode_map = self.inode_map.write().await;
-        let path = {
-            let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(&name)
-        };
+        let path = self.inode_map.read().await.get_path(parent)?.join(&name);
 
         trace!("create symlink: {} => {}", path.display(), link.display());
 
-        let path_clone = path.clone();
-        spawn_blocking(move || symlinkat(&link, None, &path_clone)).await??;
+        let real_path = self.write_path(&path).await?;
+        let real_path_clone = real_path.clone();
+        let link_size = link.as_os_str().len() as i64;
+        spawn_blocking(move || symlinkat(&link, None, &real_path_clone)).await??;
+        self.used(&path, link_size, 1).await?;
 
         trace!("setting owner {}:{}", uid, gid);
-        async_lchown(&path, Some(uid), Some(gid)).await?;
+        async_lchown(&real_path, Some(uid), Some(gid)).await?;
 
+        let seen = self.removals();
         let stat = self.get_file_attr(&path).await?;
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
-        let mut reply = Entry::new(stat, 0);
+        let generation = self.remember(&stat, &path, seen).await?;
+        let mut reply = Entry::new(stat, generation, self.kernel.entry_timeout);
         inject_reply!(self, LOOKUP, path.as_path(), reply, Entry);
 
         Ok(reply)
@@ -641,7 +1603,7 @@ impl AsyncFileSystemImpl for HookFs {
         name: OsString,
         newparent: u64,
         newname: OsString,
-        _flags: u32,
+        flags: u32,
     ) -> Result<()> {
         trace!("rename");
         inject_with_parent_and_name!(self, RENAME, parent, &name);
@@ -666,15 +1628,48 @@ impl AsyncFileSystemImpl for HookFs {
             new_path.display()
         );
 
-        let new_path_clone = new_path.clone();
-        let old_path_clone = old_path.clone();
-        spawn_blocking(move || renameat(None, &old_path_clone, None, &new_path_clone)).await??;
+        // the usage moves with the renamed entry, and the one of the replaced
+        // entry is freed, or moved the other way on an exchange
+        let usage = if self.tracks_usage(&old_path).await? || self.tracks_usage(&new_path).await? {
+            Some((
+                self.entry_usage(&old_path).await?,
+                self.entry_usage(&new_path).await?,
+            ))
+        } else {
+            None
+        };
 
-        let stat = self.get_file_attr(&new_path).await?;
-        trace!("remove ({:x}, {})", stat.ino, old_path.display());
-        inode_map.remove_path(stat.ino, &old_path);
-        trace!("insert ({:x}, {})", stat.ino, new_path.display());
-        inode_map.insert_path(stat.ino, &new_path);
+        let real_new_path = self.write_path(&new_path).await?;
+        let real_old_path = self.rename_path(&old_path).await?;
+        if flags == 0 {
+            spawn_blocking(move || renameat(None, &real_old_path, None, &real_new_path)).await??;
+        } else {
+            // RENAME_NOREPLACE, RENAME_EXCHANGE and RENAME_WHITEOUT are left
+            // to the backing file system, which fails with EINVAL if it
+            // doesn't support them
+            let old = CString::new(real_old_path.as_os_str().as_bytes())?;
+            let new = CString::new(real_new_path.as_os_str().as_bytes())?;
+            async_renameat2(old, new, flags).await?;
+        }
+
+        self.hide_renamed(&old_path, &new_path).await?;
+
+        let exchange = flags & libc::RENAME_EXCHANGE as u32 != 0;
+        if let Some((moved, replaced)) = usage {
+            self.used(&old_path, -moved.0, -moved.1).await?;
+            self.used(&new_path, moved.0 - replaced.0, moved.1 - replaced.1)
+                .await?;
+            if exchange {
+                self.used(&old_path, replaced.0, replaced.1).await?;
+            }
+        }
+        trace!(
+            "rename paths from {} to {}",
+            old_path.display(),
+            new_path.display()
+        );
+        inode_map.rename_paths(&old_path, &new_path, exchange);
+        self.removals.fetch_add(1, Ordering::SeqCst);
 
         Ok(())
     }
@@ -684,10 +1679,12 @@ impl AsyncFileSystemImpl for HookFs {
         trace!("link");
         inject_with_ino!(self, LINK, ino);
 
-        let mut inode_map = self.inode_map.write().await;
-        let original_path = inode_map.get_path(ino)?.to_owned();
-        let new_parent_path = inode_map.get_path(newparent)?.to_owned();
-        let new_path = new_parent_path.join(&newname);
+        let (original_path, new_path) = {
+            let inode_map = self.inode_map.read().await;
+            let original_path = inode_map.get_path(ino)?.to_owned();
+            let new_path = inode_map.get_path(newparent)?.join(&newname);
+            (original_path, new_path)
+        };
 
         trace!(
             "link from {} to {}",
@@ -695,7 +1692,8 @@ impl AsyncFileSystemImpl for HookFs {
             original_path.display()
         );
 
-        let new_path_clone = new_path.clone();
+        let original_path = self.write_path(&original_path).await?;
+        let new_path_clone = self.write_path(&new_path).await?;
         spawn_blocking(move || {
             linkat(
                 None,
@@ -706,11 +1704,12 @@ impl AsyncFileSystemImpl for HookFs {
             )
         })
         .await??;
+        self.used(&new_path, 0, 1).await?;
 
+        let seen = self.removals();
         let stat = self.get_file_attr(&new_path).await?;
-        inode_map.insert_path(stat.ino, new_path.clone());
-        inode_map.increase_ref(stat.ino);
-        let mut reply = Entry::new(stat, 0);
+        let generation = self.remember(&stat, &new_path, seen).await?;
+        let mut reply = Entry::new(stat, generation, self.kernel.entry_timeout);
         inject_reply!(self, LOOKUP, new_path.as_path(), reply, Entry);
 
         Ok(reply)
@@ -719,37 +1718,64 @@ impl AsyncFileSystemImpl for HookFs {
     #[instrument(skip(self))]
     async fn open(&self, ino: u64, flags: i32) -> Result<Open> {
         trace!("open");
+        caller::set_flags(flags);
         inject_with_ino!(self, OPEN, ino);
 
-        // TODO: support direct io
-        if flags & libc::O_DIRECT != 0 {
-            debug!("direct io flag is ignored directly")
-        }
-        // filter out append. The kernel layer will translate the
-        // offsets for us appropriately.
-        let filtered_flags = flags & (!libc::O_APPEND) & (!libc::O_DIRECT);
-        let filtered_flags = OFlag::from_bits_truncate(filtered_flags as i32);
+        // the kernel has already followed the symlinks, so the backing file
+        // shouldn't be a symlink, unless it's replaced after the lookup
+        let filtered_flags = OFlag::from_bits_truncate(flags) | OFlag::O_NOFOLLOW;
 
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?;
 
         trace!("open with flags: {:?}", filtered_flags);
 
-        let fd = async_open(path, filtered_flags, stat::Mode::S_IRWXU).await?;
-        let fh = self.opened_files.write().await.insert(File::new(fd, path)) as u64;
+        let real_path = if flags & (libc::O_WRONLY | libc::O_RDWR | libc::O_TRUNC) != 0 {
+            self.write_path(path).await?
+        } else {
+            self.read_path(path).await?
+        };
+        let truncated =
+            if filtered_flags.contains(OFlag::O_TRUNC) && self.tracks_usage(path).await? {
+                Some(self.entry_usage(path).await?.0)
+            } else {
+                None
+            };
+        let fd = self
+            .open_backing(&real_path, filtered_flags, stat::Mode::S_IRWXU)
+            .await?;
+        if let Some(bytes) = truncated {
+            self.used(path, -bytes, 0).await?;
+        }
+        if let Some(checksum) = self
+            .checksum
+            .as_ref()
+            .filter(|_| flags & libc::O_TRUNC != 0)
+        {
+            checksum.truncate(ino, 0);
+        }
+        let fh = self
+            .opened_files
+            .write()
+            .await
+            .insert(Arc::new(File::new(fd, path, flags))) as u64;
+        if self.injector.read().await.sniffs_magic() {
+            let header = spawn_blocking(move || magic::sniff(&real_path)).await?;
+            self.headers.opened(self.rebuild_path(path)?, fh, header);
+        }
 
-        trace!("return with fh: {}, flags: {}", fh, 0);
+        let open_flags = direct_io_flags(flags);
+        trace!("return with fh: {}, flags: {}", fh, open_flags);
 
-        let mut reply = Open::new(fh, 0);
+        let mut reply = Open::new(fh, open_flags);
         inject_reply!(self, OPEN, path, reply, Open);
-        // TODO: force DIRECT_IO is not a great option
         Ok(reply)
     }
 
     #[instrument(skip(self))]
     async fn read(
         &self,
-        _ino: u64,
+        ino: u64,
         fh: u64,
         offset: i64,
         size: u32,
@@ -757,21 +1783,19 @@ impl AsyncFileSystemImpl for HookFs {
         _lock_owner: Option<u64>,
     ) -> Result<Data> {
         trace!("read");
+        let _permits = enter_with_fh!(self, READ, fh);
         inject_with_fh!(self, READ, fh);
-
-        let opened_files = self.opened_files.read().await;
-        let file = opened_files.get(fh as usize)?;
-        let buf = async_read(file.fd, size as usize, offset).await?;
-
-        let mut reply = Data::new(buf);
-        inject_reply!(self, READ, &file.original_path(), reply, Data);
-        Ok(reply)
+        let io = inject_io!(self, READ, fh, offset, size as u64);
+        let result = self.read_data(ino, fh, offset, size).await;
+        let done = result.as_ref().map_or(0, |reply| reply.data.len() as u64);
+        self.io_done(io, done).await;
+        result
     }
 
     #[instrument(skip(self, data))]
     async fn write(
         &self,
-        _ino: u64,
+        ino: u64,
         fh: u64,
         offset: i64,
         mut data: Vec<u8>,
@@ -780,65 +1804,67 @@ impl AsyncFileSystemImpl for HookFs {
         _lock_owner: Option<u64>,
     ) -> Result<Write> {
         trace!("write");
+        let _permits = enter_with_fh!(self, WRITE, fh);
         inject_with_fh!(self, WRITE, fh);
-        inject_write_data!(self, fh, data);
-        let opened_files = self.opened_files.read().await;
-        let file = opened_files.get(fh as usize)?;
-
-        let size = async_write(file.fd, data, offset).await?;
-        let mut reply = Write::new(size as u32);
-        inject_reply!(self, WRITE, file.original_path(), reply, Write);
-        Ok(reply)
+        let io = inject_io!(self, WRITE, fh, offset, data.len() as u64);
+        let result = self.write_data(ino, fh, offset, data).await;
+        let done = result.as_ref().map_or(0, |reply| reply.size as u64);
+        self.io_done(io, done).await;
+        result
     }
 
     #[instrument(skip(self))]
-    async fn flush(&self, _ino: u64, fh: u64, _lock_owner: u64) -> Result<()> {
+    async fn flush(&self, ino: u64, fh: u64, lock_owner: u64) -> Result<()> {
         trace!("flush");
+        // the locks of the owner are released on close, like the kernel does
+        self.locks.release(ino, lock_owner);
+        let _permits = enter_with_fh!(self, FLUSH, fh);
         inject_with_fh!(self, FLUSH, fh);
 
         // flush is implemented with fsync. Is it the correct way?
-        let opened_files = self.opened_files.read().await;
-        let fd: RawFd = {
-            let file = opened_files.get(fh as usize)?;
-            file.fd
-        };
-        spawn_blocking(move || fsync(fd)).await??;
-        Ok(())
+        self.sync_file(Method::FLUSH, ino, fh).await
     }
 
     #[instrument(skip(self))]
     async fn release(
         &self,
-        _ino: u64,
+        ino: u64,
         fh: u64,
         _flags: i32,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _flush: bool,
     ) -> Result<()> {
         trace!("release");
-
-        let mut opened_files = self.opened_files.write().await;
-        if let Ok(file) = opened_files.get(fh as usize) {
-            async_close(file.fd).await?;
+        if let Some(lock_owner) = lock_owner {
+            self.locks.release(ino, lock_owner);
+        }
+        self.locks.release_fh(fh);
+        self.injector.read().await.release_fh(fh);
+
+        // the slot is freed before closing, so that it never refers to a
+        // closed fd, which may be reused by another file
+        let file = self.opened_files.write().await.remove(fh as usize)?;
+        let path = file.original_path().to_owned();
+        // the file is closed here to report the error, unless an operation in
+        // flight still uses it and closes it once done
+        if let Ok(mut file) = Arc::try_unwrap(file) {
+            let fd = std::mem::replace(&mut file.fd, -1);
+            async_close(fd).await?;
         }
-        opened_files.remove(fh as usize);
+        self.headers.released(&self.rebuild_path(&path)?, fh);
+
+        // injected after the file is closed, so that a fault never leaks it
+        inject!(self, RELEASE, &path);
         Ok(())
     }
 
     #[instrument(skip(self))]
-    async fn fsync(&self, _ino: u64, fh: u64, _datasync: bool) -> Result<()> {
+    async fn fsync(&self, ino: u64, fh: u64, _datasync: bool) -> Result<()> {
         trace!("fsync");
+        let _permits = enter_with_fh!(self, FSYNC, fh);
         inject_with_fh!(self, FSYNC, fh);
 
-        let opened_files = self.opened_files.read().await;
-        let fd: RawFd = {
-            let file = opened_files.get(fh as usize)?;
-            file.fd
-        };
-
-        spawn_blocking(move || fsync(fd)).await??;
-
-        Ok(())
+        self.sync_file(Method::FSYNC, ino, fh).await
     }
 
     #[instrument(skip(self))]
@@ -851,14 +1877,18 @@ impl AsyncFileSystemImpl for HookFs {
         let filtered_flags = flags & (!libc::O_APPEND);
         let filtered_flags = OFlag::from_bits_truncate(filtered_flags as i32);
 
-        let path_clone = path.clone();
+        let path_clone = self.read_path(&path).await?;
         let dir = spawn_blocking(move || {
             trace!("opening directory {}", path_clone.display());
             dir::Dir::open(&path_clone, filtered_flags, stat::Mode::S_IRWXU)
         })
         .await??;
         trace!("directory {} opened", path.display());
-        let fh = self.opened_dirs.write().await.insert(Dir::new(dir, &path)) as u64;
+        let fh = self
+            .opened_dirs
+            .write()
+            .await
+            .insert(Arc::new(Mutex::new(Dir::new(dir, &path)))) as u64;
         trace!("return with fh: {}, flags: {}", fh, flags);
 
         let mut reply = Open::new(fh, flags);
@@ -878,27 +1908,58 @@ impl AsyncFileSystemImpl for HookFs {
         inject_with_dir_fh!(self, READDIR, fh);
 
         let offset = offset as usize;
-        let mut opened_dirs = self.opened_dirs.write().await;
-        // TODO: optimize the implementation
-        let all_entries: Vec<_> = {
-            let dir = opened_dirs.get_mut(fh as usize)?;
-
-            dir.iter().collect()
-        };
+        let dir = self.opened_dirs.read().await.get(fh as usize)?.clone();
+        let mut dir = dir.lock().await;
+        // the directory is read again from the start at offset 0 (like
+        // rewinddir), so that the entries created after opendir are seen
+        if offset == 0 || dir.entries.is_none() {
+            let mut entries: Vec<DirEntry> = match &self.overlay {
+                Some(Overlay::Shadow(shadow)) => {
+                    let shadow = shadow.clone();
+                    let path = dir.original_path().to_owned();
+                    spawn_blocking(move || shadow.read_dir(&path)).await??
+                }
+                _ => {
+                    let fd = dir.as_raw_fd();
+                    dir.iter()
+                        .map(|entry| -> Result<_> {
+                            let entry = entry?;
+                            let name = OsStr::from_bytes(entry.file_name().to_bytes()).to_owned();
+                            // some file systems don't fill the type of entries
+                            let file_type = match entry.file_type() {
+                                Some(file_type) => convert_filetype(file_type),
+                                None => {
+                                    let stat = stat::fstatat(
+                                        fd,
+                                        name.as_os_str(),
+                                        AtFlags::AT_SYMLINK_NOFOLLOW,
+                                    )?;
+                                    convert_libc_stat_to_fuse_stat(stat)?.kind
+                                }
+                            };
+                            Ok((entry.ino(), file_type, name))
+                        })
+                        .collect::<Result<_>>()?
+                }
+            };
+            if self.injecting() {
+                let path = self.rebuild_path(dir.original_path())?;
+                self.injector
+                    .read()
+                    .await
+                    .inject_entries(&path, &mut entries);
+            }
+            dir.entries = Some(entries);
+        }
+        let all_entries = dir.entries.as_ref().unwrap();
         if offset >= all_entries.len() {
             trace!("empty reply");
             return Ok(());
         }
-        for (index, entry) in all_entries.iter().enumerate().skip(offset as usize) {
-            let entry = (*entry)?;
-
-            let name = entry.file_name();
-            let name = OsStr::from_bytes(name.to_bytes());
-
-            let file_type = convert_filetype(entry.file_type().ok_or(Error::UnknownFileType)?);
-
-            if !reply.add(entry.ino(), (index + 1) as i64, file_type, name) {
-                trace!("add file {:?}", entry);
+        for (index, (ino, file_type, name)) in all_entries.iter().enumerate().skip(offset as usize)
+        {
+            if !reply.add(*ino, (index + 1) as i64, *file_type, name) {
+                trace!("add file {:?}", name);
             } else {
                 trace!("buffer is full");
                 break;
@@ -913,16 +1974,23 @@ impl AsyncFileSystemImpl for HookFs {
     async fn releasedir(&self, _ino: u64, fh: u64, _flags: i32) -> Result<()> {
         trace!("releasedir");
 
-        self.opened_dirs.write().await.remove(fh as usize);
+        let dir = self.opened_dirs.write().await.remove(fh as usize)?;
+        let path = dir.lock().await.original_path().to_owned();
+        drop(dir);
+
+        // injected after the directory is closed, so that a fault never leaks
+        // it
+        inject!(self, RELEASEDIR, &path);
         Ok(())
     }
 
     #[instrument(skip(self))]
     async fn fsyncdir(&self, ino: u64, _fh: u64, _datasync: bool) -> Result<()> {
-        // TODO: inject
+        trace!("fsyncdir");
+        inject_with_ino!(self, FSYNCDIR, ino);
 
         let inode_map = self.inode_map.read().await;
-        let path = inode_map.get_path(ino)?.to_owned();
+        let path = self.read_path(inode_map.get_path(ino)?).await?;
         spawn_blocking(move || -> Result<_> {
             std::fs::File::open(path)?.sync_all()?;
 
@@ -940,8 +2008,9 @@ impl AsyncFileSystemImpl for HookFs {
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?.to_owned();
 
-        let origin_path = self.original_path.clone();
-        let stat = spawn_blocking(move || statfs::statfs(&origin_path)).await??;
+        // the blocks are counted in the fragment size
+        let real_path = self.read_path(&path).await?;
+        let stat = spawn_blocking(move || statvfs::statvfs(&real_path)).await??;
 
         let mut reply = StatFs::new(
             stat.blocks(),
@@ -950,8 +2019,8 @@ impl AsyncFileSystemImpl for HookFs {
             stat.files(),
             stat.files_free(),
             stat.block_size() as u32,
-            stat.maximum_name_length() as u32,
-            stat.block_size() as u32,
+            stat.name_max() as u32,
+            stat.fragment_size() as u32,
         );
         inject_reply!(self, STATFS, &path, reply, StatFs);
 
@@ -971,7 +2040,7 @@ impl AsyncFileSystemImpl for HookFs {
         inject_with_ino!(self, SETXATTR, ino);
 
         let inode_map = self.inode_map.read().await;
-        let path = inode_map.get_path(ino)?.to_owned();
+        let path = self.write_path(inode_map.get_path(ino)?).await?;
         let path = CString::new(path.as_os_str().as_bytes())?;
         let name = CString::new(name.as_bytes())?;
 
@@ -987,7 +2056,8 @@ impl AsyncFileSystemImpl for HookFs {
 
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?;
-        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let real_path = self.read_path(path).await?;
+        let cpath = CString::new(real_path.as_os_str().as_bytes())?;
         let name = CString::new(name.as_bytes())?;
 
         let mut buf = Vec::new();
@@ -1014,7 +2084,8 @@ impl AsyncFileSystemImpl for HookFs {
 
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?.to_owned();
-        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let real_path = self.read_path(&path).await?;
+        let cpath = CString::new(real_path.as_os_str().as_bytes())?;
 
         let mut buf = Vec::new();
         buf.resize(size as usize, 0u8);
@@ -1049,7 +2120,7 @@ impl AsyncFileSystemImpl for HookFs {
         inject_with_ino!(self, REMOVEXATTR, ino);
 
         let inode_map = self.inode_map.read().await;
-        let path = inode_map.get_path(ino)?.to_owned();
+        let path = self.write_path(inode_map.get_path(ino)?).await?;
         let path = CString::new(path.as_os_str().as_bytes())?;
         let name = CString::new(name.as_bytes())?;
 
@@ -1073,12 +2144,9 @@ impl AsyncFileSystemImpl for HookFs {
 
         let inode_map = self.inode_map.read().await;
         let path = inode_map.get_path(ino)?.to_owned();
-        let mask = AccessFlags::from_bits_truncate(mask as i32);
-        let path_clone = path.to_path_buf();
+        let real_path = self.read_path(&path).await?;
 
-        spawn_blocking(move || nix::unistd::access(&path_clone, mask)).await??;
-
-        Ok(())
+        spawn_blocking(move || check_access(&real_path, mask)).await?
     }
 
     #[instrument(skip(self))]
@@ -1093,32 +2161,73 @@ impl AsyncFileSystemImpl for HookFs {
         gid: u32,
     ) -> Result<Create> {
         trace!("create");
+        caller::set_flags(flags);
         inject_with_parent_and_name!(self, CREATE, parent, &name);
 
-        let mut inode_map = self.inode_map.write().await;
-        let path = {
-            let parent_path = inode_map.get_path(parent)?;
-            parent_path.join(name)
-        };
+        let path = self.inode_map.read().await.get_path(parent)?.join(name);
 
-        let filtered_flags = flags & (!libc::O_APPEND);
-        let filtered_flags = OFlag::from_bits_truncate(filtered_flags as i32);
+        let filtered_flags = OFlag::from_bits_truncate(flags) | OFlag::O_CREAT;
         let mode = stat::Mode::from_bits_truncate(mode);
 
         trace!("create with flags: {:?}, mode: {:?}", filtered_flags, mode);
-        let fd = async_open(&path, filtered_flags, mode).await?;
-        trace!("setting owner {}:{} for file", uid, gid);
-        async_lchown(&path, Some(uid), Some(gid)).await?;
+        let real_path = self.write_path(&path).await?;
+        // the file is created exclusively, so that only the file created here
+        // is chowned. If it's created by others since the lookup, it's opened
+        // as is, unless O_EXCL is requested.
+        let fd = match self
+            .open_backing(&real_path, filtered_flags | OFlag::O_EXCL, mode)
+            .await
+        {
+            Ok(fd) => {
+                trace!("setting owner {}:{} for file", uid, gid);
+                async_lchown(&real_path, Some(uid), Some(gid)).await?;
+                self.used(&path, 0, 1).await?;
+                fd
+            }
+            Err(Error::Sys(Errno::EEXIST)) if !filtered_flags.contains(OFlag::O_EXCL) => {
+                trace!("file is created by others, open it");
+                let filtered_flags = (filtered_flags - OFlag::O_CREAT) | OFlag::O_NOFOLLOW;
+                self.open_backing(&real_path, filtered_flags, mode).await?
+            }
+            Err(err) => return Err(err),
+        };
 
-        let stat = self.get_file_attr(&path).await?;
-        let fh = self.opened_files.write().await.insert(File::new(fd, &path));
+        // the file is closed if it's removed by others before it's remembered
+        let seen = self.removals();
+        let remembered = async {
+            let stat = self.get_file_attr(&path).await?;
+            let generation = self.remember(&stat, &path, seen).await?;
+            Ok((stat, generation))
+        };
+        let (stat, generation) = match remembered.await {
+            Ok(remembered) => remembered,
+            Err(err) => {
+                async_close(fd).await?;
+                return Err(err);
+            }
+        };
+        let fh = self
+            .opened_files
+            .write()
+            .await
+            .insert(Arc::new(File::new(fd, &path, flags)));
+        if self.injector.read().await.sniffs_magic() {
+            self.headers
+                .opened(self.rebuild_path(&path)?, fh as u64, Vec::new());
+        }
+        if let Some(checksum) = &self.checksum {
+            checksum.truncate(stat.ino, stat.size);
+        }
 
-        // TODO: support generation number
-        // this can be implemented with ioctl FS_IOC_GETVERSION
         trace!("return with stat: {:?} fh: {}", stat, fh);
-        inode_map.insert_path(stat.ino, path.clone());
-        inode_map.increase_ref(stat.ino);
-        let mut reply = Create::new(stat, 0, fh as u64, flags);
+        // the flags of the reply are FOPEN_* flags, rather than the open flags
+        let mut reply = Create::new(
+            stat,
+            generation,
+            fh as u64,
+            direct_io_flags(flags),
+            self.kernel.entry_timeout,
+        );
         inject_reply!(self, CREATE, path.as_path(), reply, Create);
         Ok(reply)
     }
@@ -1126,33 +2235,142 @@ impl AsyncFileSystemImpl for HookFs {
     #[instrument(skip(self))]
     async fn getlk(
         &self,
-        _ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: i32,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
         _pid: u32,
     ) -> Result<Lock> {
         trace!("getlk");
-        // kernel will implement for hookfs
-        Err(Error::Sys(Errno::ENOSYS))
+        inject_with_fh!(self, GETLK, fh);
+
+        let path = self.inode_map.read().await.get_path(ino)?.to_owned();
+        let fd = self
+            .locks
+            .fd(ino, lock_owner, fh, &self.read_path(&path).await?)?;
+        let mut reply = spawn_blocking(move || lock::getlk(fd.fd(), start, end, typ)).await??;
+        inject_reply!(self, GETLK, &path, reply, Lock);
+        Ok(reply)
     }
 
     #[instrument(skip(self))]
     async fn setlk(
         &self,
-        _ino: u64,
-        _fh: u64,
-        _lock_owner: u64,
-        _start: u64,
-        _end: u64,
-        _typ: i32,
+        ino: u64,
+        fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
         _pid: u32,
-        _sleep: bool,
+        sleep: bool,
     ) -> Result<()> {
         trace!("setlk");
-        Err(Error::Sys(Errno::ENOSYS))
+        let _permits = enter_with_fh!(self, SETLK, fh);
+        inject_with_fh!(self, SETLK, fh);
+
+        // unlocking an owner which has never locked does nothing, and
+        // unlocking the whole file releases the owner
+        let fd = if typ == libc::F_UNLCK {
+            if start == 0 && end >= i64::MAX as u64 {
+                self.locks.release(ino, lock_owner);
+                return Ok(());
+            }
+            match self.locks.opened(ino, lock_owner) {
+                Some(fd) => fd,
+                None => return Ok(()),
+            }
+        } else {
+            let path = self.inode_map.read().await.get_path(ino)?.to_owned();
+            self.locks
+                .fd(ino, lock_owner, fh, &self.read_path(&path).await?)?
+        };
+
+        // a waiter retries until the lock is released, instead of holding a
+        // blocking thread, and gives up once its owner releases its locks,
+        // e.g. as the waiting process is killed
+        let mut delay = lock::RETRY_DELAY;
+        loop {
+            let lock_fd = fd.clone();
+            match spawn_blocking(move || lock::setlk(lock_fd.fd(), start, end, typ)).await? {
+                Err(Error::Sys(Errno::EAGAIN)) if sleep => {}
+                result => return result,
+            }
+            if !self.locks.holds(ino, lock_owner, &fd) {
+                return Err(Error::Sys(Errno::EINTR));
+            }
+            tokio::time::delay_for(delay).await;
+            delay = (delay * 2).min(lock::MAX_RETRY_DELAY);
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn fallocate(
+        &self,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+    ) -> Result<()> {
+        trace!("fallocate");
+        let _permits = enter_with_fh!(self, FALLOCATE, fh);
+        inject_with_fh!(self, FALLOCATE, fh);
+        let io = inject_io!(self, FALLOCATE, fh, offset, length as u64);
+        let result = self.allocate(ino, fh, offset, length, mode).await;
+        let done = if result.is_ok() { length as u64 } else { 0 };
+        self.io_done(io, done).await;
+        result
+    }
+
+    // lseek is only sent for SEEK_HOLE and SEEK_DATA, as the kernel handles
+    // the other whences by itself
+    #[instrument(skip(self))]
+    async fn lseek(&self, _ino: u64, fh: u64, offset: i64, whence: i32) -> Result<Lseek> {
+        trace!("lseek");
+        inject_with_fh!(self, LSEEK, fh);
+
+        let file = self.file(fh).await?;
+        let fd = file.fd;
+        let offset = spawn_blocking(move || {
+            let ret = unsafe { libc::lseek(fd, offset, whence) };
+            if ret == -1 {
+                Err(Error::last())
+            } else {
+                Ok(ret)
+            }
+        })
+        .await??;
+        Ok(Lseek::new(offset))
+    }
+
+    // copy_file_range copies between the backing files, which is never seen
+    // by the injectors of read and write
+    #[instrument(skip(self))]
+    async fn copy_file_range(
+        &self,
+        _ino_in: u64,
+        fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+    ) -> Result<Write> {
+        trace!("copy_file_range");
+        let _permits = enter_with_fh!(self, COPY_FILE_RANGE, fh_out);
+        inject_with_fh!(self, COPY_FILE_RANGE, fh_in);
+        inject_with_fh!(self, COPY_FILE_RANGE, fh_out);
+        let io = inject_io!(self, COPY_FILE_RANGE, fh_out, offset_out, len);
+        let result = self
+            .copy_range(fh_in, offset_in, ino_out, fh_out, offset_out, len, flags)
+            .await;
+        let done = result.as_ref().map_or(0, |reply| reply.size as u64);
+        self.io_done(io, done).await;
+        result
     }
 
     #[instrument(skip(self))]
@@ -1198,33 +2416,109 @@ async fn async_getxattr(path: CString, name: CString, size: usize) -> Result<Vec
     .await?
 }
 
-async fn async_read(fd: RawFd, count: usize, offset: i64) -> Result<Vec<u8>> {
-    spawn_blocking(move || unsafe {
-        let mut buf = Vec::new();
-        buf.resize(count, 0);
-        let ret = libc::pread(fd, buf.as_ptr() as *mut c_void, count, offset);
-        if ret == -1 {
-            Err(Error::last())
-        } else {
-            buf.resize(ret as usize, 0);
-            Ok(buf)
+// direct_io_flags returns the FOPEN_* flags of the reply to an open. The file
+// opened with O_DIRECT bypasses the page cache of the mount, so that every io
+// reaches the backing file with the offset and the size of the application.
+fn direct_io_flags(flags: i32) -> i32 {
+    if flags & libc::O_DIRECT != 0 {
+        fuser::consts::FOPEN_DIRECT_IO as i32
+    } else {
+        0
+    }
+}
+
+// async_read reads with pread at the offset of every request, so the
+// concurrent requests on one fd never race on the file offset. The short reads
+// are retried, as the kernel takes them as the end of the file. The file opened
+// with O_DIRECT is read into an aligned buffer, or through the page cache if
+// the read isn't aligned.
+async fn async_read(fd: RawFd, count: usize, offset: i64, direct: bool) -> Result<Vec<u8>> {
+    spawn_blocking(move || {
+        if direct && direct::is_aligned(offset, count) {
+            let mut buf = AlignedBuffer::new(count);
+            let read = pread_all(fd, &mut buf, offset)?;
+            return Ok(buf[..read].to_vec());
         }
+        let buffered = if direct {
+            Some(Buffered::open(fd)?)
+        } else {
+            None
+        };
+        let fd = buffered.as_ref().map_or(fd, |buffered| buffered.0);
+        let mut buf = vec![0u8; count];
+        let read = pread_all(fd, &mut buf, offset)?;
+        buf.truncate(read);
+        Ok(buf)
     })
     .await?
 }
 
-async fn async_write(fd: RawFd, data: Vec<u8>, offset: i64) -> Result<isize> {
-    spawn_blocking(move || unsafe {
-        let ret = libc::pwrite(fd, data.as_ptr() as *const c_void, data.len(), offset);
-        if ret == -1 {
-            Err(Error::last())
-        } else {
-            Ok(ret)
+fn pread_all(fd: RawFd, buf: &mut [u8], offset: i64) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        let remaining = &mut buf[read..];
+        let ret = unsafe {
+            libc::pread(
+                fd,
+                remaining.as_mut_ptr() as *mut c_void,
+                remaining.len(),
+                offset + read as i64,
+            )
+        };
+        match ret {
+            -1 if Errno::last() == Errno::EINTR => continue,
+            -1 if read == 0 => return Err(Error::last()),
+            -1 | 0 => break,
+            ret => read += ret as usize,
         }
+    }
+    Ok(read)
+}
+
+// async_write writes the whole data, retrying the short and interrupted writes,
+// as the kernel takes a short write reply as the failure of the rest. The count
+// written before an error is returned instead of the error, if it's not zero.
+// The data to a file opened with O_DIRECT is copied into an aligned buffer, or
+// written through the page cache if the write isn't aligned.
+async fn async_write(fd: RawFd, data: Vec<u8>, offset: i64, direct: bool) -> Result<isize> {
+    spawn_blocking(move || {
+        if direct && direct::is_aligned(offset, data.len()) {
+            let written = pwrite_all(fd, &AlignedBuffer::from_slice(&data), offset)?;
+            return Ok(written as isize);
+        }
+        let buffered = if direct {
+            Some(Buffered::open(fd)?)
+        } else {
+            None
+        };
+        let fd = buffered.as_ref().map_or(fd, |buffered| buffered.0);
+        Ok(pwrite_all(fd, &data, offset)? as isize)
     })
     .await?
 }
 
+fn pwrite_all(fd: RawFd, data: &[u8], offset: i64) -> Result<usize> {
+    let mut written = 0;
+    while written < data.len() {
+        let remaining = &data[written..];
+        let ret = unsafe {
+            libc::pwrite(
+                fd,
+                remaining.as_ptr() as *const c_void,
+                remaining.len(),
+                offset + written as i64,
+            )
+        };
+        match ret {
+            -1 if Errno::last() == Errno::EINTR => continue,
+            -1 if written == 0 => return Err(Error::last()),
+            -1 | 0 => break,
+            ret => written += ret as usize,
+        }
+    }
+    Ok(written)
+}
+
 async fn async_stat(path: &Path) -> Result<stat::FileStat> {
     let path_clone = path.to_path_buf();
     trace!("async read stat from path {}", path_clone.display());
@@ -1246,6 +2540,16 @@ async fn async_lchown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result
     Ok(())
 }
 
+async fn async_fchown(fd: RawFd, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+    spawn_blocking(move || fchown(fd, uid.map(Uid::from_raw), gid.map(Gid::from_raw))).await??;
+    Ok(())
+}
+
+async fn async_fchmod(fd: RawFd, mode: u32) -> Result<()> {
+    spawn_blocking(move || stat::fchmod(fd, stat::Mode::from_bits_truncate(mode))).await??;
+    Ok(())
+}
+
 async fn async_fchmodat(path: &Path, mode: u32) -> Result<()> {
     let path_clone = path.to_path_buf();
     spawn_blocking(move || {
@@ -1266,6 +2570,63 @@ async fn async_truncate(path: &Path, len: i64) -> Result<()> {
     Ok(())
 }
 
+async fn async_renameat2(old: CString, new: CString, flags: u32) -> Result<()> {
+    spawn_blocking(move || {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                libc::AT_FDCWD,
+                old.as_ptr(),
+                libc::AT_FDCWD,
+                new.as_ptr(),
+                flags,
+            )
+        };
+
+        if ret != 0 {
+            Err(Error::last())
+        } else {
+            Ok(())
+        }
+    })
+    .await?
+}
+
+async fn async_fallocate(fd: RawFd, mode: i32, offset: i64, length: i64) -> Result<()> {
+    spawn_blocking(move || {
+        let ret = unsafe { libc::fallocate(fd, mode, offset, length) };
+
+        if ret != 0 {
+            Err(Error::last())
+        } else {
+            Ok(())
+        }
+    })
+    .await?
+}
+
+async fn async_ftruncate(fd: RawFd, len: i64) -> Result<()> {
+    spawn_blocking(move || ftruncate(fd, len)).await??;
+    Ok(())
+}
+
+async fn async_futimens(fd: RawFd, times: [libc::timespec; 2]) -> Result<()> {
+    spawn_blocking(move || unsafe {
+        let ret = libc::futimens(
+            fd,
+            &times as *const [libc::timespec; 2] as *const libc::timespec,
+        );
+
+        if ret != 0 {
+            Err(Error::last())
+        } else {
+            Ok(())
+        }
+    })
+    .await??;
+    Ok(())
+}
+
 async fn async_utimensat(path: CString, times: [libc::timespec; 2]) -> Result<()> {
     spawn_blocking(move || unsafe {
         let path_ptr = &path.as_bytes_with_nul()[0] as *const u8 as *mut i8;
@@ -1331,12 +2692,106 @@ async fn async_rmdir(path: CString) -> Result<()> {
     .await?
 }
 
+// reopen replaces the fd with the file at the path opened the same way
+fn reopen(fd: RawFd, path: &Path) -> Result<()> {
+    let flags = fcntl(fd, FcntlArg::F_GETFL)?;
+    let flags = OFlag::from_bits_truncate(flags & (libc::O_ACCMODE | libc::O_DIRECT))
+        | OFlag::O_NOFOLLOW
+        | OFlag::O_CLOEXEC;
+    let new = open(path, flags, stat::Mode::empty())?;
+    let result = dup3(new, fd, OFlag::O_CLOEXEC);
+    let _ = close(new);
+    result?;
+    Ok(())
+}
+
+// async_open opens the backing file. The file systems refusing O_DIRECT, like
+// tmpfs on the older kernels, fail the open with EINVAL, and the file is then
+// opened without it, as the mount bypasses its own page cache anyway.
 async fn async_open(path: &Path, filtered_flags: OFlag, mode: stat::Mode) -> Result<RawFd> {
     let path_clone = path.to_path_buf();
-    let fd = spawn_blocking(move || open(&path_clone, filtered_flags, mode)).await??;
+    let fd = spawn_blocking(move || match open(&path_clone, filtered_flags, mode) {
+        Err(nix::Error::Sys(Errno::EINVAL)) if filtered_flags.contains(OFlag::O_DIRECT) => {
+            debug!("{} refuses O_DIRECT, open it without", path_clone.display());
+            // the file created exclusively by the failed open is ours
+            let flags = filtered_flags - OFlag::O_DIRECT - OFlag::O_EXCL;
+            open(&path_clone, flags, mode)
+        }
+        result => result,
+    })
+    .await??;
     Ok(fd)
 }
 
 async fn async_close(fd: RawFd) -> Result<()> {
     Ok(spawn_blocking(move || close(fd)).await??)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generation_counter() {
+        let backing = std::env::temp_dir().join(format!("toda-generation-{}", std::process::id()));
+        std::fs::create_dir_all(&backing).unwrap();
+        let fifo = backing.join("fifo");
+        let link = backing.join("link");
+        nix::unistd::mkfifo(&fifo, stat::Mode::S_IRWXU).unwrap();
+        std::os::unix::fs::symlink("fifo", &link).unwrap();
+        let hookfs = HookFs::new("/mnt", &backing, MultiInjector::build(Vec::new()).unwrap());
+        let attr =
+            |path: &Path| convert_libc_stat_to_fuse_stat(stat::lstat(path).unwrap()).unwrap();
+
+        // the files which aren't opened for their generation are numbered by
+        // the counter, and keep their number until they are forgotten
+        futures::executor::block_on(async {
+            let fifo_attr = attr(&fifo);
+            assert_eq!(hookfs.remember(&fifo_attr, &fifo, 0).await.unwrap(), 1);
+            assert_eq!(hookfs.remember(&attr(&link), &link, 0).await.unwrap(), 2);
+            assert_eq!(hookfs.remember(&fifo_attr, &fifo, 0).await.unwrap(), 1);
+
+            hookfs.forget(fifo_attr.ino, 2).await;
+            assert_eq!(hookfs.remember(&fifo_attr, &fifo, 0).await.unwrap(), 3);
+        });
+
+        std::fs::remove_dir_all(&backing).unwrap();
+    }
+
+    #[test]
+    fn test_remember_unlinked() {
+        let backing = std::env::temp_dir().join(format!("toda-unlinked-{}", std::process::id()));
+        std::fs::create_dir_all(&backing).unwrap();
+        let file = backing.join("file");
+        let other = backing.join("other");
+        std::fs::write(&file, "hello").unwrap();
+        std::fs::write(&other, "hello").unwrap();
+        let hookfs = HookFs::new("/mnt", &backing, MultiInjector::build(Vec::new()).unwrap());
+        let attr =
+            |path: &Path| convert_libc_stat_to_fuse_stat(stat::lstat(path).unwrap()).unwrap();
+
+        futures::executor::block_on(async {
+            // the file is unlinked between the stat of a lookup and the
+            // remembering of its path, which is then refused
+            let seen = hookfs.removals();
+            let file_attr = attr(&file);
+            let other_attr = attr(&other);
+            hookfs.unlink(1, "file".into()).await.unwrap();
+            let err = hookfs.remember(&file_attr, &file, seen).await.unwrap_err();
+            assert!(matches!(err, Error::Sys(Errno::ENOENT)));
+            assert!(hookfs
+                .inode_map
+                .read()
+                .await
+                .get_path(file_attr.ino)
+                .is_err());
+
+            // the paths which still name their inode are remembered
+            hookfs.remember(&other_attr, &other, seen).await.unwrap();
+            let inode_map = hookfs.inode_map.read().await;
+            assert_eq!(inode_map.get_path(other_attr.ino).unwrap(), other);
+        });
+
+        std::fs::remove_dir_all(&backing).unwrap();
+    }
+}
@@ -2,12 +2,10 @@ use std::fmt::Debug;
 use std::time::Duration;
 
 use fuser::*;
-use tracing::{debug, error, trace};
+use tracing::{debug, trace};
 
 use super::errors::Result;
 
-const TTL: Duration = Duration::from_secs(0);
-
 #[derive(Debug)]
 pub enum Reply<'a> {
     Entry(&'a mut Entry),
@@ -17,7 +15,7 @@ pub enum Reply<'a> {
     StatFs(&'a mut StatFs),
     Write(&'a mut Write),
     Create(&'a mut Create),
-    _Lock(&'a mut Lock),
+    Lock(&'a mut Lock),
     Xattr(&'a mut Xattr),
 }
 
@@ -25,10 +23,16 @@ pub enum Reply<'a> {
 pub struct Entry {
     pub stat: FileAttr,
     pub generation: u64,
+    // how long the kernel caches the entry and its attributes
+    pub ttl: Duration,
 }
 impl Entry {
-    pub fn new(stat: FileAttr, generation: u64) -> Self {
-        Self { stat, generation }
+    pub fn new(stat: FileAttr, generation: u64, ttl: Duration) -> Self {
+        Self {
+            stat,
+            generation,
+            ttl,
+        }
     }
 }
 
@@ -46,10 +50,12 @@ impl Open {
 #[derive(Debug)]
 pub struct Attr {
     pub attr: FileAttr,
+    // how long the kernel caches the attributes
+    pub ttl: Duration,
 }
 impl Attr {
-    pub fn new(attr: FileAttr) -> Self {
-        Self { attr }
+    pub fn new(attr: FileAttr, ttl: Duration) -> Self {
+        Self { attr, ttl }
     }
 }
 
@@ -114,14 +120,17 @@ pub struct Create {
     pub generation: u64,
     pub fh: u64,
     pub flags: i32,
+    // how long the kernel caches the entry and its attributes
+    pub ttl: Duration,
 }
 impl Create {
-    pub fn new(attr: FileAttr, generation: u64, fh: u64, flags: i32) -> Self {
+    pub fn new(attr: FileAttr, generation: u64, fh: u64, flags: i32, ttl: Duration) -> Self {
         Self {
             attr,
             generation,
             fh,
             flags,
+            ttl,
         }
     }
 }
@@ -135,7 +144,7 @@ pub struct Lock {
 }
 
 impl Lock {
-    pub fn _new(start: u64, end: u64, typ: i32, pid: u32) -> Self {
+    pub fn new(start: u64, end: u64, typ: i32, pid: u32) -> Self {
         Self {
             start,
             end,
@@ -145,6 +154,16 @@ impl Lock {
     }
 }
 
+#[derive(Debug)]
+pub struct Lseek {
+    pub offset: i64,
+}
+impl Lseek {
+    pub fn new(offset: i64) -> Self {
+        Self { offset }
+    }
+}
+
 #[derive(Debug)]
 pub enum Xattr {
     Data { data: Vec<u8> },
@@ -171,12 +190,7 @@ pub trait FsReply<T: Debug>: Sized {
             }
             Err(err) => {
                 debug!("err. reply with {}", err);
-
-                let err = err.into();
-                if err == -1 {
-                    error!("returned -1");
-                }
-                self.reply_err(err)
+                self.reply_err(err.into())
             }
         }
     }
@@ -184,7 +198,7 @@ pub trait FsReply<T: Debug>: Sized {
 
 impl FsReply<Entry> for ReplyEntry {
     fn reply_ok(self, item: Entry) {
-        self.entry(&TTL, &item.stat, item.generation);
+        self.entry(&item.ttl, &item.stat, item.generation);
     }
     fn reply_err(self, err: libc::c_int) {
         self.error(err);
@@ -202,7 +216,7 @@ impl FsReply<Open> for ReplyOpen {
 
 impl FsReply<Attr> for ReplyAttr {
     fn reply_ok(self, item: Attr) {
-        self.attr(&TTL, &item.attr);
+        self.attr(&item.ttl, &item.attr);
     }
     fn reply_err(self, err: libc::c_int) {
         self.error(err);
@@ -248,7 +262,7 @@ impl FsReply<Write> for ReplyWrite {
 impl FsReply<Create> for ReplyCreate {
     fn reply_ok(self, item: Create) {
         self.created(
-            &TTL,
+            &item.ttl,
             &item.attr,
             item.generation,
             item.fh,
@@ -269,6 +283,15 @@ impl FsReply<Lock> for ReplyLock {
     }
 }
 
+impl FsReply<Lseek> for ReplyLseek {
+    fn reply_ok(self, item: Lseek) {
+        self.offset(item.offset);
+    }
+    fn reply_err(self, err: libc::c_int) {
+        self.error(err);
+    }
+}
+
 impl FsReply<Xattr> for ReplyXattr {
     fn reply_ok(self, item: Xattr) {
         use Xattr::*;
@@ -1,24 +1,71 @@
 use std::future::Future;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 
+use anyhow::anyhow;
 use once_cell::sync::Lazy;
 use tokio::runtime::Runtime;
 use tokio::task::JoinHandle;
-use tracing::trace;
+use tracing::{info, trace};
+
+use super::caller;
+
+// WorkerConfig sizes the thread pools which the requests are handled in. The
+// workers run the async part of the requests, and the blocking threads run the
+// IO on the backing files.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WorkerConfig {
+    // the count of cpus by default
+    pub workers: Option<usize>,
+    // 512 by default
+    pub blocking_threads: Option<usize>,
+}
+
+// parse_thread_count parses the size of a thread pool, as a pool without a
+// thread would never handle a request
+pub fn parse_thread_count(count: &str) -> anyhow::Result<usize> {
+    match count.parse()? {
+        0 => Err(anyhow!("the count of threads should be at least 1")),
+        count => Ok(count),
+    }
+}
+
+static WORKER_CONFIG: Lazy<Mutex<WorkerConfig>> = Lazy::new(Default::default);
+
+// configure sizes the thread pools, which takes effect only if it's called
+// before the first request
+pub fn configure(config: WorkerConfig) {
+    info!("configure worker pool {:?}", config);
+    *WORKER_CONFIG.lock().unwrap() = config;
+}
 
 pub static RUNTIME: Lazy<RwLock<Option<Runtime>>> = Lazy::new(|| {
     trace!("build tokio runtime");
 
-    RwLock::new(Some(
-        tokio::runtime::Builder::new()
-            .threaded_scheduler()
-            .thread_name("toda")
-            .enable_all()
-            .build()
-            .unwrap(),
-    ))
+    let config = *WORKER_CONFIG.lock().unwrap();
+    let mut builder = tokio::runtime::Builder::new();
+    builder
+        .threaded_scheduler()
+        .thread_name("toda")
+        .enable_all();
+    let workers = config.workers.unwrap_or_else(cpus);
+    builder.core_threads(workers);
+    if let Some(blocking_threads) = config.blocking_threads {
+        // the max threads of tokio include the workers
+        builder.max_threads(workers + blocking_threads);
+    }
+
+    RwLock::new(Some(builder.build().unwrap()))
 });
 
+fn cpus() -> usize {
+    let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+    if cpus < 1 {
+        1
+    } else {
+        cpus as usize
+    }
+}
+
 pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
 where
     F: Future + Send + 'static,
@@ -30,13 +77,19 @@ where
     unreachable!()
 }
 
+// spawn_blocking runs `func` in a blocking thread, as the caller of the
+// request if the mount leaves the permissions to the backing files
 pub fn spawn_blocking<F, R>(func: F) -> JoinHandle<R>
 where
     R: Send + 'static,
     F: FnOnce() -> R + Send + 'static,
 {
+    let credentials = caller::credentials();
     if let Some(runtime) = &*RUNTIME.read().unwrap() {
-        return runtime.handle().spawn_blocking(func);
+        return runtime.handle().spawn_blocking(move || match credentials {
+            Some(caller) => caller::run_as(caller, func),
+            None => func(),
+        });
     }
     unreachable!()
 }
@@ -0,0 +1,349 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fs, io};
+
+use fuser::FileType;
+use nix::errno::Errno;
+use tracing::{info, trace};
+
+use super::utils::copy_entry;
+use super::{Error, Result};
+
+// the prefix of the whiteouts, which hide the entry with the rest of the name
+const WHITEOUT_PREFIX: &str = ".wh.";
+
+// Shadow diverts all modifications of the backing directory into the shadow
+// directory. An entry in the shadow directory hides the entry with the same
+// path in the backing directory, so the application reads what it has
+// written while the backing directory is kept untouched.
+//
+// An entry of the backing directory which is removed or renamed is hidden by
+// a whiteout, an empty file named ".wh.<name>" beside it in the shadow
+// directory. A directory created again over its whiteout hides all the
+// entries of the backing one. The names starting with ".wh." are reserved.
+//
+// An entry copied up keeps the inode number of the backing entry, and the
+// files opened before the copy up are switched to the copy by the mount.
+#[derive(Debug, Clone)]
+pub struct Shadow {
+    dir: PathBuf,
+    original_path: PathBuf,
+    // the inode numbers of the copied up entries, and of the backing entries
+    // they are copied from
+    copied: Arc<Mutex<HashMap<u64, u64>>>,
+}
+
+impl Shadow {
+    pub fn new<P1: AsRef<Path>, P2: AsRef<Path>>(dir: P1, original_path: P2) -> io::Result<Shadow> {
+        fs::create_dir_all(dir.as_ref())?;
+
+        Ok(Shadow {
+            dir: dir.as_ref().to_owned(),
+            original_path: original_path.as_ref().to_owned(),
+            copied: Default::default(),
+        })
+    }
+
+    fn shadow_path(&self, path: &Path) -> Result<PathBuf> {
+        Ok(self.dir.join(path.strip_prefix(&self.original_path)?))
+    }
+
+    // hidden tells whether the entry of the backing directory is hidden by a
+    // whiteout of it or of one of its parents
+    fn hidden(&self, path: &Path) -> Result<bool> {
+        let mut shadow_path = self.dir.clone();
+        for name in path.strip_prefix(&self.original_path)?.iter() {
+            if exists(&whiteout(&shadow_path, name)) {
+                return Ok(true);
+            }
+            shadow_path.push(name);
+        }
+        Ok(false)
+    }
+
+    // inode returns the inode number to reply for the entry, which is the one
+    // of the backing entry if it's copied up
+    pub fn inode(&self, ino: u64) -> u64 {
+        self.copied
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .copied()
+            .unwrap_or(ino)
+    }
+
+    // resolve returns the path whose content should be served
+    pub fn resolve(&self, path: &Path) -> Result<PathBuf> {
+        let shadow_path = self.shadow_path(path)?;
+        if path == self.original_path {
+            return Ok(path.to_owned());
+        }
+        if exists(&shadow_path) || self.hidden(path)? {
+            Ok(shadow_path)
+        } else {
+            Ok(path.to_owned())
+        }
+    }
+
+    // copy_up returns the path in the shadow directory which should be
+    // modified instead of `path`, and whether the entry itself is copied up
+    // now. The parent directories and the entry itself (if it exists and
+    // isn't hidden) are copied from the backing directory first.
+    pub fn copy_up(&self, path: &Path) -> Result<(PathBuf, bool)> {
+        let shadow_path = self.shadow_path(path)?;
+        if path == self.original_path || exists(&shadow_path) {
+            return Ok((shadow_path, false));
+        }
+
+        if let Some(parent) = path.parent() {
+            self.copy_up(parent)?;
+        }
+        if self.hidden(path)? {
+            return Ok((shadow_path, false));
+        }
+
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                trace!("copy up {}", path.display());
+                copy_entry(path, &shadow_path, &metadata)?;
+                let copy = fs::symlink_metadata(&shadow_path)?;
+                self.copied
+                    .lock()
+                    .unwrap()
+                    .insert(copy.ino(), metadata.ino());
+                Ok((shadow_path, true))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok((shadow_path, false)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // unlink removes the file, and hides the one in the backing directory
+    pub fn unlink(&self, path: &Path) -> Result<()> {
+        let (shadow_path, metadata) = self.prepare_remove(path)?;
+        if metadata.is_dir() {
+            return Err(Error::Sys(Errno::EISDIR));
+        }
+        match fs::remove_file(&shadow_path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        self.whiteout(path)
+    }
+
+    // rmdir removes the directory if it looks empty through the mount, along
+    // with the whiteouts in it, and hides the one in the backing directory
+    pub fn rmdir(&self, path: &Path) -> Result<()> {
+        let (shadow_path, metadata) = self.prepare_remove(path)?;
+        if !metadata.is_dir() {
+            return Err(Error::Sys(Errno::ENOTDIR));
+        }
+        // the entries are "." and ".."
+        if self.read_dir(path)?.len() > 2 {
+            return Err(Error::Sys(Errno::ENOTEMPTY));
+        }
+        match fs::remove_dir_all(&shadow_path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        self.whiteout(path)
+    }
+
+    // prepare_remove looks up the entry through the mount, copies up its
+    // parent for the whiteout, and returns its path in the shadow directory
+    fn prepare_remove(&self, path: &Path) -> Result<(PathBuf, fs::Metadata)> {
+        if path == self.original_path {
+            return Err(Error::Sys(Errno::EBUSY));
+        }
+        let metadata = fs::symlink_metadata(self.resolve(path)?)?;
+        if let Some(parent) = path.parent() {
+            self.copy_up(parent)?;
+        }
+        Ok((self.shadow_path(path)?, metadata))
+    }
+
+    // whiteout hides the entry of the backing directory, if there is one.
+    // The whiteout is kept if the entry is created again in the shadow
+    // directory, so that a directory doesn't reveal the entries of the
+    // backing one.
+    pub fn whiteout(&self, path: &Path) -> Result<()> {
+        if self.hidden(path)? || fs::symlink_metadata(path).is_err() {
+            return Ok(());
+        }
+        let shadow_path = self.shadow_path(path)?;
+        let (parent, name) = match (shadow_path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Ok(()),
+        };
+        trace!("whiteout {}", path.display());
+        fs::File::create(whiteout(parent, name))?;
+        Ok(())
+    }
+
+    // check_rename fails with EXDEV if the entry is a directory of the backing
+    // directory, which cannot be renamed along with its entries
+    pub fn check_rename(&self, path: &Path) -> Result<()> {
+        let metadata = fs::symlink_metadata(self.resolve(path)?)?;
+        if metadata.is_dir() && !self.hidden(path)? && exists(path) {
+            return Err(Error::Sys(Errno::EXDEV));
+        }
+        Ok(())
+    }
+
+    // read_dir merges the entries of the directory in the shadow and backing
+    // directory
+    pub fn read_dir(&self, path: &Path) -> Result<Vec<(u64, FileType, OsString)>> {
+        let mut entries = BTreeMap::new();
+        let shadow_path = self.shadow_path(path)?;
+        let mut dirs = vec![shadow_path.clone()];
+        if !self.hidden(path)? {
+            dirs.push(path.to_owned());
+        }
+        for (index, dir) in dirs.iter().enumerate() {
+            let read_dir = match fs::read_dir(dir) {
+                Ok(read_dir) => read_dir,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            for entry in read_dir {
+                let entry = entry?;
+                let name = entry.file_name();
+                // the entries in the shadow directory come first, and hide
+                // the ones in the backing directory, as the whiteouts do
+                let backing = index > 0;
+                if (!backing && is_whiteout(&name))
+                    || (backing
+                        && (entries.contains_key(&name) || exists(&whiteout(&shadow_path, &name))))
+                {
+                    continue;
+                }
+                let metadata = entry.metadata()?;
+                let kind = convert_filetype(metadata.file_type())?;
+                entries.insert(name, (self.inode(metadata.ino()), kind));
+            }
+        }
+
+        let dir = fs::metadata(self.resolve(path)?)?;
+        let ino = self.inode(dir.ino());
+        let mut all_entries = vec![
+            (ino, FileType::Directory, OsString::from(".")),
+            (ino, FileType::Directory, OsString::from("..")),
+        ];
+        all_entries.extend(
+            entries
+                .into_iter()
+                .map(|(name, (ino, kind))| (ino, kind, name)),
+        );
+
+        Ok(all_entries)
+    }
+
+    // commit copies every entry of the shadow directory into the backing
+    // directory, after removing the entries hidden by whiteouts, and then
+    // discards the shadow
+    pub fn commit(&self) -> Result<()> {
+        info!("commit shadow {}", self.dir.display());
+        commit_dir(&self.dir, &self.original_path)?;
+        self.discard()
+    }
+
+    pub fn discard(&self) -> Result<()> {
+        info!("discard shadow {}", self.dir.display());
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let result = if fs::symlink_metadata(&path)?.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            result?;
+        }
+        self.copied.lock().unwrap().clear();
+
+        Ok(())
+    }
+}
+
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+fn whiteout(dir: &Path, name: &OsStr) -> PathBuf {
+    let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+    whiteout.push(name);
+    dir.join(whiteout)
+}
+
+fn is_whiteout(name: &OsStr) -> bool {
+    name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes())
+}
+
+fn remove_entry(path: &Path) -> Result<()> {
+    let result = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    };
+    Ok(result?)
+}
+
+fn commit_dir(shadow: &Path, original: &Path) -> Result<()> {
+    let entries = fs::read_dir(shadow)?.collect::<io::Result<Vec<_>>>()?;
+    // the whiteouts are applied first, as a directory created again over its
+    // whiteout replaces the backing one
+    for entry in entries.iter() {
+        let name = entry.file_name();
+        if is_whiteout(&name) {
+            let name = &name.as_bytes()[WHITEOUT_PREFIX.len()..];
+            remove_entry(&original.join(OsStr::from_bytes(name)))?;
+        }
+    }
+    for entry in entries
+        .iter()
+        .filter(|entry| !is_whiteout(&entry.file_name()))
+    {
+        let target = original.join(entry.file_name());
+        let metadata = fs::symlink_metadata(entry.path())?;
+
+        if metadata.is_dir() {
+            if fs::symlink_metadata(&target).is_err() {
+                copy_entry(&entry.path(), &target, &metadata)?;
+            }
+            commit_dir(&entry.path(), &target)?;
+        } else {
+            if fs::symlink_metadata(&target).is_ok() {
+                fs::remove_file(&target)?;
+            }
+            copy_entry(&entry.path(), &target, &metadata)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn convert_filetype(file_type: fs::FileType) -> Result<FileType> {
+    if file_type.is_dir() {
+        Ok(FileType::Directory)
+    } else if file_type.is_file() {
+        Ok(FileType::RegularFile)
+    } else if file_type.is_symlink() {
+        Ok(FileType::Symlink)
+    } else if file_type.is_fifo() {
+        Ok(FileType::NamedPipe)
+    } else if file_type.is_char_device() {
+        Ok(FileType::CharDevice)
+    } else if file_type.is_block_device() {
+        Ok(FileType::BlockDevice)
+    } else if file_type.is_socket() {
+        Ok(FileType::Socket)
+    } else {
+        Err(Error::UnknownFileType)
+    }
+}
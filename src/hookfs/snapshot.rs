@@ -0,0 +1,106 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use nix::unistd::{fchownat, FchownatFlags, Gid, Uid};
+use tracing::{info, trace};
+
+use super::utils::copy_entry;
+use super::Result;
+
+// _IOW(0x94, 9, int)
+const FICLONE: libc::c_ulong = 0x4004_9409;
+
+// Snapshot serves a read-only copy of the backing directory taken when it is
+// created, while the backing directory keeps changing.
+//
+// Regular files are cloned with reflink if the filesystem supports it, or
+// copied otherwise, so that the files modified in place in the backing
+// directory become stale as well.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    dir: PathBuf,
+    original_path: PathBuf,
+}
+
+impl Snapshot {
+    pub fn create<P1: AsRef<Path>, P2: AsRef<Path>>(
+        dir: P1,
+        original_path: P2,
+    ) -> Result<Snapshot> {
+        let dir = dir.as_ref().to_owned();
+        let original_path = original_path.as_ref().to_owned();
+        info!(
+            "snapshot {} into {}",
+            original_path.display(),
+            dir.display()
+        );
+
+        copy_entry(&original_path, &dir, &fs::metadata(&original_path)?)?;
+        snapshot_dir(&original_path, &dir)?;
+
+        Ok(Snapshot { dir, original_path })
+    }
+
+    // resolve returns the path in the snapshot
+    pub fn resolve(&self, path: &Path) -> Result<PathBuf> {
+        Ok(self.dir.join(path.strip_prefix(&self.original_path)?))
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        info!("remove snapshot {}", self.dir.display());
+        Ok(fs::remove_dir_all(&self.dir)?)
+    }
+}
+
+fn snapshot_dir(from: &Path, to: &Path) -> Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let metadata = fs::symlink_metadata(entry.path())?;
+
+        if metadata.is_dir() {
+            copy_entry(&entry.path(), &target, &metadata)?;
+            snapshot_dir(&entry.path(), &target)?;
+        } else if metadata.is_file() {
+            clone_file(&entry.path(), &target, &metadata)?;
+        } else {
+            copy_entry(&entry.path(), &target, &metadata)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn clone_file(from: &Path, to: &Path, metadata: &fs::Metadata) -> Result<()> {
+    let mut source = fs::File::open(from)?;
+    let mut target = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(metadata.mode())
+        .open(to)?;
+
+    let ret = unsafe { libc::ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) };
+    if ret != 0 {
+        // a hard link would share the inode, whose content keeps changing
+        // through the backing directory
+        trace!(
+            "reflink {} is not supported, fallback to copy",
+            from.display()
+        );
+        io::copy(&mut source, &mut target)?;
+    }
+    drop(target);
+
+    fs::set_permissions(to, fs::Permissions::from_mode(metadata.mode()))?;
+    fchownat(
+        None,
+        to,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
@@ -1,9 +1,82 @@
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
 use fuser::{FileAttr, FileType, TimeOrNow};
 use libc::{UTIME_NOW, UTIME_OMIT};
 use nix::dir;
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat;
+use nix::unistd::{close, fchownat, FchownatFlags, Gid, Uid};
 
 use super::{Error, Result};
 
+// FS_IOC_GETVERSION is defined with the size of a long, while the file
+// systems write an int
+nix::ioctl_read_bad!(
+    fs_ioc_getversion,
+    nix::request_code_read!(b'v', 1, std::mem::size_of::<libc::c_long>()),
+    libc::c_int
+);
+
+// file_generation returns the generation of the file, or None if the file
+// system doesn't support FS_IOC_GETVERSION
+pub fn file_generation(fd: RawFd) -> Option<u64> {
+    let mut generation: libc::c_int = 0;
+    unsafe { fs_ioc_getversion(fd, &mut generation) }.ok()?;
+    Some(generation as u32 as u64)
+}
+
+// path_generation opens the regular file or the directory for its generation.
+// The other files are never opened, as opening them may block or have side
+// effects. The file can't be opened with O_PATH, whose fds refuse the ioctls,
+// so it's opened without updating its atime where the owner allows it.
+pub fn path_generation(path: &Path, kind: FileType) -> Option<u64> {
+    if kind != FileType::RegularFile && kind != FileType::Directory {
+        return None;
+    }
+    let flags = OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC;
+    let fd = match open(path, flags | OFlag::O_NOATIME, stat::Mode::empty()) {
+        Err(nix::Error::Sys(Errno::EPERM)) => open(path, flags, stat::Mode::empty()),
+        result => result,
+    }
+    .ok()?;
+    let generation = file_generation(fd);
+    let _ = close(fd);
+    generation
+}
+
+// the number of the faccessat2 syscall, which libc doesn't define yet
+const SYS_FACCESSAT2: libc::c_long = 439;
+
+// check_access checks the access to the path with the file system ids of the
+// current thread, which are the ones of the caller while the operation runs as
+// it. Only faccessat2 of Linux 5.8 checks with them, as faccessat checks with
+// the real ids, and glibc emulates AT_EACCESS with the effective ones. It fails
+// with ENOSYS on the older kernels, on which fuse stops sending access and
+// leaves the checks to the operations.
+pub fn check_access(path: &Path, mask: i32) -> Result<()> {
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::syscall(
+            SYS_FACCESSAT2,
+            libc::AT_FDCWD,
+            cpath.as_ptr(),
+            mask,
+            libc::AT_EACCESS,
+        )
+    };
+    if ret == -1 {
+        Err(Error::last())
+    } else {
+        Ok(())
+    }
+}
+
 pub fn convert_filetype(file_type: dir::Type) -> FileType {
     match file_type {
         dir::Type::Fifo => FileType::NamedPipe,
@@ -76,3 +149,51 @@ pub fn convert_time(t: Option<TimeOrNow>) -> libc::timespec {
         },
     }
 }
+
+// copy_entry copies a file, directory, symlink or special file with its
+// permissions and owner. The content of directories is not copied.
+pub fn copy_entry(from: &Path, to: &Path, metadata: &fs::Metadata) -> Result<()> {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        fs::create_dir(to)?;
+        fs::set_permissions(to, fs::Permissions::from_mode(metadata.mode()))?;
+    } else if file_type.is_symlink() {
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+    } else if file_type.is_file() {
+        fs::copy(from, to)?;
+    } else {
+        stat::mknod(
+            to,
+            stat::SFlag::from_bits_truncate(metadata.mode()),
+            stat::Mode::from_bits_truncate(metadata.mode()),
+            metadata.rdev(),
+        )?;
+    }
+
+    fchownat(
+        None,
+        to,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+
+    Ok(())
+}
+
+// drop_page_cache drops the cached pages of a regular file, and returns
+// whether it succeeds
+pub fn drop_page_cache(path: &Path) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => {}
+        _ => return false,
+    }
+    match fs::File::open(path) {
+        Ok(file) => unsafe {
+            libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) == 0
+        },
+        Err(_) => false,
+    }
+}
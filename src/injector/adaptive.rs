@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::debug;
+
+use super::injector_config::AdaptiveConfig;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+// The rates of the idle paths are only dropped once there are more paths than
+// this limit
+const RATES_GC_THRESHOLD: usize = 4096;
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    count: u64,
+}
+
+// Adaptive keeps a broad rule from bringing the whole system to a standstill.
+// The paths operated more than `max_rate` times per second are exempted from
+// the delay, and the total delay injected per second is capped by `max_delay`.
+#[derive(Debug)]
+pub struct Adaptive {
+    max_rate: Option<u64>,
+    max_delay: Option<Duration>,
+
+    rates: Mutex<HashMap<PathBuf, Window>>,
+    // the start of the current window and the delay injected since then
+    budget: Mutex<(Instant, Duration)>,
+}
+
+impl Adaptive {
+    pub fn build(conf: AdaptiveConfig) -> Adaptive {
+        Adaptive {
+            max_rate: conf.max_rate,
+            max_delay: conf.max_delay,
+            rates: Mutex::new(HashMap::new()),
+            budget: Mutex::new((Instant::now(), Duration::from_secs(0))),
+        }
+    }
+
+    // admit records an operation on `path`, and returns the part of `delay`
+    // which should still be injected
+    pub fn admit(&self, path: &Path, delay: Duration) -> Duration {
+        if self.is_hot(path) {
+            debug!("exempt hot path {}", path.display());
+            return Duration::from_secs(0);
+        }
+
+        let max_delay = match self.max_delay {
+            Some(max_delay) => max_delay,
+            None => return delay,
+        };
+        let mut budget = self.budget.lock().unwrap();
+        if budget.0.elapsed() >= WINDOW {
+            *budget = (Instant::now(), Duration::from_secs(0));
+        }
+        let admitted = delay.min(max_delay.checked_sub(budget.1).unwrap_or_default());
+        if admitted < delay {
+            debug!("delay per second is capped, inject {:?}", admitted);
+        }
+        budget.1 += admitted;
+
+        admitted
+    }
+
+    fn is_hot(&self, path: &Path) -> bool {
+        let max_rate = match self.max_rate {
+            Some(max_rate) => max_rate,
+            None => return false,
+        };
+
+        let mut rates = self.rates.lock().unwrap();
+        if rates.len() > RATES_GC_THRESHOLD {
+            rates.retain(|_, window| window.start.elapsed() < WINDOW);
+        }
+        let window = rates.entry(path.to_owned()).or_insert(Window {
+            start: Instant::now(),
+            count: 0,
+        });
+        if window.start.elapsed() >= WINDOW {
+            *window = Window {
+                start: Instant::now(),
+                count: 0,
+            };
+        }
+        window.count += 1;
+
+        window.count > max_rate
+    }
+}
@@ -1,4 +1,5 @@
 use std::path::Path;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use fuser::{FileAttr, FileType};
@@ -6,7 +7,7 @@ use tracing::{debug, trace};
 
 use super::injector_config::{AttrOverrideConfig, FileType as ConfigFileType, FilterConfig};
 use super::{filter, Injector};
-use crate::hookfs::Result;
+use crate::hookfs::{Reply, Result};
 
 #[derive(Debug)]
 pub struct AttrOverrideInjector {
@@ -18,12 +19,14 @@ pub struct AttrOverrideInjector {
     atime: Option<std::time::SystemTime>,
     mtime: Option<std::time::SystemTime>,
     ctime: Option<std::time::SystemTime>,
+    time_offset: Option<std::time::Duration>,
     kind: Option<FileType>,
     perm: Option<u16>,
     nlink: Option<u32>,
     uid: Option<u32>,
     gid: Option<u32>,
     rdev: Option<u32>,
+    cache_timeout: Option<Duration>,
 }
 
 #[async_trait]
@@ -32,12 +35,36 @@ impl Injector for AttrOverrideInjector {
         Ok(())
     }
 
+    fn inject_reply(&self, _: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        // the timeout applies to every reply about the path rather than the
+        // percent of them overridden, so that the kernel doesn't keep the
+        // attributes which are not
+        let timeout = match self.cache_timeout {
+            Some(timeout) if self.filter.match_path(path) => timeout,
+            _ => return Ok(()),
+        };
+        match reply {
+            Reply::Entry(entry) => entry.ttl = timeout,
+            Reply::Attr(attr) => attr.ttl = timeout,
+            Reply::Create(create) => create.ttl = timeout,
+            _ => {}
+        }
+        Ok(())
+    }
+
     fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
         // AttrOverrideInjector should always pass method filter
         if !self.filter.filter(&filter::Method::LOOKUP, path) {
             return;
         }
 
+        if let Some(offset) = self.time_offset {
+            trace!("shifting times by {:?}", offset);
+            for time in [&mut attr.atime, &mut attr.mtime, &mut attr.ctime] {
+                // a time which would overflow is kept as it is
+                *time = time.checked_add(offset).unwrap_or(*time);
+            }
+        }
         if let Some(ino) = self.ino {
             trace!("overriding ino");
             attr.ino = ino
@@ -97,6 +124,8 @@ impl AttrOverrideInjector {
             path: Some(conf.path),
             methods: None,
             percent: conf.percent,
+            on_match: conf.on_match,
+            ..Default::default()
         })?;
 
         let atime = conf.atime;
@@ -122,12 +151,61 @@ impl AttrOverrideInjector {
             atime,
             mtime,
             ctime,
+            time_offset: conf.time_offset,
             kind,
             perm: conf.perm,
             nlink: conf.nlink,
             uid: conf.uid,
             gid: conf.gid,
             rdev: conf.rdev,
+            cache_timeout: conf.cache_timeout,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use super::*;
+
+    fn attr(time: SystemTime) -> FileAttr {
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: time,
+            mtime: time,
+            ctime: time,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 4096,
+            padding: 0,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn test_time_offset_overflow() {
+        let injector = AttrOverrideInjector::build(
+            serde_json::from_str(r#"{"path":"/file","percent":100,"timeOffset":"1h"}"#).unwrap(),
+        )
+        .unwrap();
+        let hour = Duration::from_secs(3600);
+
+        let mut shifted = attr(UNIX_EPOCH);
+        injector.inject_attr(&mut shifted, Path::new("/file"));
+        assert_eq!(shifted.mtime, UNIX_EPOCH + hour);
+
+        // the last representable times are kept rather than overflowing
+        let last = UNIX_EPOCH + Duration::from_secs(i64::MAX as u64) - hour / 2;
+        let mut kept = attr(last);
+        injector.inject_attr(&mut kept, Path::new("/file"));
+        assert_eq!((kept.atime, kept.mtime, kept.ctime), (last, last, last));
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use nix::errno::Errno;
+use tokio::select;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+use super::injector_config::{ConcurrencyConfig, ConcurrencyMode};
+use super::{drain, filter, Injector, Permit};
+use crate::hookfs::{Error, Result};
+
+// Semaphores are only dropped once there are more files than this limit, and
+// only for the files without in-flight operations.
+const SEMAPHORES_GC_THRESHOLD: usize = 1024;
+
+// ConcurrencyInjector caps the in-flight operations per file. The operations
+// beyond the limit either fail with EBUSY or wait for a free slot.
+#[derive(Debug)]
+pub struct ConcurrencyInjector {
+    filter: filter::Filter,
+    limit: usize,
+    mode: ConcurrencyMode,
+
+    semaphores: Mutex<HashMap<PathBuf, Arc<Semaphore>>>,
+    cancel_token: CancellationToken,
+}
+
+#[async_trait]
+impl Injector for ConcurrencyInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    async fn enter(&self, method: &filter::Method, path: &Path) -> Result<Vec<Permit>> {
+        if !self.filter.filter(method, path) {
+            return Ok(Vec::new());
+        }
+
+        let semaphore = self.semaphore(path);
+        let permit = match self.mode {
+            ConcurrencyMode::Busy => match semaphore.try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    debug!("too many in-flight operations on {}", path.display());
+                    return Err(Error::Sys(Errno::EBUSY));
+                }
+            },
+            ConcurrencyMode::Queue => {
+                trace!("wait for a free slot on {}", path.display());
+                let token = self.cancel_token.clone();
+                select! {
+                    permit = semaphore.acquire_owned() => permit,
+                    _ = token.cancelled() => {
+                        debug!("cancelled");
+                        drain::drain(None).await?;
+                        return Ok(Vec::new());
+                    }
+                }
+            }
+        };
+
+        Ok(vec![(box permit) as Permit])
+    }
+
+    fn interrupt(&self) {
+        debug!("interrupt concurrency limit");
+        self.cancel_token.cancel();
+    }
+}
+
+impl ConcurrencyInjector {
+    pub fn build(conf: ConcurrencyConfig) -> anyhow::Result<Self> {
+        trace!("build concurrency injector");
+
+        if conf.limit == 0 {
+            return Err(anyhow::anyhow!("limit should be positive"));
+        }
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            limit: conf.limit,
+            mode: conf.mode,
+            semaphores: Mutex::new(HashMap::new()),
+            cancel_token: CancellationToken::new(),
+        })
+    }
+
+    fn semaphore(&self, path: &Path) -> Arc<Semaphore> {
+        let mut semaphores = self.semaphores.lock().unwrap();
+        if semaphores.len() > SEMAPHORES_GC_THRESHOLD {
+            semaphores.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+        }
+
+        let limit = self.limit;
+        semaphores
+            .entry(path.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone()
+    }
+}
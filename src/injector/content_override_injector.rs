@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use fuser::FileAttr;
+use tracing::{debug, trace};
+
+use super::injector_config::ContentOverrideConfig;
+use super::{filter, Injector, IoContext};
+use crate::hookfs::Result;
+
+// ContentOverrideInjector serves the replacement content for reads of the
+// matching files, while the backing files are kept untouched. The size in the
+// attributes is overridden too, so that the whole replacement could be read.
+#[derive(Debug)]
+pub struct ContentOverrideInjector {
+    filter: filter::Filter,
+    content: Vec<u8>,
+}
+
+#[async_trait]
+impl Injector for ContentOverrideInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_read_data(&self, path: &Path, io: &IoContext, data: &mut Vec<u8>) -> Result<()> {
+        if !self.filter.filter(&filter::Method::READ, path) {
+            return Ok(());
+        }
+
+        let start = (io.offset.max(0) as usize).min(self.content.len());
+        let end = start
+            .saturating_add(io.size as usize)
+            .min(self.content.len());
+        debug!(
+            "override content [{}, {}) of {}",
+            start,
+            end,
+            path.display()
+        );
+
+        data.clear();
+        data.extend_from_slice(&self.content[start..end]);
+        Ok(())
+    }
+
+    fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
+        if !self.filter.filter(&filter::Method::READ, path) {
+            return;
+        }
+
+        trace!("overriding size {} with {}", attr.size, self.content.len());
+        attr.size = self.content.len() as u64;
+        attr.blocks = (attr.size + 511) / 512;
+    }
+}
+
+impl ContentOverrideInjector {
+    pub fn build(conf: ContentOverrideConfig) -> anyhow::Result<Self> {
+        trace!("build content override injector");
+
+        let content = match (conf.file, conf.content) {
+            (Some(file), None) => std::fs::read(file)?,
+            (None, Some(content)) => base64::decode(content)?,
+            _ => return Err(anyhow!("exactly one of file and content should be set")),
+        };
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            content,
+        })
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use nix::errno::Errno;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use super::filter::Method;
+use crate::hookfs::{budget, Error, Result};
+
+// Key identifies where a rule decides on an operation. The decisions are
+// numbered by their occurrence under the same key, rather than in the order
+// of all the operations, so that a replay doesn't depend on the interleaving
+// of the operations on different paths.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+struct Key {
+    experiment: String,
+    rule: usize,
+    // the hook deciding: "inject", "io", "enter" or "reply"
+    stage: String,
+    op: String,
+    #[serde(with = "crate::utils::os_path")]
+    path: PathBuf,
+}
+
+// Decision is one line of the recording, for every decision of a rule on an
+// operation. The operation succeeds if there is neither an errno nor an error.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Decision {
+    #[serde(flatten)]
+    key: Key,
+    occurrence: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    errno: Option<i32>,
+    // a failure without an errno, which is replayed as an internal error
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Decision {
+    fn new<T>(key: Key, occurrence: u64, result: &Result<T>) -> Decision {
+        let (errno, error) = match result {
+            Ok(_) => (None, None),
+            Err(Error::Sys(errno)) => (Some(*errno as i32), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        Decision {
+            key,
+            occurrence,
+            errno,
+            error,
+        }
+    }
+
+    fn replay<T: Default>(&self, result: Result<T>) -> Result<T> {
+        match (self.errno, &self.error) {
+            (Some(errno), _) => Err(Error::Sys(Errno::from_i32(errno))),
+            (None, Some(_)) => Err(Error::UnknownError),
+            (None, None) => Ok(result.unwrap_or_default()),
+        }
+    }
+}
+
+// the bytes charged to the memory budget for the count of every key, besides
+// its path
+const KEY_COST: usize = 128;
+
+const SUBSYSTEM: &str = "decisions";
+
+enum Mode {
+    Record(LineWriter<File>),
+    // every recorded decision
+    Replay(HashMap<(Key, u64), Decision>),
+}
+
+struct Decisions {
+    mode: Mode,
+    occurrences: HashMap<Key, u64>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static DECISIONS: Lazy<Mutex<Option<Decisions>>> = Lazy::new(|| Mutex::new(None));
+
+fn configure(mode: Mode) {
+    let mut decisions = DECISIONS.lock().unwrap();
+    if let Some(decisions) = decisions.as_ref() {
+        budget::release(SUBSYSTEM, occurrences_cost(&decisions.occurrences));
+    }
+    *decisions = Some(Decisions {
+        mode,
+        occurrences: HashMap::new(),
+    });
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+fn key_cost(key: &Key) -> usize {
+    KEY_COST + key.experiment.len() + key.path.as_os_str().len()
+}
+
+fn occurrences_cost(occurrences: &HashMap<Key, u64>) -> usize {
+    occurrences.keys().map(key_cost).sum()
+}
+
+// record writes every decision of the rules to the file, a json line each,
+// for `replay` to inject them again
+pub fn record(path: &Path) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("open decisions {}", path.display()))?;
+    info!("record injection decisions to {}", path.display());
+    configure(Mode::Record(LineWriter::new(file)));
+    Ok(())
+}
+
+// replay injects the decisions recorded in the file, in place of the ones the
+// rules decide on. The decisions which aren't recorded are left to the rules,
+// and the other effects of the rules, like the latency or the corruption, are
+// still drawn from the generator.
+pub fn replay(path: &Path) -> anyhow::Result<()> {
+    let file = File::open(path).with_context(|| format!("open decisions {}", path.display()))?;
+    let mut script = HashMap::new();
+    for line in BufReader::new(file).lines() {
+        let decision: Decision = serde_json::from_str(&line?)?;
+        script.insert((decision.key.clone(), decision.occurrence), decision);
+    }
+    info!(
+        "replay {} injection decisions from {}",
+        script.len(),
+        path.display()
+    );
+    configure(Mode::Replay(script));
+    Ok(())
+}
+
+// decide records the result of a rule on an operation, or replaces it with
+// the recorded one
+pub fn decide<T: Default>(
+    experiment: &str,
+    rule: usize,
+    stage: &str,
+    method: &Method,
+    path: &Path,
+    result: Result<T>,
+) -> Result<T> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return result;
+    }
+    let mut decisions = DECISIONS.lock().unwrap();
+    let decisions = match decisions.as_mut() {
+        Some(decisions) => decisions,
+        None => return result,
+    };
+
+    let key = Key {
+        experiment: experiment.to_owned(),
+        rule,
+        stage: stage.to_owned(),
+        op: format!("{:?}", method).to_lowercase(),
+        path: path.to_owned(),
+    };
+    if !decisions.occurrences.contains_key(&key) && !budget::charge(SUBSYSTEM, key_cost(&key)) {
+        budget::overflow()?;
+    }
+    let occurrences = decisions.occurrences.entry(key.clone()).or_default();
+    let occurrence = *occurrences;
+    *occurrences += 1;
+
+    match &mut decisions.mode {
+        Mode::Record(writer) => {
+            let decision = Decision::new(key, occurrence, &result);
+            let written = serde_json::to_writer(&mut *writer, &decision)
+                .map_err(std::io::Error::from)
+                .and_then(|_| writer.write_all(b"\n"));
+            if let Err(err) = written {
+                warn!("fail to record decision {:?}: {}", decision, err);
+            }
+            result
+        }
+        Mode::Replay(script) => match script.get(&(key, occurrence)) {
+            Some(decision) => decision.replay(result),
+            None => result,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decide_write(path: &str, result: Result<()>) -> Result<()> {
+        decide("test", 0, "inject", &Method::WRITE, Path::new(path), result)
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("toda-decisions-{}", std::process::id()));
+
+        record(&path).unwrap();
+        assert!(decide_write("/a", Err(Error::Sys(Errno::EIO))).is_err());
+        assert!(decide_write("/a", Ok(())).is_ok());
+        assert!(decide_write("/b", Err(Error::UnknownError)).is_err());
+
+        replay(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // the recorded failure is injected even if the rule lets it pass
+        assert!(matches!(
+            decide_write("/a", Ok(())),
+            Err(Error::Sys(Errno::EIO))
+        ));
+        // and the recorded success is kept even if the rule fails it
+        assert!(decide_write("/a", Err(Error::Sys(Errno::EACCES))).is_ok());
+        assert!(matches!(
+            decide_write("/b", Ok(())),
+            Err(Error::UnknownError)
+        ));
+        // the decisions which aren't recorded are passed through
+        assert!(matches!(
+            decide_write("/a", Err(Error::Sys(Errno::EINTR))),
+            Err(Error::Sys(Errno::EINTR))
+        ));
+        assert!(decide_write("/c", Ok(())).is_ok());
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use nix::errno::Errno;
+use tracing::{debug, info, trace};
+
+use super::injector_config::DiskFullConfig;
+use super::{filter, Injector, IoContext};
+use crate::hookfs::{Error, Reply, Result};
+
+// DiskFullInjector emulates a disk filling up. Every byte written through the
+// mount is taken from the budget, and once it's exhausted the writes fail with
+// ENOSPC. statfs reports the remaining budget as the free space, if it's less
+// than the real one.
+//
+// The bytes are taken before the io, and the ones which aren't written by a
+// short or failed io are given back once it's done. They are never given back
+// by a truncate or an unlink, the budget is only restored by `reset_space`.
+#[derive(Debug)]
+pub struct DiskFullInjector {
+    filter: filter::Filter,
+    budget: u64,
+
+    written: AtomicU64,
+    // the ios whose bytes are taken, by fh, offset and size, until they are
+    // done
+    taken: Mutex<HashMap<(u64, i64, u64), usize>>,
+}
+
+#[async_trait]
+impl Injector for DiskFullInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        let method = *method
+            & (filter::Method::WRITE | filter::Method::FALLOCATE | filter::Method::COPY_FILE_RANGE);
+        if method.is_empty() {
+            return Ok(());
+        }
+
+        // the bytes are taken only if they fit in the budget, so that the
+        // concurrent ios don't exceed it together
+        let budget = self.budget;
+        let taken = self
+            .written
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |written| {
+                written.checked_add(io.size).filter(|&sum| sum <= budget)
+            });
+        let written = match taken {
+            Ok(written) => written + io.size,
+            Err(_) if self.filter.filter(&method, path) => {
+                debug!("inject ENOSPC");
+                return Err(Error::Sys(Errno::ENOSPC));
+            }
+            // the bytes of the paths which aren't filtered are taken anyway
+            Err(_) => add(&self.written, io.size),
+        };
+        trace!("{} of {} bytes written", written, self.budget);
+
+        *self
+            .taken
+            .lock()
+            .unwrap()
+            .entry((io.fh, io.offset, io.size))
+            .or_default() += 1;
+        Ok(())
+    }
+
+    fn io_done(&self, io: &IoContext, done: u64) {
+        let key = (io.fh, io.offset, io.size);
+        let mut taken = self.taken.lock().unwrap();
+        match taken.get_mut(&key) {
+            Some(count) if *count > 1 => *count -= 1,
+            Some(_) => {
+                taken.remove(&key);
+            }
+            // the io is refused by the injector, or its bytes are taken by a
+            // replaced one
+            None => return,
+        }
+        drop(taken);
+
+        let unwritten = io.size.saturating_sub(done);
+        if unwritten > 0 {
+            trace!("give back {} unwritten bytes", unwritten);
+            let _ = self
+                .written
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |written| {
+                    Some(written.saturating_sub(unwritten))
+                });
+        }
+    }
+
+    fn inject_reply(&self, method: &filter::Method, _: &Path, reply: &mut Reply) -> Result<()> {
+        if !method.contains(filter::Method::STATFS) {
+            return Ok(());
+        }
+
+        if let Reply::StatFs(statfs) = reply {
+            let free = self.remaining() / statfs.frsize.max(1) as u64;
+            statfs.bfree = statfs.bfree.min(free);
+            statfs.bavail = statfs.bavail.min(free);
+        }
+        Ok(())
+    }
+
+    fn reset_space(&self) {
+        info!("reset the written bytes");
+        self.written.store(0, Ordering::SeqCst);
+    }
+}
+
+impl DiskFullInjector {
+    pub fn build(conf: DiskFullConfig) -> anyhow::Result<Self> {
+        trace!("build disk full injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            budget: conf.budget,
+            written: AtomicU64::new(0),
+            taken: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn remaining(&self) -> u64 {
+        self.budget
+            .saturating_sub(self.written.load(Ordering::SeqCst))
+    }
+}
+
+// add adds the bytes to the written ones, without overflowing, and returns the
+// sum
+fn add(written: &AtomicU64, bytes: u64) -> u64 {
+    let previous = written
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |written| {
+            Some(written.saturating_add(bytes))
+        })
+        .unwrap_or_default();
+    previous.saturating_add(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn build(budget: u64) -> DiskFullInjector {
+        let conf = format!(r#"{{"percent":100,"budget":{}}}"#, budget);
+        DiskFullInjector::build(serde_json::from_str(&conf).unwrap()).unwrap()
+    }
+
+    fn io(offset: i64, size: u64) -> IoContext {
+        IoContext {
+            fh: 0,
+            fd: -1,
+            offset,
+            size,
+        }
+    }
+
+    fn write(injector: &DiskFullInjector, io: &IoContext) -> Result<()> {
+        block_on(injector.inject_io(&filter::Method::WRITE, Path::new("/file"), io))
+    }
+
+    #[test]
+    fn test_concurrent_writes_within_budget() {
+        let injector = Arc::new(build(10));
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let injector = injector.clone();
+                std::thread::spawn(move || write(&injector, &io(i * 4, 4)).is_ok())
+            })
+            .collect();
+        let written = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|&written| written)
+            .count();
+        assert_eq!(written, 2);
+        assert_eq!(injector.remaining(), 2);
+    }
+
+    #[test]
+    fn test_give_back_unwritten() {
+        let injector = build(10);
+        let failed = io(0, 6);
+        write(&injector, &failed).unwrap();
+        injector.io_done(&failed, 0);
+        assert_eq!(injector.remaining(), 10);
+
+        let short = io(0, 8);
+        write(&injector, &short).unwrap();
+        injector.io_done(&short, 5);
+        assert_eq!(injector.remaining(), 5);
+
+        // the refused io has nothing to give back
+        let refused = io(5, 6);
+        assert!(matches!(
+            write(&injector, &refused),
+            Err(Error::Sys(Errno::ENOSPC))
+        ));
+        injector.io_done(&refused, 0);
+        assert_eq!(injector.remaining(), 5);
+    }
+}
@@ -0,0 +1,107 @@
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use nix::errno::Errno;
+use tokio::select;
+use tokio::time::delay_for;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info};
+
+use crate::hookfs::{Error, Result};
+
+// DrainPolicy decides what happens to the operations which are still being
+// delayed when the injection is interrupted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DrainPolicy {
+    // release them immediately
+    Release,
+    // complete the remaining delay, but wait at most this long
+    Complete(Duration),
+    // fail them with the errno
+    Fail(Errno),
+}
+
+impl FromStr for DrainPolicy {
+    type Err = anyhow::Error;
+
+    // The policy is "release", "complete:<max duration>" or "fail:<errno>"
+    fn from_str(policy: &str) -> anyhow::Result<Self> {
+        let mut parts = policy.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("release"), None) => Ok(DrainPolicy::Release),
+            (Some("complete"), Some(max)) => {
+                Ok(DrainPolicy::Complete(humantime::parse_duration(max)?))
+            }
+            (Some("fail"), Some(errno)) => Ok(DrainPolicy::Fail(Errno::from_i32(errno.parse()?))),
+            _ => Err(anyhow!("unknown drain policy {}", policy)),
+        }
+    }
+}
+
+// Drain holds the drain policy of a mount, which the operations delayed on it
+// read once they are interrupted, so that it can be set on recovery
+#[derive(Debug, Clone)]
+pub struct Drain(Arc<RwLock<DrainPolicy>>);
+
+impl Default for Drain {
+    fn default() -> Self {
+        Drain(Arc::new(RwLock::new(DrainPolicy::Release)))
+    }
+}
+
+impl Drain {
+    pub fn set(&self, policy: DrainPolicy) {
+        info!("set drain policy {:?}", policy);
+        *self.0.write().unwrap() = policy;
+    }
+
+    fn get(&self) -> DrainPolicy {
+        *self.0.read().unwrap()
+    }
+
+    // scope runs the operation with the drain policy of its mount
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        POLICY.scope(self, f).await
+    }
+}
+
+tokio::task_local! {
+    static POLICY: Drain;
+}
+
+// drain applies the drain policy to an interrupted operation, which should
+// still be delayed for `remaining` (or forever if it's None)
+pub async fn drain(remaining: Option<Duration>) -> Result<()> {
+    // the operations outside of a mount, like the ones of the tests, are
+    // released
+    let policy = POLICY.try_with(Drain::get).unwrap_or(DrainPolicy::Release);
+    match policy {
+        DrainPolicy::Release => Ok(()),
+        DrainPolicy::Complete(max) => {
+            let remaining = remaining.map_or(max, |remaining| remaining.min(max));
+            debug!("complete the remaining delay {:?}", remaining);
+            delay_for(remaining).await;
+            Ok(())
+        }
+        DrainPolicy::Fail(errno) => {
+            debug!("fail the delayed operation with {}", errno);
+            Err(Error::Sys(errno))
+        }
+    }
+}
+
+// delay waits for `latency` unless it's interrupted by the token, in which
+// case the drain policy is applied
+pub async fn delay(latency: Duration, token: &CancellationToken) -> Result<()> {
+    let started = Instant::now();
+    select! {
+        _ = delay_for(latency) => Ok(()),
+        _ = token.cancelled() => {
+            debug!("cancelled");
+            drain(Some(latency.checked_sub(started.elapsed()).unwrap_or_default())).await
+        }
+    }
+}
@@ -0,0 +1,615 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use fuser::FileAttr;
+use serde::Serialize;
+use tracing::{info, trace};
+
+use super::filter::{OnMatch, StartTime};
+use super::injector_config::{InjectorConfig, ScheduleConfig};
+use super::multi_injector::MultiInjector;
+use super::{decisions, filter, Injector, IoContext, Permit};
+use crate::hookfs::{caller, DirEntry, Error, Reply, Result};
+use crate::{audit, metrics};
+
+// the experiment which the rules applied by `update` belong to
+pub const DEFAULT_EXPERIMENT: &str = "default";
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExperimentStatus {
+    pub name: String,
+    pub started: u64,
+    // the seconds before the experiment expires
+    pub expires_in: Option<u64>,
+    // the count of the rules which haven't expired
+    pub rules: usize,
+    pub errors: u64,
+}
+
+// ExpiryEvent is emitted on the control plane once an experiment, or one rule
+// of it, expires and is removed
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpiryEvent {
+    pub timestamp: u64,
+    pub experiment: String,
+    // the index of the rule in the config, or None if the whole experiment
+    // expires
+    pub rule: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    index: usize,
+    inner: MultiInjector,
+    expires: Option<Instant>,
+    removed: AtomicBool,
+    metrics: metrics::RuleMetrics,
+    schedule: Option<ScheduleConfig>,
+    // whether the schedule was active when it was last logged
+    scheduled: AtomicBool,
+}
+
+impl Rule {
+    fn expired(&self) -> bool {
+        match self.expires {
+            Some(expires) => Instant::now() >= expires,
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ExperimentState {
+    name: String,
+    // the config the rules are built from
+    config: Vec<InjectorConfig>,
+    rules: Vec<Rule>,
+    started: SystemTime,
+    // when the schedules of the rules start
+    clock: StartTime,
+    expires: Option<Instant>,
+    errors: AtomicU64,
+    // set once the delayed operations are released, after which the rules
+    // no longer decide on them
+    interrupted: AtomicBool,
+    // the mount the experiment runs on, or None for every mount sharing the
+    // control plane
+    mount: Option<PathBuf>,
+    on_error: OnError,
+}
+
+// OnError is called with every error a rule of the experiment fails an
+// operation with
+#[derive(Clone, Default)]
+pub struct OnError(Option<Arc<dyn Fn(&filter::Method, &Path, &Error) + Send + Sync>>);
+
+impl OnError {
+    pub fn new<F: Fn(&filter::Method, &Path, &Error) + Send + Sync + 'static>(f: F) -> Self {
+        OnError(Some(Arc::new(f)))
+    }
+
+    fn call(&self, method: &filter::Method, path: &Path, err: &Error) {
+        if let Some(f) = &self.0 {
+            f(method, path, err)
+        }
+    }
+}
+
+impl fmt::Debug for OnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OnError")
+    }
+}
+
+// Experiment owns a rule set, along with the statistics and the expiry of it.
+// The experiments running on one mount are installed together, but started,
+// replaced and stopped independently. An expired experiment stops injecting
+// immediately, and is removed from the mount on the next change. So does an
+// expired rule, which is removed from its experiment.
+#[derive(Debug, Clone)]
+pub struct Experiment(Arc<ExperimentState>);
+
+impl Experiment {
+    pub fn build(
+        name: String,
+        conf: Vec<InjectorConfig>,
+        ttl: Option<Duration>,
+    ) -> anyhow::Result<Self> {
+        Self::build_on(name, conf, ttl, None)
+    }
+
+    // build_on builds an experiment running on the mount only, or on every
+    // mount if it's None
+    pub fn build_on(
+        name: String,
+        conf: Vec<InjectorConfig>,
+        ttl: Option<Duration>,
+        mount: Option<PathBuf>,
+    ) -> anyhow::Result<Self> {
+        trace!("build experiment {}", name);
+
+        let now = Instant::now();
+        let clock = StartTime::default();
+        let rules = conf
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(index, mut conf)| {
+                let expires = conf.ttl().map(|ttl| now + ttl);
+                let schedule = conf.schedule().cloned();
+                conf.start_at(clock.clone());
+                let metrics = metrics::rule(&name, index);
+                let hits = metrics.hits.clone();
+                let (experiment, kind) = (name.clone(), conf.kind());
+                conf.on_match(OnMatch::new(move |method, path| {
+                    hits.incr();
+                    audit(&experiment, index, method, path, kind.to_owned());
+                }));
+                let inner = MultiInjector::build(vec![conf])
+                    .map_err(|err| anyhow!("rule {}: {}", index, err))?;
+                Ok(Rule {
+                    index,
+                    expires,
+                    inner,
+                    removed: AtomicBool::new(false),
+                    metrics,
+                    scheduled: AtomicBool::new(
+                        schedule
+                            .as_ref()
+                            .map_or(true, |schedule| schedule.active(Duration::from_secs(0))),
+                    ),
+                    schedule,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Experiment(Arc::new(ExperimentState {
+            name,
+            config: conf,
+            rules,
+            started: SystemTime::now(),
+            clock,
+            expires: ttl.map(|ttl| now + ttl),
+            errors: AtomicU64::new(0),
+            interrupted: AtomicBool::new(false),
+            mount,
+            on_error: OnError::default(),
+        })))
+    }
+
+    // on_error sets the hook called with every error of the experiment. It
+    // should be set before the experiment is started.
+    pub fn on_error(mut self, on_error: OnError) -> Self {
+        Arc::get_mut(&mut self.0)
+            .expect("the hook should be set before the experiment is shared")
+            .on_error = on_error;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    pub fn config(&self) -> &[InjectorConfig] {
+        &self.0.config
+    }
+
+    pub fn expired(&self) -> bool {
+        match self.0.expires {
+            Some(expires) => Instant::now() >= expires,
+            None => false,
+        }
+    }
+
+    pub fn status(&self) -> ExperimentStatus {
+        ExperimentStatus {
+            name: self.0.name.clone(),
+            started: unix_timestamp(self.0.started),
+            expires_in: self.0.expires.map(|expires| {
+                expires
+                    .checked_duration_since(Instant::now())
+                    .unwrap_or_default()
+                    .as_secs()
+            }),
+            rules: self.rules().count(),
+            errors: self.0.errors.load(Ordering::SeqCst),
+        }
+    }
+
+    // active_rules returns the rules which haven't expired
+    fn active_rules(&self) -> impl Iterator<Item = &Rule> {
+        self.0.rules.iter().filter(|rule| !rule.expired())
+    }
+
+    fn rules(&self) -> impl Iterator<Item = &MultiInjector> {
+        self.active_rules().map(|rule| &rule.inner)
+    }
+
+    // start starts the schedules of the rules
+    fn start(&self) {
+        self.0.clock.start();
+    }
+
+    // log_schedules logs the rules whose schedule has been activated or
+    // deactivated since the last call
+    fn log_schedules(&self) {
+        let elapsed = self.0.clock.elapsed();
+        for rule in self.active_rules() {
+            let schedule = match &rule.schedule {
+                Some(schedule) => schedule,
+                None => continue,
+            };
+            let active = schedule.active(elapsed);
+            if rule.scheduled.swap(active, Ordering::SeqCst) != active {
+                let state = if active { "activated" } else { "deactivated" };
+                info!(
+                    "rule {} of experiment {} {} by its schedule",
+                    rule.index, self.0.name, state
+                );
+            }
+        }
+    }
+
+    // remove_expired_rules releases the operations delayed by the rules which
+    // have expired since the last call, and returns their indexes
+    fn remove_expired_rules(&self) -> Vec<usize> {
+        self.0
+            .rules
+            .iter()
+            .filter(|rule| rule.expired() && !rule.removed.swap(true, Ordering::SeqCst))
+            .map(|rule| {
+                info!("rule {} of experiment {} expired", rule.index, self.0.name);
+                rule.inner.interrupt();
+                rule.index
+            })
+            .collect()
+    }
+
+    fn record<T: Default>(
+        &self,
+        rule: &Rule,
+        stage: &str,
+        method: &filter::Method,
+        path: &Path,
+        result: Result<T>,
+    ) -> Result<T> {
+        // the operations released by an interruption fail or pass by the
+        // drain policy rather than by the rule, so that they are neither
+        // recorded nor replayed
+        let result =
+            if self.0.interrupted.load(Ordering::SeqCst) || rule.removed.load(Ordering::SeqCst) {
+                result
+            } else {
+                decisions::decide(&self.0.name, rule.index, stage, method, path, result)
+            };
+        if let Err(err) = &result {
+            self.0.errors.fetch_add(1, Ordering::SeqCst);
+            rule.metrics.faults.incr();
+            let action = match err {
+                Error::Sys(errno) => format!("{:?}", errno),
+                err => err.to_string(),
+            };
+            audit(&self.0.name, rule.index, method, path, action);
+            self.0.on_error.call(method, path, err);
+        }
+        result
+    }
+}
+
+// audit records an operation a rule fired on, or failed with the action
+fn audit(experiment: &str, rule: usize, method: &filter::Method, path: &Path, action: String) {
+    if audit::enabled() {
+        audit::record(&audit::Record {
+            timestamp: audit::timestamp(),
+            op: format!("{:?}", method).to_lowercase(),
+            path,
+            pid: caller::current().map(|caller| caller.pid),
+            experiment,
+            rule,
+            action,
+        });
+    }
+}
+
+#[async_trait]
+impl Injector for Experiment {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if self.expired() {
+            return Ok(());
+        }
+        for rule in self.active_rules() {
+            self.record(
+                rule,
+                "inject",
+                method,
+                path,
+                rule.inner.inject(method, path).await,
+            )?;
+        }
+        Ok(())
+    }
+
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        if self.expired() {
+            return Ok(());
+        }
+        for rule in self.active_rules() {
+            self.record(
+                rule,
+                "io",
+                method,
+                path,
+                rule.inner.inject_io(method, path, io).await,
+            )?;
+        }
+        Ok(())
+    }
+
+    async fn enter(&self, method: &filter::Method, path: &Path) -> Result<Vec<Permit>> {
+        let mut permits = Vec::new();
+        if self.expired() {
+            return Ok(permits);
+        }
+        for rule in self.active_rules() {
+            permits.extend(self.record(
+                rule,
+                "enter",
+                method,
+                path,
+                rule.inner.enter(method, path).await,
+            )?);
+        }
+        Ok(permits)
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        if self.expired() {
+            return Ok(());
+        }
+        for rule in self.active_rules() {
+            self.record(
+                rule,
+                "reply",
+                method,
+                path,
+                rule.inner.inject_reply(method, path, reply),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn inject_write_data(&self, path: &Path, io: &IoContext, data: &mut Vec<u8>) -> Result<()> {
+        if self.expired() {
+            return Ok(());
+        }
+        for rule in self.rules() {
+            rule.inject_write_data(path, io, data)?;
+        }
+        Ok(())
+    }
+
+    fn inject_read_data(&self, path: &Path, io: &IoContext, data: &mut Vec<u8>) -> Result<()> {
+        if self.expired() {
+            return Ok(());
+        }
+        for rule in self.rules() {
+            rule.inject_read_data(path, io, data)?;
+        }
+        Ok(())
+    }
+
+    fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
+        if self.expired() {
+            return;
+        }
+        for rule in self.rules() {
+            rule.inject_attr(attr, path)
+        }
+    }
+
+    fn interrupt(&self) {
+        self.0.interrupted.store(true, Ordering::SeqCst);
+        for rule in self.0.rules.iter() {
+            rule.inner.interrupt()
+        }
+    }
+
+    fn inject_entries(&self, path: &Path, entries: &mut Vec<DirEntry>) {
+        if self.expired() {
+            return;
+        }
+        for rule in self.rules() {
+            rule.inject_entries(path, entries);
+        }
+    }
+
+    fn journal_writes(&self, path: &Path) -> bool {
+        !self.expired() && self.rules().any(|rule| rule.journal_writes(path))
+    }
+
+    fn drop_unsynced(&self, method: &filter::Method, path: &Path) -> bool {
+        !self.expired() && self.rules().any(|rule| rule.drop_unsynced(method, path))
+    }
+
+    fn tear_write(&self, path: &Path, io: &IoContext) -> Option<Vec<Range<usize>>> {
+        if self.expired() {
+            return None;
+        }
+        self.rules().find_map(|rule| rule.tear_write(path, io))
+    }
+
+    fn io_done(&self, io: &IoContext, done: u64) {
+        // the rules expired since the io was let through give it back as well
+        for rule in self.0.rules.iter() {
+            rule.inner.io_done(io, done)
+        }
+    }
+
+    fn tracks_usage(&self, path: &Path) -> bool {
+        !self.expired() && self.rules().any(|rule| rule.tracks_usage(path))
+    }
+
+    fn used(&self, path: &Path, bytes: i64, inodes: i64) {
+        for rule in self.rules() {
+            rule.used(path, bytes, inodes)
+        }
+    }
+
+    fn sniffs_magic(&self) -> bool {
+        !self.expired() && self.rules().any(|rule| rule.sniffs_magic())
+    }
+
+    fn release_fh(&self, fh: u64) {
+        for rule in self.0.rules.iter() {
+            rule.inner.release_fh(fh)
+        }
+    }
+
+    fn reset_zones(&self) {
+        for rule in self.rules() {
+            rule.reset_zones()
+        }
+    }
+
+    fn reset_space(&self) {
+        for rule in self.rules() {
+            rule.reset_space()
+        }
+    }
+
+    fn reconnect(&self) {
+        for rule in self.0.rules.iter() {
+            rule.inner.reconnect()
+        }
+    }
+
+    fn resume(&self) {
+        for rule in self.0.rules.iter() {
+            rule.inner.resume()
+        }
+    }
+
+    fn crash(&self) {
+        for rule in self.rules() {
+            rule.crash()
+        }
+    }
+}
+
+// Experiments is the set of experiments running on one mount
+#[derive(Debug, Default)]
+pub struct Experiments {
+    experiments: BTreeMap<String, Experiment>,
+}
+
+impl Experiments {
+    // start adds the experiment, or replaces the one with the same name and
+    // releases the operations it delays
+    pub fn start(&mut self, experiment: Experiment) {
+        info!("start experiment {}", experiment.name());
+        experiment.start();
+        if let Some(replaced) = self
+            .experiments
+            .insert(experiment.name().to_owned(), experiment)
+        {
+            replaced.interrupt();
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Experiment> {
+        self.experiments.get(name)
+    }
+
+    // stop removes the experiment and releases the operations it delays
+    pub fn stop(&mut self, name: &str) -> anyhow::Result<()> {
+        info!("stop experiment {}", name);
+        let experiment = self
+            .experiments
+            .remove(name)
+            .ok_or(anyhow!("experiment {} not found", name))?;
+        experiment.interrupt();
+        Ok(())
+    }
+
+    // prune removes the expired experiments and rules, and returns an event
+    // for every one of them. It also logs the transitions of the schedules,
+    // as it's called periodically.
+    pub fn prune(&mut self) -> Vec<ExpiryEvent> {
+        let timestamp = unix_timestamp(SystemTime::now());
+        let mut events = Vec::new();
+
+        let expired: Vec<_> = self
+            .experiments
+            .values()
+            .filter(|experiment| experiment.expired())
+            .map(|experiment| experiment.name().to_owned())
+            .collect();
+        for name in expired {
+            info!("experiment {} expired", name);
+            if let Some(experiment) = self.experiments.remove(&name) {
+                experiment.interrupt();
+            }
+            events.push(ExpiryEvent {
+                timestamp,
+                experiment: name,
+                rule: None,
+            });
+        }
+
+        for experiment in self.experiments.values() {
+            experiment.log_schedules();
+            events.extend(
+                experiment
+                    .remove_expired_rules()
+                    .into_iter()
+                    .map(|index| ExpiryEvent {
+                        timestamp,
+                        experiment: experiment.name().to_owned(),
+                        rule: Some(index),
+                    }),
+            );
+        }
+
+        events
+    }
+
+    pub fn status(&self) -> Vec<ExperimentStatus> {
+        self.experiments
+            .values()
+            .map(|experiment| experiment.status())
+            .collect()
+    }
+
+    // injector combines the experiments running on the mount, to be
+    // installed on it
+    pub fn injector(&self, mount: &Path) -> MultiInjector {
+        MultiInjector::new(
+            self.experiments
+                .values()
+                .filter(|experiment| {
+                    experiment
+                        .0
+                        .mount
+                        .as_ref()
+                        .map_or(true, |path| path == mount)
+                })
+                .map(|experiment| (box experiment.clone()) as Box<dyn Injector>)
+                .collect(),
+        )
+    }
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
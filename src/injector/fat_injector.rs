@@ -0,0 +1,109 @@
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use fuser::FileAttr;
+use nix::errno::Errno;
+use tracing::{debug, trace};
+
+use super::injector_config::FatConfig;
+use super::{filter, Injector, IoContext};
+use crate::hookfs::{caller, Error, Result};
+
+// 4 GiB - 1, the largest file FAT32 can hold
+const DEFAULT_MAX_FILE_SIZE: u64 = (1 << 32) - 1;
+
+const FORBIDDEN_CHARACTERS: &[u8] = b"\"*:<>?\\|";
+
+// FatInjector emulates the limitations of FAT-like filesystems: no symlinks or
+// hard links, a maximum file size (for the writes, the truncates and the
+// allocations), 2 seconds mtime granularity and a restricted set of characters
+// in names.
+//
+// Only the names of newly created entries are checked, as the hooks only see
+// the source of a rename.
+#[derive(Debug)]
+pub struct FatInjector {
+    filter: filter::Filter,
+    max_file_size: u64,
+}
+
+#[async_trait]
+impl Injector for FatInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if !self.filter.filter(method, path) {
+            return Ok(());
+        }
+
+        if method.intersects(filter::Method::SYMLINK | filter::Method::LINK) {
+            debug!("links are not supported");
+            return Err(Error::Sys(Errno::EPERM));
+        }
+
+        if method.intersects(filter::Method::CREATE | filter::Method::MKNOD | filter::Method::MKDIR)
+            && !valid_name(path)
+        {
+            debug!("invalid name {}", path.display());
+            return Err(Error::Sys(Errno::EINVAL));
+        }
+
+        if method.contains(filter::Method::SETATTR)
+            && caller::size().map_or(false, |size| size > self.max_file_size)
+        {
+            debug!("truncate beyond the max file size");
+            return Err(Error::Sys(Errno::EFBIG));
+        }
+
+        Ok(())
+    }
+
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        let growing =
+            filter::Method::WRITE | filter::Method::FALLOCATE | filter::Method::COPY_FILE_RANGE;
+        if method.intersects(growing)
+            && (io.offset.max(0) as u64).saturating_add(io.size) > self.max_file_size
+            && self.filter.filter(method, path)
+        {
+            debug!("{:?} beyond the max file size", method);
+            return Err(Error::Sys(Errno::EFBIG));
+        }
+
+        Ok(())
+    }
+
+    fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
+        if !self.filter.filter(&filter::Method::GETATTR, path) {
+            return;
+        }
+
+        if let Ok(mtime) = attr.mtime.duration_since(UNIX_EPOCH) {
+            trace!("coarsening mtime");
+            attr.mtime = UNIX_EPOCH + Duration::from_secs(mtime.as_secs() & !1);
+        }
+    }
+}
+
+fn valid_name(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.as_bytes(),
+        None => return true,
+    };
+
+    !name
+        .iter()
+        .any(|c| *c < 0x20 || FORBIDDEN_CHARACTERS.contains(c))
+        && !name.ends_with(b".")
+        && !name.ends_with(b" ")
+}
+
+impl FatInjector {
+    pub fn build(conf: FatConfig) -> anyhow::Result<Self> {
+        trace!("build fat injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            max_file_size: conf.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE),
+        })
+    }
+}
@@ -1,12 +1,13 @@
 use std::path::Path;
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 use nix::errno::Errno;
 use rand::Rng;
 use tracing::{debug, trace};
 
 use super::injector_config::FaultsConfig;
-use super::{filter, Injector};
+use super::{filter, rng, Injector, IoContext};
 use crate::hookfs::{Error, Result};
 
 #[derive(Debug)]
@@ -23,19 +24,15 @@ impl Injector for FaultInjector {
     async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
         debug!("test filter");
         if self.filter.filter(method, path) {
-            debug!("inject io fault");
-            let mut rng = rand::thread_rng();
-            let attempt: f64 = rng.gen();
-            let mut attempt = (attempt * (self.sum as f64)) as i32;
-
-            for (err, p) in self.errnos.iter() {
-                attempt -= p;
-
-                if attempt < 0 {
-                    debug!("return with error {}", err);
-                    return Err(Error::Sys(*err));
-                }
-            }
+            return self.fail();
+        }
+
+        Ok(())
+    }
+
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        if self.filter.filter_range(method, path, io).is_some() {
+            return self.fail();
         }
 
         Ok(())
@@ -49,8 +46,12 @@ impl FaultInjector {
         let errnos: Vec<_> = conf
             .faults
             .iter()
-            .map(|item| (Errno::from_i32(item.errno), item.weight))
-            .collect();
+            .map(|item| match Errno::from_i32(item.errno) {
+                Errno::UnknownErrno => Err(anyhow!("unknown errno {}", item.errno)),
+                _ if item.weight < 0 => Err(anyhow!("negative weight {}", item.weight)),
+                errno => Ok((errno, item.weight)),
+            })
+            .collect::<anyhow::Result<_>>()?;
 
         let sum = errnos.iter().fold(0, |acc, w| acc + w.1);
         Ok(Self {
@@ -59,4 +60,22 @@ impl FaultInjector {
             sum,
         })
     }
+
+    // fail returns one of the errnos, chosen by their weights
+    fn fail(&self) -> Result<()> {
+        debug!("inject io fault");
+        let attempt: f64 = rng::with(|rng| rng.gen());
+        let mut attempt = (attempt * (self.sum as f64)) as i32;
+
+        for (err, p) in self.errnos.iter() {
+            attempt -= p;
+
+            if attempt < 0 {
+                debug!("return with error {}", err);
+                return Err(Error::Sys(*err));
+            }
+        }
+
+        Ok(())
+    }
 }
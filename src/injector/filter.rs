@@ -1,16 +1,27 @@
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::path::Path;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Error, Result};
 use bitflags::bitflags;
-use glob::{MatchOptions, Pattern};
+use nix::sys::stat::fstat;
+use once_cell::sync::Lazy;
 use rand::Rng;
+use regex::Regex;
 use tracing::{info, trace};
 
-use super::injector_config::FilterConfig;
+use super::injector_config::{FilterConfig, RangeConfig, ScheduleConfig, TriggerConfig};
+use super::{rng, IoContext};
+use crate::hookfs::{caller, magic};
+use crate::utils::PathPattern;
 
 bitflags! {
-    pub struct Method: u32 {
+    pub struct Method: u64 {
         const LOOKUP = 1;
         const FORGET = 1<<1;
         const GETATTR = 1<<2;
@@ -43,6 +54,9 @@ bitflags! {
         const GETLK = 1<<29;
         const SETLK = 1<<30;
         const BMAP = 1<<31;
+        const FALLOCATE = 1<<32;
+        const COPY_FILE_RANGE = 1<<33;
+        const LSEEK = 1<<34;
     }
 }
 
@@ -81,69 +95,711 @@ impl TryFrom<&str> for Method {
             "getlk" => Ok(Method::GETLK),
             "setlk" => Ok(Method::SETLK),
             "bmap" => Ok(Method::BMAP),
-            _ => Err(anyhow!("")),
+            "fallocate" => Ok(Method::FALLOCATE),
+            "copy_file_range" => Ok(Method::COPY_FILE_RANGE),
+            "lseek" => Ok(Method::LSEEK),
+            _ => Err(anyhow!("unknown method {}", s)),
         }
     }
     type Error = Error;
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoClass {
+    RealTime,
+    BestEffort,
+    Idle,
+}
+
+impl TryFrom<&str> for IoClass {
+    fn try_from(s: &str) -> Result<IoClass> {
+        match s.to_lowercase().as_str() {
+            "realtime" | "rt" => Ok(IoClass::RealTime),
+            "besteffort" | "be" => Ok(IoClass::BestEffort),
+            "idle" => Ok(IoClass::Idle),
+            _ => Err(anyhow!("unknown io class {}", s)),
+        }
+    }
+    type Error = Error;
+}
+
+// the time the calling threads read from /proc are cached for, which is the
+// delay before a rule sees a renamed or moved process
+const PROCESS_TTL: Duration = Duration::from_secs(1);
+
+// at most this number of calling threads are cached
+const MAX_PROCESSES: usize = 4096;
+
+// Process is what the filters read of a calling thread from /proc. It's
+// cached per thread, so that the workers don't read /proc on every operation.
+#[derive(Debug)]
+struct Process {
+    tgid: Option<u32>,
+    comm: Option<String>,
+    cgroup: Option<PathBuf>,
+}
+
+static PROCESSES: Lazy<Mutex<HashMap<u32, (Instant, Arc<Process>)>>> = Lazy::new(Default::default);
+
+// process returns the calling thread, read again once its cache expires
+fn process(tid: u32) -> Arc<Process> {
+    if let Some((read, process)) = PROCESSES.lock().unwrap().get(&tid) {
+        if read.elapsed() < PROCESS_TTL {
+            return process.clone();
+        }
+    }
+
+    let process = Arc::new(Process {
+        tgid: tgid(tid),
+        comm: std::fs::read_to_string(format!("/proc/{}/comm", tid))
+            .ok()
+            .map(|comm| comm.trim_end().to_owned()),
+        cgroup: process_cgroup(tid),
+    });
+    let mut processes = PROCESSES.lock().unwrap();
+    if processes.len() >= MAX_PROCESSES {
+        processes.retain(|_, (read, _)| read.elapsed() < PROCESS_TTL);
+        if processes.len() >= MAX_PROCESSES {
+            processes.clear();
+        }
+    }
+    processes.insert(tid, (Instant::now(), process.clone()));
+    process
+}
+
+// tgid returns the id of the process of a thread
+fn tgid(tid: u32) -> Option<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", tid)).ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Tgid:"))
+        .and_then(|tgid| tgid.trim().parse().ok())
+}
+
+// process_cgroup returns the cgroup of a process in the unified hierarchy
+fn process_cgroup(pid: u32) -> Option<PathBuf> {
+    let content = std::fs::read_to_string(format!("/proc/{}/cgroup", pid)).ok()?;
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(PathBuf::from)
+}
+
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+
+// io_priority reads the io priority class and level of a process. A process
+// without explicit io priority is best effort with a level derived from its
+// nice value, as the kernel does.
+fn io_priority(pid: u32) -> Option<(IoClass, u8)> {
+    if pid == 0 {
+        return None;
+    }
+
+    let prio =
+        unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, pid as libc::c_int) };
+    if prio < 0 {
+        trace!("fail to get io priority of {}", pid);
+        return None;
+    }
+
+    let level = (prio & ((1 << IOPRIO_CLASS_SHIFT) - 1)) as u8;
+    match prio >> IOPRIO_CLASS_SHIFT {
+        0 => {
+            let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, pid as libc::id_t) };
+            Some((
+                IoClass::BestEffort,
+                ((nice.max(-20).min(19) + 20) / 5) as u8,
+            ))
+        }
+        1 => Some((IoClass::RealTime, level)),
+        2 => Some((IoClass::BestEffort, level)),
+        3 => Some((IoClass::Idle, level)),
+        _ => None,
+    }
+}
+
+// parse_open_flag converts the name of an open flag into the mask and the
+// value which the flags of a file handle are compared with
+fn parse_open_flag(flag: &str) -> Result<(i32, i32)> {
+    let flag = flag.to_lowercase();
+    let bit = match flag.strip_prefix("o_").unwrap_or(&flag) {
+        "rdonly" => return Ok((libc::O_ACCMODE, libc::O_RDONLY)),
+        "wronly" => return Ok((libc::O_ACCMODE, libc::O_WRONLY)),
+        "rdwr" => return Ok((libc::O_ACCMODE, libc::O_RDWR)),
+        "append" => libc::O_APPEND,
+        "direct" => libc::O_DIRECT,
+        "dsync" => libc::O_DSYNC,
+        // O_SYNC includes the bit of O_DSYNC
+        "sync" => libc::O_SYNC,
+        "nonblock" => libc::O_NONBLOCK,
+        "noatime" => libc::O_NOATIME,
+        _ => return Err(anyhow!("unknown open flag {}", flag)),
+    };
+    Ok((bit, bit))
+}
+
+// parse_magic converts a file type or hex encoded bytes into the leading bytes
+fn parse_magic(magic: &str) -> Result<Vec<u8>> {
+    let bytes = match magic.to_lowercase().as_str() {
+        "sqlite" => b"SQLite format 3\0".to_vec(),
+        "gzip" => vec![0x1f, 0x8b],
+        "elf" => b"\x7fELF".to_vec(),
+        "zip" => b"PK\x03\x04".to_vec(),
+        "png" => b"\x89PNG".to_vec(),
+        "pdf" => b"%PDF".to_vec(),
+        magic => {
+            let hex = magic
+                .strip_prefix("hex:")
+                .ok_or(anyhow!("unknown magic {}", magic))?;
+            if hex.len() % 2 != 0 {
+                return Err(anyhow!("invalid hex magic {}", magic));
+            }
+            (0..hex.len())
+                .step_by(2)
+                .map(|index| u8::from_str_radix(&hex[index..index + 2], 16))
+                .collect::<std::result::Result<Vec<_>, _>>()?
+        }
+    };
+    if bytes.is_empty() || bytes.len() > magic::HEADER_LENGTH {
+        return Err(anyhow!("invalid length of magic {}", magic));
+    }
+
+    Ok(bytes)
+}
+
+// StartTime is when the schedule of a rule starts, which is when its
+// experiment starts, or when the rule is built if it runs without one
+#[derive(Debug, Clone)]
+pub struct StartTime(Arc<Mutex<Instant>>);
+
+impl Default for StartTime {
+    fn default() -> Self {
+        StartTime(Arc::new(Mutex::new(Instant::now())))
+    }
+}
+
+impl StartTime {
+    pub fn start(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.0.lock().unwrap().elapsed()
+    }
+}
+
+// OnMatch is called with every operation a filter matches
+#[derive(Clone, Default)]
+pub struct OnMatch(Option<Arc<dyn Fn(&Method, &Path) + Send + Sync>>);
+
+impl OnMatch {
+    pub fn new<F: Fn(&Method, &Path) + Send + Sync + 'static>(f: F) -> Self {
+        OnMatch(Some(Arc::new(f)))
+    }
+
+    fn call(&self, method: &Method, path: &Path) {
+        if let Some(f) = &self.0 {
+            f(method, path)
+        }
+    }
+}
+
+impl fmt::Debug for OnMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OnMatch")
+    }
+}
+
 #[derive(Debug)]
 pub struct Filter {
-    path_filter: Option<Pattern>,
+    path_filter: Option<PathPattern>,
+    path_regex: Option<PathPattern>,
+    exclude: Vec<PathPattern>,
     methods: Method,
     probability: f64,
+    io_classes: Option<Vec<IoClass>>,
+    max_io_level: Option<u8>,
+    magic: Option<Vec<Vec<u8>>>,
+    ranges: Vec<RangeConfig>,
+
+    pids: Option<Vec<u32>>,
+    uids: Option<Vec<u32>>,
+    gids: Option<Vec<u32>>,
+    comm: Option<Regex>,
+    cgroup: Option<PathBuf>,
+    open_flags: Option<Vec<(i32, i32)>>,
+
+    schedule: Option<ScheduleConfig>,
+    started: StartTime,
+
+    trigger: Option<TriggerConfig>,
+    // the operations matched so far, except for the trigger and the
+    // probability
+    matched: AtomicU64,
+    on_match: OnMatch,
 }
 
 impl Filter {
     pub fn build(conf: FilterConfig) -> Result<Self> {
         info!("build filter");
+        // a misspelled method is rejected, rather than silently matching
+        // nothing
         let methods = conf
             .methods
             .filter(|methods| !methods.is_empty())
             .map(|methods| {
                 methods
                     .iter()
-                    .filter_map(|method| Method::try_from(method.as_str()).ok())
+                    .map(|method| Method::try_from(method.as_str()))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?
+            .map(|methods| {
+                methods
+                    .into_iter()
                     .fold(Method::empty(), |methods, method| methods | method)
             })
             .unwrap_or(Method::all());
 
-        let path_filter = conf
-            .path.and_then(|path| -> Option<Pattern> {
-                if !path.is_empty() {
-                    Pattern::new(&path).ok()
-                } else {
-                    None
-                }
-            });
+        let path_filter = conf.path.and_then(|path| -> Option<PathPattern> {
+            if !path.is_empty() {
+                PathPattern::glob(&path, true).ok()
+            } else {
+                None
+            }
+        });
+        let path_regex = conf
+            .path_regex
+            .map(|regex| PathPattern::regex(&regex))
+            .transpose()?;
+        let exclude = conf
+            .exclude
+            .unwrap_or_default()
+            .iter()
+            .map(|exclude| PathPattern::glob(exclude, true))
+            .collect::<Result<Vec<_>>>()?;
+        let io_classes = conf
+            .io_classes
+            .map(|classes| {
+                classes
+                    .iter()
+                    .map(|class| IoClass::try_from(class.as_str()))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+
+        let comm = conf
+            .comm
+            .map(|comm| Regex::new(&format!("^(?:{})$", comm)))
+            .transpose()?;
+
+        let open_flags = conf
+            .open_flags
+            .map(|flags| {
+                flags
+                    .iter()
+                    .map(|flag| parse_open_flag(flag))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+
+        let magic = conf
+            .magic
+            .map(|magic| {
+                magic
+                    .iter()
+                    .map(|magic| parse_magic(magic))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?;
+
         Ok(Self {
             path_filter,
+            path_regex,
+            exclude,
             methods,
             probability: conf.percent as f64 / 100f64,
+            io_classes,
+            max_io_level: conf.max_io_level,
+            magic,
+            ranges: conf.ranges.unwrap_or_default(),
+            pids: conf.pids,
+            uids: conf.uids,
+            gids: conf.gids,
+            comm,
+            cgroup: conf.cgroup.map(PathBuf::from),
+            open_flags,
+            schedule: conf.schedule,
+            started: conf.started,
+            trigger: conf.trigger,
+            matched: AtomicU64::new(0),
+            on_match: conf.on_match,
         })
     }
 
-    pub fn filter(&self, method: &Method, path: &Path) -> bool {
-        let mut rng = rand::thread_rng();
-        let p: f64 = rng.gen();
-
-        let match_path = match &self.path_filter {
-            Some(filter) => filter.matches_path_with(
-                path,
-                MatchOptions {
-                    case_sensitive: true,
-                    require_literal_separator: true,
-                    require_literal_leading_dot: false,
-                },
-            ),
+    // match_magic only matches the opened files whose leading bytes are one
+    // of the magic
+    fn match_magic(&self, path: &Path) -> bool {
+        let magic = match &self.magic {
+            Some(magic) => magic,
+            None => return true,
+        };
+
+        match magic::header(path) {
+            Some(header) => magic.iter().any(|magic| header.starts_with(magic)),
+            None => false,
+        }
+    }
+
+    fn match_caller(&self) -> bool {
+        if self.pids.is_none()
+            && self.uids.is_none()
+            && self.gids.is_none()
+            && self.comm.is_none()
+            && self.cgroup.is_none()
+        {
+            return true;
+        }
+
+        let caller = match caller::current().filter(|caller| caller.pid != 0) {
+            Some(caller) => caller,
+            None => return false,
+        };
+        let contains =
+            |ids: &Option<Vec<u32>>, id: u32| ids.as_ref().map_or(true, |ids| ids.contains(&id));
+        if !contains(&self.uids, caller.uid) || !contains(&self.gids, caller.gid) {
+            return false;
+        }
+        if self.pids.is_none() && self.comm.is_none() && self.cgroup.is_none() {
+            return true;
+        }
+
+        // the pid of a request is the id of the calling thread
+        let process = process(caller.pid);
+        if !contains(&self.pids, caller.pid)
+            && !process
+                .tgid
+                .map_or(false, |tgid| contains(&self.pids, tgid))
+        {
+            return false;
+        }
+        if let Some(comm) = &self.comm {
+            match &process.comm {
+                Some(name) if comm.is_match(name) => {}
+                _ => return false,
+            }
+        }
+        if let Some(cgroup) = &self.cgroup {
+            match &process.cgroup {
+                Some(path) if path.starts_with(cgroup) => {}
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    fn match_open_flags(&self) -> bool {
+        let open_flags = match &self.open_flags {
+            Some(open_flags) => open_flags,
+            None => return true,
+        };
+
+        match caller::flags() {
+            Some(flags) => open_flags
+                .iter()
+                .any(|(mask, value)| flags & mask == *value),
+            None => false,
+        }
+    }
+
+    // match_schedule decides whether the rule is in an active window of its
+    // schedule, which is counted from the start of its experiment
+    fn match_schedule(&self) -> bool {
+        match &self.schedule {
+            Some(schedule) => schedule.active(self.started.elapsed()),
             None => true,
+        }
+    }
+
+    // match_trigger counts the matched operation and decides whether it's
+    // one of the triggered ones. An operation is counted once, although an
+    // injector may filter it again to inject into its io or its reply.
+    fn match_trigger(&self) -> bool {
+        let trigger = match &self.trigger {
+            Some(trigger) => trigger,
+            None => return true,
         };
+
+        caller::once(self as *const Filter as usize, || {
+            self.count_trigger(trigger)
+        })
+    }
+
+    fn count_trigger(&self, trigger: &TriggerConfig) -> bool {
+        let count = self.matched.fetch_add(1, Ordering::SeqCst) + 1;
+        let count = match count.checked_sub(trigger.after.unwrap_or(0)) {
+            Some(count) if count > 0 => count,
+            _ => return false,
+        };
+        let count = match trigger.every {
+            Some(every) if count % every != 0 => return false,
+            Some(every) => count / every,
+            None => count,
+        };
+        trigger.first.map_or(true, |first| count <= first)
+    }
+
+    fn match_io_priority(&self) -> bool {
+        if self.io_classes.is_none() && self.max_io_level.is_none() {
+            return true;
+        }
+
+        let (class, level) = match caller::current().and_then(|caller| io_priority(caller.pid)) {
+            Some(priority) => priority,
+            None => return false,
+        };
+        trace!("io priority: {:?} {}", class, level);
+
+        let match_class = match &self.io_classes {
+            Some(classes) => classes.contains(&class),
+            None => true,
+        };
+        let match_level = match self.max_io_level {
+            Some(max_level) => level <= max_level,
+            None => true,
+        };
+
+        match_class && match_level
+    }
+
+    // match_path matches the path alone, regardless of the method and the
+    // probability
+    pub fn match_path(&self, path: &Path) -> bool {
+        let match_glob = match &self.path_filter {
+            Some(filter) => filter.matches(path),
+            None => true,
+        };
+        let match_regex = match &self.path_regex {
+            Some(regex) => regex.matches(path),
+            None => true,
+        };
+        let excluded = self.exclude.iter().any(|exclude| exclude.matches(path));
+        match_glob && match_regex && !excluded
+    }
+
+    // filter matches the operations of the rules which are not scoped to byte
+    // ranges. Only the fault and mistake rules can be scoped, which is checked
+    // when they are built.
+    pub fn filter(&self, method: &Method, path: &Path) -> bool {
+        self.ranges.is_empty() && self.matches(method, path)
+    }
+
+    // filter_range matches the reads and writes of the rules scoped to byte
+    // ranges, and returns the part of the io (relative to its offset) within
+    // the first range it overlaps
+    pub fn filter_range(
+        &self,
+        method: &Method,
+        path: &Path,
+        io: &IoContext,
+    ) -> Option<Range<usize>> {
+        let start = io.offset.max(0) as u64;
+        let end = start.saturating_add(io.size);
+        let size = if self.ranges.iter().any(|range| range.start < 0) {
+            fstat(io.fd).ok()?.st_size as u64
+        } else {
+            0
+        };
+        let range = self
+            .ranges
+            .iter()
+            .map(|range| {
+                let from = if range.start < 0 {
+                    size.saturating_sub(range.start.unsigned_abs())
+                } else {
+                    range.start as u64
+                };
+                from..from.saturating_add(range.length)
+            })
+            .find(|range| range.start < end && start < range.end)?;
+        if !self.matches(method, path) {
+            return None;
+        }
+
+        trace!("io {}..{} overlaps range {:?}", start, end, range);
+        Some((range.start.max(start) - start) as usize..(range.end.min(end) - start) as usize)
+    }
+
+    // match_probability draws whether the operation is injected. The shared
+    // generator is only drawn from for the operations matching everything
+    // else, and only if the probability leaves a choice, so that a seeded run
+    // doesn't depend on the unrelated operations.
+    fn match_probability(&self) -> bool {
+        if self.probability >= 1.0 {
+            return true;
+        }
+        if self.probability <= 0.0 {
+            return false;
+        }
+        let p: f64 = rng::with(|rng| rng.gen());
+        p < self.probability
+    }
+
+    fn matches(&self, method: &Method, path: &Path) -> bool {
+        let match_path = self.match_path(path);
         let match_method = !(self.methods & *method).is_empty();
-        let match_probability = p < self.probability;
         trace!("path filter: {}", match_path);
         trace!("method filter: {}", match_method);
-        trace!("probability: {}", match_probability);
 
-        match_path && match_method && match_probability
+        let matched = match_path
+            && match_method
+            && self.match_magic(path)
+            && self.match_caller()
+            && self.match_open_flags()
+            && self.match_io_priority()
+            && self.match_schedule()
+            && self.match_trigger()
+            && self.match_probability();
+        trace!("matched: {}", matched);
+        if matched {
+            self.on_match.call(method, path);
+        }
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hookfs::caller::Caller;
+
+    fn filter(config: &str) -> Filter {
+        Filter::build(serde_json::from_str(config).unwrap()).unwrap()
+    }
+
+    // match_caller matches the filter as the current thread
+    fn match_caller(filter: &Filter) -> bool {
+        let caller = Caller {
+            pid: nix::unistd::gettid().as_raw() as u32,
+            uid: nix::unistd::getuid().as_raw(),
+            gid: nix::unistd::getgid().as_raw(),
+        };
+        futures::executor::block_on(caller::scope(caller, false, async {
+            filter.match_caller()
+        }))
+    }
+
+    #[test]
+    fn test_match_caller() {
+        let comm = std::fs::read_to_string("/proc/thread-self/comm").unwrap();
+        let comm = regex::escape(comm.trim_end());
+        let pid = std::process::id();
+
+        assert!(match_caller(&filter(&format!(
+            r#"{{"percent":100,"pids":[{}]}}"#,
+            pid
+        ))));
+        assert!(!match_caller(&filter(r#"{"percent":100,"pids":[1]}"#)));
+        assert!(match_caller(&filter(&format!(
+            r#"{{"percent":100,"pids":[{}],"comm":"{}"}}"#,
+            pid, comm
+        ))));
+        assert!(!match_caller(&filter(
+            r#"{"percent":100,"comm":"no-such-command"}"#
+        )));
+
+        // the kernel itself never matches
+        let filter = filter(&format!(r#"{{"percent":100,"pids":[{}]}}"#, pid));
+        let kernel = Caller {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        assert!(!futures::executor::block_on(caller::scope(
+            kernel,
+            false,
+            async { filter.match_caller() }
+        )));
+    }
+
+    #[test]
+    fn test_trigger_once_per_operation() {
+        let filter = filter(r#"{"percent":100,"trigger":{"every":2}}"#);
+        let caller = Caller {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let operation = || {
+            futures::executor::block_on(caller::scope(caller, false, async {
+                (0..3).map(|_| filter.match_trigger()).collect::<Vec<_>>()
+            }))
+        };
+        assert_eq!(operation(), vec![false; 3]);
+        assert_eq!(operation(), vec![true; 3]);
+        assert_eq!(operation(), vec![false; 3]);
+    }
+
+    #[test]
+    fn test_process_cached() {
+        let tid = nix::unistd::gettid().as_raw() as u32;
+        let first = process(tid);
+        assert_eq!(first.tgid, Some(std::process::id()));
+        assert!(Arc::ptr_eq(&first, &process(tid)));
+
+        PROCESSES.lock().unwrap().get_mut(&tid).unwrap().0 -= PROCESS_TTL;
+        assert!(!Arc::ptr_eq(&first, &process(tid)));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let secs = Duration::from_secs;
+        let schedule: ScheduleConfig =
+            serde_json::from_str(r#"{"delay":"10s","duration":"2s","period":"5s"}"#).unwrap();
+        assert!(!schedule.active(secs(0)));
+        assert!(!schedule.active(secs(9)));
+        assert!(schedule.active(secs(10)));
+        assert!(schedule.active(secs(11)));
+        assert!(!schedule.active(secs(12)));
+        assert!(schedule.active(secs(15)));
+
+        // the schedule is counted from the start of the experiment
+        let filter = filter(r#"{"percent":100,"schedule":{"duration":"1s"}}"#);
+        *filter.started.0.lock().unwrap() -= secs(2);
+        assert!(!filter.match_schedule());
+        filter.started.start();
+        assert!(filter.match_schedule());
+
+        // a window longer than its period would never end
+        let conf: crate::injector::InjectorConfig = serde_json::from_str(
+            r#"{"type":"fault","percent":100,"faults":[{"errno":5,"weight":1}],
+                "schedule":{"duration":"10s","period":"5s"}}"#,
+        )
+        .unwrap();
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn test_draw_only_when_undecided() {
+        // the filters are matched while the generator is locked, so that they
+        // would block if they drew from it
+        let matched = rng::with(|_| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                let unrelated = filter(r#"{"path":"/other","percent":50}"#);
+                let certain = filter(r#"{"percent":100}"#);
+                let never = filter(r#"{"percent":0}"#);
+                let path = Path::new("/file");
+                let _ = sender.send((
+                    unrelated.filter(&Method::READ, path),
+                    certain.filter(&Method::READ, path),
+                    never.filter(&Method::READ, path),
+                ));
+            });
+            receiver.recv_timeout(Duration::from_secs(5))
+        });
+        assert_eq!(matched, Ok((false, true, false)));
     }
 }
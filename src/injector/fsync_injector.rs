@@ -0,0 +1,65 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use nix::errno::Errno;
+use tracing::{debug, trace};
+
+use super::injector_config::{FsyncConfig, FsyncMode};
+use super::{filter, Injector};
+use crate::hookfs::{Error, Result};
+
+// FsyncInjector makes the writes look durable while they are not. The writes
+// succeed, but fsync (and flush, if configured) fails with EIO every time or
+// only once, or succeeds after silently dropping the writes since the last
+// sync, like a page cache losing its dirty pages.
+#[derive(Debug)]
+pub struct FsyncInjector {
+    filter: filter::Filter,
+    mode: FsyncMode,
+    methods: filter::Method,
+
+    failed: AtomicBool,
+}
+
+#[async_trait]
+impl Injector for FsyncInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        let method = *method & self.methods;
+        if method.is_empty() || self.mode == FsyncMode::Drop || !self.filter.filter(&method, path) {
+            return Ok(());
+        }
+        if self.mode == FsyncMode::Once && self.failed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        debug!("inject EIO on {:?}", method);
+        Err(Error::Sys(Errno::EIO))
+    }
+
+    fn journal_writes(&self, path: &Path) -> bool {
+        self.mode == FsyncMode::Drop && self.filter.match_path(path)
+    }
+
+    fn drop_unsynced(&self, method: &filter::Method, path: &Path) -> bool {
+        let method = *method & self.methods;
+        self.mode == FsyncMode::Drop && !method.is_empty() && self.filter.filter(&method, path)
+    }
+}
+
+impl FsyncInjector {
+    pub fn build(conf: FsyncConfig) -> anyhow::Result<Self> {
+        trace!("build fsync injector");
+
+        let mut methods = filter::Method::FSYNC;
+        if conf.flush {
+            methods |= filter::Method::FLUSH;
+        }
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            mode: conf.mode,
+            methods,
+            failed: AtomicBool::new(false),
+        })
+    }
+}
@@ -0,0 +1,74 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use rand::Rng;
+use tracing::{debug, trace};
+
+use super::injector_config::{GarbageConfig, GarbageFill};
+use super::{filter, rng, Injector, IoContext};
+use crate::hookfs::Result;
+
+// GarbageInjector replaces the data read from the matching files, like a
+// device returning garbage sectors without reporting an error. The length of
+// the reads is kept, and the backing files are untouched.
+#[derive(Debug)]
+pub struct GarbageInjector {
+    filter: filter::Filter,
+    fill: GarbageFill,
+    pattern: Vec<u8>,
+}
+
+#[async_trait]
+impl Injector for GarbageInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_read_data(&self, path: &Path, io: &IoContext, data: &mut Vec<u8>) -> Result<()> {
+        if data.is_empty() || !self.filter.filter(&filter::Method::READ, path) {
+            return Ok(());
+        }
+
+        debug!(
+            "fill {} bytes at {} of {} with {:?}",
+            data.len(),
+            io.offset,
+            path.display(),
+            self.fill
+        );
+        match self.fill {
+            GarbageFill::Zero => data.iter_mut().for_each(|byte| *byte = 0),
+            // the pattern is aligned on the offset in the file, so that the
+            // same bytes are read back wherever a read starts
+            GarbageFill::Pattern => {
+                let offset = io.offset.max(0) as usize;
+                for (i, byte) in data.iter_mut().enumerate() {
+                    *byte = self.pattern[(offset + i) % self.pattern.len()];
+                }
+            }
+            GarbageFill::Random => rng::with(|rng| rng.fill(&mut data[..])),
+        }
+        Ok(())
+    }
+}
+
+impl GarbageInjector {
+    pub fn build(conf: GarbageConfig) -> anyhow::Result<Self> {
+        trace!("build garbage injector");
+
+        let pattern = match &conf.pattern {
+            Some(pattern) => base64::decode(pattern)?,
+            None => Vec::new(),
+        };
+        if conf.fill == GarbageFill::Pattern && pattern.is_empty() {
+            return Err(anyhow!("pattern fill requires a non-empty pattern"));
+        }
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            fill: conf.fill,
+            pattern,
+        })
+    }
+}
@@ -0,0 +1,120 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::select;
+use tokio::time::delay_for;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace};
+
+use super::injector_config::HangConfig;
+use super::{drain, filter, Injector};
+use crate::hookfs::Result;
+
+// HangInjector models a wedged disk. Matching operations hang until the disk
+// is resumed with `resume`, or at most for `max`, and then go on as usual. The
+// operations after a resume hang again, until the next one.
+#[derive(Debug)]
+pub struct HangInjector {
+    filter: filter::Filter,
+    max: Option<Duration>,
+
+    // cancelled by `resume`, and replaced for the operations after it
+    resume_token: Mutex<CancellationToken>,
+    cancel_token: CancellationToken,
+}
+
+#[async_trait]
+impl Injector for HangInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if !self.filter.filter(method, path) {
+            return Ok(());
+        }
+
+        debug!("hang operation for at most {:?}", self.max);
+        let started = Instant::now();
+        let resumed = self.resume_token.lock().unwrap().clone();
+        let token = self.cancel_token.clone();
+        let cancelled = match self.max {
+            Some(max) => select! {
+                _ = delay_for(max) => false,
+                _ = resumed.cancelled() => false,
+                _ = token.cancelled() => true,
+            },
+            None => select! {
+                _ = resumed.cancelled() => false,
+                _ = token.cancelled() => true,
+            },
+        };
+        if !cancelled {
+            return Ok(());
+        }
+
+        debug!("cancelled");
+        let remaining = self
+            .max
+            .map(|max| max.checked_sub(started.elapsed()).unwrap_or_default());
+        drain::drain(remaining).await
+    }
+
+    fn interrupt(&self) {
+        debug!("interrupt hang");
+        self.cancel_token.cancel();
+    }
+
+    fn resume(&self) {
+        info!("resume the hung operations");
+        let resumed = std::mem::replace(
+            &mut *self.resume_token.lock().unwrap(),
+            CancellationToken::new(),
+        );
+        resumed.cancel();
+    }
+}
+
+impl HangInjector {
+    pub fn build(conf: HangConfig) -> anyhow::Result<Self> {
+        trace!("build hang injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            max: conf.max,
+            resume_token: Mutex::new(CancellationToken::new()),
+            cancel_token: CancellationToken::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    fn hang(injector: &HangInjector) -> impl std::future::Future<Output = Result<()>> + '_ {
+        injector.inject(&filter::Method::OPEN, Path::new("/file"))
+    }
+
+    #[tokio::test]
+    async fn test_resume_hung_operations() {
+        let conf = serde_json::from_str(r#"{"percent":100}"#).unwrap();
+        let injector = Arc::new(HangInjector::build(conf).unwrap());
+
+        for _ in 0..2 {
+            let hung = {
+                let injector = injector.clone();
+                tokio::spawn(async move { hang(&injector).await })
+            };
+            delay_for(Duration::from_millis(100)).await;
+            injector.resume();
+            hung.await.unwrap().unwrap();
+
+            // the operations after the resume hang again
+            let hung = timeout(Duration::from_millis(100), hang(&injector)).await;
+            assert!(hung.is_err());
+        }
+    }
+}
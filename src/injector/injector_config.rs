@@ -1,7 +1,11 @@
+use std::path::PathBuf;
 use std::time::Duration;
 
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 
+use super::filter::{OnMatch, StartTime};
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "camelCase")]
@@ -10,6 +14,296 @@ pub enum InjectorConfig {
     Fault(FaultsConfig),
     AttrOverride(AttrOverrideConfig),
     Mistake(MistakesConfig),
+    Overflow(OverflowConfig),
+    SeekLatency(SeekLatencyConfig),
+    SsdWear(SsdWearConfig),
+    Zoned(ZonedConfig),
+    Fat(FatConfig),
+    Partition(PartitionConfig),
+    Concurrency(ConcurrencyConfig),
+    ContentOverride(ContentOverrideConfig),
+    Quota(QuotaConfig),
+    InodeExhaustion(InodeExhaustionConfig),
+    Toctou(ToctouConfig),
+    Throttle(ThrottleConfig),
+    ShortIo(ShortIoConfig),
+    DiskFull(DiskFullConfig),
+    Fsync(FsyncConfig),
+    Hang(HangConfig),
+    ReadOnly(ReadOnlyConfig),
+    Listing(ListingConfig),
+    Nfs(NfsConfig),
+    Statfs(StatfsConfig),
+    Garbage(GarbageConfig),
+    TornWrite(TornWriteConfig),
+}
+
+impl InjectorConfig {
+    // kind returns the type of the rule, as it's configured
+    pub fn kind(&self) -> &'static str {
+        match self {
+            InjectorConfig::Latency(_) => "latency",
+            InjectorConfig::Fault(_) => "fault",
+            InjectorConfig::AttrOverride(_) => "attrOverride",
+            InjectorConfig::Mistake(_) => "mistake",
+            InjectorConfig::Overflow(_) => "overflow",
+            InjectorConfig::SeekLatency(_) => "seekLatency",
+            InjectorConfig::SsdWear(_) => "ssdWear",
+            InjectorConfig::Zoned(_) => "zoned",
+            InjectorConfig::Fat(_) => "fat",
+            InjectorConfig::Partition(_) => "partition",
+            InjectorConfig::Concurrency(_) => "concurrency",
+            InjectorConfig::ContentOverride(_) => "contentOverride",
+            InjectorConfig::Quota(_) => "quota",
+            InjectorConfig::InodeExhaustion(_) => "inodeExhaustion",
+            InjectorConfig::Toctou(_) => "toctou",
+            InjectorConfig::Throttle(_) => "throttle",
+            InjectorConfig::ShortIo(_) => "shortIo",
+            InjectorConfig::DiskFull(_) => "diskFull",
+            InjectorConfig::Fsync(_) => "fsync",
+            InjectorConfig::Hang(_) => "hang",
+            InjectorConfig::ReadOnly(_) => "readOnly",
+            InjectorConfig::Listing(_) => "listing",
+            InjectorConfig::Nfs(_) => "nfs",
+            InjectorConfig::Statfs(_) => "statfs",
+            InjectorConfig::Garbage(_) => "garbage",
+            InjectorConfig::TornWrite(_) => "tornWrite",
+        }
+    }
+
+    // filter returns the filter of the rule, if it has one
+    fn filter(&self) -> Option<&FilterConfig> {
+        match self {
+            InjectorConfig::Latency(conf) => Some(&conf.filter),
+            InjectorConfig::Fault(conf) => Some(&conf.filter),
+            InjectorConfig::Mistake(conf) => Some(&conf.filter),
+            InjectorConfig::Overflow(conf) => Some(&conf.filter),
+            InjectorConfig::SeekLatency(conf) => Some(&conf.filter),
+            InjectorConfig::SsdWear(conf) => Some(&conf.filter),
+            InjectorConfig::Zoned(conf) => Some(&conf.filter),
+            InjectorConfig::Fat(conf) => Some(&conf.filter),
+            InjectorConfig::Partition(conf) => Some(&conf.filter),
+            InjectorConfig::Concurrency(conf) => Some(&conf.filter),
+            InjectorConfig::ContentOverride(conf) => Some(&conf.filter),
+            InjectorConfig::InodeExhaustion(conf) => Some(&conf.filter),
+            InjectorConfig::Toctou(conf) => Some(&conf.filter),
+            InjectorConfig::Throttle(conf) => Some(&conf.filter),
+            InjectorConfig::ShortIo(conf) => Some(&conf.filter),
+            InjectorConfig::DiskFull(conf) => Some(&conf.filter),
+            InjectorConfig::Fsync(conf) => Some(&conf.filter),
+            InjectorConfig::Hang(conf) => Some(&conf.filter),
+            InjectorConfig::ReadOnly(conf) => Some(&conf.filter),
+            InjectorConfig::Listing(conf) => Some(&conf.filter),
+            InjectorConfig::Nfs(conf) => Some(&conf.filter),
+            InjectorConfig::Statfs(conf) => Some(&conf.filter),
+            InjectorConfig::Garbage(conf) => Some(&conf.filter),
+            InjectorConfig::TornWrite(conf) => Some(&conf.filter),
+            InjectorConfig::AttrOverride(_) | InjectorConfig::Quota(_) => None,
+        }
+    }
+
+    // filter_mut returns the filter of the rule, if it has one
+    fn filter_mut(&mut self) -> Option<&mut FilterConfig> {
+        match self {
+            InjectorConfig::Latency(conf) => Some(&mut conf.filter),
+            InjectorConfig::Fault(conf) => Some(&mut conf.filter),
+            InjectorConfig::Mistake(conf) => Some(&mut conf.filter),
+            InjectorConfig::Overflow(conf) => Some(&mut conf.filter),
+            InjectorConfig::SeekLatency(conf) => Some(&mut conf.filter),
+            InjectorConfig::SsdWear(conf) => Some(&mut conf.filter),
+            InjectorConfig::Zoned(conf) => Some(&mut conf.filter),
+            InjectorConfig::Fat(conf) => Some(&mut conf.filter),
+            InjectorConfig::Partition(conf) => Some(&mut conf.filter),
+            InjectorConfig::Concurrency(conf) => Some(&mut conf.filter),
+            InjectorConfig::ContentOverride(conf) => Some(&mut conf.filter),
+            InjectorConfig::InodeExhaustion(conf) => Some(&mut conf.filter),
+            InjectorConfig::Toctou(conf) => Some(&mut conf.filter),
+            InjectorConfig::Throttle(conf) => Some(&mut conf.filter),
+            InjectorConfig::ShortIo(conf) => Some(&mut conf.filter),
+            InjectorConfig::DiskFull(conf) => Some(&mut conf.filter),
+            InjectorConfig::Fsync(conf) => Some(&mut conf.filter),
+            InjectorConfig::Hang(conf) => Some(&mut conf.filter),
+            InjectorConfig::ReadOnly(conf) => Some(&mut conf.filter),
+            InjectorConfig::Listing(conf) => Some(&mut conf.filter),
+            InjectorConfig::Nfs(conf) => Some(&mut conf.filter),
+            InjectorConfig::Statfs(conf) => Some(&mut conf.filter),
+            InjectorConfig::Garbage(conf) => Some(&mut conf.filter),
+            InjectorConfig::TornWrite(conf) => Some(&mut conf.filter),
+            InjectorConfig::AttrOverride(_) | InjectorConfig::Quota(_) => None,
+        }
+    }
+
+    // start_at sets when the schedule of the rule starts
+    pub fn start_at(&mut self, started: StartTime) {
+        if let Some(filter) = self.filter_mut() {
+            filter.started = started;
+        }
+    }
+
+    // on_match sets the callback of the operations the rule fires on
+    pub fn on_match(&mut self, on_match: OnMatch) {
+        match self {
+            InjectorConfig::AttrOverride(conf) => conf.on_match = on_match,
+            // a quota only fires by failing the operation
+            InjectorConfig::Quota(_) => {}
+            _ => {
+                if let Some(filter) = self.filter_mut() {
+                    filter.on_match = on_match;
+                }
+            }
+        }
+    }
+
+    // ttl returns how long the rule runs, if it's limited
+    pub fn ttl(&self) -> Option<Duration> {
+        self.filter()?.ttl
+    }
+
+    pub fn schedule(&self) -> Option<&ScheduleConfig> {
+        self.filter()?.schedule.as_ref()
+    }
+
+    // matches_magic returns whether the rule matches the leading bytes of the
+    // files
+    pub fn matches_magic(&self) -> bool {
+        self.filter().map_or(false, |filter| filter.magic.is_some())
+    }
+
+    // validate checks the constraints between the fields, which cannot be
+    // expressed by the types
+    pub fn validate(&self) -> Result<()> {
+        let percent = match self {
+            InjectorConfig::AttrOverride(conf) => Some(conf.percent),
+            _ => self.filter().map(|filter| filter.percent),
+        };
+        if let Some(percent) = percent {
+            if !(0..=100).contains(&percent) {
+                return Err(anyhow!("percent {} is out of 0..=100", percent));
+            }
+        }
+
+        let trigger = self.filter().and_then(|filter| filter.trigger.as_ref());
+        if let Some(TriggerConfig { every: Some(0), .. }) = trigger {
+            return Err(anyhow!("trigger requires a positive every"));
+        }
+
+        if let Some(schedule) = self.filter().and_then(|filter| filter.schedule.as_ref()) {
+            match (schedule.duration, schedule.period) {
+                (_, Some(period)) if period == Duration::from_secs(0) => {
+                    return Err(anyhow!("schedule requires a positive period"));
+                }
+                (None, Some(_)) => {
+                    return Err(anyhow!("schedule requires a duration to repeat"));
+                }
+                (Some(duration), Some(period)) if duration > period => {
+                    return Err(anyhow!(
+                        "schedule duration {:?} exceeds its period {:?}",
+                        duration,
+                        period
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(ranges) = self.filter().and_then(|filter| filter.ranges.as_ref()) {
+            if !matches!(self, InjectorConfig::Fault(_) | InjectorConfig::Mistake(_)) {
+                return Err(anyhow!(
+                    "ranges are only supported by fault and mistake rules"
+                ));
+            }
+            if ranges.iter().any(|range| range.length == 0) {
+                return Err(anyhow!("ranges require a positive length"));
+            }
+        }
+
+        match self {
+            InjectorConfig::Latency(conf)
+                if conf.latency == Duration::from_secs(0)
+                    && conf.per_byte.is_none()
+                    && conf.per_method.is_empty()
+                    && conf.jitter.is_none() =>
+            {
+                Err(anyhow!("latency requires a non-zero duration"))
+            }
+            InjectorConfig::Latency(LatencyConfig {
+                jitter: Some(jitter),
+                ..
+            }) => jitter.validate(),
+            InjectorConfig::Fault(conf) if conf.faults.iter().all(|fault| fault.weight == 0) => {
+                Err(anyhow!("fault requires an errno with a positive weight"))
+            }
+            InjectorConfig::Mistake(conf)
+                if conf.mistake.max_length == 0 || conf.mistake.max_occurrences == 0 =>
+            {
+                Err(anyhow!(
+                    "mistake requires a positive maxLength and maxOccurrences"
+                ))
+            }
+            InjectorConfig::Throttle(conf)
+                if conf.read_bytes_per_second.is_none()
+                    && conf.write_bytes_per_second.is_none() =>
+            {
+                Err(anyhow!(
+                    "throttle requires readBytesPerSecond or writeBytesPerSecond"
+                ))
+            }
+            InjectorConfig::Throttle(conf)
+                if conf.read_bytes_per_second == Some(0)
+                    || conf.write_bytes_per_second == Some(0)
+                    || conf.burst == Some(0) =>
+            {
+                Err(anyhow!("throttle requires a positive rate and burst"))
+            }
+            InjectorConfig::Statfs(conf)
+                if conf.free_blocks.is_none() && conf.free_inodes.is_none() =>
+            {
+                Err(anyhow!("statfs requires freeBlocks or freeInodes"))
+            }
+            InjectorConfig::Garbage(conf)
+                if conf.fill == GarbageFill::Pattern && conf.pattern.is_none() =>
+            {
+                Err(anyhow!("garbage requires a pattern to fill with"))
+            }
+            InjectorConfig::TornWrite(TornWriteConfig {
+                page_size: Some(0), ..
+            }) => Err(anyhow!("tornWrite requires a positive pageSize")),
+            InjectorConfig::Zoned(conf) if conf.zones.is_empty() => {
+                Err(anyhow!("zoned requires a zone"))
+            }
+            InjectorConfig::Quota(conf) if conf.quotas.is_empty() => {
+                Err(anyhow!("quota requires a subtree quota"))
+            }
+            // the other constraints of these are checked by their injectors,
+            // which are built right after
+            InjectorConfig::Latency(_)
+            | InjectorConfig::Fault(_)
+            | InjectorConfig::AttrOverride(_)
+            | InjectorConfig::Mistake(_)
+            | InjectorConfig::Overflow(_)
+            | InjectorConfig::SeekLatency(_)
+            | InjectorConfig::SsdWear(_)
+            | InjectorConfig::Zoned(_)
+            | InjectorConfig::Fat(_)
+            | InjectorConfig::Partition(_)
+            | InjectorConfig::Concurrency(_)
+            | InjectorConfig::ContentOverride(_)
+            | InjectorConfig::Quota(_)
+            | InjectorConfig::InodeExhaustion(_)
+            | InjectorConfig::Toctou(_)
+            | InjectorConfig::Throttle(_)
+            | InjectorConfig::ShortIo(_)
+            | InjectorConfig::DiskFull(_)
+            | InjectorConfig::Fsync(_)
+            | InjectorConfig::Hang(_)
+            | InjectorConfig::ReadOnly(_)
+            | InjectorConfig::Listing(_)
+            | InjectorConfig::Nfs(_)
+            | InjectorConfig::Statfs(_)
+            | InjectorConfig::Garbage(_)
+            | InjectorConfig::TornWrite(_) => Ok(()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -19,6 +313,88 @@ pub struct LatencyConfig {
     pub filter: FilterConfig,
     #[serde(with = "humantime_serde")]
     pub latency: Duration,
+    // an extra delay for every byte read or written
+    #[serde(default, with = "humantime_serde")]
+    pub per_byte: Option<Duration>,
+    // exempts the hot paths and caps the delay, if set
+    #[serde(default)]
+    pub adaptive: Option<AdaptiveConfig>,
+    // the delays of the listed methods, instead of `latency`
+    #[serde(default)]
+    pub per_method: Vec<MethodLatencyConfig>,
+    // draws the delays from a distribution instead of `latency`, if set
+    #[serde(default)]
+    pub jitter: Option<JitterConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct JitterConfig {
+    #[serde(flatten)]
+    pub distribution: Distribution,
+    // the weight of the previous delay in the next one, within 0..1
+    #[serde(default)]
+    pub correlation: f64,
+}
+
+impl JitterConfig {
+    fn validate(&self) -> Result<()> {
+        if !(0.0..1.0).contains(&self.correlation) {
+            return Err(anyhow!("correlation {} is out of 0..1", self.correlation));
+        }
+        match &self.distribution {
+            Distribution::Uniform { min, max } if min > max => {
+                Err(anyhow!("uniform distribution requires min <= max"))
+            }
+            Distribution::Pareto { shape, .. } if *shape <= 0.0 => {
+                Err(anyhow!("pareto distribution requires a positive shape"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "distribution")]
+#[serde(rename_all = "camelCase")]
+pub enum Distribution {
+    Uniform {
+        #[serde(with = "humantime_serde")]
+        min: Duration,
+        #[serde(with = "humantime_serde")]
+        max: Duration,
+    },
+    Normal {
+        #[serde(with = "humantime_serde")]
+        mean: Duration,
+        #[serde(with = "humantime_serde")]
+        stddev: Duration,
+    },
+    // the delays are `scale` or longer, with a heavier tail for a smaller
+    // `shape`
+    Pareto {
+        #[serde(with = "humantime_serde")]
+        scale: Duration,
+        shape: f64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MethodLatencyConfig {
+    pub method: String,
+    #[serde(with = "humantime_serde")]
+    pub latency: Duration,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AdaptiveConfig {
+    // the paths operated more times per second are not delayed
+    pub max_rate: Option<u64>,
+    // the total delay injected per second
+    #[serde(default, with = "humantime_serde")]
+    pub max_delay: Option<Duration>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -30,12 +406,113 @@ pub struct FaultsConfig {
     pub faults: Vec<FaultConfig>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct FilterConfig {
     pub path: Option<String>,
+    // a regex which the whole path must match, besides `path`
+    pub path_regex: Option<String>,
+    // globs of the paths which never match
+    pub exclude: Option<Vec<String>>,
     pub methods: Option<Vec<String>>,
     pub percent: i32,
+    // io priority classes of the calling process: "realtime", "bestEffort" or "idle"
+    pub io_classes: Option<Vec<String>>,
+    // only matches callers whose io priority level (0 is the highest, 7 the
+    // lowest) is not lower than this one. Combined with the realtime class it
+    // delays the high priority IO, to provoke priority inversion.
+    pub max_io_level: Option<u8>,
+    // leading bytes of the opened file, either a known type ("sqlite", "gzip",
+    // "elf", "zip", "png", "pdf") or hex encoded bytes like "hex:1f8b"
+    // The bytes are sniffed when the file is opened, so the files which are
+    // not opened (and the open itself) never match.
+    pub magic: Option<Vec<String>>,
+    // the callers: thread or process ids, user and group ids, a regex which
+    // the whole command name (/proc/pid/comm) must match, and a cgroup v2 path
+    // (like /system.slice/app.service) of which the process or its descendant
+    // cgroups are members. The kernel itself never matches.
+    pub pids: Option<Vec<u32>>,
+    pub uids: Option<Vec<u32>>,
+    pub gids: Option<Vec<u32>>,
+    pub comm: Option<String>,
+    pub cgroup: Option<String>,
+    // the open flags of the file handle, any of "rdonly", "wronly", "rdwr",
+    // "append", "direct", "dsync", "sync", "nonblock" or "noatime". Only the
+    // opens and the operations on an opened file match.
+    pub open_flags: Option<Vec<String>>,
+    // byte ranges of the files. A fault or mistake rule with ranges only
+    // matches the reads and writes overlapping one of them, and a mistake only
+    // sabotages the bytes within the range. A negative start is relative to
+    // the end of the file, e.g. `{start: -4096, length: 4096}` is its last
+    // block.
+    pub ranges: Option<Vec<RangeConfig>>,
+    // injects only some of the matching operations, counted in order
+    pub trigger: Option<TriggerConfig>,
+    // the windows in which the rule is active
+    pub schedule: Option<ScheduleConfig>,
+    // the rule is removed once it has been running for this period
+    #[serde(default, with = "humantime_serde")]
+    pub ttl: Option<Duration>,
+
+    // called with the operations the rule fires on, set by its experiment
+    #[serde(skip)]
+    pub on_match: OnMatch,
+    // when the schedule starts, set by its experiment
+    #[serde(skip)]
+    pub started: StartTime,
+}
+
+// TriggerConfig picks the matching operations by their order. The first
+// `after` ones are skipped, then every `every`th one is picked, up to `first`
+// of them. For example, only the third one is picked with `after` 2 and
+// `first` 1.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TriggerConfig {
+    pub after: Option<u64>,
+    pub every: Option<u64>,
+    pub first: Option<u64>,
+}
+
+// ScheduleConfig activates the rule `delay` after its experiment starts, for
+// `duration` (or forever), and repeats the activation every `period`. For
+// example, 30s of faults every 5 minutes are a duration of 30s and a period of
+// 5m.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleConfig {
+    #[serde(default, with = "humantime_serde")]
+    pub delay: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub duration: Option<Duration>,
+    #[serde(default, with = "humantime_serde")]
+    pub period: Option<Duration>,
+}
+
+impl ScheduleConfig {
+    // active decides whether the schedule is in an active window `elapsed`
+    // after it starts
+    pub fn active(&self, elapsed: Duration) -> bool {
+        let elapsed = match elapsed.checked_sub(self.delay.unwrap_or_default()) {
+            Some(elapsed) => elapsed,
+            None => return false,
+        };
+        let elapsed = match self.period {
+            Some(period) => {
+                let period = period.as_nanos().max(1);
+                Duration::from_nanos((elapsed.as_nanos() % period) as u64)
+            }
+            None => elapsed,
+        };
+        self.duration.map_or(true, |duration| elapsed < duration)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RangeConfig {
+    pub start: i64,
+    pub length: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -50,6 +527,8 @@ pub struct FaultConfig {
 pub struct AttrOverrideConfig {
     pub path: String,
     pub percent: i32,
+    #[serde(skip)]
+    pub on_match: OnMatch,
 
     pub ino: Option<u64>,
     pub size: Option<u64>,
@@ -57,12 +536,20 @@ pub struct AttrOverrideConfig {
     pub atime: Option<std::time::SystemTime>,
     pub mtime: Option<std::time::SystemTime>,
     pub ctime: Option<std::time::SystemTime>,
+    // moves the times which are not overridden into the future
+    #[serde(default, with = "humantime_serde")]
+    pub time_offset: Option<Duration>,
     pub kind: Option<FileType>,
     pub perm: Option<u16>,
     pub nlink: Option<u32>,
     pub uid: Option<u32>,
     pub gid: Option<u32>,
     pub rdev: Option<u32>,
+    // how long the kernel caches the entries and the attributes of the
+    // matched paths, in place of --attr-timeout and --entry-timeout. Zero
+    // makes every access see the overridden attributes.
+    #[serde(default, with = "humantime_serde")]
+    pub cache_timeout: Option<Duration>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -89,6 +576,10 @@ pub struct Timespec {
 pub enum MistakeType {
     Zero,
     Random,
+    // inverts every bit of the bytes
+    Flip,
+    // fills the bytes with the constant
+    Constant(u8),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -106,3 +597,345 @@ pub struct MistakesConfig {
     #[serde(flatten)]
     pub filter: FilterConfig,
 }
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum OverflowMode {
+    Size,
+    Errno,
+}
+
+impl Default for OverflowMode {
+    fn default() -> Self {
+        OverflowMode::Size
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct OverflowConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    #[serde(default)]
+    pub mode: OverflowMode,
+    pub size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SeekLatencyConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // the delay of the shortest (track-to-track) seek
+    #[serde(with = "humantime_serde")]
+    pub settle: Duration,
+    // the delay of a seek across `full_stroke` bytes or more
+    #[serde(with = "humantime_serde")]
+    pub max_seek: Duration,
+    pub full_stroke: u64,
+    // an optional uniformly distributed rotational delay added to every seek
+    #[serde(default, with = "humantime_serde")]
+    pub rotation: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SsdWearConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // bytes written through the mount before the garbage collection starts
+    pub threshold: u64,
+    #[serde(with = "humantime_serde")]
+    pub stall: Duration,
+    #[serde(with = "humantime_serde")]
+    pub stall_interval: Duration,
+    // sustained write throughput in bytes per second once worn
+    pub throughput: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ThrottleConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // the throughput of the reads and the writes, unlimited if unset
+    pub read_bytes_per_second: Option<u64>,
+    pub write_bytes_per_second: Option<u64>,
+    // bytes transferred at full speed after an idle period, one second of
+    // throughput by default
+    pub burst: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ShortIoConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // the bytes always transferred, at least one
+    #[serde(default)]
+    pub min_length: usize,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskFullConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // bytes written through the mount before the disk is full
+    pub budget: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum FsyncMode {
+    // fail every matching sync with EIO
+    Always,
+    // fail only the first matching sync with EIO
+    Once,
+    // succeed, but drop the writes since the last sync
+    Drop,
+}
+
+impl Default for FsyncMode {
+    fn default() -> Self {
+        FsyncMode::Always
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FsyncConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    #[serde(default)]
+    pub mode: FsyncMode,
+    // whether flush, which syncs too, is injected like fsync
+    #[serde(default)]
+    pub flush: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ZoneConfig {
+    pub start: u64,
+    pub length: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ZonedConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    pub zones: Vec<ZoneConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FatConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    pub max_file_size: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PartitionConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    #[serde(with = "humantime_serde")]
+    pub freeze: Duration,
+    pub errnos: Option<Vec<i32>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct HangConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // the longest an operation hangs, forever until resumed if unset
+    #[serde(default, with = "humantime_serde")]
+    pub max: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ConcurrencyMode {
+    Busy,
+    Queue,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConcurrencyConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    pub limit: usize,
+    pub mode: ConcurrencyMode,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ContentOverrideConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // the replacement content is either read from `file` or decoded from the
+    // base64 `content`
+    pub file: Option<PathBuf>,
+    pub content: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SubtreeQuotaConfig {
+    // the root of the subtree in the mount
+    pub path: PathBuf,
+    pub bytes: Option<u64>,
+    pub inodes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaConfig {
+    pub quotas: Vec<SubtreeQuotaConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InodeExhaustionConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadOnlyConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NfsConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // the weights of ESTALE and of the stalls among the faults, 1 by default
+    pub stale_weight: Option<u32>,
+    pub stall_weight: Option<u32>,
+    // how long an operation stalls before failing with ETIMEDOUT, 10s by
+    // default
+    #[serde(default, with = "humantime_serde")]
+    pub stall: Option<Duration>,
+    // how long the attributes are cached, not cached if unset
+    #[serde(default, with = "humantime_serde")]
+    pub attr_cache: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum TornMode {
+    // a random prefix of the write is persisted
+    Prefix,
+    // a random subset of the pages of the write is persisted
+    Pages,
+}
+
+impl Default for TornMode {
+    fn default() -> Self {
+        TornMode::Prefix
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TornWriteConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    #[serde(default)]
+    pub mode: TornMode,
+    // 4096 by default
+    pub page_size: Option<usize>,
+    // the writes are only torn after the crash rpc
+    #[serde(default)]
+    pub on_crash: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum GarbageFill {
+    Zero,
+    // the base64 pattern repeated
+    Pattern,
+    Random,
+}
+
+impl Default for GarbageFill {
+    fn default() -> Self {
+        GarbageFill::Zero
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct GarbageConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    #[serde(default)]
+    pub fill: GarbageFill,
+    pub pattern: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StatfsConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // the free blocks (of the fragment size) and the free inodes reported,
+    // the real ones if unset
+    pub free_blocks: Option<u64>,
+    pub free_inodes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ListingConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // globs of the names of the entries left out of the listings
+    pub hide: Option<Vec<String>>,
+    // globs of the names of the entries listed twice
+    pub duplicate: Option<Vec<String>>,
+    // names of the entries listed, but which don't exist
+    pub phantoms: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ToctouConfig {
+    #[serde(flatten)]
+    pub filter: FilterConfig,
+
+    // the delay inserted before the open or rename following a check
+    #[serde(with = "humantime_serde")]
+    pub delay: Duration,
+    // only the uses within this period after the check are delayed
+    #[serde(default, with = "humantime_serde")]
+    pub window: Option<Duration>,
+}
@@ -0,0 +1,57 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use nix::errno::Errno;
+use tracing::{debug, trace};
+
+use super::injector_config::InodeExhaustionConfig;
+use super::{filter, Injector};
+use crate::hookfs::{Error, Reply, Result};
+
+// InodeExhaustionInjector emulates a filesystem running out of inodes while
+// there is still plenty of free space: creating new entries fails with ENOSPC
+// and statfs reports no free inodes, but keeps the free blocks untouched.
+#[derive(Debug)]
+pub struct InodeExhaustionInjector {
+    filter: filter::Filter,
+}
+
+#[async_trait]
+impl Injector for InodeExhaustionInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        let creation = filter::Method::CREATE
+            | filter::Method::MKNOD
+            | filter::Method::MKDIR
+            | filter::Method::SYMLINK;
+        if method.intersects(creation) && self.filter.filter(method, path) {
+            debug!("no inode left for {}", path.display());
+            return Err(Error::Sys(Errno::ENOSPC));
+        }
+
+        Ok(())
+    }
+
+    fn inject_reply(&self, method: &filter::Method, _: &Path, reply: &mut Reply) -> Result<()> {
+        // statfs always reports the exhaustion, so that the application sees
+        // a consistent view
+        if !method.contains(filter::Method::STATFS) {
+            return Ok(());
+        }
+
+        if let Reply::StatFs(statfs) = reply {
+            trace!("overriding free inodes {} with 0", statfs.ffree);
+            statfs.ffree = 0;
+        }
+
+        Ok(())
+    }
+}
+
+impl InodeExhaustionInjector {
+    pub fn build(conf: InodeExhaustionConfig) -> anyhow::Result<Self> {
+        trace!("build inode exhaustion injector");
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+        })
+    }
+}
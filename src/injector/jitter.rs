@@ -0,0 +1,59 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rand::Rng;
+
+use super::injector_config::{Distribution, JitterConfig};
+use super::rng;
+
+// Jitter draws the delays from a distribution. With a correlation, every delay
+// is weighted with the previous one, so the slow periods last for a while like
+// on a real disk.
+#[derive(Debug)]
+pub struct Jitter {
+    distribution: Distribution,
+    correlation: f64,
+
+    // the previous delay in seconds
+    last: Mutex<Option<f64>>,
+}
+
+impl Jitter {
+    pub fn build(conf: JitterConfig) -> Jitter {
+        Jitter {
+            distribution: conf.distribution,
+            correlation: conf.correlation,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub fn sample(&self) -> Duration {
+        let sample = rng::with(|rng| match &self.distribution {
+            Distribution::Uniform { min, max } => {
+                let (min, max) = (min.as_secs_f64(), max.as_secs_f64());
+                min + (max - min) * rng.gen::<f64>()
+            }
+            Distribution::Normal { mean, stddev } => {
+                // Box-Muller transform
+                let (u1, u2): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+                let z = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+                mean.as_secs_f64() + stddev.as_secs_f64() * z
+            }
+            Distribution::Pareto { scale, shape } => {
+                let u = 1.0 - rng.gen::<f64>();
+                scale.as_secs_f64() / u.powf(1.0 / shape)
+            }
+        });
+
+        let mut last = self.last.lock().unwrap();
+        let sample = match *last {
+            Some(last) => self.correlation * last + (1.0 - self.correlation) * sample,
+            None => sample,
+        };
+        *last = Some(sample);
+
+        // a normal distribution may draw negative delays, and a pareto one
+        // huge delays
+        Duration::from_secs_f64(sample.max(0.0).min(u32::MAX as f64))
+    }
+}
@@ -1,20 +1,25 @@
+use std::convert::TryFrom;
 use std::path::Path;
 use std::time::Duration;
 
 use async_trait::async_trait;
-use tokio::time::delay_for;
-use tokio::select;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace};
 
+use super::adaptive::Adaptive;
 use super::injector_config::LatencyConfig;
-use super::{filter, Injector};
+use super::jitter::Jitter;
+use super::{drain, filter, Injector, IoContext};
 use crate::hookfs::Result;
 
 #[derive(Debug)]
 pub struct LatencyInjector {
     latency: Duration,
+    per_method: Vec<(filter::Method, Duration)>,
+    per_byte: Option<Duration>,
+    jitter: Option<Jitter>,
     filter: filter::Filter,
+    adaptive: Option<Adaptive>,
     cancel_token: CancellationToken,
 }
 
@@ -23,18 +28,27 @@ impl Injector for LatencyInjector {
     async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
         trace!("test for filter");
         if self.filter.filter(method, path) {
-            let token = self.cancel_token.clone();
-            let latency = self.latency;
-            debug!("inject io delay {:?}", latency);
-
-            select! {
-                _ = delay_for(latency) => {}
-                _ = token.cancelled() => {
-                    debug!("cancelled");
-                }
-            }
+            let latency = self
+                .per_method
+                .iter()
+                .find(|(per_method, _)| per_method.contains(*method))
+                .map(|(_, latency)| *latency)
+                .unwrap_or_else(|| match &self.jitter {
+                    Some(jitter) => jitter.sample(),
+                    None => self.latency,
+                });
+            self.delay(path, latency).await?;
+        }
+
+        Ok(())
+    }
 
-            debug!("latency finished");
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        if let Some(per_byte) = self.per_byte {
+            if self.filter.filter(method, path) {
+                let nanos = (per_byte.as_nanos() as u64).saturating_mul(io.size);
+                self.delay(path, Duration::from_nanos(nanos)).await?;
+            }
         }
 
         Ok(())
@@ -49,11 +63,38 @@ impl Injector for LatencyInjector {
 impl LatencyInjector {
     pub fn build(conf: LatencyConfig) -> anyhow::Result<Self> {
         trace!("build latency injector");
+        let per_method = conf
+            .per_method
+            .iter()
+            .map(|per_method| {
+                filter::Method::try_from(per_method.method.as_str())
+                    .map(|method| (method, per_method.latency))
+            })
+            .collect::<anyhow::Result<_>>()?;
 
         Ok(Self {
             latency: conf.latency,
+            per_method,
+            per_byte: conf.per_byte,
+            jitter: conf.jitter.map(Jitter::build),
             filter: filter::Filter::build(conf.filter)?,
+            adaptive: conf.adaptive.map(Adaptive::build),
             cancel_token: CancellationToken::new(),
         })
     }
+
+    async fn delay(&self, path: &Path, latency: Duration) -> Result<()> {
+        let latency = match &self.adaptive {
+            Some(adaptive) => adaptive.admit(path, latency),
+            None => latency,
+        };
+        if latency == Duration::from_secs(0) {
+            return Ok(());
+        }
+        debug!("inject io delay {:?}", latency);
+        drain::delay(latency, &self.cancel_token).await?;
+
+        debug!("latency finished");
+        Ok(())
+    }
 }
@@ -0,0 +1,86 @@
+use std::ffi::OsString;
+use std::path::Path;
+
+use async_trait::async_trait;
+use fuser::FileType;
+use tracing::{debug, trace};
+
+use super::injector_config::ListingConfig;
+use super::{filter, Injector};
+use crate::hookfs::{DirEntry, Result};
+use crate::utils::PathPattern;
+
+// The inode numbers reported for the phantom entries, counting down from the
+// largest one, which no real file is expected to have
+const PHANTOM_INO: u64 = u64::MAX;
+
+// ListingInjector tampers with the listings of the matching directories: the
+// entries whose names match `hide` are left out, the ones matching
+// `duplicate` are listed twice, and the `phantoms` are listed although they
+// don't exist, so their lookup fails with ENOENT.
+#[derive(Debug)]
+pub struct ListingInjector {
+    filter: filter::Filter,
+    hide: Vec<PathPattern>,
+    duplicate: Vec<PathPattern>,
+    phantoms: Vec<OsString>,
+}
+
+#[async_trait]
+impl Injector for ListingInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_entries(&self, path: &Path, entries: &mut Vec<DirEntry>) {
+        if !self.filter.filter(&filter::Method::READDIR, path) {
+            return;
+        }
+
+        debug!("tamper with the listing of {}", path.display());
+        let matches = |patterns: &[PathPattern], name: &OsString| {
+            name != "." && name != ".." && patterns.iter().any(|pattern| pattern.matches(name))
+        };
+        entries.retain(|(_, _, name)| !matches(&self.hide, name));
+
+        let duplicates: Vec<_> = entries
+            .iter()
+            .filter(|(_, _, name)| matches(&self.duplicate, name))
+            .cloned()
+            .collect();
+        entries.extend(duplicates);
+
+        for (index, name) in self.phantoms.iter().enumerate() {
+            entries.push((
+                PHANTOM_INO - index as u64,
+                FileType::RegularFile,
+                name.clone(),
+            ));
+        }
+    }
+}
+
+impl ListingInjector {
+    pub fn build(conf: ListingConfig) -> anyhow::Result<Self> {
+        trace!("build listing injector");
+
+        let patterns = |patterns: Option<Vec<String>>| {
+            patterns
+                .unwrap_or_default()
+                .iter()
+                .map(|pattern| PathPattern::glob(pattern, false))
+                .collect::<anyhow::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            hide: patterns(conf.hide)?,
+            duplicate: patterns(conf.duplicate)?,
+            phantoms: conf
+                .phantoms
+                .unwrap_or_default()
+                .into_iter()
+                .map(OsString::from)
+                .collect(),
+        })
+    }
+}
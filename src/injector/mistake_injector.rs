@@ -6,7 +6,7 @@ use rand::Rng;
 use tracing::{debug, trace};
 
 use super::injector_config::{MistakeConfig, MistakeType, MistakesConfig};
-use super::{filter, Injector};
+use super::{filter, rng, Injector, IoContext};
 use crate::hookfs::{Reply, Result};
 
 #[derive(Debug)]
@@ -33,10 +33,25 @@ impl Injector for MistakeInjector {
         Ok(())
     }
 
-    fn inject_write_data(&self, path: &Path, data: &mut Vec<u8>) -> Result<()> {
+    fn inject_write_data(&self, path: &Path, io: &IoContext, data: &mut Vec<u8>) -> Result<()> {
         if self.filter.filter(&super::Method::WRITE, path) {
             debug!("MI:Injecting write data");
             self.handle(data)?;
+        } else if let Some(range) = self.filter.filter_range(&super::Method::WRITE, path, io) {
+            let range = range.start.min(data.len())..range.end.min(data.len());
+            debug!("MI:Injecting write data in {:?}", range);
+            self.handle(&mut data[range])?;
+        }
+        Ok(())
+    }
+
+    // the reads of the rules scoped to byte ranges are sabotaged here, as the
+    // reply doesn't know the offset
+    fn inject_read_data(&self, path: &Path, io: &IoContext, data: &mut Vec<u8>) -> Result<()> {
+        if let Some(range) = self.filter.filter_range(&super::Method::READ, path, io) {
+            let range = range.start.min(data.len())..range.end.min(data.len());
+            debug!("MI:Injecting read data in {:?}", range);
+            self.handle(&mut data[range])?;
         }
         Ok(())
     }
@@ -50,9 +65,9 @@ impl MistakeInjector {
             filter: filter::Filter::build(conf.filter)?,
         })
     }
-    pub fn handle(&self, data: &mut Vec<u8>) -> Result<()> {
+    pub fn handle(&self, data: &mut [u8]) -> Result<()> {
         trace!("sabotage data");
-        let mut rng = rand::thread_rng();
+        let mut rng = rng::fork();
         let data_length = data.len();
         let mistake = &self.mistake;
         let occurrence = match mistake.max_occurrences {
@@ -78,6 +93,16 @@ impl MistakeInjector {
                     }
                 }
                 MistakeType::Random => rng.fill(&mut data[pos..pos + length]),
+                MistakeType::Flip => {
+                    for item in data.iter_mut().skip(pos).take(length) {
+                        *item = !*item;
+                    }
+                }
+                MistakeType::Constant(byte) => {
+                    for item in data.iter_mut().skip(pos).take(length) {
+                        *item = byte;
+                    }
+                }
             }
         }
         Ok(())
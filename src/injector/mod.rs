@@ -1,25 +1,85 @@
+mod adaptive;
 mod attr_override_injector;
+mod concurrency_injector;
+mod content_override_injector;
+pub mod decisions;
+mod disk_full_injector;
+mod drain;
+mod experiment;
+mod fat_injector;
 mod fault_injector;
 mod filter;
-mod injector_config;
+mod fsync_injector;
+mod garbage_injector;
+mod hang_injector;
+pub mod injector_config;
+mod inode_exhaustion_injector;
+mod jitter;
 mod latency_injector;
+mod listing_injector;
 mod mistake_injector;
 mod multi_injector;
+mod nfs_injector;
+mod overflow_injector;
+mod partition_injector;
+mod quota_injector;
+mod read_only_injector;
+pub mod rng;
+mod seek_latency_injector;
+mod short_io_injector;
+mod ssd_wear_injector;
+mod statfs_injector;
+mod throttle_injector;
+mod toctou_injector;
+mod torn_write_injector;
+mod zoned_injector;
 
+use std::ops::Range;
+use std::os::unix::io::RawFd;
 use std::path::Path;
 
 use async_trait::async_trait;
+pub use drain::{Drain, DrainPolicy};
+pub use experiment::{
+    Experiment, ExperimentStatus, Experiments, ExpiryEvent, OnError, DEFAULT_EXPERIMENT,
+};
 pub use filter::Method;
 use fuser::FileAttr;
 pub use injector_config::InjectorConfig;
 pub use multi_injector::MultiInjector;
 
-use crate::hookfs::{Reply, Result};
+use crate::hookfs::{DirEntry, Reply, Result};
+
+// IoContext describes the range touched by a read or write request
+#[derive(Debug, Clone, Copy)]
+pub struct IoContext {
+    pub fh: u64,
+    // the backing file, whose size the ranges relative to its end refer to
+    pub fd: RawFd,
+    pub offset: i64,
+    pub size: u64,
+}
+
+// Permit is held by hookfs until the operation finishes
+pub type Permit = Box<dyn std::any::Any + Send + Sync>;
 
 #[async_trait]
 pub trait Injector: Send + Sync + std::fmt::Debug {
     async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()>;
 
+    async fn inject_io(
+        &self,
+        _method: &filter::Method,
+        _path: &Path,
+        _io: &IoContext,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    async fn enter(&self, _method: &filter::Method, _path: &Path) -> Result<Vec<Permit>> {
+        Ok(Vec::new())
+    }
+
     fn inject_reply(
         &self,
         _method: &filter::Method,
@@ -28,11 +88,69 @@ pub trait Injector: Send + Sync + std::fmt::Debug {
     ) -> Result<()> {
         Ok(())
     }
-    fn inject_write_data(&self, _path: &Path, _data: &mut Vec<u8>) -> Result<()> {
+    fn inject_write_data(&self, _path: &Path, _io: &IoContext, _data: &mut Vec<u8>) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_read_data(&self, _path: &Path, _io: &IoContext, _data: &mut Vec<u8>) -> Result<()> {
         Ok(())
     }
 
     fn inject_attr(&self, _attr: &mut FileAttr, _path: &Path) {}
 
+    fn inject_entries(&self, _path: &Path, _entries: &mut Vec<DirEntry>) {}
+
+    // journal_writes returns whether the writes to the path are journaled, so
+    // that drop_unsynced can drop them
+    fn journal_writes(&self, _path: &Path) -> bool {
+        false
+    }
+
+    // drop_unsynced returns whether the sync of the path undoes the journaled
+    // writes before syncing
+    fn drop_unsynced(&self, _method: &filter::Method, _path: &Path) -> bool {
+        false
+    }
+
+    // tear_write returns the ranges of the write, relative to its offset,
+    // which are persisted while the whole of it is acknowledged, or None to
+    // persist it all
+    fn tear_write(&self, _path: &Path, _io: &IoContext) -> Option<Vec<Range<usize>>> {
+        None
+    }
+
+    // io_done is told how many bytes of the io, which inject_io let through,
+    // are done. It's told 0 if the operation fails, even if the injector
+    // hasn't let the io through, as a later one has refused it.
+    fn io_done(&self, _io: &IoContext, _done: u64) {}
+
+    // tracks_usage returns whether the injector is told the bytes and the
+    // inodes which the operations on the path allocate, through `used`
+    fn tracks_usage(&self, _path: &Path) -> bool {
+        false
+    }
+
+    // used is told the bytes and the inodes which an operation on the path
+    // allocated, or freed if they are negative
+    fn used(&self, _path: &Path, _bytes: i64, _inodes: i64) {}
+
+    // sniffs_magic returns whether a rule matches the leading bytes of the
+    // files, which are then read when they are opened
+    fn sniffs_magic(&self) -> bool {
+        false
+    }
+
     fn interrupt(&self) {}
+
+    fn release_fh(&self, _fh: u64) {}
+
+    fn reset_zones(&self) {}
+
+    fn reset_space(&self) {}
+
+    fn reconnect(&self) {}
+
+    fn resume(&self) {}
+
+    fn crash(&self) {}
 }
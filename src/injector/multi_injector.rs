@@ -1,3 +1,4 @@
+use std::ops::Range;
 use std::path::Path;
 
 use async_trait::async_trait;
@@ -5,24 +6,57 @@ use fuser::FileAttr;
 use tracing::trace;
 
 use super::attr_override_injector::AttrOverrideInjector;
+use super::concurrency_injector::ConcurrencyInjector;
+use super::content_override_injector::ContentOverrideInjector;
+use super::disk_full_injector::DiskFullInjector;
+use super::fat_injector::FatInjector;
 use super::fault_injector::FaultInjector;
+use super::fsync_injector::FsyncInjector;
+use super::garbage_injector::GarbageInjector;
+use super::hang_injector::HangInjector;
 use super::injector_config::InjectorConfig;
+use super::inode_exhaustion_injector::InodeExhaustionInjector;
 use super::latency_injector::LatencyInjector;
+use super::listing_injector::ListingInjector;
 use super::mistake_injector::MistakeInjector;
-use super::{filter, Injector};
-use crate::hookfs::{Reply, Result};
+use super::nfs_injector::NfsInjector;
+use super::overflow_injector::OverflowInjector;
+use super::partition_injector::PartitionInjector;
+use super::quota_injector::QuotaInjector;
+use super::read_only_injector::ReadOnlyInjector;
+use super::seek_latency_injector::SeekLatencyInjector;
+use super::short_io_injector::ShortIoInjector;
+use super::ssd_wear_injector::SsdWearInjector;
+use super::statfs_injector::StatfsInjector;
+use super::throttle_injector::ThrottleInjector;
+use super::toctou_injector::ToctouInjector;
+use super::torn_write_injector::TornWriteInjector;
+use super::zoned_injector::ZonedInjector;
+use super::{filter, Injector, IoContext, Permit};
+use crate::hookfs::{DirEntry, Reply, Result};
 
 #[derive(Debug)]
 pub struct MultiInjector {
     injectors: Vec<Box<dyn Injector>>,
+    // whether one of the rules it's built from matches the magic
+    magic: bool,
 }
 
 impl MultiInjector {
+    pub fn new(injectors: Vec<Box<dyn Injector>>) -> Self {
+        Self {
+            injectors,
+            magic: false,
+        }
+    }
+
     pub fn build(conf: Vec<InjectorConfig>) -> anyhow::Result<Self> {
         trace!("build multiinjectors");
+        let magic = conf.iter().any(InjectorConfig::matches_magic);
         let mut injectors = Vec::new();
 
         for injector in conf.into_iter() {
+            injector.validate()?;
             let injector = match injector {
                 InjectorConfig::Fault(faults) => {
                     (box FaultInjector::build(faults)?) as Box<dyn Injector>
@@ -36,11 +70,71 @@ impl MultiInjector {
                 InjectorConfig::Mistake(mistakes) => {
                     (box MistakeInjector::build(mistakes)?) as Box<dyn Injector>
                 }
+                InjectorConfig::Overflow(overflow) => {
+                    (box OverflowInjector::build(overflow)?) as Box<dyn Injector>
+                }
+                InjectorConfig::SeekLatency(seek_latency) => {
+                    (box SeekLatencyInjector::build(seek_latency)?) as Box<dyn Injector>
+                }
+                InjectorConfig::SsdWear(ssd_wear) => {
+                    (box SsdWearInjector::build(ssd_wear)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Zoned(zoned) => {
+                    (box ZonedInjector::build(zoned)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Fat(fat) => (box FatInjector::build(fat)?) as Box<dyn Injector>,
+                InjectorConfig::Partition(partition) => {
+                    (box PartitionInjector::build(partition)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Concurrency(concurrency) => {
+                    (box ConcurrencyInjector::build(concurrency)?) as Box<dyn Injector>
+                }
+                InjectorConfig::ContentOverride(content_override) => {
+                    (box ContentOverrideInjector::build(content_override)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Quota(quota) => {
+                    (box QuotaInjector::build(quota)?) as Box<dyn Injector>
+                }
+                InjectorConfig::InodeExhaustion(inode_exhaustion) => {
+                    (box InodeExhaustionInjector::build(inode_exhaustion)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Toctou(toctou) => {
+                    (box ToctouInjector::build(toctou)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Throttle(throttle) => {
+                    (box ThrottleInjector::build(throttle)?) as Box<dyn Injector>
+                }
+                InjectorConfig::ShortIo(short_io) => {
+                    (box ShortIoInjector::build(short_io)?) as Box<dyn Injector>
+                }
+                InjectorConfig::DiskFull(disk_full) => {
+                    (box DiskFullInjector::build(disk_full)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Fsync(fsync) => {
+                    (box FsyncInjector::build(fsync)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Hang(hang) => (box HangInjector::build(hang)?) as Box<dyn Injector>,
+                InjectorConfig::ReadOnly(read_only) => {
+                    (box ReadOnlyInjector::build(read_only)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Listing(listing) => {
+                    (box ListingInjector::build(listing)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Nfs(nfs) => (box NfsInjector::build(nfs)?) as Box<dyn Injector>,
+                InjectorConfig::Statfs(statfs) => {
+                    (box StatfsInjector::build(statfs)?) as Box<dyn Injector>
+                }
+                InjectorConfig::Garbage(garbage) => {
+                    (box GarbageInjector::build(garbage)?) as Box<dyn Injector>
+                }
+                InjectorConfig::TornWrite(torn_write) => {
+                    (box TornWriteInjector::build(torn_write)?) as Box<dyn Injector>
+                }
             };
             injectors.push(injector)
         }
 
-        Ok(Self { injectors })
+        Ok(Self { injectors, magic })
     }
 }
 
@@ -54,6 +148,23 @@ impl Injector for MultiInjector {
         Ok(())
     }
 
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        for injector in self.injectors.iter() {
+            injector.inject_io(method, path, io).await?
+        }
+
+        Ok(())
+    }
+
+    async fn enter(&self, method: &filter::Method, path: &Path) -> Result<Vec<Permit>> {
+        let mut permits = Vec::new();
+        for injector in self.injectors.iter() {
+            permits.extend(injector.enter(method, path).await?);
+        }
+
+        Ok(permits)
+    }
+
     fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
         for injector in self.injectors.iter() {
             injector.inject_reply(method, path, reply)?
@@ -68,9 +179,16 @@ impl Injector for MultiInjector {
         }
     }
 
-    fn inject_write_data(&self, path: &Path, data: &mut Vec<u8>) -> Result<()> {
+    fn inject_write_data(&self, path: &Path, io: &IoContext, data: &mut Vec<u8>) -> Result<()> {
+        for injector in self.injectors.iter() {
+            injector.inject_write_data(path, io, data)?;
+        }
+        Ok(())
+    }
+
+    fn inject_read_data(&self, path: &Path, io: &IoContext, data: &mut Vec<u8>) -> Result<()> {
         for injector in self.injectors.iter() {
-            injector.inject_write_data(path, data)?;
+            injector.inject_read_data(path, io, data)?;
         }
         Ok(())
     }
@@ -80,4 +198,90 @@ impl Injector for MultiInjector {
             injector.interrupt();
         }
     }
+
+    fn inject_entries(&self, path: &Path, entries: &mut Vec<DirEntry>) {
+        for injector in self.injectors.iter() {
+            injector.inject_entries(path, entries);
+        }
+    }
+
+    fn journal_writes(&self, path: &Path) -> bool {
+        self.injectors
+            .iter()
+            .any(|injector| injector.journal_writes(path))
+    }
+
+    fn drop_unsynced(&self, method: &filter::Method, path: &Path) -> bool {
+        self.injectors
+            .iter()
+            .any(|injector| injector.drop_unsynced(method, path))
+    }
+
+    fn io_done(&self, io: &IoContext, done: u64) {
+        for injector in self.injectors.iter() {
+            injector.io_done(io, done);
+        }
+    }
+
+    fn tracks_usage(&self, path: &Path) -> bool {
+        self.injectors
+            .iter()
+            .any(|injector| injector.tracks_usage(path))
+    }
+
+    fn used(&self, path: &Path, bytes: i64, inodes: i64) {
+        for injector in self.injectors.iter() {
+            injector.used(path, bytes, inodes);
+        }
+    }
+
+    fn sniffs_magic(&self) -> bool {
+        self.magic
+            || self
+                .injectors
+                .iter()
+                .any(|injector| injector.sniffs_magic())
+    }
+
+    fn release_fh(&self, fh: u64) {
+        for injector in self.injectors.iter() {
+            injector.release_fh(fh);
+        }
+    }
+
+    fn reset_zones(&self) {
+        for injector in self.injectors.iter() {
+            injector.reset_zones();
+        }
+    }
+
+    fn reset_space(&self) {
+        for injector in self.injectors.iter() {
+            injector.reset_space();
+        }
+    }
+
+    fn tear_write(&self, path: &Path, io: &IoContext) -> Option<Vec<Range<usize>>> {
+        self.injectors
+            .iter()
+            .find_map(|injector| injector.tear_write(path, io))
+    }
+
+    fn crash(&self) {
+        for injector in self.injectors.iter() {
+            injector.crash();
+        }
+    }
+
+    fn reconnect(&self) {
+        for injector in self.injectors.iter() {
+            injector.reconnect();
+        }
+    }
+
+    fn resume(&self) {
+        for injector in self.injectors.iter() {
+            injector.resume();
+        }
+    }
 }
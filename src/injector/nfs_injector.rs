@@ -0,0 +1,280 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use fuser::FileAttr;
+use nix::errno::Errno;
+use rand::Rng;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+use super::injector_config::NfsConfig;
+use super::{drain, filter, rng, Injector};
+use crate::hookfs::{budget, Error, Reply, Result};
+
+const DEFAULT_STALL: Duration = Duration::from_secs(10);
+
+// modifying returns the operations changing the attributes, after which the
+// cached ones are dropped
+fn modifying() -> filter::Method {
+    filter::Method::SETATTR
+        | filter::Method::WRITE
+        | filter::Method::CREATE
+        | filter::Method::FALLOCATE
+        | filter::Method::COPY_FILE_RANGE
+        | filter::Method::RENAME
+        | filter::Method::UNLINK
+        | filter::Method::LINK
+}
+
+// Expired attributes are only dropped once there are more paths than this
+// limit
+const ATTRS_GC_THRESHOLD: usize = 4096;
+
+// the name of this subsystem in the memory budget
+const SUBSYSTEM: &str = "nfs";
+
+// the memory accounted for every cached attribute, besides its path
+const ATTR_COST: usize = 128;
+
+// at most this many paths are kept stale
+const MAX_STALE: usize = 4096;
+
+fn cost(path: &Path) -> usize {
+    path.as_os_str().len() + ATTR_COST
+}
+
+// NfsInjector is a profile of the faults seen on a network filesystem. The
+// matching operations fail with ESTALE, as if their handle went stale on the
+// server, or stall and then fail with ETIMEDOUT, like a soft mount, chosen by
+// the weights. A stale handle stays stale, so every operation on the path fails
+// with ESTALE until it's looked up again. Besides, the attributes are cached
+// like the attribute cache of the client, so the changes made by others are
+// only seen once the cached attributes expire, while the changes made through
+// the mount are seen at once.
+#[derive(Debug)]
+pub struct NfsInjector {
+    filter: filter::Filter,
+    stale_weight: u64,
+    stall_weight: u64,
+    stall: Duration,
+    attr_cache: Option<Duration>,
+
+    // map from path to the attributes and the time they were cached
+    attrs: Mutex<HashMap<PathBuf, (FileAttr, Instant)>>,
+    // the paths whose handle went stale
+    stale: Mutex<HashSet<PathBuf>>,
+    cancel_token: CancellationToken,
+}
+
+#[async_trait]
+impl Injector for NfsInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if method.intersects(modifying()) {
+            self.forget_attrs(path);
+        }
+        if method.contains(filter::Method::LOOKUP) {
+            // the lookup resolves a new handle
+            self.stale.lock().unwrap().remove(path);
+        } else if self.stale.lock().unwrap().contains(path) {
+            debug!("return with error ESTALE of the stale {}", path.display());
+            return Err(Error::Sys(Errno::ESTALE));
+        }
+
+        let sum = self.stale_weight + self.stall_weight;
+        if sum == 0 || !self.filter.filter(method, path) {
+            return Ok(());
+        }
+
+        if rng::with(|rng| rng.gen_range(0, sum)) < self.stale_weight {
+            if !method.contains(filter::Method::LOOKUP) {
+                let mut stale = self.stale.lock().unwrap();
+                if stale.len() < MAX_STALE {
+                    stale.insert(path.to_owned());
+                }
+            }
+            debug!("return with error ESTALE");
+            return Err(Error::Sys(Errno::ESTALE));
+        }
+
+        debug!("stall operation for {:?}", self.stall);
+        drain::delay(self.stall, &self.cancel_token).await?;
+        debug!("return with error ETIMEDOUT");
+        Err(Error::Sys(Errno::ETIMEDOUT))
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, _: &mut Reply) -> Result<()> {
+        // the attributes may have been cached again while the write was going
+        // on
+        if method.intersects(modifying()) {
+            self.forget_attrs(path);
+        }
+        Ok(())
+    }
+
+    fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
+        let attr_cache = match self.attr_cache {
+            Some(attr_cache) if self.filter.match_path(path) => attr_cache,
+            _ => return,
+        };
+
+        let mut attrs = self.attrs.lock().unwrap();
+        if let Some((cached, cached_at)) = attrs.get(path) {
+            if cached_at.elapsed() <= attr_cache {
+                trace!("report cached attributes of {}", path.display());
+                *attr = *cached;
+                return;
+            }
+        }
+
+        if attrs.len() > ATTRS_GC_THRESHOLD {
+            attrs.retain(|path, (_, cached_at)| {
+                let expired = cached_at.elapsed() > attr_cache;
+                if expired {
+                    budget::release(SUBSYSTEM, cost(path));
+                }
+                !expired
+            });
+        }
+        match attrs.insert(path.to_owned(), (*attr, Instant::now())) {
+            Some(_) => {}
+            // the attributes are not cached once the memory budget is exceeded
+            None if !budget::charge(SUBSYSTEM, cost(path)) => {
+                attrs.remove(path);
+            }
+            None => {}
+        }
+    }
+
+    fn interrupt(&self) {
+        debug!("interrupt nfs");
+        self.cancel_token.cancel();
+    }
+}
+
+impl Drop for NfsInjector {
+    fn drop(&mut self) {
+        let attrs = self.attrs.lock().unwrap();
+        budget::release(SUBSYSTEM, attrs.keys().map(|path| cost(path)).sum());
+    }
+}
+
+impl NfsInjector {
+    // forget_attrs drops the cached attributes of the path, which the client
+    // has changed itself
+    fn forget_attrs(&self, path: &Path) {
+        if self.attr_cache.is_none() {
+            return;
+        }
+        if self.attrs.lock().unwrap().remove(path).is_some() {
+            budget::release(SUBSYSTEM, cost(path));
+        }
+    }
+
+    pub fn build(conf: NfsConfig) -> anyhow::Result<Self> {
+        trace!("build nfs injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            stale_weight: u64::from(conf.stale_weight.unwrap_or(1)),
+            stall_weight: u64::from(conf.stall_weight.unwrap_or(1)),
+            stall: conf.stall.unwrap_or(DEFAULT_STALL),
+            attr_cache: conf.attr_cache,
+            attrs: Mutex::new(HashMap::new()),
+            stale: Mutex::new(HashSet::new()),
+            cancel_token: CancellationToken::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use fuser::FileType;
+    use futures::executor::block_on;
+
+    use super::*;
+
+    fn build(conf: &str) -> NfsInjector {
+        NfsInjector::build(serde_json::from_str(conf).unwrap()).unwrap()
+    }
+
+    fn inject(injector: &NfsInjector, method: filter::Method, path: &str) -> Result<()> {
+        block_on(injector.inject(&method, Path::new(path)))
+    }
+
+    fn attr(size: u64) -> FileAttr {
+        FileAttr {
+            ino: 2,
+            size,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 4096,
+            padding: 0,
+            flags: 0,
+        }
+    }
+
+    #[test]
+    fn test_weights_overflow() {
+        let injector = build(&format!(
+            r#"{{"percent":100,"staleWeight":{0},"stallWeight":{0},"stall":"1ms"}}"#,
+            u32::MAX
+        ));
+        for _ in 0..16 {
+            assert!(matches!(
+                inject(&injector, filter::Method::READ, "/file"),
+                Err(Error::Sys(Errno::ESTALE)) | Err(Error::Sys(Errno::ETIMEDOUT))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_stale_until_lookup() {
+        let injector = build(r#"{"methods":["read"],"percent":100,"stallWeight":0}"#);
+        assert!(matches!(
+            inject(&injector, filter::Method::READ, "/file"),
+            Err(Error::Sys(Errno::ESTALE))
+        ));
+        // the other operations on the handle fail as well
+        assert!(matches!(
+            inject(&injector, filter::Method::GETATTR, "/file"),
+            Err(Error::Sys(Errno::ESTALE))
+        ));
+        assert!(inject(&injector, filter::Method::GETATTR, "/other").is_ok());
+
+        inject(&injector, filter::Method::LOOKUP, "/file").unwrap();
+        inject(&injector, filter::Method::GETATTR, "/file").unwrap();
+    }
+
+    #[test]
+    fn test_attr_cache_sees_own_writes() {
+        let injector = build(r#"{"percent":100,"staleWeight":0,"stallWeight":0,"attrCache":"1h"}"#);
+        let path = Path::new("/file");
+        let mut cached = attr(1);
+        injector.inject_attr(&mut cached, path);
+
+        // the changes made by others are hidden
+        let mut changed = attr(2);
+        injector.inject_attr(&mut changed, path);
+        assert_eq!(changed.size, 1);
+
+        // while the ones made through the mount are not
+        inject(&injector, filter::Method::WRITE, "/file").unwrap();
+        let mut written = attr(3);
+        injector.inject_attr(&mut written, path);
+        assert_eq!(written.size, 3);
+    }
+}
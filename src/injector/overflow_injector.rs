@@ -0,0 +1,72 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use fuser::FileAttr;
+use nix::errno::Errno;
+use tracing::{debug, trace};
+
+use super::injector_config::{OverflowConfig, OverflowMode};
+use super::{filter, Injector};
+use crate::hookfs::{Error, Result};
+
+// The size reported in `size` mode if not configured, which overflows both
+// signed and unsigned 32-bit offsets.
+const DEFAULT_OVERFLOW_SIZE: u64 = 1 << 32;
+
+#[derive(Debug)]
+pub struct OverflowInjector {
+    filter: filter::Filter,
+    mode: OverflowMode,
+    size: Option<u64>,
+}
+
+#[async_trait]
+impl Injector for OverflowInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if let OverflowMode::Size = self.mode {
+            return Ok(());
+        }
+
+        // only the operations which report or consume a file size can overflow
+        let method = *method
+            & (filter::Method::LOOKUP
+                | filter::Method::GETATTR
+                | filter::Method::OPEN
+                | filter::Method::READ);
+        if !method.is_empty() && self.filter.filter(&method, path) {
+            debug!("inject EOVERFLOW");
+            return Err(Error::Sys(Errno::EOVERFLOW));
+        }
+
+        Ok(())
+    }
+
+    fn inject_attr(&self, attr: &mut FileAttr, path: &Path) {
+        if let OverflowMode::Errno = self.mode {
+            return;
+        }
+
+        if !self.filter.filter(&filter::Method::GETATTR, path) {
+            return;
+        }
+
+        let size = match self.size {
+            Some(size) => size,
+            None => attr.size.saturating_add(DEFAULT_OVERFLOW_SIZE),
+        };
+        trace!("overriding size {} with {}", attr.size, size);
+        attr.size = size;
+    }
+}
+
+impl OverflowInjector {
+    pub fn build(conf: OverflowConfig) -> anyhow::Result<Self> {
+        trace!("build overflow injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            mode: conf.mode,
+            size: conf.size,
+        })
+    }
+}
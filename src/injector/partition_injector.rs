@@ -0,0 +1,103 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use nix::errno::Errno;
+use rand::seq::SliceRandom;
+use tokio::select;
+use tokio::time::delay_for;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, trace};
+
+use super::injector_config::PartitionConfig;
+use super::{drain, filter, rng, Injector};
+use crate::hookfs::{Error, Result};
+
+const DEFAULT_PARTITION_ERRNOS: [i32; 2] = [libc::ETIMEDOUT, libc::ESTALE];
+
+// PartitionInjector models a network filesystem whose server becomes
+// unreachable. Matching operations hang until the freeze period (counted from
+// the time the rule is applied) has passed, and fail afterwards until the
+// partition is healed with `reconnect`.
+#[derive(Debug)]
+pub struct PartitionInjector {
+    filter: filter::Filter,
+
+    started: Instant,
+    freeze: Duration,
+    errnos: Vec<Errno>,
+
+    connected: AtomicBool,
+    cancel_token: CancellationToken,
+}
+
+#[async_trait]
+impl Injector for PartitionInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if self.connected.load(Ordering::SeqCst) || !self.filter.filter(method, path) {
+            return Ok(());
+        }
+
+        let elapsed = self.started.elapsed();
+        if elapsed < self.freeze {
+            debug!("freeze operation for {:?}", self.freeze - elapsed);
+            let token = self.cancel_token.clone();
+            select! {
+                _ = delay_for(self.freeze - elapsed) => {}
+                _ = token.cancelled() => {
+                    debug!("cancelled");
+                    if self.connected.load(Ordering::SeqCst) {
+                        return Ok(());
+                    }
+                    return drain::drain(self.freeze.checked_sub(self.started.elapsed())).await;
+                }
+            }
+        }
+
+        if self.connected.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+
+        let errno = rng::with(|rng| self.errnos.choose(rng).copied()).unwrap_or(Errno::ETIMEDOUT);
+        debug!("return with error {}", errno);
+        Err(Error::Sys(errno))
+    }
+
+    fn interrupt(&self) {
+        debug!("interrupt partition");
+        self.cancel_token.cancel();
+    }
+
+    fn reconnect(&self) {
+        info!("heal the partition");
+        self.connected.store(true, Ordering::SeqCst);
+        self.cancel_token.cancel();
+    }
+}
+
+impl PartitionInjector {
+    pub fn build(conf: PartitionConfig) -> anyhow::Result<Self> {
+        trace!("build partition injector");
+
+        let errnos = conf
+            .errnos
+            .unwrap_or(DEFAULT_PARTITION_ERRNOS.to_vec())
+            .into_iter()
+            .map(|errno| match Errno::from_i32(errno) {
+                Errno::UnknownErrno => Err(anyhow!("unknown errno {}", errno)),
+                errno => Ok(errno),
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            started: Instant::now(),
+            freeze: conf.freeze,
+            errnos,
+            connected: AtomicBool::new(false),
+            cancel_token: CancellationToken::new(),
+        })
+    }
+}
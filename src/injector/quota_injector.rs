@@ -0,0 +1,187 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use nix::errno::Errno;
+use nix::sys::stat::fstat;
+use tracing::{debug, trace};
+
+use super::injector_config::QuotaConfig;
+use super::{filter, Injector, IoContext};
+use crate::hookfs::{Error, Reply, Result};
+use crate::utils::usage;
+
+#[derive(Debug)]
+struct SubtreeQuota {
+    path: PathBuf,
+    bytes: Option<u64>,
+    inodes: Option<u64>,
+
+    used_bytes: AtomicU64,
+    used_inodes: AtomicU64,
+}
+
+impl SubtreeQuota {
+    fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.path)
+    }
+}
+
+// QuotaInjector enforces virtual byte and inode quotas on subtrees of the
+// mount, returning EDQUOT once a quota is exceeded. The usage of every subtree
+// is calculated by walking it once, and then tracked from the bytes and the
+// inodes which the operations allocate and free.
+//
+// statfs within a subtree reports the quota of the innermost subtree.
+#[derive(Debug)]
+pub struct QuotaInjector {
+    quotas: Arc<Vec<SubtreeQuota>>,
+}
+
+#[async_trait]
+impl Injector for QuotaInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        let creation = filter::Method::CREATE
+            | filter::Method::MKNOD
+            | filter::Method::MKDIR
+            | filter::Method::SYMLINK;
+        if !method.intersects(creation) {
+            return Ok(());
+        }
+
+        self.check(path, |quota| (quota.inodes, &quota.used_inodes), 1)
+    }
+
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        let growing =
+            filter::Method::WRITE | filter::Method::FALLOCATE | filter::Method::COPY_FILE_RANGE;
+        if !method.intersects(growing) {
+            return Ok(());
+        }
+
+        // only the bytes beyond the end of the file are allocated
+        let size = fstat(io.fd)
+            .map(|stat| stat.st_size as u64)
+            .unwrap_or_default();
+        let end = (io.offset.max(0) as u64).saturating_add(io.size);
+        self.check(
+            path,
+            |quota| (quota.bytes, &quota.used_bytes),
+            end.saturating_sub(size),
+        )
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        if !method.contains(filter::Method::STATFS) {
+            return Ok(());
+        }
+
+        let quota = self
+            .quotas
+            .iter()
+            .filter(|quota| quota.contains(path))
+            .max_by_key(|quota| quota.path.components().count());
+        if let (Some(quota), Reply::StatFs(statfs)) = (quota, reply) {
+            trace!("report quota of {}", quota.path.display());
+            if let Some(bytes) = quota.bytes {
+                let frsize = statfs.frsize.max(1) as u64;
+                let free = bytes.saturating_sub(quota.used_bytes.load(Ordering::SeqCst));
+                statfs.blocks = bytes / frsize;
+                statfs.bfree = free / frsize;
+                statfs.bavail = free / frsize;
+            }
+            if let Some(inodes) = quota.inodes {
+                statfs.files = inodes;
+                statfs.ffree = inodes.saturating_sub(quota.used_inodes.load(Ordering::SeqCst));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn tracks_usage(&self, path: &Path) -> bool {
+        self.quotas.iter().any(|quota| quota.contains(path))
+    }
+
+    fn used(&self, path: &Path, bytes: i64, inodes: i64) {
+        for quota in self.quotas.iter().filter(|quota| quota.contains(path)) {
+            add(&quota.used_bytes, bytes);
+            add(&quota.used_inodes, inodes);
+        }
+    }
+}
+
+impl QuotaInjector {
+    pub fn build(conf: QuotaConfig) -> anyhow::Result<Self> {
+        trace!("build quota injector");
+
+        let quotas = Arc::new(
+            conf.quotas
+                .into_iter()
+                .map(|quota| SubtreeQuota {
+                    path: quota.path,
+                    bytes: quota.bytes,
+                    inodes: quota.inodes,
+                    used_bytes: AtomicU64::new(0),
+                    used_inodes: AtomicU64::new(0),
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        // the usage is calculated through the mount, so it should never be
+        // done while handling a request. It's added to the usage tracked
+        // meanwhile, which counts the files changed during the walk twice.
+        let weak = Arc::downgrade(&quotas);
+        std::thread::spawn(move || {
+            if let Some(quotas) = weak.upgrade() {
+                for quota in quotas.iter() {
+                    let (bytes, inodes) = usage(&quota.path);
+                    trace!(
+                        "usage of {}: {} bytes, {} inodes",
+                        quota.path.display(),
+                        bytes,
+                        inodes
+                    );
+                    quota.used_bytes.fetch_add(bytes, Ordering::SeqCst);
+                    quota.used_inodes.fetch_add(inodes, Ordering::SeqCst);
+                }
+            }
+        });
+
+        Ok(Self { quotas })
+    }
+
+    // check fails if adding `amount` to the usage of any subtree containing
+    // `path` would exceed its quota. The usage itself is added once the
+    // operation is done.
+    fn check<F>(&self, path: &Path, select: F, amount: u64) -> Result<()>
+    where
+        F: Fn(&SubtreeQuota) -> (Option<u64>, &AtomicU64),
+    {
+        if amount == 0 {
+            return Ok(());
+        }
+        for quota in self.quotas.iter().filter(|quota| quota.contains(path)) {
+            if let (Some(limit), used) = select(quota) {
+                if used.load(Ordering::SeqCst).saturating_add(amount) > limit {
+                    debug!("quota of {} exceeded", quota.path.display());
+                    return Err(Error::Sys(Errno::EDQUOT));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// add adds the signed delta to the usage, which never goes below zero
+fn add(used: &AtomicU64, delta: i64) {
+    let _ = used.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+        Some(if delta < 0 {
+            used.saturating_sub(delta.unsigned_abs())
+        } else {
+            used.saturating_add(delta as u64)
+        })
+    });
+}
@@ -0,0 +1,66 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use nix::errno::Errno;
+use tracing::{debug, trace};
+
+use super::injector_config::ReadOnlyConfig;
+use super::{filter, Injector};
+use crate::hookfs::{caller, Error, Result};
+
+// ReadOnlyInjector makes the mount (or the subtree matching the filter) behave
+// like a filesystem remounted read-only after an error: every operation
+// modifying it, and every open for writing, fails with EROFS, while the reads
+// go on. The ST_RDONLY flag of statvfs comes from the flags of the mount,
+// which a fuse filesystem cannot report, so it isn't set.
+#[derive(Debug)]
+pub struct ReadOnlyInjector {
+    filter: filter::Filter,
+}
+
+#[async_trait]
+impl Injector for ReadOnlyInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        let mut modifying = *method
+            & (filter::Method::SETATTR
+                | filter::Method::MKNOD
+                | filter::Method::MKDIR
+                | filter::Method::UNLINK
+                | filter::Method::RMDIR
+                | filter::Method::SYMLINK
+                | filter::Method::RENAME
+                | filter::Method::LINK
+                | filter::Method::WRITE
+                | filter::Method::SETXATTR
+                | filter::Method::REMOVEXATTR
+                | filter::Method::CREATE
+                | filter::Method::FALLOCATE
+                | filter::Method::COPY_FILE_RANGE);
+        if method.contains(filter::Method::OPEN) && opened_for_write() {
+            modifying |= filter::Method::OPEN;
+        }
+        if !modifying.is_empty() && self.filter.filter(&modifying, path) {
+            debug!("{} is read-only", path.display());
+            return Err(Error::Sys(Errno::EROFS));
+        }
+
+        Ok(())
+    }
+}
+
+// opened_for_write returns whether the request opens a file for writing, or
+// truncates it
+fn opened_for_write() -> bool {
+    caller::flags().map_or(false, |flags| {
+        flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0
+    })
+}
+
+impl ReadOnlyInjector {
+    pub fn build(conf: ReadOnlyConfig) -> anyhow::Result<Self> {
+        trace!("build read-only injector");
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+        })
+    }
+}
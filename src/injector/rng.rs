@@ -0,0 +1,63 @@
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use rand::rngs::{OsRng, StdRng};
+use rand::{RngCore, SeedableRng};
+use tracing::info;
+
+// Seeded is the random number generator deciding every injection, with the
+// seed it's seeded with. The seed is drawn from the entropy unless it's
+// configured, and is logged either way, so that a failing run can be replayed
+// with it. With the same seed the same sequence of operations is injected the
+// same way, although the order of concurrent operations (and the draws they
+// make) isn't reproducible.
+struct Seeded {
+    seed: u64,
+    rng: StdRng,
+}
+
+impl Seeded {
+    fn new(seed: Option<u64>) -> Self {
+        let seed = match seed {
+            Some(seed) => {
+                info!("seed random number generator with {}", seed);
+                seed
+            }
+            None => {
+                let seed = OsRng.next_u64();
+                info!(
+                    "seed random number generator with {}, pass --seed {} to replay",
+                    seed, seed
+                );
+                seed
+            }
+        };
+        Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+}
+
+static RNG: Lazy<Mutex<Seeded>> = Lazy::new(|| Mutex::new(Seeded::new(None)));
+
+// configure seeds the generator, with a seed drawn from the entropy if it's
+// None
+pub fn configure(seed: Option<u64>) {
+    *RNG.lock().unwrap() = Seeded::new(seed);
+}
+
+pub fn seed() -> u64 {
+    RNG.lock().unwrap().seed
+}
+
+// with runs `f` with the shared generator locked
+pub fn with<T, F: FnOnce(&mut StdRng) -> T>(f: F) -> T {
+    f(&mut RNG.lock().unwrap().rng)
+}
+
+// fork returns a generator seeded from the shared one, for a thread drawing
+// many numbers on its own
+pub fn fork() -> StdRng {
+    with(|rng| StdRng::from_rng(rng).unwrap())
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rand::Rng;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+use super::injector_config::SeekLatencyConfig;
+use super::{drain, filter, rng, Injector, IoContext};
+use crate::hookfs::Result;
+
+// SeekLatencyInjector emulates the seek of a rotational disk. The delay grows
+// with the square root of the distance between the end of the last request
+// and the start of the current one on the same file handle, so sequential IO
+// is not delayed at all.
+#[derive(Debug)]
+pub struct SeekLatencyInjector {
+    filter: filter::Filter,
+
+    settle: Duration,
+    max_seek: Duration,
+    full_stroke: u64,
+    rotation: Option<Duration>,
+
+    // map from fh to the offset where the last request ended
+    positions: Mutex<HashMap<u64, i64>>,
+    cancel_token: CancellationToken,
+}
+
+#[async_trait]
+impl Injector for SeekLatencyInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        let last = self
+            .positions
+            .lock()
+            .unwrap()
+            .insert(io.fh, io.offset + io.size as i64);
+        let distance = match last {
+            Some(last) => (io.offset - last).abs() as u64,
+            None => 0,
+        };
+        trace!("seek distance {}", distance);
+
+        if distance == 0 || !self.filter.filter(method, path) {
+            return Ok(());
+        }
+
+        let latency = self.seek_time(distance);
+        debug!("inject seek delay {:?}", latency);
+
+        drain::delay(latency, &self.cancel_token).await
+    }
+
+    fn interrupt(&self) {
+        debug!("interrupt seek latency");
+        self.cancel_token.cancel();
+    }
+
+    fn release_fh(&self, fh: u64) {
+        self.positions.lock().unwrap().remove(&fh);
+    }
+}
+
+impl SeekLatencyInjector {
+    pub fn build(conf: SeekLatencyConfig) -> anyhow::Result<Self> {
+        trace!("build seek latency injector");
+
+        if conf.full_stroke == 0 {
+            return Err(anyhow::anyhow!("fullStroke should be positive"));
+        }
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            settle: conf.settle,
+            max_seek: conf.max_seek.max(conf.settle),
+            full_stroke: conf.full_stroke,
+            rotation: conf.rotation,
+            positions: Mutex::new(HashMap::new()),
+            cancel_token: CancellationToken::new(),
+        })
+    }
+
+    fn seek_time(&self, distance: u64) -> Duration {
+        let ratio = (distance.min(self.full_stroke) as f64 / self.full_stroke as f64).sqrt();
+        let mut latency = self.settle + (self.max_seek - self.settle).mul_f64(ratio);
+
+        if let Some(rotation) = self.rotation {
+            latency += rotation.mul_f64(rng::with(|rng| rng.gen()));
+        }
+
+        latency
+    }
+}
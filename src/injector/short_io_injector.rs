@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use rand::Rng;
+use tracing::{debug, trace};
+
+use super::injector_config::ShortIoConfig;
+use super::{filter, rng, Injector, IoContext};
+use crate::hookfs::Result;
+
+// ShortIoInjector transfers only a part of the data of a read or a write,
+// without an error. The write replies the bytes actually written, so the file
+// stays consistent with what the application is told.
+//
+// A short read is taken as the end of the file by the page cache, so it's only
+// seen as such by the application with direct io.
+#[derive(Debug)]
+pub struct ShortIoInjector {
+    filter: filter::Filter,
+    min_length: usize,
+}
+
+#[async_trait]
+impl Injector for ShortIoInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_write_data(&self, path: &Path, _: &IoContext, data: &mut Vec<u8>) -> Result<()> {
+        if self.filter.filter(&filter::Method::WRITE, path) {
+            self.truncate(data);
+        }
+        Ok(())
+    }
+
+    fn inject_read_data(&self, path: &Path, _: &IoContext, data: &mut Vec<u8>) -> Result<()> {
+        if self.filter.filter(&filter::Method::READ, path) {
+            self.truncate(data);
+        }
+        Ok(())
+    }
+}
+
+impl ShortIoInjector {
+    pub fn build(conf: ShortIoConfig) -> anyhow::Result<Self> {
+        trace!("build short io injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            min_length: conf.min_length.max(1),
+        })
+    }
+
+    // truncate keeps at least `min_length` bytes, as an empty transfer means
+    // the end of the file to a read and no progress to a write
+    fn truncate(&self, data: &mut Vec<u8>) {
+        if data.len() <= self.min_length {
+            return;
+        }
+        let length = rng::with(|rng| rng.gen_range(self.min_length, data.len()));
+        debug!(
+            "inject short transfer of {} of {} bytes",
+            length,
+            data.len()
+        );
+        data.truncate(length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build(min_length: usize) -> ShortIoInjector {
+        let conf = format!(r#"{{"percent":100,"minLength":{}}}"#, min_length);
+        ShortIoInjector::build(serde_json::from_str(&conf).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_truncate_sizes() {
+        let injector = build(4);
+        for _ in 0..64 {
+            let mut data = vec![0; 16];
+            injector.truncate(&mut data);
+            assert!(data.len() >= 4 && data.len() < 16);
+        }
+
+        // the short transfers are left as they are
+        let mut data = vec![0; 4];
+        injector.truncate(&mut data);
+        assert_eq!(data.len(), 4);
+        let mut data = Vec::new();
+        injector.truncate(&mut data);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_transfer_one_byte_at_least() {
+        let injector = build(0);
+        for _ in 0..64 {
+            let mut data = vec![0; 2];
+            injector.truncate(&mut data);
+            assert_eq!(data.len(), 1);
+        }
+    }
+}
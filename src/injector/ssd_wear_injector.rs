@@ -0,0 +1,98 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+use super::injector_config::SsdWearConfig;
+use super::{drain, filter, Injector, IoContext};
+use crate::hookfs::Result;
+
+// SsdWearInjector counts the bytes written through the mount. Once the
+// threshold is crossed, it emulates the garbage collection of a worn SSD:
+// every write is throttled to the sustained throughput and a long stall is
+// injected once per interval.
+#[derive(Debug)]
+pub struct SsdWearInjector {
+    filter: filter::Filter,
+
+    threshold: u64,
+    stall: Duration,
+    stall_interval: Duration,
+    throughput: Option<u64>,
+
+    written: AtomicU64,
+    last_stall: Mutex<Option<Instant>>,
+    cancel_token: CancellationToken,
+}
+
+#[async_trait]
+impl Injector for SsdWearInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        if !method.contains(filter::Method::WRITE) {
+            return Ok(());
+        }
+
+        let written = self.written.fetch_add(io.size, Ordering::SeqCst) + io.size;
+        trace!("{} bytes written", written);
+        if written < self.threshold || !self.filter.filter(method, path) {
+            return Ok(());
+        }
+
+        let mut latency = match self.throughput {
+            Some(throughput) if throughput > 0 => {
+                Duration::from_secs_f64(io.size as f64 / throughput as f64)
+            }
+            _ => Duration::from_secs(0),
+        };
+        {
+            let now = Instant::now();
+            let mut last_stall = self.last_stall.lock().unwrap();
+            let should_stall = match *last_stall {
+                Some(last_stall) => now.duration_since(last_stall) >= self.stall_interval,
+                None => true,
+            };
+            if should_stall {
+                debug!("inject gc stall");
+                *last_stall = Some(now);
+                latency += self.stall;
+            }
+        }
+
+        if latency > Duration::from_secs(0) {
+            debug!("inject write delay {:?}", latency);
+            drain::delay(latency, &self.cancel_token).await?;
+        }
+
+        Ok(())
+    }
+
+    fn interrupt(&self) {
+        debug!("interrupt ssd wear");
+        self.cancel_token.cancel();
+    }
+}
+
+impl SsdWearInjector {
+    pub fn build(conf: SsdWearConfig) -> anyhow::Result<Self> {
+        trace!("build ssd wear injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            threshold: conf.threshold,
+            stall: conf.stall,
+            stall_interval: conf.stall_interval,
+            throughput: conf.throughput,
+            written: AtomicU64::new(0),
+            last_stall: Mutex::new(None),
+            cancel_token: CancellationToken::new(),
+        })
+    }
+}
@@ -0,0 +1,59 @@
+use std::path::Path;
+
+use async_trait::async_trait;
+use tracing::{debug, trace};
+
+use super::injector_config::StatfsConfig;
+use super::{filter, Injector};
+use crate::hookfs::{Reply, Result};
+
+// StatfsInjector rewrites the free space reported by statfs, so that the
+// preflight checks of an application see an almost full disk while the writes
+// still succeed. The free counts are capped by the totals of the real
+// filesystem.
+#[derive(Debug)]
+pub struct StatfsInjector {
+    filter: filter::Filter,
+    free_blocks: Option<u64>,
+    free_inodes: Option<u64>,
+}
+
+#[async_trait]
+impl Injector for StatfsInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, reply: &mut Reply) -> Result<()> {
+        let method = *method & filter::Method::STATFS;
+        if method.is_empty() || !self.filter.filter(&method, path) {
+            return Ok(());
+        }
+
+        if let Reply::StatFs(statfs) = reply {
+            if let Some(free) = self.free_blocks {
+                statfs.bfree = free.min(statfs.blocks);
+                statfs.bavail = free.min(statfs.blocks);
+            }
+            if let Some(free) = self.free_inodes {
+                statfs.ffree = free.min(statfs.files);
+            }
+            debug!(
+                "report {} free blocks and {} free inodes",
+                statfs.bavail, statfs.ffree
+            );
+        }
+        Ok(())
+    }
+}
+
+impl StatfsInjector {
+    pub fn build(conf: StatfsConfig) -> anyhow::Result<Self> {
+        trace!("build statfs injector");
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            free_blocks: conf.free_blocks,
+            free_inodes: conf.free_inodes,
+        })
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+use super::injector_config::ThrottleConfig;
+use super::{drain, filter, Injector, IoContext};
+use crate::hookfs::Result;
+
+// Bucket holds up to `burst` bytes, refilled at `rate` bytes per second. An
+// operation takes its size from the bucket even if it isn't full enough, and
+// waits until the debt is paid back, so the operations are delayed in the
+// order they arrive and a large one doesn't wait forever. The bytes of an
+// operation which isn't done are given back.
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: Option<u64>) -> Self {
+        let burst = burst.unwrap_or(rate) as f64;
+        Self {
+            rate: rate as f64,
+            burst,
+            tokens: burst,
+            last: Instant::now(),
+        }
+    }
+
+    // take returns how long the operation of `size` bytes, arriving at `now`,
+    // has to wait
+    fn take(&mut self, size: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = self.last.max(now);
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst) - size as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    fn give_back(&mut self, size: u64) {
+        self.tokens = (self.tokens + size as f64).min(self.burst);
+    }
+}
+
+// ThrottleInjector caps the throughput of the reads and writes, to emulate a
+// saturated disk. The reads and the writes have their own budgets.
+#[derive(Debug)]
+pub struct ThrottleInjector {
+    filter: filter::Filter,
+
+    read: Option<Mutex<Bucket>>,
+    write: Option<Mutex<Bucket>>,
+    // the ios which took from a bucket, by fh, offset and size, with whether
+    // they are writes, until they are done
+    taken: Mutex<HashMap<(u64, i64, u64), Vec<bool>>>,
+    cancel_token: CancellationToken,
+}
+
+#[async_trait]
+impl Injector for ThrottleInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        let write = if method.contains(filter::Method::READ) {
+            false
+        } else if method.contains(filter::Method::WRITE) {
+            true
+        } else {
+            return Ok(());
+        };
+        let bucket = match self.bucket(write) {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        if !self.filter.filter(method, path) {
+            return Ok(());
+        }
+
+        let latency = bucket.lock().unwrap().take(io.size, Instant::now());
+        self.taken
+            .lock()
+            .unwrap()
+            .entry((io.fh, io.offset, io.size))
+            .or_default()
+            .push(write);
+        if latency > Duration::from_secs(0) {
+            debug!(
+                "throttle {:?} of {} bytes for {:?}",
+                method, io.size, latency
+            );
+            drain::delay(latency, &self.cancel_token).await?;
+        }
+
+        Ok(())
+    }
+
+    fn io_done(&self, io: &IoContext, done: u64) {
+        let key = (io.fh, io.offset, io.size);
+        let mut taken = self.taken.lock().unwrap();
+        let write = match taken.get_mut(&key).and_then(|writes| writes.pop()) {
+            Some(write) => write,
+            // the io hasn't taken from a bucket
+            None => return,
+        };
+        if taken.get(&key).map_or(false, |writes| writes.is_empty()) {
+            taken.remove(&key);
+        }
+        drop(taken);
+
+        let undone = io.size.saturating_sub(done);
+        if let (Some(bucket), true) = (self.bucket(write), undone > 0) {
+            trace!("give back {} bytes", undone);
+            bucket.lock().unwrap().give_back(undone);
+        }
+    }
+
+    fn interrupt(&self) {
+        debug!("interrupt throttle");
+        self.cancel_token.cancel();
+    }
+}
+
+impl ThrottleInjector {
+    fn bucket(&self, write: bool) -> Option<&Mutex<Bucket>> {
+        if write {
+            self.write.as_ref()
+        } else {
+            self.read.as_ref()
+        }
+    }
+
+    pub fn build(conf: ThrottleConfig) -> anyhow::Result<Self> {
+        trace!("build throttle injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            read: conf
+                .read_bytes_per_second
+                .map(|rate| Mutex::new(Bucket::new(rate, conf.burst))),
+            write: conf
+                .write_bytes_per_second
+                .map(|rate| Mutex::new(Bucket::new(rate, conf.burst))),
+            taken: Mutex::new(HashMap::new()),
+            cancel_token: CancellationToken::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn test_bucket_take() {
+        let mut bucket = Bucket::new(100, Some(50));
+        let start = bucket.last;
+        assert_eq!(bucket.take(50, start), Duration::from_secs(0));
+        // the debt is paid back at the rate
+        assert_eq!(bucket.take(50, start), Duration::from_millis(500));
+        assert_eq!(bucket.take(0, start), Duration::from_millis(500));
+        assert_eq!(
+            bucket.take(0, start + Duration::from_millis(250)),
+            Duration::from_millis(250)
+        );
+
+        // the bucket holds no more than the burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(50, later), Duration::from_secs(0));
+        assert_eq!(bucket.take(1, later), Duration::from_millis(10));
+
+        bucket.give_back(1);
+        assert_eq!(bucket.take(0, later), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_give_back_undone() {
+        let conf = r#"{"percent":100,"readBytesPerSecond":1,"burst":10}"#;
+        let injector = ThrottleInjector::build(serde_json::from_str(conf).unwrap()).unwrap();
+        let io = IoContext {
+            fh: 0,
+            fd: -1,
+            offset: 0,
+            size: 10,
+        };
+        let tokens = || injector.read.as_ref().unwrap().lock().unwrap().tokens;
+
+        block_on(injector.inject_io(&filter::Method::READ, Path::new("/file"), &io)).unwrap();
+        assert!(tokens() < 1.0);
+        injector.io_done(&io, 4);
+        assert!(tokens() >= 6.0 && tokens() < 7.0);
+        assert!(injector.taken.lock().unwrap().is_empty());
+
+        // an io which isn't taken from the bucket gives nothing back
+        injector.io_done(&io, 0);
+        assert!(tokens() < 7.0);
+    }
+
+    #[test]
+    fn test_reads_done() {
+        let conf = r#"{"percent":100,"readBytesPerSecond":1,"burst":30}"#;
+        let injector = ThrottleInjector::build(serde_json::from_str(conf).unwrap()).unwrap();
+        let tokens = || injector.read.as_ref().unwrap().lock().unwrap().tokens;
+        let read = |offset| IoContext {
+            fh: 0,
+            fd: -1,
+            offset,
+            size: 10,
+        };
+
+        // a read is done in full, short at the end of the file, or fails, and
+        // is forgotten either way
+        for (offset, done) in [(0, 10), (10, 3), (20, 0)].iter() {
+            let io = read(*offset);
+            block_on(injector.inject_io(&filter::Method::READ, Path::new("/file"), &io)).unwrap();
+            injector.io_done(&io, *done);
+        }
+        assert!(injector.taken.lock().unwrap().is_empty());
+        assert!(tokens() >= 17.0 && tokens() < 18.0);
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+use super::injector_config::ToctouConfig;
+use super::{drain, filter, Injector};
+use crate::hookfs::{budget, Reply, Result};
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(1);
+
+// Expired checks are only dropped once there are more paths than this limit
+const CHECKS_GC_THRESHOLD: usize = 4096;
+
+// the name of this subsystem in the memory budget
+const SUBSYSTEM: &str = "toctou";
+
+// the memory accounted for every check, besides its path
+const CHECK_COST: usize = 32;
+
+fn cost(path: &Path) -> usize {
+    path.as_os_str().len() + CHECK_COST
+}
+
+// ToctouInjector widens the window between a successful check (lookup or
+// getattr) of a path and its use (open or rename), by delaying the use.
+#[derive(Debug)]
+pub struct ToctouInjector {
+    filter: filter::Filter,
+    delay: Duration,
+    window: Duration,
+
+    // map from path to the time of its last successful check
+    checks: Mutex<HashMap<PathBuf, Instant>>,
+    cancel_token: CancellationToken,
+}
+
+#[async_trait]
+impl Injector for ToctouInjector {
+    async fn inject(&self, method: &filter::Method, path: &Path) -> Result<()> {
+        if !method.intersects(filter::Method::OPEN | filter::Method::RENAME) {
+            return Ok(());
+        }
+
+        let checked = match self.checks.lock().unwrap().remove(path) {
+            Some(checked) => {
+                budget::release(SUBSYSTEM, cost(path));
+                checked.elapsed() <= self.window
+            }
+            None => false,
+        };
+        if !checked || !self.filter.filter(method, path) {
+            return Ok(());
+        }
+
+        debug!("delay the use of {} for {:?}", path.display(), self.delay);
+        drain::delay(self.delay, &self.cancel_token).await
+    }
+
+    fn inject_reply(&self, method: &filter::Method, path: &Path, _: &mut Reply) -> Result<()> {
+        if !method.intersects(filter::Method::LOOKUP | filter::Method::GETATTR) {
+            return Ok(());
+        }
+
+        trace!("record the check of {}", path.display());
+        let mut checks = self.checks.lock().unwrap();
+        if checks.len() > CHECKS_GC_THRESHOLD {
+            let window = self.window;
+            checks.retain(|path, checked| {
+                let expired = checked.elapsed() > window;
+                if expired {
+                    budget::release(SUBSYSTEM, cost(path));
+                }
+                !expired
+            });
+        }
+        if let Some(checked) = checks.get_mut(path) {
+            *checked = Instant::now();
+            return Ok(());
+        }
+
+        // the oldest checks are dropped once the memory budget is exceeded
+        while !budget::charge(SUBSYSTEM, cost(path)) {
+            budget::overflow()?;
+            let oldest = match checks.iter().min_by_key(|(_, checked)| **checked) {
+                Some((oldest, _)) => oldest.clone(),
+                None => return Ok(()),
+            };
+            checks.remove(&oldest);
+            budget::release(SUBSYSTEM, cost(&oldest));
+        }
+        checks.insert(path.to_owned(), Instant::now());
+
+        Ok(())
+    }
+
+    fn interrupt(&self) {
+        debug!("interrupt toctou");
+        self.cancel_token.cancel();
+    }
+}
+
+impl ToctouInjector {
+    pub fn build(conf: ToctouConfig) -> anyhow::Result<Self> {
+        trace!("build toctou injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            delay: conf.delay,
+            window: conf.window.unwrap_or(DEFAULT_WINDOW),
+            checks: Mutex::new(HashMap::new()),
+            cancel_token: CancellationToken::new(),
+        })
+    }
+}
+
+impl Drop for ToctouInjector {
+    fn drop(&mut self) {
+        let checks = self.checks.lock().unwrap();
+        budget::release(SUBSYSTEM, checks.keys().map(|path| cost(path)).sum());
+    }
+}
@@ -0,0 +1,134 @@
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use async_trait::async_trait;
+use rand::Rng;
+use tracing::{debug, info, trace};
+
+use super::injector_config::{TornMode, TornWriteConfig};
+use super::{filter, rng, Injector, IoContext};
+use crate::hookfs::Result;
+
+const DEFAULT_PAGE_SIZE: usize = 4096;
+
+// TornWriteInjector persists only a part of the matching writes, while the
+// whole write is acknowledged, like a crash in the middle of writing the pages
+// of a database. Either a random prefix is persisted, or a random subset of
+// the pages, which are aligned on the offset in the file.
+//
+// With `onCrash`, the writes are intact until the `crash` rpc, and torn from
+// then on.
+#[derive(Debug)]
+pub struct TornWriteInjector {
+    filter: filter::Filter,
+    mode: TornMode,
+    page_size: usize,
+
+    crashed: AtomicBool,
+}
+
+#[async_trait]
+impl Injector for TornWriteInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn tear_write(&self, path: &Path, io: &IoContext) -> Option<Vec<Range<usize>>> {
+        let size = io.size as usize;
+        if size == 0
+            || !self.crashed.load(Ordering::SeqCst)
+            || !self.filter.filter(&filter::Method::WRITE, path)
+        {
+            return None;
+        }
+
+        let ranges = match self.mode {
+            TornMode::Prefix => vec![0..rng::with(|rng| rng.gen_range(0, size))],
+            TornMode::Pages => {
+                let offset = io.offset.max(0) as usize;
+                let mut ranges = Vec::new();
+                let mut start = 0;
+                while start < size {
+                    let end =
+                        (start + self.page_size - (offset + start) % self.page_size).min(size);
+                    if rng::with(|rng| rng.gen_bool(0.5)) {
+                        ranges.push(start..end);
+                    }
+                    start = end;
+                }
+                ranges
+            }
+        };
+        debug!(
+            "persist {:?} of {} bytes written at {} of {}",
+            ranges,
+            size,
+            io.offset,
+            path.display()
+        );
+        Some(ranges)
+    }
+
+    fn crash(&self) {
+        info!("tear the writes from now on");
+        self.crashed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl TornWriteInjector {
+    pub fn build(conf: TornWriteConfig) -> anyhow::Result<Self> {
+        trace!("build torn write injector");
+
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            mode: conf.mode,
+            page_size: conf.page_size.unwrap_or(DEFAULT_PAGE_SIZE),
+            crashed: AtomicBool::new(!conf.on_crash),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tear_pages() {
+        let conf = r#"{"percent":100,"mode":"pages","pageSize":16}"#;
+        let injector = TornWriteInjector::build(serde_json::from_str(conf).unwrap()).unwrap();
+        let io = IoContext {
+            fh: 0,
+            fd: -1,
+            offset: 8,
+            size: 64 * 16,
+        };
+        let ranges = injector.tear_write(Path::new("/file"), &io).unwrap();
+
+        // the pages are aligned on the offset in the file, and some are lost
+        let persisted: usize = ranges.iter().map(|range| range.len()).sum();
+        assert!(persisted < io.size as usize);
+        for range in ranges {
+            assert!(range.start == 0 || (8 + range.start) % 16 == 0);
+            assert!(range.end == io.size as usize || (8 + range.end) % 16 == 0);
+        }
+    }
+
+    #[test]
+    fn test_intact_until_crash() {
+        let conf = r#"{"percent":100,"mode":"prefix","onCrash":true}"#;
+        let injector = TornWriteInjector::build(serde_json::from_str(conf).unwrap()).unwrap();
+        let io = IoContext {
+            fh: 0,
+            fd: -1,
+            offset: 0,
+            size: 16,
+        };
+        assert!(injector.tear_write(Path::new("/file"), &io).is_none());
+
+        injector.crash();
+        let ranges = injector.tear_write(Path::new("/file"), &io).unwrap();
+        assert_eq!(ranges.len(), 1);
+        assert!(ranges[0].start == 0 && ranges[0].end < 16);
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use nix::errno::Errno;
+use tracing::{debug, info, trace};
+
+use super::injector_config::ZonedConfig;
+use super::{filter, Injector, IoContext};
+use crate::hookfs::{Error, Result};
+
+// ZonedInjector emulates the sequential write requirement of zoned storage.
+// Every zone has a write pointer starting at the beginning of the zone, and a
+// write is only accepted at the write pointer. The pointers move back to the
+// start of their zones once `reset_zones` is called.
+#[derive(Debug)]
+pub struct ZonedInjector {
+    filter: filter::Filter,
+    // the byte ranges of the zones
+    zones: Vec<Range<u64>>,
+
+    // map from (path, zone index) to the write pointer
+    write_pointers: Mutex<HashMap<(PathBuf, usize), u64>>,
+}
+
+#[async_trait]
+impl Injector for ZonedInjector {
+    async fn inject(&self, _: &filter::Method, _: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    async fn inject_io(&self, method: &filter::Method, path: &Path, io: &IoContext) -> Result<()> {
+        if !method.contains(filter::Method::WRITE) || !self.filter.filter(method, path) {
+            return Ok(());
+        }
+
+        let offset = io.offset as u64;
+        let (index, zone) = match self
+            .zones
+            .iter()
+            .enumerate()
+            .find(|(_, zone)| zone.contains(&offset))
+        {
+            Some(zone) => zone,
+            None => return Ok(()),
+        };
+
+        if offset
+            .checked_add(io.size)
+            .map_or(true, |end| end > zone.end)
+        {
+            debug!("write across the end of zone {}", index);
+            return Err(Error::Sys(Errno::EINVAL));
+        }
+
+        let mut write_pointers = self.write_pointers.lock().unwrap();
+        let write_pointer = write_pointers
+            .entry((path.to_owned(), index))
+            .or_insert(zone.start);
+        if offset != *write_pointer {
+            debug!(
+                "write at {} in zone {} while the write pointer is at {}",
+                offset, index, write_pointer
+            );
+            return Err(Error::Sys(Errno::EINVAL));
+        }
+
+        *write_pointer += io.size;
+        trace!("move write pointer of zone {} to {}", index, write_pointer);
+
+        Ok(())
+    }
+
+    fn reset_zones(&self) {
+        info!("reset all zones");
+        self.write_pointers.lock().unwrap().clear();
+    }
+}
+
+impl ZonedInjector {
+    pub fn build(conf: ZonedConfig) -> anyhow::Result<Self> {
+        trace!("build zoned injector");
+
+        let zones = conf
+            .zones
+            .iter()
+            .map(|zone| {
+                let end = zone.start.checked_add(zone.length).ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "zone of {} bytes at {} is beyond the largest offset",
+                        zone.length,
+                        zone.start
+                    )
+                })?;
+                Ok(zone.start..end)
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            filter: filter::Filter::build(conf.filter)?,
+            zones,
+            write_pointers: Mutex::new(HashMap::new()),
+        })
+    }
+}
@@ -1,31 +1,175 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
 use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use jsonrpc_derive::rpc;
 use jsonrpc_stdio_server::jsonrpc_core::*;
 use jsonrpc_stdio_server::ServerBuilder;
-use tracing::{info, trace};
+use nix::sys::socket::{
+    bind, getsockopt, listen, socket, sockopt, AddressFamily, SockAddr, SockFlag, SockType,
+    UnixAddr,
+};
+use nix::unistd::geteuid;
+use tracing::{error, info, trace};
 
+use crate::coordination::{self, Coordinator};
 use crate::hookfs::HookFs;
-use crate::injector::{InjectorConfig, MultiInjector};
+use crate::injector::{
+    rng, Experiment, Experiments, ExpiryEvent, Injector, InjectorConfig, MultiInjector,
+    DEFAULT_EXPERIMENT,
+};
+
+// the interval to remove the expired experiments and rules
+const PRUNE_INTERVAL: Duration = Duration::from_millis(500);
+
+// the oldest events are dropped once there are more pending events than this
+const MAX_PENDING_EVENTS: usize = 1024;
+
+// the experiment toggled by the primary switch
+pub const PRIMARY_EXPERIMENT: &str = "primary";
+
+// the experiment of the rules loaded from the file, apart from the default one
+// replaced by the update rpc
+pub const CONFIG_EXPERIMENT: &str = "config";
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Comm {
     Shutdown = 0,
+    // recover the mount and exit, like on SIGTERM
+    Recover = 1,
 }
 
-pub async fn start_server(config: RpcImpl) {
+// the backlog of the unix socket
+const SOCKET_BACKLOG: usize = 128;
+
+pub async fn start_server(io: IoHandler) {
     info!("Starting jsonrpc server");
-    let server = new_server(config);
+    let server = new_server(io);
     let server = server.build();
     server.await;
 }
 
-pub fn new_server(config: RpcImpl) -> ServerBuilder {
+pub fn new_server(io: IoHandler) -> ServerBuilder {
     info!("Creating jsonrpc server");
-    let io = new_handler(config);
     ServerBuilder::new(io)
 }
 
+// UnixSocket is bound to serve the jsonrpc on. The socket is in the abstract
+// namespace if the path starts with '@', and the socket file is removed
+// otherwise once it's dropped.
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: Option<PathBuf>,
+}
+
+impl UnixSocket {
+    // serve answers the requests on the socket, one per line like on the
+    // standard input, so that toda can be controlled without its standard
+    // input
+    pub fn serve(&self, io: IoHandler) -> anyhow::Result<JoinHandle<()>> {
+        Ok(serve_unix(self.listener.try_clone()?, io))
+    }
+}
+
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path {
+            if let Err(err) = std::fs::remove_file(path) {
+                error!("fail to remove socket {}: {:?}", path.display(), err);
+            }
+        }
+    }
+}
+
+fn serve_unix(listener: UnixListener, io: IoHandler) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("fail to accept jsonrpc connection: {:?}", err);
+                    continue;
+                }
+            };
+            let io = io.clone();
+            std::thread::spawn(move || {
+                if let Err(err) = handle_unix(stream, &io) {
+                    error!("fail to handle jsonrpc connection: {:?}", err);
+                }
+            });
+        }
+    })
+}
+
+// bind_unix binds the socket to serve the jsonrpc on
+pub fn bind_unix(path: &str) -> anyhow::Result<UnixSocket> {
+    info!("listen for jsonrpc on {}", path);
+    let name = match path.strip_prefix('@') {
+        Some(name) => name,
+        None => {
+            // the socket left by an earlier run is replaced
+            if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                if metadata.file_type().is_socket() {
+                    std::fs::remove_file(path)?;
+                }
+            }
+            return Ok(UnixSocket {
+                listener: UnixListener::bind(path)?,
+                path: Some(PathBuf::from(path)),
+            });
+        }
+    };
+
+    let fd = socket(
+        AddressFamily::Unix,
+        SockType::Stream,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // the listener owns the fd, so that it's closed on errors
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    bind(
+        fd,
+        &SockAddr::Unix(UnixAddr::new_abstract(name.as_bytes())?),
+    )?;
+    listen(fd, SOCKET_BACKLOG)?;
+    Ok(UnixSocket {
+        listener,
+        path: None,
+    })
+}
+
+fn handle_unix(stream: UnixStream, io: &IoHandler) -> anyhow::Result<()> {
+    // anyone can connect to an abstract socket, so only root and the user of
+    // toda are answered
+    let peer = getsockopt(stream.as_raw_fd(), sockopt::PeerCredentials)?;
+    if peer.uid() != 0 && peer.uid() != geteuid().as_raw() {
+        return Err(anyhow::anyhow!(
+            "refuse jsonrpc connection from uid {}",
+            peer.uid()
+        ));
+    }
+
+    let reader = BufReader::new(stream.try_clone()?);
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // notifications have no response
+        if let Some(mut response) = io.handle_request_sync(&line) {
+            response.push('\n');
+            (&stream).write_all(response.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
 pub fn new_handler(config: RpcImpl) -> IoHandler {
     info!("Creating jsonrpc handler");
     let mut io = IoHandler::new();
@@ -39,12 +183,50 @@ pub trait Rpc {
     fn get_status(&self, inst: String) -> Result<String>;
     #[rpc(name = "update")]
     fn update(&self, config: Vec<InjectorConfig>) -> Result<String>;
+    #[rpc(name = "reset_zones")]
+    fn reset_zones(&self) -> Result<String>;
+    #[rpc(name = "reset_space")]
+    fn reset_space(&self) -> Result<String>;
+    #[rpc(name = "reconnect")]
+    fn reconnect(&self) -> Result<String>;
+    #[rpc(name = "resume")]
+    fn resume(&self) -> Result<String>;
+    #[rpc(name = "crash")]
+    fn crash(&self) -> Result<String>;
+    #[rpc(name = "get_checksum_report")]
+    fn get_checksum_report(&self) -> Result<String>;
+    #[rpc(name = "get_seed")]
+    fn get_seed(&self) -> Result<u64>;
+    #[rpc(name = "start_experiment")]
+    fn start_experiment(
+        &self,
+        name: String,
+        config: Vec<InjectorConfig>,
+        ttl: Option<String>,
+    ) -> Result<String>;
+    #[rpc(name = "stop_experiment")]
+    fn stop_experiment(&self, name: String) -> Result<String>;
+    #[rpc(name = "list_experiments")]
+    fn list_experiments(&self) -> Result<String>;
+    #[rpc(name = "poll_events")]
+    fn poll_events(&self) -> Result<String>;
+    #[rpc(name = "invalidate_cache")]
+    fn invalidate_cache(&self, path: Option<PathBuf>) -> Result<String>;
+    #[rpc(name = "recover")]
+    fn recover(&self) -> Result<String>;
 }
 
+// Mounts share the control plane, which installs the experiments on every one
+// of them. The mount of --path is the first one.
+type Mounts = Arc<Mutex<Vec<Arc<HookFs>>>>;
+
 pub struct RpcImpl {
     status: Mutex<anyhow::Result<()>>,
     tx: Mutex<mpsc::Sender<Comm>>,
-    hookfs: Option<Arc<HookFs>>,
+    mounts: Mounts,
+    coordinator: Option<Coordinator>,
+    experiments: Arc<Mutex<Experiments>>,
+    events: Arc<Mutex<Vec<ExpiryEvent>>>,
 }
 
 impl RpcImpl {
@@ -53,10 +235,224 @@ impl RpcImpl {
         tx: Mutex<mpsc::Sender<Comm>>,
         hookfs: Option<Arc<HookFs>>,
     ) -> Self {
-        Self { status, tx, hookfs }
+        let experiments = Arc::new(Mutex::new(Experiments::default()));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mounts = Arc::new(Mutex::new(hookfs.into_iter().collect::<Vec<_>>()));
+        if !mounts.lock().unwrap().is_empty() {
+            spawn_pruner(mounts.clone(), &experiments, &events);
+        }
+
+        Self {
+            status,
+            tx,
+            mounts,
+            coordinator: None,
+            experiments,
+            events,
+        }
+    }
+
+    // with_coordinator makes this instance the leader, which forwards every
+    // update to the followers
+    pub fn with_coordinator(mut self, coordinator: Coordinator) -> Self {
+        self.coordinator = Some(coordinator);
+        self
+    }
+
+    // mount adds another mount sharing the control plane. Its rules run as
+    // an experiment on it alone, named "mount:<path>", beside the experiments
+    // running on every mount.
+    pub fn mount(&self, hookfs: Arc<HookFs>, config: Vec<InjectorConfig>) -> anyhow::Result<()> {
+        let path = hookfs.label().path.clone();
+        info!("share the control plane with {}", path.display());
+        let mut experiments = self.experiments.lock().unwrap();
+        if !config.is_empty() {
+            experiments.start(Experiment::build_on(
+                format!("mount:{}", path.display()),
+                config,
+                None,
+                Some(path),
+            )?);
+        }
+        self.mounts.lock().unwrap().push(hookfs);
+        self.install(&mut experiments);
+        Ok(())
+    }
+
+    // primary_switch returns the switch to start and stop the primary rule
+    // set as an experiment beside the ones managed through rpc
+    pub fn primary_switch(&self, config: Vec<InjectorConfig>) -> Option<PrimarySwitch> {
+        Some(PrimarySwitch {
+            config,
+            mounts: self.mounted()?,
+            experiments: self.experiments.clone(),
+            events: self.events.clone(),
+        })
+    }
+
+    // config_loader returns the loader to replace the rules of the experiment
+    // without the rpc, e.g. from a file or from the coordination leader
+    pub fn config_loader(&self, name: &str) -> Option<ConfigLoader> {
+        Some(ConfigLoader {
+            name: name.to_owned(),
+            mounts: self.mounted()?,
+            experiments: self.experiments.clone(),
+            events: self.events.clone(),
+        })
+    }
+
+    // mounted returns the mounts, unless the injection has failed
+    fn mounted(&self) -> Option<Mounts> {
+        if self.mounts.lock().unwrap().is_empty() {
+            return None;
+        }
+        Some(self.mounts.clone())
+    }
+
+    fn install(&self, experiments: &mut Experiments) {
+        install(&self.mounts, experiments, &self.events);
+    }
+
+    // each_injector runs the function on the injector of every mount
+    fn each_injector(&self, f: impl Fn(&MultiInjector)) {
+        for hookfs in self.mounts.lock().unwrap().iter() {
+            futures::executor::block_on(async {
+                f(&*hookfs.injector.read().await);
+            });
+        }
+    }
+}
+
+// PrimarySwitch toggles the primary rule set without the rpc, e.g. by signals
+pub struct PrimarySwitch {
+    config: Vec<InjectorConfig>,
+    mounts: Mounts,
+    experiments: Arc<Mutex<Experiments>>,
+    events: Arc<Mutex<Vec<ExpiryEvent>>>,
+}
+
+impl PrimarySwitch {
+    pub fn enable(&self) -> anyhow::Result<()> {
+        info!("enable primary rule set");
+        let experiment =
+            Experiment::build(PRIMARY_EXPERIMENT.to_owned(), self.config.clone(), None)?;
+        let mut experiments = self.experiments.lock().unwrap();
+        experiments.start(experiment);
+        install(&self.mounts, &mut experiments, &self.events);
+        Ok(())
+    }
+
+    pub fn disable(&self) -> anyhow::Result<()> {
+        info!("disable primary rule set");
+        let mut experiments = self.experiments.lock().unwrap();
+        experiments.stop(PRIMARY_EXPERIMENT)?;
+        install(&self.mounts, &mut experiments, &self.events);
+        Ok(())
+    }
+}
+
+// ConfigLoader replaces the rules of one experiment, like the update rpc
+pub struct ConfigLoader {
+    name: String,
+    mounts: Mounts,
+    experiments: Arc<Mutex<Experiments>>,
+    events: Arc<Mutex<Vec<ExpiryEvent>>>,
+}
+
+impl ConfigLoader {
+    pub fn load(&self, config: Vec<InjectorConfig>) -> anyhow::Result<()> {
+        info!("load {} rules", config.len());
+        let experiment = self.prepare(config)?;
+        self.start(experiment);
+        Ok(())
+    }
+
+    // prepare validates the config and builds the experiment, without
+    // starting it
+    pub fn prepare(&self, config: Vec<InjectorConfig>) -> anyhow::Result<Experiment> {
+        Experiment::build(self.name.clone(), config, None)
+    }
+
+    pub fn start(&self, experiment: Experiment) {
+        let mut experiments = self.experiments.lock().unwrap();
+        experiments.start(experiment);
+        install(&self.mounts, &mut experiments, &self.events);
+    }
+
+    // rules returns the config of the experiment, if it's running
+    pub fn rules(&self) -> Option<Vec<InjectorConfig>> {
+        let experiments = self.experiments.lock().unwrap();
+        experiments
+            .get(&self.name)
+            .map(|experiment| experiment.config().to_vec())
+    }
+
+    // restore starts the experiment with the config again, or stops it if
+    // it wasn't running
+    pub fn restore(&self, config: Option<Vec<InjectorConfig>>) -> anyhow::Result<()> {
+        match config {
+            Some(config) => self.load(config),
+            None => {
+                let mut experiments = self.experiments.lock().unwrap();
+                experiments.stop(&self.name)?;
+                install(&self.mounts, &mut experiments, &self.events);
+                Ok(())
+            }
+        }
     }
 }
 
+// install replaces the injector of every mount with the experiments running
+// on it
+fn install(
+    mounts: &Mutex<Vec<Arc<HookFs>>>,
+    experiments: &mut Experiments,
+    events: &Mutex<Vec<ExpiryEvent>>,
+) {
+    record_events(events, experiments.prune());
+    for hookfs in mounts.lock().unwrap().iter() {
+        let injector = experiments.injector(&hookfs.label().path);
+        futures::executor::block_on(async {
+            *hookfs.injector.write().await = injector;
+        });
+    }
+}
+
+fn record_events(events: &Mutex<Vec<ExpiryEvent>>, expired: Vec<ExpiryEvent>) {
+    let mut events = events.lock().unwrap();
+    events.extend(expired);
+    if events.len() > MAX_PENDING_EVENTS {
+        let dropped = events.len() - MAX_PENDING_EVENTS;
+        events.drain(..dropped);
+    }
+}
+
+// spawn_pruner removes the expired experiments and rules in the background,
+// until the rpc handler is dropped
+fn spawn_pruner(
+    mounts: Mounts,
+    experiments: &Arc<Mutex<Experiments>>,
+    events: &Arc<Mutex<Vec<ExpiryEvent>>>,
+) {
+    let experiments = Arc::downgrade(experiments);
+    let events = Arc::downgrade(events);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(PRUNE_INTERVAL);
+        let (experiments, events) = match (experiments.upgrade(), events.upgrade()) {
+            (Some(experiments), Some(events)) => (experiments, events),
+            _ => break,
+        };
+
+        let mut experiments = experiments.lock().unwrap();
+        let expired = experiments.prune();
+        if expired.is_empty() {
+            continue;
+        }
+        record_events(&events, expired);
+        install(&mounts, &mut experiments, &events);
+    });
+}
+
 impl Drop for RpcImpl {
     fn drop(&mut self) {
         trace!("Dropping jrpc handler");
@@ -81,15 +477,165 @@ impl Rpc for RpcImpl {
         if let Err(e) = &*self.status.lock().unwrap() {
             return Ok(e.to_string());
         }
-        let injectors = MultiInjector::build(config);
-        if let Err(e) = &injectors {
+        let experiment = Experiment::build(DEFAULT_EXPERIMENT.to_owned(), config.clone(), None);
+        if let Err(e) = &experiment {
             return Ok(e.to_string());
         }
-        futures::executor::block_on(async {
-            let hookfs = self.hookfs.as_ref().unwrap();
-            let mut current_injectors = hookfs.injector.write().await;
-            *current_injectors = injectors.unwrap();
+        if let Some(coordinator) = &self.coordinator {
+            match coordinator.broadcast(&config) {
+                Ok(at) => coordination::wait_until(at),
+                Err(e) => return Ok(e.to_string()),
+            }
+        }
+        let mut experiments = self.experiments.lock().unwrap();
+        experiments.start(experiment.unwrap());
+        self.install(&mut experiments);
+        Ok("ok".to_string())
+    }
+    fn reset_zones(&self) -> Result<String> {
+        info!("rpc reset_zones called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        self.each_injector(|injector| injector.reset_zones());
+        Ok("ok".to_string())
+    }
+    fn reset_space(&self) -> Result<String> {
+        info!("rpc reset_space called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        self.each_injector(|injector| injector.reset_space());
+        Ok("ok".to_string())
+    }
+    fn reconnect(&self) -> Result<String> {
+        info!("rpc reconnect called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        self.each_injector(|injector| injector.reconnect());
+        Ok("ok".to_string())
+    }
+    fn resume(&self) -> Result<String> {
+        info!("rpc resume called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        self.each_injector(|injector| injector.resume());
+        Ok("ok".to_string())
+    }
+    fn crash(&self) -> Result<String> {
+        info!("rpc crash called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        self.each_injector(|injector| injector.crash());
+        Ok("ok".to_string())
+    }
+    fn get_seed(&self) -> Result<u64> {
+        info!("rpc get_seed called");
+        Ok(rng::seed())
+    }
+    fn get_checksum_report(&self) -> Result<String> {
+        info!("rpc get_checksum_report called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        // the other mounts log their reports on recovery
+        let report = self
+            .mounts
+            .lock()
+            .unwrap()
+            .first()
+            .and_then(|hookfs| hookfs.checksum_report());
+        match report {
+            Some(report) => serde_json::to_string(&report).map_err(|_| Error::internal_error()),
+            None => Ok("checksum verification is disabled".to_string()),
+        }
+    }
+    fn start_experiment(
+        &self,
+        name: String,
+        config: Vec<InjectorConfig>,
+        ttl: Option<String>,
+    ) -> Result<String> {
+        info!("rpc start_experiment called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        let ttl = match ttl.map(|ttl| humantime::parse_duration(&ttl)).transpose() {
+            Ok(ttl) => ttl,
+            Err(e) => return Ok(e.to_string()),
+        };
+        let experiment = match Experiment::build(name, config, ttl) {
+            Ok(experiment) => experiment,
+            Err(e) => return Ok(e.to_string()),
+        };
+        let mut experiments = self.experiments.lock().unwrap();
+        experiments.start(experiment);
+        self.install(&mut experiments);
+        Ok("ok".to_string())
+    }
+    fn stop_experiment(&self, name: String) -> Result<String> {
+        info!("rpc stop_experiment called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        let mut experiments = self.experiments.lock().unwrap();
+        if let Err(e) = experiments.stop(&name) {
+            return Ok(e.to_string());
+        }
+        self.install(&mut experiments);
+        Ok("ok".to_string())
+    }
+    fn list_experiments(&self) -> Result<String> {
+        info!("rpc list_experiments called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        let experiments = self.experiments.lock().unwrap();
+        serde_json::to_string(&experiments.status()).map_err(|_| Error::internal_error())
+    }
+    fn poll_events(&self) -> Result<String> {
+        info!("rpc poll_events called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        let events: Vec<_> = self.events.lock().unwrap().drain(..).collect();
+        serde_json::to_string(&events).map_err(|_| Error::internal_error())
+    }
+    // invalidate_cache drops the page cache of the files under the path, or
+    // the whole mount, and returns the count of them
+    fn invalidate_cache(&self, path: Option<PathBuf>) -> Result<String> {
+        info!("rpc invalidate_cache called");
+        if let Err(e) = &*self.status.lock().unwrap() {
+            return Ok(e.to_string());
+        }
+        // the path is in the mount containing it, and every mount is
+        // invalidated without a path
+        let mounts = self.mounts.lock().unwrap().clone();
+        let result = futures::executor::block_on(async {
+            let mut count = 0;
+            for hookfs in mounts.iter() {
+                match &path {
+                    Some(path) if !path.starts_with(&hookfs.label().path) => continue,
+                    _ => count += hookfs.invalidate(path.as_deref()).await?,
+                }
+            }
+            Ok::<_, crate::hookfs::Error>(count)
         });
+        match result {
+            Ok(count) => serde_json::to_string(&count).map_err(|_| Error::internal_error()),
+            Err(e) => Ok(e.to_string()),
+        }
+    }
+    // recover asks toda to recover the mount and exit. It's accepted even if
+    // the injection has failed, in which case toda just exits.
+    fn recover(&self) -> Result<String> {
+        info!("rpc recover called");
+        let tx = &self.tx.lock().unwrap();
+        tx.send(Comm::Recover)
+            .map_err(|_| Error::internal_error())?;
         Ok("ok".to_string())
     }
 }
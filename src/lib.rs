@@ -19,13 +19,22 @@
 #![allow(clippy::or_fun_call)]
 #![allow(clippy::too_many_arguments)]
 
+pub mod audit;
+pub mod builder;
+pub mod cgroup;
+pub mod coordination;
+pub mod daemon;
 pub mod fuse_device;
 pub mod hookfs;
 pub mod injector;
 pub mod jsonrpc;
+pub mod manifest;
+pub mod metrics;
 pub mod mount;
 pub mod mount_injector;
+pub mod namespace;
 pub mod ptrace;
 pub mod replacer;
+pub mod soak;
 pub mod stop;
 pub mod utils;
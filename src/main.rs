@@ -21,35 +21,37 @@
 
 extern crate derive_more;
 
-mod fuse_device;
-mod hookfs;
-mod injector;
-mod jsonrpc;
-mod mount;
-mod mount_injector;
-mod ptrace;
-mod replacer;
-mod stop;
-mod utils;
-
 use std::convert::TryFrom;
 use std::os::unix::io::RawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::{mpsc, Mutex};
+use std::time::Duration;
 use std::{io, thread};
 
-use anyhow::Result;
-use injector::InjectorConfig;
-use jsonrpc::start_server;
-use mount_injector::{MountInjectionGuard, MountInjector};
+use anyhow::{Context, Result};
+use nix::errno::Errno;
 use nix::sys::signal::{signal, SigHandler, Signal};
 use nix::unistd::{pipe, read, write};
-use replacer::{Replacer, UnionReplacer};
 use structopt::StructOpt;
+use toda::builder::{Toda, TodaBuilder};
+use toda::cgroup::Cgroup;
+use toda::coordination::Coordinator;
+use toda::daemon::{Pidfile, StopOptions};
+use toda::hookfs::budget::{self, OverflowPolicy};
+use toda::hookfs::runtime::{self, WorkerConfig};
+use toda::hookfs::{AllowAccess, KernelOptions};
+use toda::injector::{
+    decisions, rng, DrainPolicy, InjectorConfig, MultiInjector, DEFAULT_EXPERIMENT,
+};
+use toda::jsonrpc::{start_server, ConfigLoader, PrimarySwitch, CONFIG_EXPERIMENT};
+use toda::manifest::{Manifest, VerifyOptions};
+use toda::soak::{Soak, SoakOptions, SOAK_EXPERIMENT};
+use toda::utils::PathPattern;
+use toda::{audit, coordination, daemon, hookfs, jsonrpc, metrics, mount_injector, namespace};
 use tokio::runtime::Runtime;
-use tracing::{info, instrument};
+use tracing::{error, info};
 use tracing_subscriber::EnvFilter;
-use utils::encode_path;
 
 #[derive(StructOpt, Debug, Clone)]
 #[structopt(name = "basic")]
@@ -57,84 +59,296 @@ struct Options {
     #[structopt(long)]
     path: PathBuf,
 
+    /// Another path to inject into, as "<path>=<rules file>". Its rules are fixed on it, and the rules driven by the rpc run on every path
+    #[structopt(long = "mount")]
+    mounts: Vec<MountSpec>,
+
     #[structopt(long = "mount-only")]
     mount_only: bool,
 
+    /// Leave the working directory of the processes under the path, which bypasses the injection
+    #[structopt(long = "keep-cwd")]
+    keep_cwd: bool,
+
     #[structopt(short = "v", long = "verbose", default_value = "trace")]
     verbose: String,
-}
 
-#[instrument(skip(option))]
-fn inject(option: Options, injector_config: Vec<InjectorConfig>) -> Result<MountInjectionGuard> {
-    info!("inject with config {:?}", injector_config);
+    #[structopt(long = "coordinate-listen")]
+    coordinate_listen: Option<String>,
 
-    let path = option.path.clone();
+    /// Unix socket to serve the jsonrpc besides the standard input, in the abstract namespace if it starts with '@'
+    #[structopt(long = "rpc-socket")]
+    rpc_socket: Option<String>,
 
-    info!("canonicalizing path {}", path.display());
-    let path = path.canonicalize()?;
+    /// File to append a json line to for every operation a rule fires on or fails
+    #[structopt(long = "audit-log", parse(from_os_str))]
+    audit_log: Option<PathBuf>,
 
-    let replacer = if !option.mount_only {
-        let mut replacer = UnionReplacer::default();
-        replacer.prepare(&path, &path)?;
+    /// Inherited file descriptor to write a json line to for every operation a rule fires on or fails
+    #[structopt(long = "audit-fd", conflicts_with = "audit-log")]
+    audit_fd: Option<i32>,
 
-        Some(replacer)
-    } else {
-        None
-    };
+    /// File to record the failures injected by the rules to, for --replay-decisions
+    #[structopt(long = "record-decisions", parse(from_os_str))]
+    record_decisions: Option<PathBuf>,
 
-    if let Err(err) = fuse_device::mkfuse_node() {
-        info!("fail to make /dev/fuse node: {}", err)
-    }
+    /// File of the failures recorded by --record-decisions, injected again in place of the ones the rules decide on
+    #[structopt(
+        long = "replay-decisions",
+        parse(from_os_str),
+        conflicts_with = "record-decisions"
+    )]
+    replay_decisions: Option<PathBuf>,
+
+    /// Address to serve the prometheus metrics of the operations and of the rules on, at /metrics
+    #[structopt(long)]
+    metrics: Option<String>,
 
-    let mut injection = MountInjector::create_injection(&option.path, injector_config)?;
-    let mount_guard = injection.mount()?;
-    info!("mount successfully");
+    #[structopt(long = "coordinate-peers")]
+    coordinate_peers: Vec<String>,
 
-    if let Some(mut replacer) = replacer {
-        // At this time, `mount --move` has already been executed.
-        // Our FUSE are mounted on the "path", so we
-        replacer.run()?;
-        drop(replacer);
-        info!("replacer detached");
-    }
+    #[structopt(long = "coordinate-delay", default_value = "500ms", parse(try_from_str = humantime::parse_duration))]
+    coordinate_delay: Duration,
 
-    info!("enable injection");
-    mount_guard.enable_injection();
+    /// File of the token shared by the coordination leader and its followers, required by --coordinate-listen and --coordinate-peers
+    #[structopt(long = "coordinate-token-file", parse(from_os_str))]
+    coordinate_token_file: Option<PathBuf>,
 
-    Ok(mount_guard)
-}
+    #[structopt(long, parse(from_os_str))]
+    shadow: Option<PathBuf>,
 
-#[instrument(skip(option, mount_guard))]
-fn resume(option: Options, mount_guard: MountInjectionGuard) -> Result<()> {
-    info!("disable injection");
-    mount_guard.disable_injection();
+    #[structopt(long, parse(from_os_str), conflicts_with = "shadow")]
+    snapshot: Option<PathBuf>,
 
-    let path = option.path.clone();
+    #[structopt(long = "shadow-commit")]
+    shadow_commit: bool,
 
-    info!("canonicalizing path {}", path.display());
-    let path = path.canonicalize()?;
-    let (_, new_path) = encode_path(&path)?;
+    #[structopt(long = "verify-checksum")]
+    verify_checksum: bool,
 
-    let replacer = if !option.mount_only {
-        let mut replacer = UnionReplacer::default();
-        replacer.prepare(&path, &new_path)?;
-        info!("running replacer");
-        let result = replacer.run();
-        info!("replace result: {:?}", result);
+    /// Cache the writes in the kernel, which flushes them in the background. The rules then see the coalesced writes of the kernel rather than of the caller, and short or torn writes are lost data instead of short replies
+    #[structopt(long = "writeback-cache")]
+    writeback_cache: bool,
 
-        Some(replacer)
-    } else {
-        None
-    };
+    /// Largest write request the kernel sends, like "1M"
+    #[structopt(long = "max-write", parse(try_from_str = hookfs::parse_request_size))]
+    max_write: Option<u32>,
 
-    info!("recovering mount");
-    mount_guard.recover_mount()?;
+    /// Largest read request and readahead of the kernel, like "1M"
+    #[structopt(long = "max-read", parse(try_from_str = hookfs::parse_request_size))]
+    max_read: Option<u32>,
 
-    info!("replacers detached");
-    info!("recover successfully");
+    /// How long the kernel caches the attributes, "0s" to get them on every access
+    #[structopt(long = "attr-timeout", default_value = "0s", parse(try_from_str = humantime::parse_duration))]
+    attr_timeout: Duration,
 
-    drop(replacer);
-    Ok(())
+    /// How long the kernel caches the lookups of the names, "0s" to look them up on every access
+    #[structopt(long = "entry-timeout", default_value = "0s", parse(try_from_str = humantime::parse_duration))]
+    entry_timeout: Duration,
+
+    /// Check the permissions against the backing files instead of in the kernel, by doing every operation as the caller
+    #[structopt(long = "passthrough-permissions")]
+    passthrough_permissions: bool,
+
+    /// Who may access the mount besides toda: "other" for every user, "root" or "owner" for toda alone
+    #[structopt(long, default_value = "other")]
+    allow: AllowAccess,
+
+    /// Source of the mount shown in /proc/mounts, "toda" by default. The status and recover commands find the mount by it.
+    #[structopt(long, parse(try_from_str = hookfs::parse_mount_label))]
+    fsname: Option<String>,
+
+    /// Subtype of the mount, whose type in /proc/mounts is "fuse.<subtype>", "toda" by default. The status and recover commands find the mount by it.
+    #[structopt(long, parse(try_from_str = hookfs::parse_mount_label))]
+    subtype: Option<String>,
+
+    /// What happens to the delayed operations on recovery: "release", "complete:<max duration>" or "fail:<errno>"
+    #[structopt(long, default_value = "release")]
+    drain: DrainPolicy,
+
+    /// How long the operations in flight are waited for on recovery, before the mount is recovered anyway
+    #[structopt(long = "shutdown-timeout", default_value = "10s", parse(try_from_str = humantime::parse_duration))]
+    shutdown_timeout: Duration,
+
+    /// The experiment id labeling the logs and reports of this mount
+    #[structopt(long)]
+    experiment: Option<String>,
+
+    /// File to save the manifest of the path to before the injection, for the verify command
+    #[structopt(long, parse(from_os_str))]
+    manifest: Option<PathBuf>,
+
+    /// Memory budget of the buffered fault state, like "64M"
+    #[structopt(long = "memory-limit", parse(try_from_str = budget::parse_size))]
+    memory_limit: Option<usize>,
+
+    /// What happens once the memory budget is exceeded: "drop-oldest", "fail" or "spill:<dir>"
+    #[structopt(long = "memory-overflow", default_value = "drop-oldest")]
+    memory_overflow: OverflowPolicy,
+
+    /// Existing cgroup (v2) to move toda into
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["cgroup_cpu", "cgroup_memory"])]
+    cgroup: Option<PathBuf>,
+
+    /// Move toda into a new cgroup limited to this count of cpus, like "0.5"
+    #[structopt(long = "cgroup-cpu")]
+    cgroup_cpu: Option<f64>,
+
+    /// Move toda into a new cgroup limited to this memory, like "256M"
+    #[structopt(long = "cgroup-memory", parse(try_from_str = budget::parse_size))]
+    cgroup_memory: Option<usize>,
+    /// File of the rules (an array of injector configs, in yaml if it is named *.yaml or *.yml and in json otherwise) injected from the start as the "config" experiment, reloaded on SIGHUP
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// File of the primary rule set (an array of injector configs, like --config), toggled by signals
+    #[structopt(long, parse(from_os_str))]
+    primary: Option<PathBuf>,
+
+    /// Signal to enable the primary rule set
+    #[structopt(long = "enable-signal", default_value = "SIGUSR1")]
+    enable_signal: Signal,
+
+    /// Signal to disable the primary rule set
+    #[structopt(long = "disable-signal", default_value = "SIGUSR2")]
+    disable_signal: Signal,
+
+    /// Count of the threads handling the requests, which is the count of cpus by default
+    #[structopt(long, parse(try_from_str = runtime::parse_thread_count))]
+    workers: Option<usize>,
+
+    /// Count of the threads doing the blocking IO on the backing files
+    #[structopt(long = "blocking-threads", parse(try_from_str = runtime::parse_thread_count))]
+    blocking_threads: Option<usize>,
+
+    /// Seed of the random number generator deciding the injection, to reproduce a run. A random one is logged if unset
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Process whose mount namespace is entered before the injection, e.g. the one in the target container
+    #[structopt(long)]
+    pid: Option<i32>,
+
+    /// Enter the pid namespace of --pid as well, which forks toda
+    #[structopt(long = "pid-namespace", requires = "pid")]
+    pid_namespace: bool,
+
+    /// Enter the user namespace of --pid as well
+    #[structopt(long = "user-namespace", requires = "pid")]
+    user_namespace: bool,
+
+    /// Detach from the terminal and run in the background, which requires --pidfile
+    #[structopt(long, requires = "pidfile")]
+    daemon: bool,
+
+    /// File to write the pid to, which is used by the stop command
+    #[structopt(long, parse(from_os_str))]
+    pidfile: Option<PathBuf>,
+
+    /// File to write the logs of the daemon to
+    #[structopt(long = "log-file", parse(from_os_str), default_value = "/dev/null")]
+    log_file: PathBuf,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+// MountSpec is a path injected besides --path, with the file of its rules
+#[derive(Debug, Clone)]
+struct MountSpec {
+    path: PathBuf,
+    rules: PathBuf,
+}
+
+impl FromStr for MountSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        match spec.split_once('=') {
+            Some((path, rules)) if !path.is_empty() && !rules.is_empty() => Ok(MountSpec {
+                path: PathBuf::from(path),
+                rules: PathBuf::from(rules),
+            }),
+            _ => Err(anyhow::anyhow!("mount {} isn't <path>=<rules file>", spec)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum Command {
+    /// Cycle randomized fault presets over the path and report the errors seen by the application
+    Soak(SoakOptions),
+    /// Compare the recovered path against the manifest captured before the injection
+    Verify(VerifyOptions),
+    /// Recover and stop the toda recorded in the pidfile
+    Stop(StopOptions),
+    /// Restore the original mount of the path left behind by a toda killed before recovering it
+    Recover,
+    /// Print whether the path is injected, and whether its original mount is moved aside
+    Status,
+}
+
+// builder configures the injection into the path with the options shared by
+// every mount
+fn builder(option: &Options, path: &Path) -> TodaBuilder {
+    let mut builder = TodaBuilder::new(path)
+        .mount_only(option.mount_only)
+        .keep_cwd(option.keep_cwd)
+        .verify_checksum(option.verify_checksum)
+        .drain(option.drain)
+        .shutdown_timeout(option.shutdown_timeout)
+        .kernel_options(kernel_options(option));
+    if let Some(experiment) = &option.experiment {
+        builder = builder.experiment(experiment);
+    }
+    builder
+}
+
+fn kernel_options(option: &Options) -> KernelOptions {
+    KernelOptions {
+        writeback_cache: option.writeback_cache,
+        max_write: option.max_write,
+        max_read: option.max_read,
+        attr_timeout: option.attr_timeout,
+        entry_timeout: option.entry_timeout,
+        passthrough_permissions: option.passthrough_permissions,
+        allow: option.allow,
+        fsname: option.fsname.clone(),
+        subtype: option.subtype.clone(),
+    }
+}
+
+// inject mounts the hookfs over --path, with the rules loaded later. The
+// overlays and the manifest only apply to it.
+fn inject(option: &Options) -> Result<Toda> {
+    let mut builder = builder(option, &option.path);
+    if let Some(shadow) = &option.shadow {
+        builder = builder.shadow(shadow, option.shadow_commit);
+    }
+    if let Some(snapshot) = &option.snapshot {
+        builder = builder.snapshot(snapshot);
+    }
+    if let Some(manifest) = &option.manifest {
+        builder = builder.manifest(manifest);
+    }
+    builder.inject()
+}
+
+fn verify(path: &Path, option: &VerifyOptions) -> Result<()> {
+    let exclude = option
+        .exclude
+        .iter()
+        .map(|pattern| PathPattern::glob(pattern, false))
+        .collect::<Result<Vec<_>>>()?;
+    let differences = Manifest::load(&option.manifest)?.verify(path, &exclude)?;
+    println!("{}", serde_json::to_string_pretty(&differences)?);
+
+    if differences.is_empty() {
+        info!("verified successfully");
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!("{} differences found", differences.len()))
+    }
 }
 
 static mut SIGNAL_PIPE_WRITER: RawFd = 0;
@@ -142,6 +356,10 @@ static mut SIGNAL_PIPE_WRITER: RawFd = 0;
 const SIGNAL_MSG: [u8; 6] = *b"SIGNAL";
 
 extern "C" fn signal_handler(_: libc::c_int) {
+    notify_exit();
+}
+
+fn notify_exit() {
     unsafe {
         write(SIGNAL_PIPE_WRITER, &SIGNAL_MSG).unwrap();
     }
@@ -153,6 +371,129 @@ fn wait_for_signal(chan: RawFd) -> Result<()> {
     Ok(())
 }
 
+// load_rules reads and validates the rules in the file. The rules are built
+// to be validated, but dropped rather than started, so that they don't show up
+// in the metrics of any experiment.
+fn load_rules(path: &Path) -> Result<Vec<InjectorConfig>> {
+    let config = read_rules(path)?;
+    for (index, rule) in config.iter().enumerate() {
+        MultiInjector::build(vec![rule.clone()])
+            .map_err(|err| anyhow::anyhow!("rule {}: {}", index, err))?;
+    }
+    Ok(config)
+}
+
+// read_rules parses the rules as yaml if the file is named *.yaml or *.yml,
+// and as json otherwise
+fn read_rules(path: &Path) -> Result<Vec<InjectorConfig>> {
+    info!("load rules from {}", path.display());
+    let file = std::fs::File::open(path)?;
+    let rules = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("yaml") | Some("yml") => serde_yaml::from_reader(file)?,
+        _ => serde_json::from_reader(file)?,
+    };
+    Ok(rules)
+}
+
+static mut TOGGLE_PIPE_WRITER: RawFd = 0;
+
+extern "C" fn toggle_handler(signum: libc::c_int) {
+    unsafe {
+        let _ = write(TOGGLE_PIPE_WRITER, &[signum as u8]);
+    }
+}
+
+// watch_toggle_signals enables the primary rule set on the `enable` signal and
+// disables it on the `disable` signal
+fn watch_toggle_signals(switch: PrimarySwitch, enable: Signal, disable: Signal) -> Result<()> {
+    let (reader, writer) = pipe()?;
+    unsafe {
+        TOGGLE_PIPE_WRITER = writer;
+    }
+
+    unsafe { signal(enable, SigHandler::Handler(toggle_handler))? };
+    unsafe { signal(disable, SigHandler::Handler(toggle_handler))? };
+    info!(
+        "toggle primary rule set with {:?} and {:?}",
+        enable, disable
+    );
+
+    thread::spawn(move || loop {
+        let mut buf = [0u8; 1];
+        if read(reader, &mut buf).is_err() {
+            break;
+        }
+        let result = if buf[0] as libc::c_int == enable as libc::c_int {
+            switch.enable()
+        } else {
+            switch.disable()
+        };
+        if let Err(err) = result {
+            error!("fail to toggle primary rule set: {:?}", err);
+        }
+    });
+    Ok(())
+}
+
+static mut RELOAD_PIPE_WRITER: RawFd = 0;
+
+extern "C" fn reload_handler(_: libc::c_int) {
+    unsafe {
+        let _ = write(RELOAD_PIPE_WRITER, &[0u8]);
+    }
+}
+
+// watch_reload_signal reloads the rules from the file on SIGHUP. An invalid
+// file is reported and the running rules are kept. Only the experiment of the
+// loader is replaced, so the rules set through the rpc are kept as well.
+fn watch_reload_signal(loader: ConfigLoader, path: PathBuf) -> Result<()> {
+    let (reader, writer) = pipe()?;
+    unsafe {
+        RELOAD_PIPE_WRITER = writer;
+    }
+
+    unsafe { signal(Signal::SIGHUP, SigHandler::Handler(reload_handler))? };
+    info!("reload rules from {} on SIGHUP", path.display());
+
+    thread::spawn(move || loop {
+        let mut buf = [0u8; 1];
+        match read(reader, &mut buf) {
+            Ok(_) => {}
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(_) => break,
+        }
+        let experiment = match read_rules(&path).and_then(|config| loader.prepare(config)) {
+            Ok(experiment) => experiment,
+            Err(err) => {
+                error!("fail to reload rules, keep the running ones: {:?}", err);
+                continue;
+            }
+        };
+        // the diff is against the rules running right now, and only logged
+        // once the new ones are valid
+        let running = loader.rules().unwrap_or_default();
+        log_rules_diff(&running, experiment.config());
+        loader.start(experiment);
+    });
+    Ok(())
+}
+
+fn log_rules_diff(old: &[InjectorConfig], new: &[InjectorConfig]) {
+    let describe = |rules: &[InjectorConfig]| -> Vec<String> {
+        rules
+            .iter()
+            .filter_map(|rule| serde_json::to_string(rule).ok())
+            .collect()
+    };
+    let (old, new) = (describe(old), describe(new));
+    for rule in old.iter().filter(|rule| !new.contains(rule)) {
+        info!("rule removed: {}", rule);
+    }
+    for rule in new.iter().filter(|rule| !old.contains(rule)) {
+        info!("rule added: {}", rule);
+    }
+}
+
 fn main() -> Result<()> {
     let (reader, writer) = pipe()?;
     unsafe {
@@ -163,6 +504,16 @@ fn main() -> Result<()> {
     unsafe { signal(Signal::SIGTERM, SigHandler::Handler(signal_handler))? };
 
     let option = Options::from_args();
+    let managed = !matches!(
+        option.command,
+        Some(Command::Verify(_))
+            | Some(Command::Stop(_))
+            | Some(Command::Recover)
+            | Some(Command::Status)
+    );
+    if option.daemon && managed {
+        daemon::daemonize(option.pidfile.as_ref().unwrap(), &option.log_file)?;
+    }
     let env_filter = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_from(&option.verbose))
         .or_else(|_| EnvFilter::try_new("trace"))
@@ -172,34 +523,238 @@ fn main() -> Result<()> {
         .with_env_filter(env_filter)
         .init();
     info!("start with option: {:?}", option);
-    let mount_injector = inject(option.clone(), vec![]);
+    if let Some(Command::Verify(verify_option)) = &option.command {
+        return verify(&option.path, verify_option);
+    }
+    if let Some(Command::Stop(stop_option)) = &option.command {
+        let pidfile = option
+            .pidfile
+            .as_ref()
+            .ok_or(anyhow::anyhow!("--pidfile is required to stop"))?;
+        return daemon::stop(pidfile, stop_option);
+    }
+    if let Some(Command::Recover) | Some(Command::Status) = &option.command {
+        if let Some(pid) = option.pid {
+            namespace::enter(pid, option.user_namespace, false)?;
+        }
+        // the path cannot be canonicalized through a dead fuse mount
+        let path = std::env::current_dir()?.join(&option.path);
+        if let Some(Command::Status) = &option.command {
+            let status = mount_injector::status(&path, &kernel_options(&option))?;
+            println!("{}", serde_json::to_string(&status)?);
+        } else if !mount_injector::recover_stale(&path, &kernel_options(&option))? {
+            info!("nothing to recover on {}", path.display());
+        }
+        return Ok(());
+    }
+    let pidfile = option.pidfile.as_ref().map(Pidfile::create).transpose()?;
+    budget::configure(option.memory_limit, option.memory_overflow.clone());
+    runtime::configure(WorkerConfig {
+        workers: option.workers,
+        blocking_threads: option.blocking_threads,
+    });
+    rng::configure(option.seed);
+    // the rules are validated before the mount, which would be left behind
+    // by an error later
+    let config = option.config.as_deref().map(load_rules).transpose()?;
+    let mounts = option
+        .mounts
+        .iter()
+        .map(|mount| Ok((mount.path.clone(), load_rules(&mount.rules)?)))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(path) = &option.audit_log {
+        audit::open(path)?;
+    }
+    if let Some(fd) = option.audit_fd {
+        audit::open_fd(fd)?;
+    }
+    if let Some(path) = &option.record_decisions {
+        decisions::record(path)?;
+    }
+    if let Some(path) = &option.replay_decisions {
+        decisions::replay(path)?;
+    }
+    let cgroup = match (&option.cgroup, option.cgroup_cpu, option.cgroup_memory) {
+        (Some(path), _, _) => Some(Cgroup::join(path)?),
+        (None, None, None) => None,
+        (None, cpu, memory) => Some(Cgroup::create(cpu, memory)?),
+    };
+    if let Some(cgroup) = &cgroup {
+        info!("confined in cgroup {}", cgroup.path().display());
+    }
+    if let Some(pid) = option.pid {
+        if let Some(code) = namespace::enter(pid, option.user_namespace, option.pid_namespace)? {
+            info!("child in the pid namespace exited with {}", code);
+            if let Some(cgroup) = cgroup {
+                cgroup.release()?;
+            }
+            drop(pidfile);
+            std::process::exit(code);
+        }
+    }
+    // the endpoint is served before the mount, to count its first operations
+    if let Some(addr) = &option.metrics {
+        metrics::serve(addr)?;
+    }
+    let coordinate_token = match &option.coordinate_token_file {
+        Some(path) => Some(std::fs::read_to_string(path)?.trim().to_owned()),
+        None if option.coordinate_listen.is_some() || !option.coordinate_peers.is_empty() => {
+            anyhow::bail!("coordination requires --coordinate-token-file")
+        }
+        None => None,
+    };
+    // the listeners are bound and the files are read before the mount, so
+    // that their errors don't leave it behind
+    let coordinate_listener = match &option.coordinate_listen {
+        Some(addr) => Some(coordination::listen(addr)?),
+        None => None,
+    };
+    let rpc_socket = match &option.rpc_socket {
+        Some(path) => Some(jsonrpc::bind_unix(path)?),
+        None => None,
+    };
+    let primary = match &option.primary {
+        Some(path) => Some(load_rules(path)?),
+        None => None,
+    };
+    let mount_injector = inject(&option);
 
-    let status = match &mount_injector {
+    let mut status = match &mount_injector {
         Ok(_) => Ok(()),
         Err(e) => Err(anyhow::Error::msg(e.to_string())),
     };
+    // the other mounts run their own fuse sessions, sharing the control plane
+    // and the metrics of this process. A failure to inject one of them fails
+    // the status like a failure on --path.
+    let mut extra_mounts = Vec::new();
+    let mut extra_rules = Vec::new();
+    if status.is_ok() {
+        for (path, rules) in mounts {
+            match builder(&option, &path).rules(rules.clone()).inject() {
+                Ok(toda) => {
+                    extra_mounts.push(toda);
+                    extra_rules.push(rules);
+                }
+                Err(err) => {
+                    error!("fail to inject into {}: {:?}", path.display(), err);
+                    status = Err(anyhow::anyhow!("inject into {}: {}", path.display(), err));
+                    break;
+                }
+            }
+        }
+    }
 
-    let (tx, _) = mpsc::channel();
-    {
+    let (tx, rx) = mpsc::channel();
+    // both a failed status and the recover rpc end toda like SIGTERM
+    thread::spawn(move || {
+        if let Ok(comm) = rx.recv() {
+            info!("exit on {:?}", comm);
+            notify_exit();
+        }
+    });
+    // a failure to set up the control plane once the mount is moved falls
+    // through to the recovery below, so that the mount isn't left behind
+    let setup = (|| -> Result<Option<Soak>> {
         let hookfs = match &mount_injector {
             Ok(e) => Some(e.hookfs.clone()),
             Err(_) => None,
         };
+        let mut rpc = jsonrpc::RpcImpl::new(Mutex::new(status), Mutex::new(tx), hookfs);
+        for (toda, rules) in extra_mounts.iter().zip(extra_rules) {
+            rpc.mount(toda.hookfs.clone(), rules)?;
+        }
+        if !option.coordinate_peers.is_empty() {
+            rpc = rpc.with_coordinator(Coordinator::new(
+                option.coordinate_peers.clone(),
+                option.coordinate_delay,
+                coordinate_token.clone().unwrap_or_default(),
+            ));
+        }
+        if let (Some(listener), Some(loader)) =
+            (coordinate_listener, rpc.config_loader(DEFAULT_EXPERIMENT))
+        {
+            coordination::serve(listener, coordinate_token.unwrap_or_default(), loader);
+        }
+        if let (Some(config), Some(loader)) = (config, rpc.config_loader(CONFIG_EXPERIMENT)) {
+            if let Err(err) = loader.load(config) {
+                error!("fail to load rules: {:?}", err);
+            }
+            if let Some(path) = &option.config {
+                watch_reload_signal(loader, path.clone())?;
+            }
+        }
+        if let Some(config) = primary {
+            if let Some(switch) = rpc.primary_switch(config) {
+                watch_toggle_signals(switch, option.enable_signal, option.disable_signal)?;
+            }
+        }
+        let soak_loader = rpc.config_loader(SOAK_EXPERIMENT);
+        let io = jsonrpc::new_handler(rpc);
+        if let Some(socket) = &rpc_socket {
+            socket.serve(io.clone())?;
+        }
         thread::spawn(|| {
             Runtime::new()
                 .expect("Failed to create Tokio runtime")
-                .block_on(start_server(jsonrpc::RpcImpl::new(
-                    Mutex::new(status),
-                    Mutex::new(tx),
-                    hookfs,
-                )));
+                .block_on(start_server(io));
         });
+
+        match (&option.command, &mount_injector, soak_loader) {
+            (Some(Command::Soak(soak_option)), Ok(guard), Some(loader)) => Ok(Some(Soak::start(
+                soak_option.clone(),
+                loader,
+                guard.hookfs.label().clone(),
+                notify_exit,
+            )?)),
+            _ => Ok(None),
+        }
+    })();
+
+    let mut result = Ok(());
+    match setup {
+        Ok(soak) => {
+            info!("waiting for signal to exit");
+            if let Err(err) = wait_for_signal(reader) {
+                error!("fail to wait for signal: {:?}", err);
+                result = Err(err);
+            }
+            if let Some(soak) = soak {
+                info!("stop soak");
+                if let Err(err) = soak.finish() {
+                    error!("fail to finish soak: {:?}", err);
+                    result = Err(err);
+                }
+            }
+        }
+        Err(err) => {
+            error!("fail to set up after the mount: {:?}", err);
+            result = Err(err);
+        }
     }
-    info!("waiting for signal to exit");
-    wait_for_signal(reader)?;
     info!("start to recover and exit");
+    // the injection is stopped on every mount first, so that the operations
+    // delayed on one don't hold the recovery of the others
+    for toda in extra_mounts.iter().chain(mount_injector.as_ref().ok()) {
+        toda.hookfs.set_drain_policy(option.drain);
+        toda.disable_injection();
+    }
+    for toda in extra_mounts {
+        let path = toda.path().to_owned();
+        if let Err(err) = toda.recover() {
+            error!("fail to recover {}: {:?}", path.display(), err);
+            result = Err(err);
+        }
+    }
     if let Ok(v) = mount_injector {
-        resume(option, v)?;
+        result = v.recover().and(result);
     }
-    Ok(())
+    // the log is closed once the operations released by the recovery are
+    // audited
+    audit::close();
+    // the cgroup is released even if the recovery fails, which still exits
+    // with an error for the operator to notice
+    if let Some(cgroup) = cgroup {
+        cgroup.release()?;
+    }
+    result.context("fail to restore the original mount")
 }
@@ -0,0 +1,286 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter, Read};
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+use tracing::{error, info, trace};
+
+use crate::utils::{Fnv, PathPattern};
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct VerifyOptions {
+    /// Manifest captured before the injection with --manifest
+    #[structopt(long, parse(from_os_str))]
+    pub manifest: PathBuf,
+
+    /// Glob of the paths (relative to the target) which are expected to be modified, e.g. by write corruption rules
+    #[structopt(long)]
+    pub exclude: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestEntry {
+    // the path relative to the root
+    #[serde(with = "crate::utils::os_path")]
+    pub path: PathBuf,
+    pub kind: String,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    // the checksum of the content of a regular file
+    pub checksum: Option<u64>,
+    // the target of a symlink
+    #[serde(default, with = "crate::utils::os_path::option")]
+    pub target: Option<PathBuf>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Difference {
+    #[serde(serialize_with = "crate::utils::os_path::serialize")]
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+// Manifest records the structure, metadata and content checksums of a
+// directory, so that it can be compared after the recovery
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn capture<P: AsRef<Path>>(root: P) -> Result<Manifest> {
+        let root = root.as_ref();
+        info!("capture manifest of {}", root.display());
+
+        let mut manifest = Manifest::default();
+        manifest.walk(root, Path::new(""))?;
+        Ok(manifest)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Manifest> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        serde_json::to_writer(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    fn walk(&mut self, root: &Path, relative: &Path) -> Result<()> {
+        let mut entries = fs::read_dir(root.join(relative))?.collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let relative = relative.join(entry.file_name());
+            let entry = capture_entry(root, &relative)?;
+            let is_dir = entry.kind == "directory";
+            self.entries.push(entry);
+            if is_dir {
+                self.walk(root, &relative)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // verify compares the directory against the manifest, ignoring the paths
+    // matching any of `exclude`
+    pub fn verify<P: AsRef<Path>>(
+        &self,
+        root: P,
+        exclude: &[PathPattern],
+    ) -> Result<Vec<Difference>> {
+        let root = root.as_ref();
+        info!("verify {} against the manifest", root.display());
+
+        let excluded = |path: &Path| exclude.iter().any(|pattern| pattern.matches(path));
+        let current = Manifest::capture(root)?;
+        let expected_entries: BTreeMap<_, _> = self
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_path(), entry))
+            .collect();
+        let actual_entries: BTreeMap<_, _> = current
+            .entries
+            .iter()
+            .map(|entry| (entry.path.as_path(), entry))
+            .collect();
+        let mut differences = Vec::new();
+
+        for expected in self.entries.iter().filter(|entry| !excluded(&entry.path)) {
+            let actual = actual_entries.get(expected.path.as_path());
+            let reason = match actual {
+                None => "missing".to_owned(),
+                Some(actual) => match describe_difference(expected, actual) {
+                    Some(reason) => reason,
+                    None => continue,
+                },
+            };
+            differences.push(Difference {
+                path: expected.path.clone(),
+                reason,
+            });
+        }
+
+        for actual in current
+            .entries
+            .iter()
+            .filter(|entry| !excluded(&entry.path))
+        {
+            if !expected_entries.contains_key(actual.path.as_path()) {
+                differences.push(Difference {
+                    path: actual.path.clone(),
+                    reason: "unexpected".to_owned(),
+                });
+            }
+        }
+
+        for difference in differences.iter() {
+            error!("{}: {}", difference.path.display(), difference.reason);
+        }
+        Ok(differences)
+    }
+}
+
+fn describe_difference(expected: &ManifestEntry, actual: &ManifestEntry) -> Option<String> {
+    if expected.kind != actual.kind {
+        Some(format!("kind {} became {}", expected.kind, actual.kind))
+    } else if expected.mode != actual.mode {
+        Some(format!("mode {:o} became {:o}", expected.mode, actual.mode))
+    } else if (expected.uid, expected.gid) != (actual.uid, actual.gid) {
+        Some(format!(
+            "owner {}:{} became {}:{}",
+            expected.uid, expected.gid, actual.uid, actual.gid
+        ))
+    } else if expected.size != actual.size {
+        Some(format!("size {} became {}", expected.size, actual.size))
+    } else if expected.checksum != actual.checksum {
+        Some("content changed".to_owned())
+    } else if expected.target != actual.target {
+        Some("symlink target changed".to_owned())
+    } else {
+        None
+    }
+}
+
+fn capture_entry(root: &Path, relative: &Path) -> Result<ManifestEntry> {
+    let path = root.join(relative);
+    trace!("capture {}", path.display());
+    let metadata = fs::symlink_metadata(&path)?;
+    let file_type = metadata.file_type();
+
+    let kind = if file_type.is_dir() {
+        "directory"
+    } else if file_type.is_file() {
+        "file"
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else {
+        "device"
+    };
+
+    Ok(ManifestEntry {
+        path: relative.to_owned(),
+        kind: kind.to_owned(),
+        mode: metadata.mode(),
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        size: if file_type.is_dir() {
+            0
+        } else {
+            metadata.len()
+        },
+        checksum: if file_type.is_file() {
+            Some(checksum(&path)?)
+        } else {
+            None
+        },
+        target: if file_type.is_symlink() {
+            Some(fs::read_link(&path)?)
+        } else {
+            None
+        },
+    })
+}
+
+// checksum is the FNV-1a hash of the content, as the manifest is saved
+fn checksum(path: &Path) -> Result<u64> {
+    let mut file = File::open(path)?;
+    let mut hasher = Fnv::default();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.write(&buf[..read]);
+    }
+    Ok(hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn test_non_utf8_names() {
+        let root = std::env::temp_dir().join(format!("toda-manifest-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let name = OsStr::from_bytes(b"\xff\xfe");
+        fs::write(root.join(name), "hello").unwrap();
+        std::os::unix::fs::symlink(OsStr::from_bytes(b"caf\xe9"), root.join("link")).unwrap();
+
+        let saved = root.with_extension("json");
+        Manifest::capture(&root).unwrap().save(&saved).unwrap();
+        let manifest = Manifest::load(&saved).unwrap();
+        assert!(manifest.verify(&root, &[]).unwrap().is_empty());
+        assert_eq!(
+            manifest.entries[0].target,
+            Some(PathBuf::from(OsStr::from_bytes(b"caf\xe9")))
+        );
+
+        fs::write(root.join(name), "world").unwrap();
+        let differences = manifest.verify(&root, &[]).unwrap();
+        assert_eq!(differences.len(), 1);
+        assert_eq!(differences[0].path, Path::new(name));
+        // the name is excluded by its bytes
+        let exclude = PathPattern::regex(r"(?-u:\xff\xfe)").unwrap();
+        assert!(manifest.verify(&root, &[exclude]).unwrap().is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_file(&saved).unwrap();
+    }
+
+    #[test]
+    fn test_checksum() {
+        let path = std::env::temp_dir().join(format!("toda-checksum-{}", std::process::id()));
+        // the content spans several reads
+        let content: Vec<u8> = (0..200 * 1024).map(|i| i as u8).collect();
+        fs::write(&path, &content).unwrap();
+
+        let mut hasher = Fnv::default();
+        hasher.write(&content);
+        assert_eq!(checksum(&path).unwrap(), hasher.finish());
+
+        fs::write(&path, "foobar").unwrap();
+        assert_eq!(checksum(&path).unwrap(), 0x8594_4171_f739_67e8);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use tracing::{error, info};
+
+// the upper bounds of the latency buckets, in seconds
+const BUCKETS: [f64; 8] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.1, 1.0, 10.0];
+
+// the methods replied to by the mount
+const METHODS: [&str; 31] = [
+    "lookup",
+    "getattr",
+    "setattr",
+    "readlink",
+    "mknod",
+    "mkdir",
+    "unlink",
+    "rmdir",
+    "symlink",
+    "rename",
+    "link",
+    "open",
+    "read",
+    "write",
+    "flush",
+    "release",
+    "fsync",
+    "opendir",
+    "releasedir",
+    "fsyncdir",
+    "statfs",
+    "setxattr",
+    "getxattr",
+    "listxattr",
+    "removexattr",
+    "access",
+    "create",
+    "getlk",
+    "setlk",
+    "fallocate",
+    "lseek",
+];
+
+// the time a scraper gets to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Counter is a monotonic count shared by its clones
+#[derive(Debug, Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn incr(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// RuleMetrics counts the operations a rule fired on, and the ones of them it
+// failed. The counters of a rule are kept once its experiment is stopped, and
+// shared with the rule of the same index when it's started again.
+#[derive(Debug, Clone, Default)]
+pub struct RuleMetrics {
+    pub hits: Counter,
+    pub faults: Counter,
+}
+
+#[derive(Debug, Default)]
+struct Operation {
+    ok: AtomicU64,
+    errors: AtomicU64,
+    buckets: [AtomicU64; BUCKETS.len()],
+    nanos: AtomicU64,
+}
+
+// Metrics is only locked to register a rule and to render, so that the
+// operations don't contend on it
+#[derive(Debug)]
+struct Metrics {
+    operations: BTreeMap<&'static str, Operation>,
+    read: AtomicU64,
+    written: AtomicU64,
+    // by experiment and rule index
+    rules: Mutex<BTreeMap<(String, usize), RuleMetrics>>,
+}
+
+// Nothing but the rules is recorded until the endpoint is served, so that a
+// mount without it doesn't pay for the clock on every operation
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// record_op records the latency and the result of an operation replied to
+pub fn record_op(method: &'static str, latency: Duration, ok: bool) {
+    if enabled() {
+        METRICS.record_op(method, latency, ok)
+    }
+}
+
+pub fn record_read(bytes: usize) {
+    if enabled() {
+        METRICS.read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+pub fn record_written(bytes: usize) {
+    if enabled() {
+        METRICS.written.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+// rule returns the counters of the rule of the experiment
+pub fn rule(experiment: &str, index: usize) -> RuleMetrics {
+    METRICS.rule(experiment, index)
+}
+
+// render formats the metrics in the prometheus text format
+pub fn render() -> String {
+    METRICS.render()
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            operations: METHODS
+                .iter()
+                .map(|method| (*method, Operation::default()))
+                .collect(),
+            read: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            rules: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn record_op(&self, method: &'static str, latency: Duration, ok: bool) {
+        let operation = match self.operations.get(method) {
+            Some(operation) => operation,
+            None => return,
+        };
+        if ok {
+            operation.ok.fetch_add(1, Ordering::Relaxed);
+        } else {
+            operation.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        operation
+            .nanos
+            .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+        let seconds = latency.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            operation.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn rule(&self, experiment: &str, index: usize) -> RuleMetrics {
+        self.rules
+            .lock()
+            .unwrap()
+            .entry((experiment.to_owned(), index))
+            .or_default()
+            .clone()
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP toda_operations_total The operations replied to, by method and result.\n",
+        );
+        out.push_str("# TYPE toda_operations_total counter\n");
+        for (method, operation) in self.operations.iter() {
+            let results = [
+                ("ok", operation.ok.load(Ordering::Relaxed)),
+                ("error", operation.errors.load(Ordering::Relaxed)),
+            ];
+            for (result, count) in results.iter() {
+                let _ = writeln!(
+                    out,
+                    "toda_operations_total{{method=\"{}\",result=\"{}\"}} {}",
+                    method, result, count
+                );
+            }
+        }
+
+        out.push_str(
+            "# HELP toda_operation_duration_seconds The latency of the operations, by method.\n",
+        );
+        out.push_str("# TYPE toda_operation_duration_seconds histogram\n");
+        for (method, operation) in self.operations.iter() {
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(operation.buckets.iter()) {
+                cumulative += count.load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "toda_operation_duration_seconds_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    method, bound, cumulative
+                );
+            }
+            let count =
+                operation.ok.load(Ordering::Relaxed) + operation.errors.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "toda_operation_duration_seconds_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+                method, count
+            );
+            let _ = writeln!(
+                out,
+                "toda_operation_duration_seconds_sum{{method=\"{}\"}} {}",
+                method,
+                Duration::from_nanos(operation.nanos.load(Ordering::Relaxed)).as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "toda_operation_duration_seconds_count{{method=\"{}\"}} {}",
+                method, count
+            );
+        }
+
+        out.push_str("# HELP toda_bytes_total The bytes read and written through the mount.\n");
+        out.push_str("# TYPE toda_bytes_total counter\n");
+        let _ = writeln!(
+            out,
+            "toda_bytes_total{{direction=\"read\"}} {}",
+            self.read.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "toda_bytes_total{{direction=\"written\"}} {}",
+            self.written.load(Ordering::Relaxed)
+        );
+
+        let rules = self.rules.lock().unwrap();
+        out.push_str(
+            "# HELP toda_rule_hits_total The operations a rule fired on, by experiment and rule.\n",
+        );
+        out.push_str("# TYPE toda_rule_hits_total counter\n");
+        for ((experiment, rule), metrics) in rules.iter() {
+            let _ = writeln!(
+                out,
+                "toda_rule_hits_total{{experiment=\"{}\",rule=\"{}\"}} {}",
+                escape(experiment),
+                rule,
+                metrics.hits.get()
+            );
+        }
+
+        out.push_str(
+            "# HELP toda_injected_faults_total The operations failed by a rule, by experiment and rule.\n",
+        );
+        out.push_str("# TYPE toda_injected_faults_total counter\n");
+        for ((experiment, rule), metrics) in rules.iter() {
+            let _ = writeln!(
+                out,
+                "toda_injected_faults_total{{experiment=\"{}\",rule=\"{}\"}} {}",
+                escape(experiment),
+                rule,
+                metrics.faults.get()
+            );
+        }
+        out
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// serve answers `GET /metrics` on the address, every scrape in its own
+// thread, and enables the recording
+pub fn serve(addr: &str) -> Result<JoinHandle<()>> {
+    let listener = TcpListener::bind(addr).with_context(|| format!("bind {}", addr))?;
+    info!("serve metrics on {}", listener.local_addr()?);
+    ENABLED.store(true, Ordering::SeqCst);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                // a scraper which never sends its request only holds its own
+                // thread, until the read timeout
+                Ok(stream) => {
+                    thread::spawn(move || {
+                        if let Err(err) = respond(stream) {
+                            error!("failed to serve metrics: {}", err);
+                        }
+                    });
+                }
+                Err(err) => error!("failed to accept metrics scrape: {}", err),
+            }
+        }
+    }))
+}
+
+fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers are drained, as closing a socket with unread data resets
+    // the connection before the client reads the response
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_operations() {
+        let metrics = Metrics::new();
+        metrics.record_op("read", Duration::from_micros(50), true);
+        metrics.record_op("read", Duration::from_millis(2), false);
+        metrics.record_op("unknown", Duration::from_millis(2), true);
+        metrics.read.fetch_add(4096, Ordering::Relaxed);
+
+        let out = metrics.render();
+        assert!(out.contains("toda_operations_total{method=\"read\",result=\"ok\"} 1\n"));
+        assert!(out.contains("toda_operations_total{method=\"read\",result=\"error\"} 1\n"));
+        assert!(out.contains("toda_operations_total{method=\"write\",result=\"ok\"} 0\n"));
+        // the buckets are cumulative
+        assert!(out
+            .contains("toda_operation_duration_seconds_bucket{method=\"read\",le=\"0.0001\"} 1\n"));
+        assert!(out
+            .contains("toda_operation_duration_seconds_bucket{method=\"read\",le=\"0.001\"} 1\n"));
+        assert!(out
+            .contains("toda_operation_duration_seconds_bucket{method=\"read\",le=\"0.005\"} 2\n"));
+        assert!(
+            out.contains("toda_operation_duration_seconds_bucket{method=\"read\",le=\"+Inf\"} 2\n")
+        );
+        assert!(out.contains("toda_operation_duration_seconds_sum{method=\"read\"} 0.00205\n"));
+        assert!(out.contains("toda_operation_duration_seconds_count{method=\"read\"} 2\n"));
+        assert!(out.contains("toda_bytes_total{direction=\"read\"} 4096\n"));
+        assert!(!out.contains("unknown"));
+    }
+
+    #[test]
+    fn test_render_rules() {
+        let metrics = Metrics::new();
+        let rule = metrics.rule("a \"quoted\" name", 1);
+        rule.hits.incr();
+        rule.hits.incr();
+        rule.faults.incr();
+        // a rule started again shares the counters
+        metrics.rule("a \"quoted\" name", 1).hits.incr();
+
+        let out = metrics.render();
+        assert!(out
+            .contains("toda_rule_hits_total{experiment=\"a \\\"quoted\\\" name\",rule=\"1\"} 3\n"));
+        assert!(out.contains(
+            "toda_injected_faults_total{experiment=\"a \\\"quoted\\\" name\",rule=\"1\"} 1\n"
+        ));
+    }
+}
@@ -1,9 +1,12 @@
 use std::fs::create_dir_all;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use nix::mount::{mount, MsFlags};
-use procfs::process::{self, Process};
+use procfs::process::{self, MountOptFields, Process};
+use tracing::{error, info};
+
+use crate::hookfs::KernelOptions;
 
 #[derive(Debug, Clone)]
 pub struct MountsInfo {
@@ -30,12 +33,80 @@ impl MountsInfo {
         Ok(false)
     }
 
+    // mount_at returns the topmost mount on the path
+    fn mount_at(&self, path: &Path) -> Option<&process::MountInfo> {
+        self.mounts
+            .iter()
+            .rev()
+            .find(|item| item.mount_point == path)
+    }
+
+    pub fn is_mount_point<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.mount_at(path.as_ref()).is_some()
+    }
+
+    // is_toda returns whether the topmost mount on the path is a toda fuse,
+    // found by either the subtype or the source it's mounted with
+    pub fn is_toda<P: AsRef<Path>>(&self, path: P, kernel: &KernelOptions) -> bool {
+        self.mount_at(path.as_ref()).map_or(false, |item| {
+            item.fs_type == format!("fuse.{}", kernel.subtype())
+                || (item.fs_type.starts_with("fuse")
+                    && item.mount_source.as_deref() == Some(kernel.fsname()))
+        })
+    }
+
+    // parent_mount returns the mount point of the mount containing the parent
+    // of the path
+    fn parent_mount(&self, path: &Path) -> Option<&process::MountInfo> {
+        let parent = path.parent()?;
+        self.mounts
+            .iter()
+            .filter(|item| parent.starts_with(&item.mount_point))
+            .max_by_key(|item| item.mount_point.components().count())
+    }
+
+    // make_parent_private stops the propagation of the parent mount of the
+    // path, as a mount under a shared mount cannot be moved. The returned
+    // guard makes it shared again, in a new peer group, once it is dropped. A
+    // mount which is also the slave of another one is kept a slave.
+    pub fn make_parent_private<P: AsRef<Path>>(&self, path: P) -> Result<Option<SharedGuard>> {
+        let parent = match self.parent_mount(path.as_ref()) {
+            Some(parent) => parent,
+            None => return Ok(None),
+        };
+        let shared = parent
+            .opt_fields
+            .iter()
+            .any(|field| matches!(field, MountOptFields::Shared(_)));
+        if !shared {
+            return Ok(None);
+        }
+        let slave = parent
+            .opt_fields
+            .iter()
+            .any(|field| matches!(field, MountOptFields::Master(_)));
+        let flags = if slave {
+            MsFlags::MS_SLAVE
+        } else {
+            MsFlags::MS_PRIVATE
+        };
+
+        let mount_point = &parent.mount_point;
+        info!("make mount {} {:?}", mount_point.display(), flags);
+        mount::<str, _, str, str>(None, mount_point, None, flags, None)
+            .context(format!("stop propagation: {}", mount_point.display()))?;
+        Ok(Some(SharedGuard {
+            mount_point: mount_point.clone(),
+        }))
+    }
+
     pub fn move_mount<P1: AsRef<Path>, P2: AsRef<Path>>(
         &self,
         original_path: P1,
         target_path: P2,
     ) -> Result<()> {
         create_dir_all(target_path.as_ref())?;
+        let _shared = self.make_parent_private(original_path.as_ref())?;
 
         mount::<_, _, str, str>(
             Some(original_path.as_ref()),
@@ -53,3 +124,23 @@ impl MountsInfo {
         Ok(())
     }
 }
+
+// SharedGuard makes the mount shared again, once a mount under it is moved
+pub struct SharedGuard {
+    mount_point: PathBuf,
+}
+
+impl Drop for SharedGuard {
+    fn drop(&mut self) {
+        info!("make mount {} shared", self.mount_point.display());
+        if let Err(err) =
+            mount::<str, _, str, str>(None, &self.mount_point, None, MsFlags::MS_SHARED, None)
+        {
+            error!(
+                "fail to make mount {} shared: {:?}",
+                self.mount_point.display(),
+                err
+            );
+        }
+    }
+}
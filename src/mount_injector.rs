@@ -1,22 +1,35 @@
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread::JoinHandle;
 
-use anyhow::{anyhow, Result};
-use nix::mount::umount;
+use anyhow::{anyhow, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::mount::{umount, umount2, MntFlags};
 use retry::delay::Fixed;
 use retry::{retry, OperationResult};
-use tracing::info;
+use serde::Serialize;
+use tracing::{error, info, info_span, warn};
 
+use crate::hookfs::budget::MemoryBudget;
 use crate::injector::{InjectorConfig, MultiInjector};
-use crate::{hookfs, mount, stop};
+use crate::{hookfs, mount, stop, utils};
 
 #[derive(Debug)]
 pub struct MountInjector {
     original_path: PathBuf,
     new_path: PathBuf,
     injector_config: Vec<InjectorConfig>,
+    shadow: Option<PathBuf>,
+    snapshot: Option<PathBuf>,
+    checksum: bool,
+    experiment: Option<String>,
+    kernel: hookfs::KernelOptions,
+    budget: Option<Arc<MemoryBudget>>,
 }
 
 pub struct MountInjectionGuard {
@@ -24,6 +37,57 @@ pub struct MountInjectionGuard {
     new_path: PathBuf,
     pub hookfs: Arc<hookfs::HookFs>,
     handler: Option<JoinHandle<Result<()>>>,
+    // released once the original mount is moved back
+    _lock: Lock,
+}
+
+// Lock is held by the toda injecting a path, so that a recovery never tears
+// down a running one. It's released by the kernel if toda is killed, and the
+// file holds the pid of its owner.
+#[derive(Debug)]
+struct Lock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl Lock {
+    // acquire takes the lock of the moved mount, which lies beside it
+    fn acquire(new_path: &Path) -> Result<Lock> {
+        let mut name = OsString::from(new_path.file_name().unwrap_or_default());
+        name.push(".lock");
+        let path = new_path.with_file_name(name);
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("open lock {}", path.display()))?;
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {}
+            Err(nix::Error::Sys(Errno::EWOULDBLOCK)) => {
+                let mut pid = String::new();
+                file.read_to_string(&mut pid)?;
+                return Err(anyhow!(
+                    "toda {} is running on {}",
+                    pid.trim(),
+                    new_path.display()
+                ));
+            }
+            Err(err) => return Err(err.into()),
+        }
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Lock { path, _file: file })
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            warn!("fail to remove lock {}: {:?}", self.path.display(), err);
+        }
+    }
 }
 
 impl MountInjectionGuard {
@@ -38,21 +102,31 @@ impl MountInjectionGuard {
     pub fn recover_mount(mut self) -> Result<()> {
         let mount_point = self.original_path.clone();
 
-        retry(Fixed::from_millis(500).take(20), || {
+        let result = retry(Fixed::from_millis(500).take(20), || {
             if let Err(err) = umount(mount_point.as_path()) {
                 info!("umount returns error: {:?}", err);
                 OperationResult::Retry(err)
             } else {
                 OperationResult::Ok(())
             }
-        })?;
+        });
 
-        info!("unmount successfully!");
-        self.handler
-            .take()
-            .ok_or(anyhow!("handler is empty"))?
-            .join()
-            .unwrap()?;
+        match result {
+            Ok(()) => {
+                info!("unmount successfully!");
+                self.handler
+                    .take()
+                    .ok_or(anyhow!("handler is empty"))?
+                    .join()
+                    .unwrap()?;
+            }
+            // the mount is still busy, so it's detached. The fuse session ends
+            // when the last file opened on it is closed, which isn't waited for.
+            Err(err) => {
+                warn!("mount is busy ({:?}), detach it lazily", err);
+                umount2(mount_point.as_path(), MntFlags::MNT_DETACH)?;
+            }
+        }
 
         let new_path = self.new_path.clone();
         let original_path = self.original_path;
@@ -60,7 +134,6 @@ impl MountInjectionGuard {
         let mounts = mount::MountsInfo::parse_mounts()?;
 
         if mounts.non_root(&original_path)? {
-            // TODO: make the parent mount points private before move mount points
             mounts.move_mount(new_path, original_path)?;
         } else {
             return Err(anyhow!("inject on a root mount"));
@@ -70,55 +143,159 @@ impl MountInjectionGuard {
     }
 }
 
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MountStatus {
+    #[serde(serialize_with = "crate::utils::os_path::serialize")]
+    pub path: PathBuf,
+    // whether toda is mounted on the path
+    pub injected: bool,
+    // whether the original mount is moved aside by toda, which is left behind
+    // if toda is killed
+    pub moved: bool,
+}
+
+// status reports the mount of toda on the path, which is found by the labels
+// of the kernel options
+pub fn status<P: AsRef<Path>>(path: P, kernel: &hookfs::KernelOptions) -> Result<MountStatus> {
+    let (original_path, new_path) = utils::encode_path(path)?;
+    let mounts = mount::MountsInfo::parse_mounts()?;
+
+    Ok(MountStatus {
+        injected: mounts.is_toda(&original_path, kernel),
+        moved: mounts.is_mount_point(&new_path),
+        path: original_path,
+    })
+}
+
+// recover_stale restores the original mount of the path left behind by a toda
+// which was killed before recovering it. The dead fuse mount is detached, and
+// the original mount is moved back from where toda moved it. It returns false
+// if there is nothing to recover, and fails if toda is still running on the
+// path. The dead mount is found by the labels of the kernel options.
+pub fn recover_stale<P: AsRef<Path>>(path: P, kernel: &hookfs::KernelOptions) -> Result<bool> {
+    let (original_path, new_path) = utils::encode_path(path)?;
+    let _lock = Lock::acquire(&new_path)?;
+    let mounts = mount::MountsInfo::parse_mounts()?;
+    if !mounts.is_mount_point(&new_path) {
+        info!("no moved mount at {}", new_path.display());
+        return Ok(false);
+    }
+
+    if mounts.is_toda(&original_path, kernel) {
+        info!("detach stale mount {}", original_path.display());
+        umount2(original_path.as_path(), MntFlags::MNT_DETACH)?;
+    }
+    mounts.move_mount(&new_path, &original_path)?;
+    info!("original mount of {} recovered", original_path.display());
+    Ok(true)
+}
+
 impl MountInjector {
     pub fn create_injection<P: AsRef<Path>>(
         path: P,
         injector_config: Vec<InjectorConfig>,
     ) -> Result<MountInjector> {
-        let original_path: PathBuf = path.as_ref().to_owned();
-
-        let mut base_path: PathBuf = path.as_ref().to_owned();
-        if !base_path.pop() {
-            return Err(anyhow!("path is the root"));
-        }
-
-        let mut new_path: PathBuf = base_path;
-        let original_filename = original_path
-            .file_name()
-            .ok_or(anyhow!("the path terminates in `..` or `/`"))?
-            .to_str()
-            .ok_or(anyhow!("path with non-UTF-8 character"))?;
-        let new_filename = format!("__chaosfs__{}__", original_filename);
-        new_path.push(new_filename.as_str());
+        let (original_path, new_path) = utils::encode_path(path)?;
 
         Ok(MountInjector {
             original_path,
             new_path,
             injector_config,
+            shadow: None,
+            snapshot: None,
+            checksum: false,
+            experiment: None,
+            kernel: Default::default(),
+            budget: None,
         })
     }
 
+    // set_shadow diverts all writes through the mount into `dir`
+    pub fn set_shadow<P: AsRef<Path>>(&mut self, dir: P) {
+        self.shadow = Some(dir.as_ref().to_owned());
+    }
+
+    // set_snapshot serves a read-only snapshot taken into `dir` at mount time
+    pub fn set_snapshot<P: AsRef<Path>>(&mut self, dir: P) {
+        self.snapshot = Some(dir.as_ref().to_owned());
+    }
+
+    // enable_checksum reports the blocks read back with a different checksum
+    // from the one written
+    pub fn enable_checksum(&mut self) {
+        self.checksum = true;
+    }
+
+    // set_experiment labels the logs and reports of this mount with the
+    // experiment id
+    pub fn set_experiment<S: Into<String>>(&mut self, experiment: S) {
+        self.experiment = Some(experiment.into());
+    }
+
+    // set_kernel_options tunes the caching and the size of the requests of
+    // the kernel
+    pub fn set_kernel_options(&mut self, kernel: hookfs::KernelOptions) {
+        self.kernel = kernel;
+    }
+
+    // set_memory_budget gives the mount its own memory budget, instead of the
+    // one of the process
+    pub fn set_memory_budget(&mut self, budget: Arc<MemoryBudget>) {
+        self.budget = Some(budget);
+    }
+
     // This method should be called in host namespace
     pub fn mount(&mut self) -> Result<MountInjectionGuard> {
         let original_path = self.original_path.clone();
         let new_path = self.new_path.clone();
 
+        let lock = Lock::acquire(&new_path)?;
         let mounts = mount::MountsInfo::parse_mounts()?;
 
         if mounts.non_root(&original_path)? {
-            // TODO: make the parent mount points private before move mount points
-            mounts.move_mount(original_path, new_path)?;
+            mounts.move_mount(&original_path, &new_path)?;
         } else {
             return Err(anyhow!("inject on a root mount"));
         }
 
+        // the original mount is moved back if the fuse cannot be set up, so
+        // that the application never sees an empty directory
+        self.serve(lock).map_err(|err| {
+            if let Err(err) = mount::MountsInfo::parse_mounts()
+                .and_then(|mounts| mounts.move_mount(&new_path, &original_path))
+            {
+                error!("fail to move back the original mount: {:?}", err);
+            }
+            err
+        })
+    }
+
+    fn serve(&mut self, lock: Lock) -> Result<MountInjectionGuard> {
         let injectors = MultiInjector::build(self.injector_config.clone())?;
 
-        let hookfs = Arc::new(hookfs::HookFs::new(
-            &self.original_path,
-            &self.new_path,
-            injectors,
-        ));
+        let mut hookfs = hookfs::HookFs::new(&self.original_path, &self.new_path, injectors);
+        if let Some(dir) = &self.shadow {
+            info!("divert writes into shadow {}", dir.display());
+            let shadow = hookfs::Shadow::new(dir, &self.new_path)?;
+            hookfs = hookfs.with_overlay(hookfs::Overlay::Shadow(shadow));
+        } else if let Some(dir) = &self.snapshot {
+            let snapshot = hookfs::Snapshot::create(dir, &self.new_path)?;
+            hookfs = hookfs.with_overlay(hookfs::Overlay::Snapshot(snapshot));
+        }
+        if let Some(budget) = &self.budget {
+            hookfs = hookfs.with_memory_budget(budget.clone());
+        }
+        if self.checksum {
+            hookfs = hookfs.with_checksum();
+        }
+        if let Some(experiment) = &self.experiment {
+            hookfs = hookfs.with_experiment(experiment.clone());
+        }
+        let mount_options = self.kernel.mount_options();
+        hookfs = hookfs.with_kernel_options(self.kernel.clone());
+        let label = hookfs.label().clone();
+        let hookfs = Arc::new(hookfs);
 
         let original_path = self.original_path.clone();
         let new_path = self.new_path.clone();
@@ -126,23 +303,29 @@ impl MountInjector {
 
         let (before_mount_waiter, before_mount_guard) = stop::lock();
         let handler = std::thread::spawn(box move || {
-            let fs = hookfs::AsyncFileSystem::from(cloned_hookfs);
+            let fs = hookfs::AsyncFileSystem::from(cloned_hookfs).with_span(info_span!(
+                "mount",
+                path = %label.path.display(),
+                experiment = ?label.experiment
+            ));
 
             std::fs::create_dir_all(new_path.as_path())?;
 
-            let args = ["allow_other", "fsname=toda", "default_permissions", "nonempty"];
+            let args = ["nonempty"];
             let flags: Vec<_> = args
                 .iter()
+                .copied()
+                .chain(mount_options.iter().map(String::as_str))
                 .flat_map(|item| vec![OsStr::new("-o"), OsStr::new(item)])
                 .collect();
 
             info!("mount with flags {:?}", flags);
 
             drop(before_mount_guard);
+            // the runtime is shared by every mount of the process, so that it's
+            // kept once this one is unmounted
             fuser::mount(fs, &original_path, &flags)?;
 
-            drop(hookfs::runtime::RUNTIME.write().unwrap().take().unwrap());
-
             Ok(())
         });
         // TODO: remove this. But wait for FUSE gets up
@@ -155,6 +338,7 @@ impl MountInjector {
             hookfs,
             original_path: self.original_path.clone(),
             new_path: self.new_path.clone(),
+            _lock: lock,
         })
     }
 }
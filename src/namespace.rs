@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sched::{setns, CloneFlags};
+use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, ForkResult};
+use tracing::info;
+
+// the child forked into the pid namespace, to forward the signals to
+static CHILD: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(signum: libc::c_int) {
+    unsafe {
+        libc::kill(CHILD.load(Ordering::SeqCst), signum);
+    }
+}
+
+// enter moves toda into the mount namespace of the process, and optionally
+// into its user and pid namespaces. It must be called before any thread is
+// spawned, as setns fails in a multithreaded process.
+//
+// Only the children of toda would be in the pid namespace, so toda forks into
+// it. The parent forwards SIGINT and SIGTERM to the child and returns the exit
+// code of the child, while the child returns None and goes on.
+pub fn enter(pid: i32, user: bool, pid_namespace: bool) -> Result<Option<i32>> {
+    // all namespaces are opened first, as /proc is another one once the mount
+    // namespace is entered
+    let mut namespaces = Vec::new();
+    if user {
+        namespaces.push((open(pid, "user")?, CloneFlags::CLONE_NEWUSER));
+    }
+    namespaces.push((open(pid, "mnt")?, CloneFlags::CLONE_NEWNS));
+    if pid_namespace {
+        namespaces.push((open(pid, "pid")?, CloneFlags::CLONE_NEWPID));
+    }
+
+    for (file, flag) in namespaces.iter() {
+        info!("enter namespace {:?} of process {}", flag, pid);
+        setns(file.as_raw_fd(), *flag).with_context(|| format!("setns {:?}", flag))?;
+    }
+    if !pid_namespace {
+        return Ok(None);
+    }
+
+    let child = match fork()? {
+        ForkResult::Child => return Ok(None),
+        ForkResult::Parent { child } => child,
+    };
+    info!("forked {} into the pid namespace", child);
+    CHILD.store(child.as_raw(), Ordering::SeqCst);
+    unsafe { signal(Signal::SIGINT, SigHandler::Handler(forward_signal))? };
+    unsafe { signal(Signal::SIGTERM, SigHandler::Handler(forward_signal))? };
+
+    loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => return Ok(Some(code)),
+            Ok(WaitStatus::Signaled(_, signal, _)) => return Ok(Some(128 + signal as i32)),
+            Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+fn open(pid: i32, namespace: &str) -> Result<File> {
+    let path = format!("/proc/{}/ns/{}", pid, namespace);
+    File::open(&path).with_context(|| format!("open {}", path))
+}
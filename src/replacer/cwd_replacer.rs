@@ -2,7 +2,7 @@ use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
-use tracing::{error, info, trace};
+use tracing::{error, info, trace, warn};
 
 use super::utils::all_processes;
 use super::{ptrace, Replacer};
@@ -19,6 +19,20 @@ impl CwdReplacer {
     ) -> Result<CwdReplacer> {
         info!("preparing cmdreplacer");
 
+        // the new mount is out of the reach of a process chrooted under the
+        // path, so its root cannot be replaced
+        for process in all_processes()? {
+            if let Ok(root) = std::fs::read_link(format!("/proc/{}/root", process.pid)) {
+                if root.starts_with(detect_path.as_ref()) {
+                    warn!(
+                        "process {} is chrooted in {}, which is not injected",
+                        process.pid,
+                        root.display()
+                    );
+                }
+            }
+        }
+
         let processes = all_processes()?
             .filter_map(|process| -> Option<_> {
                 let pid = process.pid;
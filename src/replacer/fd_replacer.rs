@@ -134,10 +134,17 @@ impl ProcessAccessor {
 
                 ; jmp ->end
                 ; ->start:
-                // fcntl
+                // fcntl(F_GETFD), as dup2 clears FD_CLOEXEC
                 ; mov rax, 0x48
                 ; mov rdi, QWORD [r14+r15] // fd
-                ; mov rsi, 0x3
+                ; mov rsi, libc::F_GETFD
+                ; mov rdx, 0x0
+                ; syscall
+                ; mov rbx, rax // store fd flags in rbx
+                // fcntl(F_GETFL)
+                ; mov rax, 0x48
+                ; mov rdi, QWORD [r14+r15] // fd
+                ; mov rsi, libc::F_GETFL
                 ; mov rdx, 0x0
                 ; syscall
                 ; mov rsi, rax
@@ -165,6 +172,12 @@ impl ProcessAccessor {
                 ; mov rdi, r12
                 ; mov rsi, QWORD [r14+r15] // fd
                 ; syscall
+                // fcntl(F_SETFD)
+                ; mov rax, 0x48
+                ; mov rdi, QWORD [r14+r15] // fd
+                ; mov rsi, libc::F_SETFD
+                ; mov rdx, rbx
+                ; syscall
                 // close
                 ; mov rax, 0x3
                 ; mov rdi, r12
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::io::{Cursor, Read, Write};
 use std::iter::FromIterator;
@@ -22,6 +22,7 @@ struct ReplaceCase {
     pub flags: u64,
     pub path: PathBuf,
     pub offset: u64,
+    pub open_flags: u64,
 }
 
 #[derive(Clone, Copy)]
@@ -34,6 +35,7 @@ struct RawReplaceCase {
     flags: u64,
     new_path_offset: u64,
     offset: u64,
+    open_flags: u64,
 }
 
 impl RawReplaceCase {
@@ -44,6 +46,7 @@ impl RawReplaceCase {
         flags: u64,
         new_path_offset: u64,
         offset: u64,
+        open_flags: u64,
     ) -> RawReplaceCase {
         RawReplaceCase {
             memory_addr,
@@ -52,6 +55,7 @@ impl RawReplaceCase {
             flags,
             new_path_offset,
             offset,
+            open_flags,
         }
     }
 }
@@ -87,6 +91,7 @@ impl ProcessAccessorBuilder {
         flags: u64,
         new_path: PathBuf,
         offset: u64,
+        open_flags: u64,
     ) -> anyhow::Result<()> {
         info!("push case");
 
@@ -108,6 +113,7 @@ impl ProcessAccessorBuilder {
             flags,
             new_path_offset,
             offset,
+            open_flags,
         ));
 
         Ok(())
@@ -125,6 +131,7 @@ impl FromIterator<ReplaceCase> for ProcessAccessorBuilder {
                 case.flags,
                 case.path,
                 case.offset,
+                case.open_flags,
             ) {
                 error!("fail to write to AccessorBuilder. Error: {:?}", err)
             }
@@ -183,42 +190,32 @@ impl ProcessAccessor {
 
                 ; jmp ->end
                 ; ->start:
-                // munmap
-                ; mov rax, 0x0B
-                ; mov rdi, QWORD [r14+r15] // addr
-                ; mov rsi, QWORD [r14+r15+8] // length
-                ; mov rdx, 0x0
-                ; syscall
                 // open
                 ; mov rax, 0x2
-
                 ; lea rdi, [-> new_paths]
-                ; add r15, 8 * 4 // set r15 to point to path
-                ; add rdi, QWORD [r14+r15] // path
-                ; sub r15, 8 * 4
-
-                ; mov rsi, libc::O_RDWR
+                ; add rdi, QWORD [r14+r15+32] // path
+                ; mov rsi, QWORD [r14+r15+48] // open flags
                 ; mov rdx, 0x0
                 ; syscall
-                ; push rax
-                ; mov r8, rax // fd
-                // mmap
+                // the original mapping is kept if the file cannot be opened
+                ; cmp rax, 0
+                ; jl ->next
+                ; mov r12, rax // store newly opened fd in r12
+                // mmap with MAP_FIXED, which replaces the original mapping
                 ; mov rax, 0x9
-                ; add r15, 8
-                ; mov rsi, QWORD [r14+r15] // length
-                ; add r15, 8
-                ; mov rdx, QWORD [r14+r15] // prot
-                ; add r15, 8
-                ; mov r10, QWORD [r14+r15] // flags
-                ; add r15, 16
-                ; mov r9, QWORD [r14+r15] // offset
+                ; mov rdi, QWORD [r14+r15] // addr
+                ; mov rsi, QWORD [r14+r15+8] // length
+                ; mov rdx, QWORD [r14+r15+16] // prot
+                ; mov r10, QWORD [r14+r15+24] // flags
+                ; mov r8, r12 // fd
+                ; mov r9, QWORD [r14+r15+40] // offset
                 ; syscall
-                ; sub r15, 8 * 5
                 // close
                 ; mov rax, 0x3
-                ; pop rdi
+                ; mov rdi, r12
                 ; syscall
 
+                ; ->next:
                 ; add r15, std::mem::size_of::<RawReplaceCase>() as i32
                 ; ->end:
                 ; mov r13, QWORD [->cases_length]
@@ -238,7 +235,9 @@ impl ProcessAccessor {
     }
 }
 
-fn get_prot_and_flags_from_perms<S: AsRef<str>>(perms: S) -> (u64, u64) {
+// get_prot_and_flags_from_perms returns the protection and flags of the
+// mapping, and the flags to open its file
+fn get_prot_and_flags_from_perms<S: AsRef<str>>(perms: S) -> (u64, u64, u64) {
     let bytes = perms.as_ref().as_bytes();
     let mut prot = ProtFlags::empty();
     let mut flags = MapFlags::MAP_PRIVATE;
@@ -262,7 +261,41 @@ fn get_prot_and_flags_from_perms<S: AsRef<str>>(perms: S) -> (u64, u64) {
         prot,
         flags
     );
-    (prot.bits() as u64, flags.bits() as u64)
+    // only a shared writable mapping needs the file to be writable
+    let open_flags = if flags == MapFlags::MAP_SHARED && prot.contains(ProtFlags::PROT_WRITE) {
+        libc::O_RDWR
+    } else {
+        libc::O_RDONLY
+    };
+    (
+        prot.bits() as u64,
+        (flags | MapFlags::MAP_FIXED).bits() as u64,
+        open_flags as u64,
+    )
+}
+
+// copied_mappings returns the start addresses of the mappings of the process
+// with anonymous pages, which are the ones copied on write for a private
+// mapping of a file
+fn copied_mappings(pid: i32) -> Option<HashSet<u64>> {
+    let smaps = std::fs::read_to_string(format!("/proc/{}/smaps", pid)).ok()?;
+    let mut copied = HashSet::new();
+    let mut start = None;
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        let field = fields.next().unwrap_or_default();
+        if field == "Anonymous:" {
+            if fields.next() != Some("0") {
+                copied.insert(start?);
+            }
+        } else if let Some((from, _)) = field.split_once('-') {
+            // the header of a mapping starts with its address range
+            if let Ok(from) = u64::from_str_radix(from, 16) {
+                start = Some(from);
+            }
+        }
+    }
+    Some(copied)
 }
 
 pub struct MmapReplacer {
@@ -285,33 +318,54 @@ impl MmapReplacer {
 
                 let traced_process = ptrace::trace(pid).ok()?;
                 let maps = process.maps().ok()?;
+                let copied = copied_mappings(pid);
 
-                Some((traced_process, maps))
+                Some((traced_process, maps, copied))
             })
-            .flat_map(|(process, maps)| {
+            .flat_map(|(process, maps, copied)| {
                 maps.into_iter()
-                    .filter_map(move |entry| {
-                        match entry.pathname {
-                            MMapPath::Path(path) => {
-                                let (start_address, end_address) = entry.address;
-                                let length = end_address - start_address;
-                                let (prot, flags) = get_prot_and_flags_from_perms(entry.perms);
-                                // TODO: extract permission from perms
-
-                                let case = ReplaceCase {
-                                    memory_addr: start_address,
-                                    length,
-                                    prot,
-                                    flags,
-                                    path,
-                                    offset: entry.offset,
-                                };
-                                Some((process.clone(), case))
-                            }
-                            _ => None,
+                    .filter_map(move |entry| match entry.pathname {
+                        MMapPath::Path(path) => {
+                            let (start_address, end_address) = entry.address;
+                            let length = end_address - start_address;
+                            let (prot, flags, open_flags) =
+                                get_prot_and_flags_from_perms(&entry.perms);
+
+                            let case = ReplaceCase {
+                                memory_addr: start_address,
+                                length,
+                                prot,
+                                flags,
+                                path,
+                                offset: entry.offset,
+                                open_flags,
+                            };
+                            Some((process.clone(), case))
                         }
+                        _ => None,
                     })
                     .filter(|(_, case)| case.path.starts_with(detect_path))
+                    .filter(move |(_, case)| {
+                        // the pages of a private mapping which have been
+                        // copied on write differ from the file, and would be
+                        // lost. So would the ones of a writable mapping, which
+                        // may still be written, and of a process whose copies
+                        // are unknown.
+                        let private = case.flags & MapFlags::MAP_SHARED.bits() as u64 == 0;
+                        let writable = case.prot & ProtFlags::PROT_WRITE.bits() as u64 != 0;
+                        let modified = copied
+                            .as_ref()
+                            .map_or(true, |copied| copied.contains(&case.memory_addr));
+                        let skipped = private && (writable || modified);
+                        if skipped {
+                            info!(
+                                "skip private mapping of {} at {:x}",
+                                case.path.display(),
+                                case.memory_addr
+                            );
+                        }
+                        !skipped
+                    })
                     .filter_map(|(process, mut case)| {
                         let stripped_path = case.path.strip_prefix(&detect_path).ok()?;
                         case.path = new_path.join(stripped_path);
@@ -18,9 +18,16 @@ pub trait Replacer {
 #[derive(Default)]
 pub struct UnionReplacer<'a> {
     replacers: Vec<Box<dyn Replacer + 'a>>,
+    keep_cwd: bool,
 }
 
 impl<'a> UnionReplacer<'a> {
+    // keep_cwd leaves the working directories under the path unchanged
+    pub fn keep_cwd(mut self) -> Self {
+        self.keep_cwd = true;
+        self
+    }
+
     pub fn prepare<P1: AsRef<Path>, P2: AsRef<Path>>(
         &mut self,
         detect_path: P1,
@@ -30,9 +37,11 @@ impl<'a> UnionReplacer<'a> {
             Err(err) => error!("Error while preparing fd replacer: {:?}", err),
             Ok(replacer) => self.replacers.push(Box::new(replacer)),
         }
-        match CwdReplacer::prepare(&detect_path, &new_path) {
-            Err(err) => error!("Error while preparing cwd replacer: {:?}", err),
-            Ok(replacer) => self.replacers.push(Box::new(replacer)),
+        if !self.keep_cwd {
+            match CwdReplacer::prepare(&detect_path, &new_path) {
+                Err(err) => error!("Error while preparing cwd replacer: {:?}", err),
+                Ok(replacer) => self.replacers.push(Box::new(replacer)),
+            }
         }
         match MmapReplacer::prepare(&detect_path, &new_path) {
             Err(err) => error!("Error while preparing mmap replacer: {:?}", err),
@@ -0,0 +1,365 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::Serialize;
+use structopt::StructOpt;
+use tracing::{error, info};
+
+use crate::hookfs::{Error, MountLabel};
+use crate::injector::injector_config::{
+    FaultConfig, FaultsConfig, FilterConfig, LatencyConfig, MistakeConfig, MistakeType,
+    MistakesConfig,
+};
+use crate::injector::{rng, InjectorConfig, Method, OnError};
+use crate::jsonrpc::ConfigLoader;
+
+#[derive(StructOpt, Debug, Clone)]
+pub struct SoakOptions {
+    /// How long the soak runs in total
+    #[structopt(long, default_value = "24")]
+    pub hours: f64,
+
+    /// How long a combination of presets stays active before the next one is chosen
+    #[structopt(long, default_value = "5m", parse(try_from_str = humantime::parse_duration))]
+    pub round: Duration,
+
+    /// Upper bound of the latency injected by the latency preset
+    #[structopt(long = "max-latency", default_value = "200ms", parse(try_from_str = humantime::parse_duration))]
+    pub max_latency: Duration,
+
+    /// Upper bound of the percent of operations affected by one preset
+    #[structopt(long = "max-percent", default_value = "10")]
+    pub max_percent: i32,
+
+    /// JSON-lines file recording every error returned to the application
+    #[structopt(long = "audit-log", parse(from_os_str))]
+    pub audit_log: Option<PathBuf>,
+
+    /// File to write the final summary report to
+    #[structopt(long, parse(from_os_str))]
+    pub report: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Preset {
+    Latency,
+    Fault,
+    Mistake,
+}
+
+const PRESETS: [Preset; 3] = [Preset::Latency, Preset::Fault, Preset::Mistake];
+
+// a hundred years, beyond which the deadline of the soak may overflow
+const MAX_HOURS: f64 = 100.0 * 365.0 * 24.0;
+
+// the experiment every round of the soak replaces
+pub const SOAK_EXPERIMENT: &str = "soak";
+
+const SOAK_ERRNOS: [i32; 4] = [libc::EIO, libc::ENOSPC, libc::EINTR, libc::EAGAIN];
+
+impl Preset {
+    fn name(&self) -> &'static str {
+        match self {
+            Preset::Latency => "latency",
+            Preset::Fault => "fault",
+            Preset::Mistake => "mistake",
+        }
+    }
+
+    fn build<R: Rng>(&self, options: &SoakOptions, rng: &mut R) -> InjectorConfig {
+        let filter = FilterConfig {
+            path: None,
+            methods: None,
+            percent: rng.gen_range(1, options.max_percent.max(1) + 1),
+            ..Default::default()
+        };
+
+        match self {
+            Preset::Latency => {
+                let max_latency = options.max_latency.as_millis().max(1) as u64;
+                InjectorConfig::Latency(LatencyConfig {
+                    filter,
+                    latency: Duration::from_millis(rng.gen_range(1, max_latency + 1)),
+                    per_byte: None,
+                    adaptive: None,
+                    per_method: Vec::new(),
+                    jitter: None,
+                })
+            }
+            Preset::Fault => {
+                let errno = *SOAK_ERRNOS.choose(rng).unwrap();
+                InjectorConfig::Fault(FaultsConfig {
+                    filter: FilterConfig {
+                        methods: Some(vec!["read".to_owned(), "write".to_owned()]),
+                        ..filter
+                    },
+                    faults: vec![FaultConfig { errno, weight: 1 }],
+                })
+            }
+            Preset::Mistake => InjectorConfig::Mistake(MistakesConfig {
+                filter: FilterConfig {
+                    methods: Some(vec!["read".to_owned()]),
+                    ..filter
+                },
+                mistake: MistakeConfig {
+                    filling: if rng.gen() {
+                        MistakeType::Zero
+                    } else {
+                        MistakeType::Random
+                    },
+                    max_length: 64,
+                    max_occurrences: 1,
+                },
+            }),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct AuditRecord<'a> {
+    mount: &'a MountLabel,
+    timestamp: u64,
+    round: usize,
+    method: String,
+    #[serde(serialize_with = "crate::utils::os_path::serialize")]
+    path: &'a Path,
+    error: String,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RoundReport {
+    pub index: usize,
+    pub started: u64,
+    pub presets: Vec<String>,
+    pub errors: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SoakReport {
+    pub mount: MountLabel,
+    pub started: u64,
+    pub finished: u64,
+    pub total_errors: u64,
+    pub errors_by_errno: BTreeMap<String, u64>,
+    pub errors_by_method: BTreeMap<String, u64>,
+    pub rounds: Vec<RoundReport>,
+}
+
+#[derive(Debug)]
+struct SoakLog {
+    mount: MountLabel,
+    audit: Mutex<Option<BufWriter<File>>>,
+    report: Mutex<SoakReport>,
+}
+
+impl SoakLog {
+    fn record(&self, round: usize, method: &Method, path: &Path, err: &Error) {
+        let errno = match err {
+            Error::Sys(errno) => format!("{:?}", errno),
+            err => err.to_string(),
+        };
+        let method = format!("{:?}", method);
+
+        {
+            let mut report = self.report.lock().unwrap();
+            report.total_errors += 1;
+            *report.errors_by_errno.entry(errno.clone()).or_default() += 1;
+            *report.errors_by_method.entry(method.clone()).or_default() += 1;
+            if let Some(current) = report.rounds.get_mut(round) {
+                current.errors += 1;
+            }
+        }
+
+        if let Some(audit) = &mut *self.audit.lock().unwrap() {
+            let record = AuditRecord {
+                mount: &self.mount,
+                timestamp: now(),
+                round,
+                method,
+                path,
+                error: errno,
+            };
+            let result = serde_json::to_writer(&mut *audit, &record)
+                .map_err(anyhow::Error::from)
+                .and_then(|_| Ok(audit.write_all(b"\n")?));
+            if let Err(err) = result {
+                error!("fail to write soak audit log: {:?}", err);
+            }
+        }
+    }
+}
+
+pub struct Soak {
+    stop: mpsc::Sender<()>,
+    handler: JoinHandle<SoakReport>,
+    report_path: Option<PathBuf>,
+}
+
+impl Soak {
+    // start spawns a thread cycling randomized presets over the mount, as the
+    // experiment of the loader. `done` is called once the soak finishes by
+    // itself.
+    pub fn start<F>(
+        options: SoakOptions,
+        loader: ConfigLoader,
+        mount: MountLabel,
+        done: F,
+    ) -> Result<Soak>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let duration = soak_duration(options.hours)?;
+        if options.round == Duration::from_secs(0) {
+            return Err(anyhow!("soak round should be positive"));
+        }
+
+        let audit = match &options.audit_log {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        let log = Arc::new(SoakLog {
+            mount: mount.clone(),
+            audit: Mutex::new(audit),
+            report: Mutex::new(SoakReport {
+                mount,
+                started: now(),
+                ..Default::default()
+            }),
+        });
+
+        let (stop, stop_receiver) = mpsc::channel();
+        let report_path = options.report.clone();
+        let handler = std::thread::spawn(move || {
+            let deadline = Instant::now() + duration;
+            let mut rng = rng::fork();
+            let mut round = 0;
+
+            info!("start soak for {:?}", duration);
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+
+                let count = rng.gen_range(1, PRESETS.len() + 1);
+                let presets: Vec<_> = PRESETS.choose_multiple(&mut rng, count).cloned().collect();
+                let config: Vec<_> = presets
+                    .iter()
+                    .map(|preset| preset.build(&options, &mut rng))
+                    .collect();
+                info!("soak round {} with config {:?}", round, config);
+
+                log.report.lock().unwrap().rounds.push(RoundReport {
+                    index: round,
+                    started: self::now(),
+                    presets: presets.iter().map(|item| item.name().to_owned()).collect(),
+                    errors: 0,
+                });
+                // the experiment of the last round is replaced, which releases
+                // the operations it delays
+                let log_errors = log.clone();
+                match loader.prepare(config) {
+                    Ok(experiment) => {
+                        loader.start(experiment.on_error(OnError::new(move |method, path, err| {
+                            log_errors.record(round, method, path, err)
+                        })))
+                    }
+                    Err(err) => error!("fail to build soak round {}: {:?}", round, err),
+                }
+                round += 1;
+
+                match stop_receiver.recv_timeout(options.round.min(deadline - now)) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+
+            info!("soak finished after {} rounds", round);
+            if let Err(err) = loader.restore(None) {
+                error!("fail to stop soak experiment: {:?}", err);
+            }
+            done();
+
+            let mut report = log.report.lock().unwrap().clone();
+            report.finished = now();
+            if let Some(audit) = &mut *log.audit.lock().unwrap() {
+                if let Err(err) = audit.flush() {
+                    error!("fail to flush soak audit log: {:?}", err);
+                }
+            }
+
+            report
+        });
+
+        Ok(Soak {
+            stop,
+            handler,
+            report_path,
+        })
+    }
+
+    pub fn finish(self) -> Result<SoakReport> {
+        // the soak thread may have already exited
+        let _ = self.stop.send(());
+        let report = self
+            .handler
+            .join()
+            .map_err(|_| anyhow!("soak thread panicked"))?;
+
+        let content = serde_json::to_string_pretty(&report)?;
+        info!("soak report: {}", content);
+        if let Some(path) = &self.report_path {
+            std::fs::write(path, content)?;
+        }
+
+        Ok(report)
+    }
+}
+
+// soak_duration converts the hours of the soak, which should be positive and
+// at most MAX_HOURS
+fn soak_duration(hours: f64) -> Result<Duration> {
+    // the comparisons are false for NaN
+    if !(hours > 0.0 && hours <= MAX_HOURS) {
+        return Err(anyhow!(
+            "soak duration should be positive and at most {} hours",
+            MAX_HOURS
+        ));
+    }
+    Ok(Duration::from_secs_f64(hours * 3600.0))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soak_duration() {
+        assert_eq!(soak_duration(0.5).unwrap(), Duration::from_secs(1800));
+        assert_eq!(
+            soak_duration(MAX_HOURS).unwrap(),
+            Duration::from_secs(MAX_HOURS as u64 * 3600)
+        );
+        for hours in &[0.0, -1.0, f64::NAN, f64::INFINITY, MAX_HOURS * 2.0] {
+            assert!(soak_duration(*hours).is_err());
+        }
+    }
+}
@@ -1,6 +1,10 @@
+use std::ffi::{OsStr, OsString};
+use std::hash::Hasher;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
+use regex::bytes::Regex;
 
 pub fn encode_path<P: AsRef<Path>>(original_path: P) -> Result<(PathBuf, PathBuf)> {
     let original_path: PathBuf = original_path.as_ref().to_owned();
@@ -13,11 +17,274 @@ pub fn encode_path<P: AsRef<Path>>(original_path: P) -> Result<(PathBuf, PathBuf
     let mut new_path: PathBuf = base_path;
     let original_filename = original_path
         .file_name()
-        .ok_or(anyhow!("the path terminates in `..` or `/`"))?
-        .to_str()
-        .ok_or(anyhow!("path with non-UTF-8 character"))?;
-    let new_filename = format!("__chaosfs__{}__", original_filename);
-    new_path.push(new_filename.as_str());
+        .ok_or(anyhow!("the path terminates in `..` or `/`"))?;
+    let mut new_filename = OsString::from("__chaosfs__");
+    new_filename.push(original_filename);
+    new_filename.push("__");
+    new_path.push(new_filename);
 
     Ok((original_path, new_path))
 }
+
+// os_path serializes a path which may not be UTF-8, as a string if it's valid
+// UTF-8, or as the array of its bytes otherwise, so that the file names are
+// never mangled or fail a report. It's used with `#[serde(with = ...)]`.
+pub mod os_path {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::path::{Path, PathBuf};
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Str(String),
+        Bytes(Vec<u8>),
+    }
+
+    impl From<Repr> for PathBuf {
+        fn from(repr: Repr) -> PathBuf {
+            match repr {
+                Repr::Str(path) => PathBuf::from(path),
+                Repr::Bytes(bytes) => PathBuf::from(OsString::from_vec(bytes)),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(path) => serializer.serialize_str(path),
+            None => serializer.serialize_bytes(path.as_os_str().as_bytes()),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(Repr::deserialize(deserializer)?.into())
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            path: &Option<PathBuf>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match path {
+                Some(path) => super::serialize(path, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<PathBuf>, D::Error> {
+            Ok(Option::<Repr>::deserialize(deserializer)?.map(PathBuf::from))
+        }
+    }
+}
+
+// usage returns the bytes and inodes used by the subtree. Entries which
+// disappear during the walk are ignored.
+pub fn usage(path: &Path) -> (u64, u64) {
+    let mut bytes = 0;
+    let mut inodes = 0;
+
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return (0, 0),
+    };
+    for entry in entries.flatten() {
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        inodes += 1;
+        if metadata.is_dir() {
+            let (dir_bytes, dir_inodes) = usage(&entry.path());
+            bytes += dir_bytes;
+            inodes += dir_inodes;
+        } else {
+            bytes += metadata.len();
+        }
+    }
+
+    (bytes, inodes)
+}
+
+// Fnv is the 64-bit FNV-1a hasher. Its hashes are stable across builds and
+// runs, unlike the ones of the hasher of std, so that they can be saved.
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// PathPattern matches the paths, which may not be UTF-8, against a glob or a
+// regex on their bytes. A path which isn't UTF-8 matches as well if the path
+// with its invalid sequences replaced matches, so that `*` and `.` match them,
+// while `(?-u:\xff)` in a regex matches them exactly.
+#[derive(Debug, Clone)]
+pub struct PathPattern(Regex);
+
+impl PathPattern {
+    // glob matches like glob::Pattern, whose syntax errors it reports. `*`,
+    // `?` and the classes match `/` only without a literal separator, and
+    // `**` matches any directories.
+    pub fn glob(glob: &str, literal_separator: bool) -> Result<Self> {
+        glob::Pattern::new(glob)?;
+
+        let any = if literal_separator { "[^/]" } else { "." };
+        let chars: Vec<char> = glob.chars().collect();
+        let mut regex = String::from("(?s)^");
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                // the recursive wildcard is a whole component, which is
+                // checked by glob::Pattern
+                '*' if chars.get(i + 1) == Some(&'*') => {
+                    if chars.get(i + 2) == Some(&'/') {
+                        regex.push_str("(?:.*/)?");
+                        i += 3;
+                    } else {
+                        regex.push_str(".*");
+                        i += 2;
+                    }
+                    continue;
+                }
+                '*' => {
+                    regex.push_str(any);
+                    regex.push('*');
+                }
+                '?' => regex.push_str(any),
+                '[' => {
+                    let negated = chars[i + 1] == '!';
+                    let start = if negated { i + 2 } else { i + 1 };
+                    // the first character of the class is never its end
+                    let end = start
+                        + 1
+                        + chars[start + 1..]
+                            .iter()
+                            .position(|&c| c == ']')
+                            .ok_or_else(|| anyhow!("invalid class in {}", glob))?;
+                    let mut class = String::new();
+                    let mut j = start;
+                    while j < end {
+                        if j + 3 <= end && chars[j + 1] == '-' {
+                            class.push_str(&escape(chars[j]));
+                            class.push('-');
+                            class.push_str(&escape(chars[j + 2]));
+                            j += 3;
+                        } else {
+                            class.push_str(&escape(chars[j]));
+                            j += 1;
+                        }
+                    }
+                    regex.push_str(&match (negated, literal_separator) {
+                        (false, false) => format!("[{}]", class),
+                        (false, true) => format!("[{}&&[^/]]", class),
+                        (true, false) => format!("[^{}]", class),
+                        (true, true) => format!("[^/{}]", class),
+                    });
+                    i = end;
+                }
+                c => regex.push_str(&escape(c)),
+            }
+            i += 1;
+        }
+        regex.push('$');
+
+        Ok(PathPattern(Regex::new(&regex)?))
+    }
+
+    // regex matches the whole path against the regex
+    pub fn regex(regex: &str) -> Result<Self> {
+        Ok(PathPattern(Regex::new(&format!("^(?:{})$", regex))?))
+    }
+
+    pub fn matches<P: AsRef<OsStr>>(&self, path: P) -> bool {
+        let path = path.as_ref().as_bytes();
+        self.0.is_match(path)
+            || (std::str::from_utf8(path).is_err()
+                && self.0.is_match(String::from_utf8_lossy(path).as_bytes()))
+    }
+}
+
+fn escape(c: char) -> String {
+    regex::escape(c.encode_utf8(&mut [0; 4]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(bytes: &[u8]) -> &OsStr {
+        OsStr::from_bytes(bytes)
+    }
+
+    #[test]
+    fn test_glob() {
+        let glob = PathPattern::glob("/data/*.log", true).unwrap();
+        assert!(glob.matches("/data/app.log"));
+        assert!(!glob.matches("/data/dir/app.log"));
+        assert!(glob.matches(name(b"/data/\xff.log")));
+        assert!(!PathPattern::glob("/data/*.log", true)
+            .unwrap()
+            .matches("/data/app.txt"));
+
+        assert!(PathPattern::glob("*.log", false)
+            .unwrap()
+            .matches("dir/app.log"));
+        let recursive = PathPattern::glob("/data/**/[a-c]?[!x].db", true).unwrap();
+        assert!(recursive.matches("/data/b1y.db"));
+        assert!(recursive.matches("/data/dir/sub/a.y.db"));
+        assert!(!recursive.matches("/data/d1y.db"));
+        assert!(!recursive.matches("/data/a1x.db"));
+        assert!(!recursive.matches("/data/a//.db"));
+        assert!(PathPattern::glob("[]]", true).unwrap().matches("]"));
+        assert!(PathPattern::glob("a**", true).is_err());
+    }
+
+    fn fnv(chunks: &[&[u8]]) -> u64 {
+        let mut hasher = Fnv::default();
+        for chunk in chunks {
+            hasher.write(chunk);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn test_fnv() {
+        assert_eq!(fnv(&[]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv(&[b"a"]), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv(&[b"foobar"]), 0x8594_4171_f739_67e8);
+        // the hash doesn't depend on how the content is split
+        assert_eq!(fnv(&[b"foo", b"", b"bar"]), fnv(&[b"foobar"]));
+    }
+
+    #[test]
+    fn test_regex() {
+        let regex = PathPattern::regex(r"/data/(?-u:\xff)").unwrap();
+        assert!(regex.matches(name(b"/data/\xff")));
+        assert!(!regex.matches(name(b"/data/\xfe")));
+        let regex = PathPattern::regex("/data/.*").unwrap();
+        assert!(regex.matches(name(b"/data/caf\xe9")));
+        assert!(!regex.matches(name(b"/other/caf\xe9")));
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2020 Chaos Mesh Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fs::{read_to_string, write, File};
+use std::path::{Path, PathBuf};
+
+use nix::mount::{mount, umount, MsFlags};
+use toda::builder::TodaBuilder;
+use toda::injector::InjectorConfig;
+
+// tmpfs mounts a tmpfs to inject into, as toda only injects into a mount
+fn tmpfs(name: &str) -> PathBuf {
+    let path: PathBuf = ["/tmp/test_builder", name].iter().collect();
+    std::fs::create_dir_all(&path).unwrap();
+    mount(
+        Some("tmpfs"),
+        path.as_path(),
+        Some("tmpfs"),
+        MsFlags::empty(),
+        None::<&str>,
+    )
+    .unwrap();
+    write(path.join("file"), "hello").unwrap();
+    path
+}
+
+fn rules(config: &str) -> Vec<InjectorConfig> {
+    serde_json::from_str(config).unwrap()
+}
+
+fn open_error(path: &Path) -> Option<i32> {
+    File::open(path.join("file"))
+        .err()
+        .and_then(|err| err.raw_os_error())
+}
+
+const FAULT: &str =
+    r#"[{"type":"fault","methods":["open"],"percent":100,"faults":[{"errno":5,"weight":1}]}]"#;
+
+#[test]
+fn inject_update_and_recover() {
+    let path = tmpfs("inject_update_and_recover");
+    let toda = TodaBuilder::new(&path).mount_only(true).inject().unwrap();
+    assert_eq!(read_to_string(path.join("file")).unwrap(), "hello");
+
+    toda.update(rules(FAULT)).unwrap();
+    assert_eq!(open_error(&path), Some(libc::EIO));
+
+    // invalid rules are refused, and the injected ones are kept
+    let invalid = rules(r#"[{"type":"fault","percent":101,"faults":[{"errno":5,"weight":1}]}]"#);
+    assert!(toda.update(invalid).is_err());
+    assert_eq!(open_error(&path), Some(libc::EIO));
+
+    toda.update(Vec::new()).unwrap();
+    assert_eq!(open_error(&path), None);
+
+    toda.recover().unwrap();
+    assert_eq!(read_to_string(path.join("file")).unwrap(), "hello");
+    umount(path.as_path()).unwrap();
+}
+
+#[test]
+fn inject_several_paths() {
+    let first = tmpfs("inject_several_paths_first");
+    let second = tmpfs("inject_several_paths_second");
+    // the rules of the builder are validated before the mount
+    assert!(TodaBuilder::new(&first)
+        .mount_only(true)
+        .rules(rules(r#"[{"type":"fault","percent":101,"faults":[]}]"#))
+        .inject()
+        .is_err());
+
+    let first_toda = TodaBuilder::new(&first)
+        .mount_only(true)
+        .rules(rules(FAULT))
+        .inject()
+        .unwrap();
+    let second_toda = TodaBuilder::new(&second).mount_only(true).inject().unwrap();
+    assert_eq!(open_error(&first), Some(libc::EIO));
+    assert_eq!(open_error(&second), None);
+
+    // the other path keeps serving once one is recovered
+    first_toda.recover().unwrap();
+    assert_eq!(open_error(&first), None);
+    second_toda.update(rules(FAULT)).unwrap();
+    assert_eq!(open_error(&second), Some(libc::EIO));
+    second_toda.recover().unwrap();
+    assert_eq!(open_error(&second), None);
+
+    umount(first.as_path()).unwrap();
+    umount(second.as_path()).unwrap();
+}
@@ -1,8 +1,57 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
 use std::sync::mpsc::channel;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use anyhow::anyhow;
+use nix::errno::Errno;
+use toda::hookfs::{self, HookFs};
+use toda::injector::{Injector, InjectorConfig, IoContext, Method, MultiInjector};
 use toda::jsonrpc::{self, new_handler, Comm};
+
+// handle returns the response of an rpc with the status, serving the mount if
+// any, to the request
+fn handle(status: anyhow::Result<()>, hookfs: Option<Arc<HookFs>>, request: &str) -> String {
+    let (tx, _rx) = channel();
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(status),
+        Mutex::new(tx),
+        hookfs,
+    ));
+    io.handle_request_sync(request).unwrap()
+}
+
+fn request(method: &str, params: &str) -> String {
+    format!(
+        r#"{{"jsonrpc": "2.0","method":"{}","params":{},"id":1}}"#,
+        method, params
+    )
+}
+
+// serve returns a handler serving the mount, which isn't mounted, so that its
+// rules are only run by the tests
+fn serve(name: &str) -> (Arc<HookFs>, jsonrpc_core::IoHandler) {
+    let (tx, _rx) = channel();
+    let hookfs = Arc::new(HookFs::new(
+        format!("/tmp/toda_{}", name),
+        format!("/tmp/__chaosfs__toda_{}__", name),
+        MultiInjector::build(Vec::new()).unwrap(),
+    ));
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        Some(hookfs.clone()),
+    ));
+    (hookfs, io)
+}
+
+fn call(io: &jsonrpc_core::IoHandler, method: &str, params: &str) -> String {
+    io.handle_request_sync(&request(method, params)).unwrap()
+}
+
+const OK: &str = r#"{"jsonrpc":"2.0","result":"ok","id":1}"#;
+
 #[test]
 fn test_status_good() {
     let (tx, _rx) = channel();
@@ -55,3 +104,362 @@ fn test_should_fail_if_config_is_bad() {
     ));
     assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
 }
+
+#[test]
+fn test_should_fail_if_errno_is_unknown() {
+    let (tx, _rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"update","params":[[{"type":"fault","percent":100,"faults":[{"errno":9999,"weight":1}]}]],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"rule 0: unknown errno 9999","id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+
+    let request = r#"{"jsonrpc": "2.0","method":"update","params":[[{"type":"partition","percent":100,"freeze":"1s","errnos":[9999]}]],"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_should_fail_if_method_is_unknown() {
+    let (tx, _rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"update","params":[[{"type":"fault","methods":["fsnyc"],"percent":100,"faults":[{"errno":5,"weight":1}]}]],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"rule 0: unknown method fsnyc","id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_should_fail_with_index_of_bad_rule() {
+    let (tx, _rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"update","params":[[{"type":"latency","percent":100,"latency":"1s"},{"type":"latency","percent":200,"latency":"1s"}]],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"rule 1: percent 200 is out of 0..=100","id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_should_fail_if_jitter_is_bad() {
+    let (tx, _rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"update","params":[[{"type":"latency","percent":100,"latency":"0s","jitter":{"distribution":"pareto","scale":"1ms","shape":0}}]],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"rule 0: pareto distribution requires a positive shape","id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_should_fail_if_ranges_are_unsupported() {
+    let (tx, _rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"update","params":[[{"type":"latency","percent":100,"latency":"1s","ranges":[{"start":0,"length":4096}]}]],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"rule 0: ranges are only supported by fault and mistake rules","id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_should_not_act_if_status_is_failed() {
+    let response = r#"{"jsonrpc":"2.0","result":"Not good","id":1}"#;
+    for (method, params) in &[
+        ("reset_zones", "[]"),
+        ("reset_space", "[]"),
+        ("resume", "[]"),
+        ("crash", "[]"),
+        ("get_checksum_report", "[]"),
+        ("start_experiment", r#"["blah",[],"1m"]"#),
+        ("invalidate_cache", "[null]"),
+    ] {
+        let request = request(method, params);
+        assert_eq!(handle(Err(anyhow!("Not good")), None, &request), response);
+    }
+}
+
+#[test]
+fn test_should_get_seed() {
+    let (tx, _rx) = channel();
+    toda::injector::rng::configure(Some(42));
+    let request = r#"{"jsonrpc": "2.0","method":"get_seed","params":[],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":42,"id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_should_not_stop_unknown_experiment() {
+    let (tx, _rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"stop_experiment","params":["blah"],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"experiment blah not found","id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_should_list_no_experiment() {
+    let (tx, _rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"list_experiments","params":[],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"[]","id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+// inject returns the errno injected on the operation by the rules installed
+// on the mount
+fn inject(hookfs: &HookFs, method: Method, path: &str) -> Option<Errno> {
+    match futures::executor::block_on(async {
+        hookfs
+            .injector
+            .read()
+            .await
+            .inject(&method, Path::new(path))
+            .await
+    }) {
+        Ok(()) => None,
+        Err(hookfs::Error::Sys(errno)) => Some(errno),
+        Err(err) => panic!("unexpected error {:?}", err),
+    }
+}
+
+// inject_write returns the errno injected on a write of `size` bytes at
+// `offset`
+fn inject_write(hookfs: &HookFs, path: &str, offset: i64, size: u64) -> Option<Errno> {
+    let io = IoContext {
+        fh: 0,
+        fd: -1,
+        offset,
+        size,
+    };
+    match futures::executor::block_on(async {
+        hookfs
+            .injector
+            .read()
+            .await
+            .inject_io(&Method::WRITE, Path::new(path), &io)
+            .await
+    }) {
+        Ok(()) => None,
+        Err(hookfs::Error::Sys(errno)) => Some(errno),
+        Err(err) => panic!("unexpected error {:?}", err),
+    }
+}
+
+#[test]
+fn test_should_share_experiments_between_mounts() {
+    let (tx, _rx) = channel();
+    let first = Arc::new(HookFs::new(
+        "/tmp/toda_first",
+        "/tmp/__chaosfs__toda_first__",
+        MultiInjector::build(Vec::new()).unwrap(),
+    ));
+    let second = Arc::new(HookFs::new(
+        "/tmp/toda_second",
+        "/tmp/__chaosfs__toda_second__",
+        MultiInjector::build(Vec::new()).unwrap(),
+    ));
+    let rpc = jsonrpc::RpcImpl::new(Mutex::new(Ok(())), Mutex::new(tx), Some(first.clone()));
+    let fixed: InjectorConfig = serde_json::from_str(
+        r#"{"type":"fault","methods":["mkdir"],"percent":100,"faults":[{"errno":28,"weight":1}]}"#,
+    )
+    .unwrap();
+    rpc.mount(second.clone(), vec![fixed]).unwrap();
+    let io = new_handler(rpc);
+
+    let request = r#"{"jsonrpc": "2.0","method":"update","params":[[{"type":"fault","methods":["open"],"percent":100,"faults":[{"errno":5,"weight":1}]}]],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"ok","id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+
+    // the rules of the rpc run on every mount, and the fixed rules on their
+    // own mount only
+    assert_eq!(
+        inject(&first, Method::OPEN, "/tmp/toda_first/file"),
+        Some(Errno::EIO)
+    );
+    assert_eq!(
+        inject(&second, Method::OPEN, "/tmp/toda_second/file"),
+        Some(Errno::EIO)
+    );
+    assert_eq!(inject(&first, Method::MKDIR, "/tmp/toda_first/dir"), None);
+    assert_eq!(
+        inject(&second, Method::MKDIR, "/tmp/toda_second/dir"),
+        Some(Errno::ENOSPC)
+    );
+
+    let request = r#"{"jsonrpc": "2.0","method":"stop_experiment","params":["mount:/tmp/toda_second"],"id":1}"#;
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+    assert_eq!(inject(&second, Method::MKDIR, "/tmp/toda_second/dir"), None);
+}
+
+#[test]
+fn test_should_poll_no_event() {
+    let (tx, _rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"poll_events","params":[],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"[]","id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+}
+
+#[test]
+fn test_should_recover_if_status_is_failed() {
+    let (tx, rx) = channel();
+    let request = r#"{"jsonrpc": "2.0","method":"recover","params":[],"id":1}"#;
+    let response = r#"{"jsonrpc":"2.0","result":"ok","id":1}"#;
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Err(anyhow!("Not good"))),
+        Mutex::new(tx),
+        None,
+    ));
+    assert_eq!(io.handle_request_sync(request), Some(response.to_string()));
+    assert_eq!(rx.recv().unwrap(), Comm::Recover);
+}
+
+#[test]
+fn test_should_serve_on_unix_socket() {
+    let (tx, _rx) = channel();
+    let io = new_handler(jsonrpc::RpcImpl::new(
+        Mutex::new(Ok(())),
+        Mutex::new(tx),
+        None,
+    ));
+    let path = "/tmp/toda_jsonrpc_test.sock";
+    let socket = jsonrpc::bind_unix(path).unwrap();
+    socket.serve(io).unwrap();
+
+    let stream = UnixStream::connect(path).unwrap();
+    let request = "{\"jsonrpc\": \"2.0\",\"method\":\"get_status\",\"params\":[\"\"],\"id\":1}\n";
+    (&stream).write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response).unwrap();
+    assert_eq!(
+        response,
+        "{\"jsonrpc\":\"2.0\",\"result\":\"ok\",\"id\":1}\n"
+    );
+    // the socket file is removed with the socket
+    drop(socket);
+    assert!(!std::path::Path::new(path).exists());
+}
+
+#[test]
+fn test_should_reset_zones() {
+    let (hookfs, io) = serve("reset_zones");
+    let rules = r#"[[{"type":"zoned","percent":100,"zones":[{"start":0,"length":100}]}]]"#;
+    assert_eq!(call(&io, "update", rules), OK);
+
+    let path = "/tmp/toda_reset_zones/file";
+    assert_eq!(inject_write(&hookfs, path, 0, 10), None);
+    assert_eq!(inject_write(&hookfs, path, 0, 10), Some(Errno::EINVAL));
+    assert_eq!(call(&io, "reset_zones", "[]"), OK);
+    assert_eq!(inject_write(&hookfs, path, 0, 10), None);
+
+    // a zone beyond the largest offset is refused
+    let overflow = format!(
+        r#"[[{{"type":"zoned","percent":100,"zones":[{{"start":{},"length":2}}]}}]]"#,
+        u64::MAX
+    );
+    assert_ne!(call(&io, "update", &overflow), OK);
+}
+
+#[test]
+fn test_should_reset_space() {
+    let (hookfs, io) = serve("reset_space");
+    let rules = r#"[[{"type":"diskFull","percent":100,"budget":10}]]"#;
+    assert_eq!(call(&io, "update", rules), OK);
+
+    let path = "/tmp/toda_reset_space/file";
+    assert_eq!(inject_write(&hookfs, path, 0, 10), None);
+    assert_eq!(inject_write(&hookfs, path, 10, 1), Some(Errno::ENOSPC));
+    assert_eq!(call(&io, "reset_space", "[]"), OK);
+    assert_eq!(inject_write(&hookfs, path, 10, 1), None);
+}
+
+#[test]
+fn test_should_resume() {
+    let (hookfs, io) = serve("resume");
+    let rules = r#"[[{"type":"hang","methods":["open"],"percent":100}]]"#;
+    assert_eq!(call(&io, "update", rules), OK);
+
+    let (tx, rx) = channel();
+    let hung = hookfs.clone();
+    std::thread::spawn(move || {
+        tx.send(inject(&hung, Method::OPEN, "/tmp/toda_resume/file"))
+            .unwrap()
+    });
+    assert!(rx
+        .recv_timeout(std::time::Duration::from_millis(100))
+        .is_err());
+    assert_eq!(call(&io, "resume", "[]"), OK);
+    assert_eq!(rx.recv().unwrap(), None);
+}
+
+#[test]
+fn test_should_crash() {
+    let (hookfs, io) = serve("crash");
+    let rules = r#"[[{"type":"tornWrite","percent":100,"onCrash":true}]]"#;
+    assert_eq!(call(&io, "update", rules), OK);
+
+    let write = IoContext {
+        fh: 0,
+        fd: -1,
+        offset: 0,
+        size: 16,
+    };
+    let tear = || {
+        futures::executor::block_on(hookfs.injector.read())
+            .tear_write(Path::new("/tmp/toda_crash/file"), &write)
+    };
+    assert_eq!(tear(), None);
+    assert_eq!(call(&io, "crash", "[]"), OK);
+    assert!(tear().is_some());
+}
+
+#[test]
+fn test_should_report_disabled_checksums() {
+    let (_hookfs, io) = serve("checksum_report");
+    assert_eq!(
+        call(&io, "get_checksum_report", "[]"),
+        r#"{"jsonrpc":"2.0","result":"checksum verification is disabled","id":1}"#
+    );
+}
+
+#[test]
+fn test_should_start_experiment() {
+    let (hookfs, io) = serve("start_experiment");
+    let rules = r#"["blah",[{"type":"fault","methods":["open"],"percent":100,"faults":[{"errno":5,"weight":1}]}],"1m"]"#;
+    assert_eq!(call(&io, "start_experiment", rules), OK);
+
+    let path = "/tmp/toda_start_experiment/file";
+    assert_eq!(inject(&hookfs, Method::OPEN, path), Some(Errno::EIO));
+    assert!(call(&io, "list_experiments", "[]").contains(r#"\"name\":\"blah\""#));
+    assert_eq!(call(&io, "stop_experiment", r#"["blah"]"#), OK);
+    assert_eq!(inject(&hookfs, Method::OPEN, path), None);
+}
@@ -11,23 +11,65 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::BTreeSet;
 use std::ffi::OsStr;
-use std::fs::{read_link, read_to_string, write, File, OpenOptions};
-use std::io::{Read, Write};
-use std::os::unix::fs::symlink;
-use std::path::PathBuf;
+use std::fs::{read_link, read_to_string, write, File, OpenOptions, Permissions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, FileExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use std::sync::{Arc, Once};
 
 use nix::sys::stat;
 use nix::{fcntl, unistd};
 use toda::hookfs;
-use toda::injector::MultiInjector;
+use toda::injector::{Experiment, Experiments, Injector, InjectorConfig, MultiInjector};
 
 // These tests are port from go-fuse test
 
 static INIT: Once = Once::new();
 
 fn init(name: &str) -> (PathBuf, fuser::BackgroundSession) {
+    let (test_path, _, session) = init_with_hookfs(name);
+    (test_path, session)
+}
+
+fn init_with_hookfs(name: &str) -> (PathBuf, Arc<hookfs::HookFs>, fuser::BackgroundSession) {
+    init_with_kernel(name, hookfs::KernelOptions::default())
+}
+
+fn init_with_kernel(
+    name: &str,
+    kernel: hookfs::KernelOptions,
+) -> (PathBuf, Arc<hookfs::HookFs>, fuser::BackgroundSession) {
+    init_with(name, kernel, |hookfs, _| hookfs)
+}
+
+// init_with_shadow diverts the modifications into a shadow directory, and
+// returns the backing directory
+fn init_with_shadow(name: &str) -> (PathBuf, PathBuf, fuser::BackgroundSession) {
+    let shadow_dir: PathBuf = ["/tmp/test_mnt_shadow", name].iter().collect();
+    std::fs::remove_dir_all(&shadow_dir).ok();
+    let (test_path, _, session) =
+        init_with(name, hookfs::KernelOptions::default(), |hookfs, backend| {
+            let shadow = hookfs::Shadow::new(&shadow_dir, backend).unwrap();
+            hookfs.with_overlay(hookfs::Overlay::Shadow(shadow))
+        });
+    let backend = ["/tmp/test_mnt_backend", name].iter().collect();
+    (test_path, backend, session)
+}
+
+fn init_with<F>(
+    name: &str,
+    kernel: hookfs::KernelOptions,
+    configure: F,
+) -> (PathBuf, Arc<hookfs::HookFs>, fuser::BackgroundSession)
+where
+    F: FnOnce(hookfs::HookFs, &Path) -> hookfs::HookFs,
+{
     let test_path_backend: PathBuf = ["/tmp/test_mnt_backend", name].iter().collect();
     let test_path: PathBuf = ["/tmp/test_mnt", name].iter().collect();
 
@@ -41,20 +83,21 @@ fn init(name: &str) -> (PathBuf, fuser::BackgroundSession) {
     std::fs::create_dir_all(&test_path_backend).ok();
     std::fs::create_dir_all(&test_path).ok();
 
-    let hookfs = Arc::new(hookfs::HookFs::new(
+    let args: Vec<_> = std::iter::once("nonempty".to_owned())
+        .chain(kernel.mount_options())
+        .collect();
+    let hookfs = hookfs::HookFs::new(
         &test_path,
         &test_path_backend,
         MultiInjector::build(Vec::new()).unwrap(),
-    ));
+    )
+    .with_kernel_options(kernel);
+    let hookfs = Arc::new(configure(hookfs, &test_path_backend));
+    // nothing is injected until a test installs its rules
+    hookfs.enable_injection();
 
-    let fs = hookfs::AsyncFileSystem::from(hookfs);
+    let fs = hookfs::AsyncFileSystem::from(hookfs.clone());
 
-    let args = [
-        "allow_other",
-        "nonempty",
-        "fsname=toda",
-        "default_permissions",
-    ];
     let flags: Vec<_> = args
         .iter()
         .flat_map(|item| vec![OsStr::new("-o"), OsStr::new(item)])
@@ -62,7 +105,13 @@ fn init(name: &str) -> (PathBuf, fuser::BackgroundSession) {
 
     let session = fuser::spawn_mount(fs, &test_path, &flags).unwrap();
     std::thread::sleep(std::time::Duration::from_secs(1));
-    (test_path, session)
+    (test_path, hookfs, session)
+}
+
+// install installs the rules on the mount
+fn install(hookfs: &hookfs::HookFs, rules: &str) {
+    let rules: Vec<InjectorConfig> = serde_json::from_str(rules).unwrap();
+    *futures::executor::block_on(hookfs.injector.write()) = MultiInjector::build(rules).unwrap();
 }
 
 #[test]
@@ -78,6 +127,25 @@ fn symlink_readlink() {
     assert_eq!(src, expected_src);
 }
 
+#[test]
+fn symlink_follow() {
+    let (test_path, _) = init("symlink_follow");
+
+    let target = test_path.join("target");
+    let link = test_path.join("link");
+    write(&target, "hello world").unwrap();
+    symlink("target", &link).unwrap();
+
+    assert_eq!(read_to_string(&link).unwrap(), "hello world");
+    let err = fcntl::open(
+        &link,
+        fcntl::OFlag::O_RDONLY | fcntl::OFlag::O_NOFOLLOW,
+        stat::Mode::empty(),
+    )
+    .unwrap_err();
+    assert_eq!(err.as_errno(), Some(nix::errno::Errno::ELOOP));
+}
+
 #[test]
 fn file_basic() {
     let (test_path, _) = init("file_basic");
@@ -98,6 +166,28 @@ fn file_basic() {
     drop(file);
 }
 
+#[test]
+fn create_exclusive() {
+    let (test_path, _) = init("create_exclusive");
+    let target_file: PathBuf = test_path.join("target_file");
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&target_file)
+        .unwrap();
+    file.write_all(b"hello").unwrap();
+    drop(file);
+
+    let err = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&target_file)
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    assert_eq!(read_to_string(&target_file).unwrap(), "hello");
+}
+
 #[test]
 fn truncate_file() {
     let (test_path, _) = init("truncate_file");
@@ -122,6 +212,583 @@ fn truncate_file() {
     assert_eq!(read_output.as_bytes(), &content[..(trunc as usize)]);
 }
 
+#[test]
+fn truncate_unlinked_file() {
+    let (test_path, _) = init("truncate_unlinked_file");
+    let target_file: PathBuf = test_path.join("target_file");
+
+    write(&target_file, b"hello world").unwrap();
+    let file = OpenOptions::new()
+        .write(true)
+        .read(true)
+        .open(&target_file)
+        .unwrap();
+    unistd::unlink(&target_file).unwrap();
+
+    file.set_len(5).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 5);
+}
+
+#[test]
+fn chmod_file() {
+    let (test_path, _) = init("chmod_file");
+    let target_file: PathBuf = test_path.join("target_file");
+
+    write(&target_file, b"hello world").unwrap();
+    std::fs::set_permissions(&target_file, Permissions::from_mode(0o600)).unwrap();
+
+    let mode = std::fs::metadata(&target_file)
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn posix_lock_conflict() {
+    let (test_path, _) = init("posix_lock_conflict");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    let lock = libc::flock {
+        l_type: libc::F_WRLCK as libc::c_short,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: 0,
+        l_len: 0,
+        l_pid: 0,
+    };
+    let first = OpenOptions::new().write(true).open(&path).unwrap();
+    let second = OpenOptions::new().write(true).open(&path).unwrap();
+    fcntl::fcntl(first.as_raw_fd(), fcntl::FcntlArg::F_OFD_SETLK(&lock)).unwrap();
+
+    let err = fcntl::fcntl(second.as_raw_fd(), fcntl::FcntlArg::F_OFD_SETLK(&lock)).unwrap_err();
+    assert_eq!(err.as_errno(), Some(nix::errno::Errno::EAGAIN));
+
+    drop(first);
+    fcntl::fcntl(second.as_raw_fd(), fcntl::FcntlArg::F_OFD_SETLK(&lock)).unwrap();
+}
+
+// the waiters of a lock don't hold the threads serving the other operations
+#[test]
+fn posix_lock_wait() {
+    let (test_path, _) = init("posix_lock_wait");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    let lock = libc::flock {
+        l_type: libc::F_WRLCK as libc::c_short,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: 0,
+        l_len: 0,
+        l_pid: 0,
+    };
+    let first = OpenOptions::new().write(true).open(&path).unwrap();
+    fcntl::fcntl(first.as_raw_fd(), fcntl::FcntlArg::F_OFD_SETLK(&lock)).unwrap();
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let waiters: Vec<_> = (0..4)
+        .map(|_| {
+            let path = path.clone();
+            let sender = sender.clone();
+            std::thread::spawn(move || {
+                let file = OpenOptions::new().write(true).open(&path).unwrap();
+                fcntl::fcntl(file.as_raw_fd(), fcntl::FcntlArg::F_OFD_SETLKW(&lock)).unwrap();
+                sender.send(()).unwrap();
+            })
+        })
+        .collect();
+    assert!(receiver
+        .recv_timeout(std::time::Duration::from_millis(200))
+        .is_err());
+    assert_eq!(read_to_string(&path).unwrap(), "hello");
+
+    // every waiter takes the lock in turn, and releases it on close
+    drop(first);
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+    assert_eq!(receiver.try_iter().count(), 4);
+}
+
+#[test]
+fn statfs_passthrough() {
+    let (test_path, _) = init("statfs_passthrough");
+    let backend = nix::sys::statvfs::statvfs("/tmp/test_mnt_backend/statfs_passthrough").unwrap();
+    let stat = nix::sys::statvfs::statvfs(&test_path).unwrap();
+
+    assert_eq!(stat.blocks(), backend.blocks());
+    assert_eq!(stat.fragment_size(), backend.fragment_size());
+    assert_eq!(stat.files(), backend.files());
+    assert_eq!(stat.name_max(), backend.name_max());
+}
+
+// runs the command as nobody with the supplementary groups
+fn succeeds_as_nobody(args: &[&OsStr], groups: &[libc::gid_t]) -> bool {
+    let groups = groups.to_vec();
+    let mut command = Command::new(args[0]);
+    command.args(&args[1..]);
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setgroups(groups.len(), groups.as_ptr()) != 0
+                || libc::setgid(65534) != 0
+                || libc::setuid(65534) != 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.output().unwrap().status.success()
+}
+
+#[test]
+fn passthrough_permissions_as_caller() {
+    // the caller is switched to nobody, which only root can do
+    if !unistd::geteuid().is_root() {
+        return;
+    }
+    let kernel = hookfs::KernelOptions {
+        passthrough_permissions: true,
+        ..Default::default()
+    };
+    let (test_path, _, _session) = init_with_kernel("passthrough_permissions_as_caller", kernel);
+    let private = test_path.join("private");
+    let shared = test_path.join("shared");
+    let public = test_path.join("public");
+    for (path, mode) in [(&private, 0o600), (&shared, 0o640), (&public, 0o644)].iter() {
+        write(path, "hello").unwrap();
+        std::fs::set_permissions(path, Permissions::from_mode(*mode)).unwrap();
+    }
+    unistd::chown(&shared, None, Some(unistd::Gid::from_raw(1234))).unwrap();
+
+    let cat = |path: &PathBuf, groups: &[libc::gid_t]| {
+        succeeds_as_nobody(&[OsStr::new("cat"), path.as_os_str()], groups)
+    };
+    let readable = |path: &PathBuf, groups: &[libc::gid_t]| {
+        succeeds_as_nobody(
+            &[OsStr::new("test"), OsStr::new("-r"), path.as_os_str()],
+            groups,
+        )
+    };
+    assert!(!cat(&private, &[]));
+    assert!(!readable(&private, &[]));
+    assert!(cat(&public, &[]));
+    assert!(readable(&public, &[]));
+    // the supplementary groups of the caller are checked as well
+    assert!(!cat(&shared, &[]));
+    assert!(cat(&shared, &[1234]));
+    assert!(readable(&shared, &[1234]));
+}
+
+#[test]
+fn writeback_write_only_file() {
+    // the caller is switched to nobody, which only root can do
+    if !unistd::geteuid().is_root() {
+        return;
+    }
+    let kernel = hookfs::KernelOptions {
+        writeback_cache: true,
+        passthrough_permissions: true,
+        ..Default::default()
+    };
+    let (test_path, _, _session) = init_with_kernel("writeback_write_only_file", kernel);
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+    std::fs::set_permissions(&path, Permissions::from_mode(0o222)).unwrap();
+
+    // the file is opened write-only if it cannot be opened for reading too
+    let args = [
+        OsStr::new("sh"),
+        OsStr::new("-c"),
+        OsStr::new("echo world > \"$0\""),
+        path.as_os_str(),
+    ];
+    assert!(succeeds_as_nobody(&args, &[]));
+    assert_eq!(read_to_string(&path).unwrap(), "world\n");
+}
+
+#[test]
+fn shadow_unlink_backing_file() {
+    let (test_path, backend, _session) = init_with_shadow("shadow_unlink_backing_file");
+    write(backend.join("file"), "hello").unwrap();
+    let path = test_path.join("file");
+    assert_eq!(read_to_string(&path).unwrap(), "hello");
+
+    std::fs::remove_file(&path).unwrap();
+    assert!(std::fs::symlink_metadata(&path).is_err());
+    let names: Vec<_> = std::fs::read_dir(&test_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert!(names.is_empty());
+
+    write(&path, "world").unwrap();
+    assert_eq!(read_to_string(&path).unwrap(), "world");
+    assert_eq!(read_to_string(backend.join("file")).unwrap(), "hello");
+}
+
+#[test]
+fn shadow_write_after_open() {
+    let (test_path, backend, _session) = init_with_shadow("shadow_write_after_open");
+    write(backend.join("file"), "hello").unwrap();
+    let path = test_path.join("file");
+    let ino = std::fs::metadata(&path).unwrap().ino();
+
+    // the file opened before the copy up reads the writes after it
+    let mut reader = File::open(&path).unwrap();
+    write(&path, "world").unwrap();
+    let mut content = String::new();
+    reader.read_to_string(&mut content).unwrap();
+    assert_eq!(content, "world");
+    assert_eq!(std::fs::metadata(&path).unwrap().ino(), ino);
+    assert_eq!(reader.metadata().unwrap().ino(), ino);
+    assert_eq!(read_to_string(backend.join("file")).unwrap(), "hello");
+}
+
+#[test]
+fn shadow_rename_backing_file() {
+    let (test_path, backend, _session) = init_with_shadow("shadow_rename_backing_file");
+    write(backend.join("old"), "hello").unwrap();
+    std::fs::create_dir(backend.join("dir")).unwrap();
+
+    std::fs::rename(test_path.join("old"), test_path.join("new")).unwrap();
+    assert!(std::fs::symlink_metadata(test_path.join("old")).is_err());
+    assert_eq!(read_to_string(test_path.join("new")).unwrap(), "hello");
+    assert!(backend.join("old").exists());
+    assert!(!backend.join("new").exists());
+
+    // a directory of the backing one cannot be renamed with its entries
+    let err = std::fs::rename(test_path.join("dir"), test_path.join("moved")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+}
+
+#[test]
+fn shadow_rmdir_backing_dir() {
+    let (test_path, backend, _session) = init_with_shadow("shadow_rmdir_backing_dir");
+    std::fs::create_dir(backend.join("dir")).unwrap();
+    write(backend.join("dir/file"), "hello").unwrap();
+    let dir = test_path.join("dir");
+
+    let err = std::fs::remove_dir(&dir).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTEMPTY));
+    std::fs::remove_file(dir.join("file")).unwrap();
+    std::fs::remove_dir(&dir).unwrap();
+
+    // the directory created again doesn't reveal the entries of the backing one
+    std::fs::create_dir(&dir).unwrap();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    assert!(backend.join("dir/file").exists());
+}
+
+#[test]
+fn snapshot_modified_backing_file() {
+    let snapshot_dir: PathBuf = ["/tmp/test_mnt_snapshot", "snapshot_modified_backing_file"]
+        .iter()
+        .collect();
+    std::fs::remove_dir_all(&snapshot_dir).ok();
+    std::fs::create_dir_all(snapshot_dir.parent().unwrap()).unwrap();
+    let (test_path, _, _session) = init_with(
+        "snapshot_modified_backing_file",
+        hookfs::KernelOptions::default(),
+        |hookfs, backend| {
+            write(backend.join("file"), "hello").unwrap();
+            let snapshot = hookfs::Snapshot::create(&snapshot_dir, backend).unwrap();
+            hookfs.with_overlay(hookfs::Overlay::Snapshot(snapshot))
+        },
+    );
+    let backend = Path::new("/tmp/test_mnt_backend/snapshot_modified_backing_file");
+    let path = test_path.join("file");
+
+    // the snapshot doesn't share the inode of the backing file, which is
+    // modified in place
+    assert_ne!(
+        std::fs::metadata(snapshot_dir.join("file")).unwrap().ino(),
+        std::fs::metadata(backend.join("file")).unwrap().ino()
+    );
+    OpenOptions::new()
+        .write(true)
+        .open(backend.join("file"))
+        .unwrap()
+        .write_all_at(b"world", 0)
+        .unwrap();
+    assert_eq!(read_to_string(&path).unwrap(), "hello");
+
+    let err = write(&path, "world").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    assert_eq!(read_to_string(backend.join("file")).unwrap(), "world");
+}
+
+#[test]
+fn statfs_low_free_space() {
+    let (test_path, hookfs, _session) = init_with_hookfs("statfs_low_free_space");
+
+    install(
+        &hookfs,
+        r#"[{"type":"statfs","percent":100,"freeBlocks":10,"freeInodes":1}]"#,
+    );
+
+    let stat = nix::sys::statvfs::statvfs(&test_path).unwrap();
+    assert_eq!(stat.blocks_free(), 10);
+    assert_eq!(stat.blocks_available(), 10);
+    assert_eq!(stat.files_free(), 1);
+    write(test_path.join("file"), "hello").unwrap();
+}
+
+#[test]
+fn disk_full_gives_back_refused() {
+    let (test_path, hookfs, _session) = init_with_hookfs("disk_full_gives_back_refused");
+    install(
+        &hookfs,
+        r#"[{"type":"diskFull","percent":100,"budget":10},{"type":"diskFull","percent":100,"budget":6}]"#,
+    );
+
+    // the bytes taken by the first budget are given back once the second
+    // one refuses the write
+    let mut file = File::create(test_path.join("file")).unwrap();
+    let err = file.write(b"01234567").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+    file.write_all(b"012345").unwrap();
+    let err = file.write(b"6").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOSPC));
+}
+
+// invalidating the cache opens the files through the mount, but isn't injected
+#[test]
+fn invalidate_not_injected() {
+    let (test_path, hookfs, _session) = init_with_hookfs("invalidate_not_injected");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    install(
+        &hookfs,
+        r#"[{"type":"fault","methods":["open"],"percent":100,"faults":[{"errno":5,"weight":1}]}]"#,
+    );
+    let err = File::open(&path).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+    assert_eq!(
+        futures::executor::block_on(hookfs.invalidate(None)).unwrap(),
+        1
+    );
+    let err = File::open(&path).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+}
+
+#[test]
+fn garbage_pattern_read() {
+    let (test_path, hookfs, _session) = init_with_hookfs("garbage_pattern_read");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    // "YWI=" is "ab"
+    install(
+        &hookfs,
+        r#"[{"type":"garbage","percent":100,"fill":"pattern","pattern":"YWI="}]"#,
+    );
+
+    assert_eq!(read_to_string(&path).unwrap(), "ababa");
+    let mut file = File::open(&path).unwrap();
+    file.seek(SeekFrom::Start(1)).unwrap();
+    let mut buf = String::new();
+    file.read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "baba");
+}
+
+#[test]
+fn torn_write_on_crash() {
+    let (test_path, hookfs, _session) = init_with_hookfs("torn_write_on_crash");
+    let path = test_path.join("file");
+    toda::injector::rng::configure(Some(42));
+
+    install(
+        &hookfs,
+        r#"[{"type":"tornWrite","percent":100,"mode":"prefix","onCrash":true}]"#,
+    );
+
+    write(&path, "hello").unwrap();
+    assert_eq!(read_to_string(&path).unwrap(), "hello");
+
+    futures::executor::block_on(hookfs.injector.read()).crash();
+    let data = vec![b'x'; 4 * 4096];
+    let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all(&data).unwrap();
+    drop(file);
+    // only a strict prefix of the write is persisted, over what it overwrote
+    let backend = std::fs::read("/tmp/test_mnt_backend/torn_write_on_crash/file").unwrap();
+    let persisted = backend.iter().take_while(|byte| **byte == b'x').count();
+    assert!(persisted < data.len());
+    assert_eq!(&backend[persisted..], &b"hello"[persisted.min(5)..]);
+}
+
+#[test]
+fn open_flags_append_only() {
+    let (test_path, hookfs, _session) = init_with_hookfs("open_flags_append_only");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    install(
+        &hookfs,
+        r#"[{"type":"fault","methods":["write"],"percent":100,"faults":[{"errno":5,"weight":1}],"openFlags":["append"]}]"#,
+    );
+
+    write(&path, "world").unwrap();
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    let err = file.write_all(b"!").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    assert_eq!(read_to_string(&path).unwrap(), "world");
+}
+
+#[test]
+fn fault_last_block_range() {
+    let (test_path, hookfs, _session) = init_with_hookfs("fault_last_block_range");
+    // the file is written behind the mount, so that no page is cached, and
+    // is large enough for the readahead of its start to miss the last block
+    let backend: PathBuf = ["/tmp/test_mnt_backend", "fault_last_block_range", "file"]
+        .iter()
+        .collect();
+    write(&backend, vec![1u8; 256 * 4096]).unwrap();
+
+    install(
+        &hookfs,
+        r#"[{"type":"fault","methods":["read"],"percent":100,"faults":[{"errno":5,"weight":1}],"ranges":[{"start":-4096,"length":4096}]}]"#,
+    );
+
+    let file = File::open(test_path.join("file")).unwrap();
+    let mut buf = [0u8; 4096];
+    assert_eq!(file.read_at(&mut buf, 0).unwrap(), 4096);
+    let err = file.read_at(&mut buf, 255 * 4096).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+}
+
+#[test]
+fn direct_io_read_write() {
+    let (test_path, _) = init("direct_io_read_write");
+    let path = test_path.join("file");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(&path)
+        .unwrap();
+
+    // O_DIRECT needs a buffer aligned on the block size
+    let mut buf = vec![0u8; 2 * 4096];
+    let start = buf.as_ptr().align_offset(4096);
+    let block = &mut buf[start..start + 4096];
+    block.copy_from_slice(&[7u8; 4096]);
+    file.write_all(block).unwrap();
+
+    block.copy_from_slice(&[0u8; 4096]);
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.read_exact(block).unwrap();
+    assert_eq!(block, &[7u8; 4096][..]);
+
+    let backend = std::fs::read("/tmp/test_mnt_backend/direct_io_read_write/file").unwrap();
+    assert_eq!(backend, vec![7u8; 4096]);
+}
+
+#[test]
+fn direct_io_short_write() {
+    let (test_path, hookfs, _session) = init_with_hookfs("direct_io_short_write");
+    let path = test_path.join("file");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(&path)
+        .unwrap();
+
+    install(
+        &hookfs,
+        r#"[{"type":"shortIo","methods":["write"],"percent":100}]"#,
+    );
+
+    // the part of the block left by the injector isn't aligned, and is written
+    // through the page cache
+    let mut buf = vec![0u8; 2 * 4096];
+    let start = buf.as_ptr().align_offset(4096);
+    let block = &mut buf[start..start + 4096];
+    block.copy_from_slice(&[7u8; 4096]);
+    let written = file.write(block).unwrap();
+    assert!(written > 0 && written < 4096);
+
+    let backend = std::fs::read("/tmp/test_mnt_backend/direct_io_short_write/file").unwrap();
+    assert_eq!(backend, vec![7u8; written]);
+}
+
+#[test]
+fn fallocate_file() {
+    let (test_path, _) = init("fallocate_file");
+    let path = test_path.join("file");
+    let file = File::create(&path).unwrap();
+
+    fcntl::fallocate(
+        file.as_raw_fd(),
+        fcntl::FallocateFlags::empty(),
+        0,
+        1024 * 1024,
+    )
+    .unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 1024 * 1024);
+}
+
+#[test]
+fn fat_max_file_size() {
+    let (test_path, hookfs, _session) = init_with_hookfs("fat_max_file_size");
+    let path = test_path.join("file");
+    let file = File::create(&path).unwrap();
+    install(
+        &hookfs,
+        r#"[{"type":"fat","percent":100,"maxFileSize":4096}]"#,
+    );
+
+    let err = file.write_at(b"x", 4096).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    let err = file.set_len(4097).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EFBIG));
+    let err =
+        fcntl::fallocate(file.as_raw_fd(), fcntl::FallocateFlags::empty(), 0, 4097).unwrap_err();
+    assert_eq!(err.as_errno(), Some(nix::errno::Errno::EFBIG));
+
+    // the file grows up to the max size
+    file.set_len(4096).unwrap();
+    fcntl::fallocate(file.as_raw_fd(), fcntl::FallocateFlags::empty(), 0, 4096).unwrap();
+    assert_eq!(file.metadata().unwrap().len(), 4096);
+}
+
+#[test]
+fn seek_hole_data() {
+    let (test_path, _) = init("seek_hole_data");
+    let path = test_path.join("file");
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .read(true)
+        .open(&path)
+        .unwrap();
+    file.set_len(1024 * 1024).unwrap();
+    file.seek(SeekFrom::Start(512 * 1024)).unwrap();
+    file.write_all(&[1u8; 4096]).unwrap();
+    file.sync_all().unwrap();
+
+    let data = unistd::lseek(file.as_raw_fd(), 0, unistd::Whence::SeekData).unwrap();
+    assert_eq!(data, 512 * 1024);
+    let hole = unistd::lseek(file.as_raw_fd(), data, unistd::Whence::SeekHole).unwrap();
+    assert!(hole > data && hole < 1024 * 1024);
+}
+
+#[test]
+fn copy_file() {
+    let (test_path, _) = init("copy_file");
+    let from = test_path.join("from");
+    let to = test_path.join("to");
+    write(&from, "hello world").unwrap();
+
+    std::fs::copy(&from, &to).unwrap();
+    assert_eq!(read_to_string(&to).unwrap(), "hello world");
+}
+
 #[test]
 fn mkdir_rmdir() {
     let (test_path, _) = init("mkdir_rmdir");
@@ -137,6 +804,26 @@ fn mkdir_rmdir() {
     std::fs::remove_dir(&dir).unwrap();
 }
 
+#[test]
+fn mknod_unlink() {
+    let (test_path, _) = init("mknod_unlink");
+    let path = test_path.join("fifo");
+
+    stat::mknod(
+        &path,
+        stat::SFlag::S_IFIFO,
+        stat::Mode::from_bits_truncate(0o644),
+        0,
+    )
+    .unwrap();
+    let st = stat::lstat(&path).unwrap();
+    assert_eq!(st.st_mode & libc::S_IFMT, libc::S_IFIFO);
+
+    unistd::unlink(&path).unwrap();
+    let err = stat::lstat(&path).unwrap_err();
+    assert_eq!(err.as_errno(), Some(nix::errno::Errno::ENOENT));
+}
+
 #[test]
 fn nlink_zero() {
     let (test_path, _) = init("nlink_zero");
@@ -239,9 +926,312 @@ fn parallel_file_open() {
     }
 }
 
+#[test]
+fn parallel_read_write() {
+    use std::os::unix::fs::FileExt;
+
+    let (test_path, _) = init("parallel_read_write");
+    let path = test_path.join("file");
+    let file = Arc::new(
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .read(true)
+            .open(&path)
+            .unwrap(),
+    );
+
+    let chunk = 64 * 1024;
+    let threads: Vec<_> = (0..16u8)
+        .map(|i| {
+            let file = file.clone();
+            std::thread::spawn(move || {
+                let data = vec![i; chunk];
+                let offset = i as u64 * chunk as u64;
+                for _ in 0..16 {
+                    file.write_all_at(&data, offset).unwrap();
+                    let mut buf = vec![0u8; chunk];
+                    file.read_exact_at(&mut buf, offset).unwrap();
+                    assert_eq!(buf, data);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn release_closes_fd() {
+    let (test_path, _) = init("release_closes_fd");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    // the mount is served in this process, so the backing fds are counted too
+    let count_fds = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+    let before = count_fds();
+    for _ in 0..1000 {
+        drop(File::open(&path).unwrap());
+    }
+    std::thread::sleep(std::time::Duration::from_secs(1));
+
+    assert!(count_fds() <= before + 1);
+}
+
+#[test]
+fn forget_bounds_inodes() {
+    let (test_path, hookfs, _session) = init_with_hookfs("forget_bounds_inodes");
+    for i in 0..20000 {
+        write(test_path.join(format!("file{}", i)), "").unwrap();
+    }
+    for i in 0..20000 {
+        stat::stat(&test_path.join(format!("file{}", i))).unwrap();
+    }
+
+    // the kernel forgets an unlinked inode once its dentry is dropped, while
+    // hookfs keeps remembering it until then
+    for i in 0..20000 {
+        unistd::unlink(&test_path.join(format!("file{}", i))).unwrap();
+    }
+    std::thread::sleep(std::time::Duration::from_secs(1));
+
+    let count = futures::executor::block_on(hookfs.inode_count());
+    assert!(count < 1000, "{} inodes remembered", count);
+}
+
+#[test]
+fn fsync_drop_unsynced() {
+    let (test_path, hookfs, _session) = init_with_hookfs("fsync_drop_unsynced");
+    let path = test_path.join("file");
+    let mut file = File::create(&path).unwrap();
+    file.write_all(b"hello").unwrap();
+    file.sync_all().unwrap();
+
+    install(&hookfs, r#"[{"type":"fsync","mode":"drop","percent":100}]"#);
+    file.seek(SeekFrom::Start(0)).unwrap();
+    file.write_all(b"world").unwrap();
+    file.sync_all().unwrap();
+    drop(file);
+
+    assert_eq!(read_to_string(&path).unwrap(), "hello");
+}
+
+#[test]
+fn fsync_drop_unsynced_after_close() {
+    let (test_path, hookfs, _session) = init_with_hookfs("fsync_drop_unsynced_after_close");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    install(&hookfs, r#"[{"type":"fsync","mode":"drop","percent":100}]"#);
+    // the writes are journaled for the inode, so that closing the file, which
+    // flushes it, doesn't sync them, and syncing another file drops them
+    let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all(b"world").unwrap();
+    drop(file);
+    assert_eq!(read_to_string(&path).unwrap(), "world");
+    File::open(&path).unwrap().sync_all().unwrap();
+
+    assert_eq!(read_to_string(&path).unwrap(), "hello");
+}
+
+#[test]
+fn trigger_third_write() {
+    let (test_path, hookfs, _session) = init_with_hookfs("trigger_third_write");
+    install(
+        &hookfs,
+        r#"[{"type":"fault","methods":["write"],"percent":100,"faults":[{"errno":5,"weight":1}],"trigger":{"after":2,"first":1}}]"#,
+    );
+
+    let mut file = File::create(test_path.join("file")).unwrap();
+    let results: Vec<_> = (0..4).map(|_| file.write(b"data").is_ok()).collect();
+    assert_eq!(results, vec![true, true, false, true]);
+}
+
+#[test]
+fn read_only_mount() {
+    let (test_path, hookfs, _session) = init_with_hookfs("read_only_mount");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    install(&hookfs, r#"[{"type":"readOnly","percent":100}]"#);
+
+    assert_eq!(read_to_string(&path).unwrap(), "hello");
+    let err = write(&path, "world").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    let err = OpenOptions::new().append(true).open(&path).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    let err = std::fs::create_dir(test_path.join("dir")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+}
+
+#[test]
+fn magic_sniffed_at_open() {
+    let (test_path, hookfs, _session) = init_with_hookfs("magic_sniffed_at_open");
+    let database = test_path.join("database");
+    let text = test_path.join("text");
+    write(&database, b"SQLite format 3\0...").unwrap();
+    write(&text, "hello").unwrap();
+
+    install(
+        &hookfs,
+        r#"[{"type":"fault","methods":["open"],"percent":100,"faults":[{"errno":5,"weight":1}],"magic":["sqlite"]}]"#,
+    );
+    // the header is sniffed once the file is opened, and forgotten once it's
+    // released
+    let opened = File::open(&database).unwrap();
+    let err = File::open(&database).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    File::open(&text).unwrap();
+    File::open(&text).unwrap();
+    drop(opened);
+    File::open(&database).unwrap();
+}
+
+#[test]
+fn replaced_experiment_releases_delayed() {
+    let (test_path, hookfs, _session) = init_with_hookfs("replaced_experiment_releases_delayed");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    let config: InjectorConfig = serde_json::from_str(
+        r#"{"type":"latency","methods":["open"],"percent":100,"latency":"60s"}"#,
+    )
+    .unwrap();
+    let mut experiments = Experiments::default();
+    experiments.start(Experiment::build("slow".to_owned(), vec![config], None).unwrap());
+    *futures::executor::block_on(hookfs.injector.write()) = experiments.injector(&test_path);
+
+    let start = std::time::Instant::now();
+    let opener = std::thread::spawn(move || File::open(&path).map(|_| ()));
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    // the open delayed by the replaced experiment is released
+    experiments.start(Experiment::build("slow".to_owned(), Vec::new(), None).unwrap());
+    *futures::executor::block_on(hookfs.injector.write()) = experiments.injector(&test_path);
+    opener.join().unwrap().unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(30));
+}
+
+#[test]
+fn audit_injected_fault() {
+    let (test_path, hookfs, _session) = init_with_hookfs("audit_injected_fault");
+    let path = test_path.join("file");
+    write(&path, "hello").unwrap();
+
+    // the log is closed even if the test fails, as it's global
+    struct Audit(PathBuf);
+    impl Drop for Audit {
+        fn drop(&mut self) {
+            toda::audit::close();
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+    let log = Audit(std::env::temp_dir().join(format!("test_audit_{}.log", std::process::id())));
+    toda::audit::open(&log.0).unwrap();
+    let mut experiments = Experiments::default();
+    let mut start = |config: &str| {
+        let config: InjectorConfig = serde_json::from_str(config).unwrap();
+        experiments.start(Experiment::build("audit".to_owned(), vec![config], None).unwrap());
+        *futures::executor::block_on(hookfs.injector.write()) = experiments.injector(&test_path);
+    };
+
+    // a rule which doesn't fail the operation is audited as it fires
+    start(r#"{"type":"latency","methods":["read"],"percent":100,"latency":"1ms"}"#);
+    assert_eq!(read_to_string(&path).unwrap(), "hello");
+    start(r#"{"type":"fault","methods":["open"],"percent":100,"faults":[{"errno":5,"weight":1}]}"#);
+    let err = File::open(&path).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+    // the other tests running meanwhile are audited as well
+    let records: Vec<serde_json::Value> = read_to_string(&log.0)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .filter(|record: &serde_json::Value| record["experiment"] == "audit")
+        .collect();
+    let (last, fired) = records.split_last().unwrap();
+    let (fault, delayed) = fired.split_last().unwrap();
+    assert!(!delayed.is_empty());
+    for record in delayed {
+        assert_eq!(record["op"], "read");
+        assert_eq!(record["action"], "latency");
+    }
+    // a fault rule fires, then fails the operation
+    assert_eq!(fault["action"], "fault");
+    assert_eq!(last["op"], "open");
+    assert_eq!(last["experiment"], "audit");
+    assert_eq!(last["rule"], 0);
+    assert_eq!(last["action"], "EIO");
+    assert!(last["pid"].is_u64());
+}
+
+#[test]
+fn quota_tracks_usage() {
+    let (test_path, hookfs, _session) = init_with_hookfs("quota_tracks_usage");
+    let dir = test_path.join("dir");
+    std::fs::create_dir(&dir).unwrap();
+    install(
+        &hookfs,
+        &format!(
+            r#"[{{"type":"quota","quotas":[{{"path":"{}","bytes":10,"inodes":2}}]}}]"#,
+            dir.display()
+        ),
+    );
+    // the usage of the empty subtree is walked in the background
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    // overwriting a file doesn't allocate more bytes
+    let path = dir.join("file");
+    for _ in 0..3 {
+        write(&path, "0123456789").unwrap();
+    }
+    let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+    let err = file.write(b"a").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EDQUOT));
+
+    // the truncated bytes are freed
+    file.set_len(5).unwrap();
+    file.write_all(b"abcde").unwrap();
+    drop(file);
+
+    write(dir.join("second"), "").unwrap();
+    let err = write(dir.join("third"), "").unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EDQUOT));
+
+    // the unlinked and the renamed out entries are freed
+    std::fs::remove_file(dir.join("second")).unwrap();
+    std::fs::rename(&path, test_path.join("file")).unwrap();
+    write(dir.join("third"), "0123456789").unwrap();
+    write(dir.join("fourth"), "").unwrap();
+}
+
+#[test]
+fn listing_hide_phantom() {
+    let (test_path, hookfs, _session) = init_with_hookfs("listing_hide_phantom");
+    write(test_path.join("file"), "hello").unwrap();
+    write(test_path.join("secret"), "hello").unwrap();
+
+    install(
+        &hookfs,
+        r#"[{"type":"listing","percent":100,"hide":["secret*"],"phantoms":["ghost"]}]"#,
+    );
+
+    let got: BTreeSet<_> = std::fs::read_dir(&test_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    let want: BTreeSet<_> = ["file", "ghost"]
+        .iter()
+        .map(|name| OsStr::new(name).to_owned())
+        .collect();
+    assert_eq!(got, want);
+    let err = std::fs::metadata(test_path.join("ghost")).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+}
+
 #[test]
 fn link() {
-    let (test_path, _) = init("parallel_file_open");
+    let (test_path, _) = init("link");
     let link = test_path.join("link");
     let target = test_path.join("target");
 
@@ -257,6 +1247,23 @@ fn link() {
     assert_eq!(st.st_nlink, 2);
 }
 
+#[test]
+fn unlink_hard_link() {
+    let (test_path, _) = init("unlink_hard_link");
+    let link = test_path.join("link");
+    let target = test_path.join("target");
+
+    write(&target, "hello").unwrap();
+    std::fs::hard_link(&target, &link).unwrap();
+    let file = File::open(&target).unwrap();
+    unistd::unlink(&target).unwrap();
+
+    assert_eq!(read_to_string(&link).unwrap(), "hello");
+    let st = stat::stat(&link).unwrap();
+    assert_eq!(st.st_nlink, 1);
+    assert_eq!(stat::fstat(file.as_raw_fd()).unwrap().st_ino, st.st_ino);
+}
+
 #[test]
 fn rename_overwrite_dest_no_exist() {
     let (test_path, _) = init("rename_overwrite_dest_no_exist");
@@ -291,6 +1298,64 @@ fn rename_overwrite(test_path: PathBuf, dest_exist: bool) {
     assert_eq!(before_ino, st.st_ino);
 }
 
+fn renameat2(from: &PathBuf, to: &PathBuf, flags: libc::c_int) -> nix::Result<()> {
+    let from = std::ffi::CString::new(from.as_os_str().as_bytes()).unwrap();
+    let to = std::ffi::CString::new(to.as_os_str().as_bytes()).unwrap();
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            from.as_ptr(),
+            libc::AT_FDCWD,
+            to.as_ptr(),
+            flags,
+        )
+    };
+    nix::errno::Errno::result(ret).map(drop)
+}
+
+#[test]
+fn rename_noreplace() {
+    let (test_path, _) = init("rename_noreplace");
+    let from = test_path.join("from");
+    let to = test_path.join("to");
+    write(&from, "from").unwrap();
+    write(&to, "to").unwrap();
+
+    let err = renameat2(&from, &to, libc::RENAME_NOREPLACE).unwrap_err();
+    assert_eq!(err.as_errno(), Some(nix::errno::Errno::EEXIST));
+    assert_eq!(read_to_string(&to).unwrap(), "to");
+}
+
+#[test]
+fn rename_exchange() {
+    let (test_path, _) = init("rename_exchange");
+    let from = test_path.join("from");
+    let to = test_path.join("to");
+    std::fs::create_dir(&from).unwrap();
+    write(from.join("file"), "from").unwrap();
+    write(&to, "to").unwrap();
+
+    renameat2(&from, &to, libc::RENAME_EXCHANGE).unwrap();
+    assert_eq!(read_to_string(&from).unwrap(), "to");
+    assert_eq!(read_to_string(to.join("file")).unwrap(), "from");
+}
+
+#[test]
+fn rename_dir_with_opened_file() {
+    let (test_path, _) = init("rename_dir_with_opened_file");
+    let from = test_path.join("from");
+    let to = test_path.join("to");
+    std::fs::create_dir(&from).unwrap();
+    write(from.join("file"), "hello").unwrap();
+
+    let file = File::open(from.join("file")).unwrap();
+    std::fs::rename(&from, &to).unwrap();
+
+    assert_eq!(file.metadata().unwrap().len(), 5);
+    assert_eq!(read_to_string(to.join("file")).unwrap(), "hello");
+}
+
 #[test]
 fn read_unlink() {
     let (test_path, _) = init("rename_overwrite_dest_no_exist");
@@ -348,6 +1413,131 @@ fn append_unlink_write() {
     assert_eq!(&output, "hello world");
 }
 
+#[test]
+fn large_write() {
+    let (test_path, _) = init("large_write");
+    let path = test_path.join("file");
+    let content: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+    let mut file = File::create(&path).unwrap();
+    file.write_all(&content).unwrap();
+    file.sync_all().unwrap();
+    drop(file);
+
+    let mut output = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut output).unwrap();
+    assert_eq!(output, content);
+}
+
+#[test]
+fn nested_lookup() {
+    let (test_path, _) = init("nested_lookup");
+    let nested = test_path.join("a").join("b").join("c");
+    std::fs::create_dir_all(&nested).unwrap();
+
+    write(nested.join("file"), "nested").unwrap();
+    let output = read_to_string(nested.join("file")).unwrap();
+    assert_eq!(output, "nested");
+
+    let stat = stat::stat(&nested).unwrap();
+    assert_eq!(stat.st_mode & libc::S_IFMT, libc::S_IFDIR);
+}
+
+#[test]
+fn same_basename_in_different_dirs() {
+    let (test_path, _) = init("same_basename_in_different_dirs");
+    for dir in ["dir1", "dir2", "dir1/dir2"].iter() {
+        std::fs::create_dir(test_path.join(dir)).unwrap();
+        write(test_path.join(dir).join("file"), dir).unwrap();
+    }
+
+    for dir in ["dir1", "dir2", "dir1/dir2"].iter() {
+        let output = read_to_string(test_path.join(dir).join("file")).unwrap();
+        assert_eq!(&output, dir);
+    }
+    let output = read_to_string(test_path.join("dir1/dir2/../file")).unwrap();
+    assert_eq!(output, "dir1");
+}
+
+// 40 bytes of filename, so 110 entries overflow a 4096 page
+#[test]
+fn read_dir() {
+    let (test_path, _) = init("read_dir");
+    let mut want = BTreeSet::new();
+    for i in 0..110 {
+        let name = format!("file{:036x}", i);
+        write(test_path.join(&name), "hello").unwrap();
+        want.insert(OsStr::new(&name).to_owned());
+
+        let got: BTreeSet<_> = std::fs::read_dir(&test_path)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(got, want);
+    }
+}
+
+// the names which aren't UTF-8 are passed through as they are
+#[test]
+fn non_utf8_names() {
+    let (test_path, _) = init("non_utf8_names");
+    let latin1 = OsStr::from_bytes(b"caf\xe9");
+    let raw = OsStr::from_bytes(b"\xff\xfe\x80name");
+
+    write(test_path.join(latin1), "latin1").unwrap();
+    std::fs::create_dir(test_path.join(raw)).unwrap();
+    write(test_path.join(raw).join(latin1), "nested").unwrap();
+    assert_eq!(
+        read_to_string(test_path.join(raw).join(latin1)).unwrap(),
+        "nested"
+    );
+
+    let names: BTreeSet<_> = std::fs::read_dir(&test_path)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    let want: BTreeSet<_> = vec![latin1.to_owned(), raw.to_owned()]
+        .into_iter()
+        .collect();
+    assert_eq!(names, want);
+
+    let renamed = OsStr::from_bytes(b"\xe9t\xe9");
+    std::fs::rename(test_path.join(latin1), test_path.join(renamed)).unwrap();
+    assert_eq!(read_to_string(test_path.join(renamed)).unwrap(), "latin1");
+    let backend = PathBuf::from("/tmp/test_mnt_backend/non_utf8_names");
+    assert!(backend.join(renamed).exists());
+    assert!(!backend.join(latin1).exists());
+}
+
+#[test]
+fn non_utf8_name_matches_rule() {
+    let (test_path, hookfs, _session) = init_with_hookfs("non_utf8_name_matches_rule");
+    let path = test_path.join(OsStr::from_bytes(b"caf\xe9"));
+    write(&path, "hello").unwrap();
+
+    install(
+        &hookfs,
+        &format!(
+            r#"[{{"type":"fault","methods":["open"],"path":"{}/*","percent":100,"faults":[{{"errno":5,"weight":1}}]}}]"#,
+            test_path.display()
+        ),
+    );
+
+    let err = File::open(&path).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EIO));
+}
+
+// readdir should pick up the file created after opendir
+#[test]
+fn read_dir_picks_up_create() {
+    let (test_path, _) = init("read_dir_picks_up_create");
+    let dir = std::fs::read_dir(&test_path).unwrap();
+
+    write(test_path.join("file"), [42]).unwrap();
+    let names: Vec<_> = dir.map(|entry| entry.unwrap().file_name()).collect();
+    assert_eq!(names, vec![OsStr::new("file").to_owned()]);
+}
+
 // func RenameOpenDir(t *testing.T, mnt string) {
 // 	if err := os.Mkdir(mnt+"/dir1", 0755); err != nil {
 // 		t.Fatalf("Mkdir: %v", err)
@@ -387,64 +1577,6 @@ fn append_unlink_write() {
 // 	}
 // }
 
-// // ReadDir creates 110 files one by one, checking that we get the expected
-// // entries after each file creation.
-// func ReadDir(t *testing.T, mnt string) {
-// 	want := map[string]bool{}
-// 	// 40 bytes of filename, so 110 entries overflows a
-// 	// 4096 page.
-// 	for i := 0; i < 110; i++ {
-// 		nm := fmt.Sprintf("file%036x", i)
-// 		want[nm] = true
-// 		if err := ioutil.WriteFile(filepath.Join(mnt, nm), []byte("hello"), 0644); err != nil {
-// 			t.Fatalf("WriteFile %q: %v", nm, err)
-// 		}
-// 		// Verify that we get the expected entries
-// 		f, err := os.Open(mnt)
-// 		if err != nil {
-// 			t.Fatalf("Open: %v", err)
-// 		}
-// 		names, err := f.Readdirnames(-1)
-// 		if err != nil {
-// 			t.Fatalf("ReadDir: %v", err)
-// 		}
-// 		f.Close()
-// 		got := map[string]bool{}
-// 		for _, e := range names {
-// 			got[e] = true
-// 		}
-// 		if len(got) != len(want) {
-// 			t.Errorf("got %d entries, want %d", len(got), len(want))
-// 		}
-// 		for k := range got {
-// 			if !want[k] {
-// 				t.Errorf("got unknown name %q", k)
-// 			}
-// 		}
-// 	}
-// }
-
-// // Readdir should pick file created after open, but before readdir.
-// func ReadDirPicksUpCreate(t *testing.T, mnt string) {
-// 	f, err := os.Open(mnt)
-// 	if err != nil {
-// 		t.Fatalf("Open: %v", err)
-// 	}
-
-// 	if err := ioutil.WriteFile(mnt+"/file", []byte{42}, 0644); err != nil {
-// 		t.Fatalf("WriteFile: %v", err)
-// 	}
-// 	names, err := f.Readdirnames(-1)
-// 	if err != nil {
-// 		t.Fatalf("ReadDir: %v", err)
-// 	}
-// 	f.Close()
-
-// 	if len(names) != 1 || names[0] != "file" {
-// 		t.Errorf("missing file created after opendir")
-// 	}
-// }
-
 // // LinkUnlinkRename implements rename with a link/unlink sequence
 // func LinkUnlinkRename(t *testing.T, mnt string) {
 // 	content := []byte("hello")